@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::vec;
+
+use kv_core::domain::{Request, Response, KV};
+use kv_core::dump::{DumpReader, DumpWriter, Header};
+use kv_core::error::KvError;
+
+use crate::client::Backend;
+use crate::connection::Connection;
+use crate::KvClient;
+
+/// 依次导出各个节点的数据，合并为一个导出文件写入 out，返回数据条数
+///
+/// 每个节点的数据都是该节点上某一时刻的快照，不同节点之间的快照时间不完全相同。
+/// 写入过程中出错时文件没有文件尾，读取时会被认为不完整。
+pub fn dump(connections: impl IntoIterator<Item = Connection>, mut out: impl Write) -> Result<u64, KvError> {
+    let mut writer: Option<DumpWriter> = None;
+
+    for mut conn in connections {
+        let first = check(conn.call(&Request::Dump)?)?;
+        let chunks = Chunks { more: first.more, lines: first.values.into_iter(), conn: &mut conn };
+        let reader = DumpReader::new(chunks)?;
+
+        // 使用第一个节点的导出时间
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => {
+                let (new, header) = DumpWriter::new(reader.header().created);
+                write_line(&mut out, &header)?;
+                writer.insert(new)
+            }
+        };
+
+        for record in reader {
+            let record = record?;
+            write_line(&mut out, &writer.record(&record.ns, &record.key, &record.value))?;
+        }
+    }
+
+    let Some(writer) = writer else {
+        return Err(KvError::Internal(String::from("No available node.")));
+    };
+    let count = writer.count();
+    write_line(&mut out, &writer.finish())?;
+    out.flush().map_err(io_error)?;
+
+    Ok(count)
+}
+
+/// 完整读取一遍导出文件，检查格式和校验和
+pub fn verify(reader: impl BufRead) -> Result<(Header, u64), KvError> {
+    let reader = DumpReader::new(lines(reader))?;
+    let header = reader.header().clone();
+
+    let mut count = 0;
+    for record in reader {
+        record?;
+        count += 1;
+    }
+    Ok((header, count))
+}
+
+/// 把导出文件中的数据写入 kv-server，返回写入的条数
+///
+/// `client_for` 返回访问指定命名空间的客户端，每个命名空间的数据攒够 batch 条后发送一次 MSet。
+/// 写入前应该先用 [verify] 检查文件，这里读到错误时已经写入的数据不会回滚。
+pub fn restore<B: Backend>(
+    reader: impl BufRead,
+    mut client_for: impl FnMut(&str) -> KvClient<B>,
+    batch: usize,
+) -> Result<u64, KvError> {
+    // 命名空间 -> (客户端, 待写入的数据)
+    let mut pending: HashMap<String, (KvClient<B>, Vec<KV>)> = HashMap::new();
+    let mut count = 0;
+
+    for record in DumpReader::new(lines(reader))? {
+        let record = record?;
+        let (client, kvs) = pending.entry(record.ns.clone()).or_insert_with(|| (client_for(&record.ns), vec![]));
+        kvs.push(KV { key: record.key, value: record.value });
+        count += 1;
+
+        if kvs.len() >= batch.max(1) {
+            check(client.execute(Request::MSet { kvs: std::mem::take(kvs) })?)?;
+        }
+    }
+
+    for (mut client, kvs) in pending.into_values() {
+        if !kvs.is_empty() {
+            check(client.execute(Request::MSet { kvs })?)?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// 把分段返回的 Response 展开为导出文件中的行
+struct Chunks<'a> {
+    conn: &'a mut Connection,
+    lines: vec::IntoIter<String>,
+    more: bool,
+}
+
+impl Iterator for Chunks<'_> {
+    type Item = Result<String, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.lines.next() {
+                return Some(Ok(line));
+            }
+            if !self.more {
+                return None;
+            }
+
+            match self.conn.recv().and_then(check) {
+                Ok(res) => {
+                    self.more = res.more;
+                    self.lines = res.values.into_iter();
+                }
+                Err(e) => {
+                    self.more = false;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn lines(reader: impl BufRead) -> impl Iterator<Item = Result<String, KvError>> {
+    reader.lines().map(|line| line.map_err(io_error))
+}
+
+fn write_line(out: &mut impl Write, line: &str) -> Result<(), KvError> {
+    writeln!(out, "{line}").map_err(io_error)
+}
+
+fn check(res: Response) -> Result<Response, KvError> {
+    if res.code != 0 {
+        return Err(KvError::Internal(format!("({}) {}", res.code, res.message)));
+    }
+    Ok(res)
+}
+
+fn io_error(e: std::io::Error) -> KvError {
+    KvError::Internal(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use kv_core::domain::Request;
+    use kv_core::dump::DumpWriter;
+    use kv_core::error::KvError;
+
+    use crate::backup::{restore, verify};
+    use crate::testing::FakeBackend;
+    use crate::KvClient;
+
+    fn dump_file(records: &[(&str, &str)]) -> String {
+        let (mut writer, header) = DumpWriter::new(1700000000);
+        let mut lines = vec![header];
+        for (ns, key) in records {
+            lines.push(writer.record(ns, key, "v"));
+        }
+        lines.push(writer.finish());
+        lines.join("\n") + "\n"
+    }
+
+    #[test]
+    fn restore_should_batch_by_namespace() {
+        let file = dump_file(&[("a", "k1"), ("a", "k2"), ("a", "k3"), ("b", "k1")]);
+        assert_eq!(4, verify(file.as_bytes()).unwrap().1);
+
+        // 每个命名空间的客户端使用以命名空间为名的节点，共用一份请求记录
+        let requests = Rc::new(RefCell::new(vec![]));
+        let client_for = |ns: &str| KvClient::with_backend(&[ns], FakeBackend::recording(&requests));
+        assert_eq!(Ok(4), restore(file.as_bytes(), client_for, 2));
+
+        let mut batches: Vec<(String, usize)> = requests.borrow().iter()
+            .filter_map(|(ns, request)| match request {
+                Request::MSet { kvs } => Some((ns.clone(), kvs.len())),
+                _ => None,
+            })
+            .collect();
+        batches.sort();
+        assert_eq!(vec![(String::from("a"), 1), (String::from("a"), 2), (String::from("b"), 1)], batches);
+    }
+
+    #[test]
+    fn corrupted_file_should_fail_verification() {
+        let file = dump_file(&[("a", "k1")]).replace("k1", "k2");
+        assert!(matches!(verify(file.as_bytes()), Err(KvError::InvalidDump(_))));
+    }
+}
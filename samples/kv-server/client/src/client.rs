@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+pub use kv_core::backend::Backend;
+use kv_core::codec::Codec;
+use kv_core::compress;
+use kv_core::domain::{Request, Response, KV};
+use kv_core::error::KvError;
+
+use crate::connection::Connection;
+use crate::ring::HashRing;
+
+/// 被限流时默认的最大重试次数
+pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+/// 单次重试的最长等待时间
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// 通过 TCP 访问 kv-server，每个节点复用一个连接
+#[derive(Default)]
+pub struct TcpBackend {
+    connections: HashMap<String, Connection>,
+    // 建立连接后使用的用户名和密码
+    auth: Option<(String, String)>,
+    // 建立连接后选择的命名空间
+    db: Option<String>,
+    // 建立连接后协商压缩
+    compression: bool,
+    // 请求的编码格式
+    codec: Codec,
+}
+
+impl TcpBackend {
+    /// 每个新建立的连接都先进行认证
+    pub fn with_auth(username: &str, password: &str) -> Self {
+        Self {
+            auth: Some((username.to_string(), password.to_string())),
+            ..Default::default()
+        }
+    }
+
+    /// 每个新建立的连接都切换到命名空间 db
+    pub fn with_db(mut self, db: &str) -> Self {
+        self.db = Some(db.to_string());
+        self
+    }
+
+    /// 每个新建立的连接都与服务端协商压缩，服务端不支持时不压缩
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// 使用 codec 编码请求，服务端使用相同的格式返回响应
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// 建立到 node 的连接，协商压缩，完成认证并切换命名空间
+    pub fn connect(&self, node: &str) -> Result<Connection, KvError> {
+        let mut conn = Connection::connect(node)?;
+        conn.set_codec(self.codec);
+
+        if self.compression {
+            conn.hello(compress::DEFAULT_THRESHOLD)?;
+        }
+
+        if let Some((username, password)) = &self.auth {
+            let request = Request::Auth { username: username.clone(), password: password.clone() };
+            let res = conn.call(&request)?;
+            if res.code != 0 {
+                return Err(KvError::AuthFailed);
+            }
+        }
+
+        if let Some(db) = &self.db {
+            let res = conn.call(&Request::Select { db: db.clone() })?;
+            if res.code != 0 {
+                return Err(KvError::Forbidden(db.clone()));
+            }
+        }
+        Ok(conn)
+    }
+}
+
+impl Backend for TcpBackend {
+    fn call(&mut self, node: &str, request: Request) -> Result<Response, KvError> {
+        if !self.connections.contains_key(node) {
+            let conn = self.connect(node)?;
+            self.connections.insert(node.to_string(), conn);
+        }
+
+        let res = self.connections.get_mut(node).unwrap().call(&request);
+        if res.is_err() {
+            // 连接出错后丢弃，下次请求重新建立
+            self.connections.remove(node);
+        }
+        res
+    }
+}
+
+/// kv-server 客户端
+///
+/// 通过一致性哈希把 key 路由到不同的节点，多 key 命令按节点拆分后再合并为一个 Response。
+/// 被服务端限流（429）时按服务端给出的等待时间重试。
+pub struct KvClient<B = TcpBackend> {
+    ring: HashRing,
+    // 保留节点的添加顺序，不含 key 的命令发往第一个节点
+    nodes: Vec<String>,
+    pub(crate) backend: B,
+    max_retries: usize,
+}
+
+impl KvClient<TcpBackend> {
+    pub fn connect<S: AsRef<str>>(nodes: &[S]) -> Self {
+        Self::with_backend(nodes, TcpBackend::default())
+    }
+}
+
+impl<B: Backend> KvClient<B> {
+    pub fn with_backend<S: AsRef<str>>(nodes: &[S], backend: B) -> Self {
+        let mut client = Self {
+            ring: HashRing::default(),
+            nodes: vec![],
+            backend,
+            max_retries: DEFAULT_MAX_RETRIES,
+        };
+
+        for node in nodes {
+            client.add_node(node.as_ref());
+        }
+        client
+    }
+
+    /// 被限流时的最大重试次数，0 表示不重试
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn add_node(&mut self, node: &str) {
+        if !self.nodes.iter().any(|n| n == node) {
+            self.nodes.push(node.to_string());
+            self.ring.add(node);
+        }
+    }
+
+    pub fn remove_node(&mut self, node: &str) {
+        self.nodes.retain(|n| n != node);
+        self.ring.remove(node);
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// key 所在的节点
+    pub fn node_for(&self, key: &str) -> Result<&str, KvError> {
+        self.ring.get(key).ok_or_else(|| KvError::Internal(String::from("No available node.")))
+    }
+
+    /// 执行命令，按 key 路由到对应节点
+    pub fn execute(&mut self, request: Request) -> Result<Response, KvError> {
+        match request {
+            Request::Get { key } => {
+                let node = self.node_for(&key)?.to_string();
+                self.call(&node, Request::Get { key })
+            }
+            Request::Set { kv } => {
+                let node = self.node_for(&kv.key)?.to_string();
+                self.call(&node, Request::Set { kv })
+            }
+            Request::MGet { keys } => self.mget(keys),
+            Request::MSet { kvs } => self.mset(kvs),
+            Request::Del { keys } => self.del(keys),
+            // 命名空间中的 key 分布在所有节点上
+            Request::FlushDb => {
+                for node in self.nodes.clone() {
+                    let res = self.call(&node, Request::FlushDb)?;
+                    if res.code != 0 {
+                        return Ok(res);
+                    }
+                }
+                Ok(Response::default())
+            }
+            // 脚本在单个节点上原子执行，访问的 key 必须在同一个节点上
+            Request::Eval { ref keys, .. } | Request::EvalSha { ref keys, .. } if !keys.is_empty() => {
+                let shards = self.split(keys)?;
+                if shards.len() > 1 {
+                    return Err(KvError::ScriptError(String::from("Keys of a script must be on the same node.")));
+                }
+                let node = shards[0].0.clone();
+                self.call(&node, request)
+            }
+            request => {
+                let node = self.nodes.first()
+                    .cloned()
+                    .ok_or_else(|| KvError::Internal(String::from("No available node.")))?;
+                self.call(&node, request)
+            }
+        }
+    }
+
+    /// 在指定节点上执行命令，不经过路由
+    pub fn execute_on(&mut self, node: &str, request: Request) -> Result<Response, KvError> {
+        self.call(node, request)
+    }
+
+    /// 发送请求，被限流时退避重试
+    fn call(&mut self, node: &str, request: Request) -> Result<Response, KvError> {
+        let mut attempt = 0;
+
+        loop {
+            let res = self.backend.call(node, request.clone())?;
+            if res.code != 429 || attempt >= self.max_retries {
+                return Ok(res);
+            }
+
+            attempt += 1;
+            thread::sleep(backoff(&res, attempt));
+        }
+    }
+
+    fn mget(&mut self, keys: Vec<String>) -> Result<Response, KvError> {
+        let mut values: Vec<Option<String>> = vec![None; keys.len()];
+
+        for (node, positions) in self.split(&keys)? {
+            let shard_keys: Vec<String> = positions.iter().map(|i| keys[*i].clone()).collect();
+            let res = self.call(&node, Request::MGet { keys: shard_keys.clone() })?;
+            if res.code != 0 {
+                return Ok(res);
+            }
+
+            if res.values.len() == positions.len() {
+                for (i, value) in positions.into_iter().zip(res.values) {
+                    values[i] = Some(value);
+                }
+                continue;
+            }
+
+            // 有 key 不存在时服务端不返回对应的值，无法对齐位置，退化为逐个 Get
+            for (i, key) in positions.into_iter().zip(shard_keys) {
+                let res = self.call(&node, Request::Get { key })?;
+                if res.code != 0 {
+                    return Ok(res);
+                }
+                values[i] = res.values.into_iter().next();
+            }
+        }
+
+        Ok(Response::from(values.into_iter().flatten().collect::<Vec<_>>()))
+    }
+
+    fn mset(&mut self, kvs: Vec<KV>) -> Result<Response, KvError> {
+        let keys: Vec<String> = kvs.iter().map(|kv| kv.key.clone()).collect();
+        let shards: Vec<_> = self.split(&keys)?
+            .into_iter()
+            .map(|(node, positions)| {
+                let kvs = positions.into_iter().map(|i| kvs[i].clone()).collect();
+                (node, Request::MSet { kvs })
+            })
+            .collect();
+
+        self.merge(shards)
+    }
+
+    fn del(&mut self, keys: Vec<String>) -> Result<Response, KvError> {
+        let shards: Vec<_> = self.split(&keys)?
+            .into_iter()
+            .map(|(node, positions)| {
+                let keys = positions.into_iter().map(|i| keys[i].clone()).collect();
+                (node, Request::Del { keys })
+            })
+            .collect();
+
+        self.merge(shards)
+    }
+
+    /// 依次发送到各个节点，遇到错误立即返回，否则合并所有节点的 values
+    fn merge(&mut self, requests: impl IntoIterator<Item = (String, Request)>) -> Result<Response, KvError> {
+        let mut values = vec![];
+        for (node, request) in requests {
+            let res = self.call(&node, request)?;
+            if res.code != 0 {
+                return Ok(res);
+            }
+            values.extend(res.values);
+        }
+
+        Ok(Response::from(values))
+    }
+
+    /// 按节点对 key 分组，返回每个节点上 key 在原列表中的位置
+    fn split(&self, keys: &[String]) -> Result<Vec<(String, Vec<usize>)>, KvError> {
+        let mut shards: Vec<(String, Vec<usize>)> = vec![];
+
+        for (i, key) in keys.iter().enumerate() {
+            let node = self.node_for(key)?;
+            match shards.iter_mut().find(|(n, _)| n == node) {
+                Some((_, positions)) => positions.push(i),
+                None => shards.push((node.to_string(), vec![i])),
+            }
+        }
+
+        Ok(shards)
+    }
+}
+
+/// 优先使用服务端给出的等待时间，否则按指数退避
+fn backoff(res: &Response, attempt: usize) -> Duration {
+    let hint = res.values.first()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_millis);
+
+    let fallback = Duration::from_millis(50 * (1 << attempt.min(6)));
+    hint.unwrap_or(fallback).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use kv_core::domain::{Request, Response, KV};
+    use kv_core::error::KvError;
+
+    use crate::client::KvClient;
+    use crate::testing::FakeBackend;
+
+    fn client() -> KvClient<FakeBackend> {
+        KvClient::with_backend(&["n1", "n2", "n3"], FakeBackend::default())
+    }
+
+    fn kv(i: usize) -> KV {
+        KV { key: format!("k{i}"), value: format!("v{i}") }
+    }
+
+    #[test]
+    fn flushdb_should_be_sent_to_all_nodes() {
+        let mut client = client();
+        for i in 0..10 {
+            client.execute(Request::Set { kv: kv(i) }).unwrap();
+        }
+        client.backend.requests.borrow_mut().clear();
+        client.execute(Request::FlushDb).unwrap();
+
+        let requests = client.backend.requests.borrow();
+        let nodes: Vec<&str> = requests.iter().map(|(node, _)| node.as_str()).collect();
+        assert_eq!(vec!["n1", "n2", "n3"], nodes);
+        assert!(client.backend.data.values().all(|data| data.is_empty()));
+    }
+
+    #[test]
+    fn script_should_be_routed_by_keys() {
+        let mut client = client();
+        let eval = |keys: Vec<String>| Request::Eval { script: String::from("return 1"), keys, args: vec![] };
+
+        // 找到分布在不同节点上的两个 key
+        let k1 = String::from("k1");
+        let k2 = (2..100).map(|i| format!("k{i}"))
+            .find(|k| client.node_for(k).unwrap() != client.node_for(&k1).unwrap())
+            .unwrap();
+
+        client.execute(eval(vec![k1.clone()])).unwrap();
+        let node = client.node_for(&k1).unwrap().to_string();
+        assert_eq!(node, client.backend.requests.borrow().last().unwrap().0);
+
+        let res = client.execute(eval(vec![k1, k2]));
+        assert!(matches!(res, Err(KvError::ScriptError(_))));
+    }
+
+    #[test]
+    fn keys_should_be_routed_by_ring() {
+        let mut client = client();
+        for i in 0..30 {
+            client.execute(Request::Set { kv: kv(i) }).unwrap();
+        }
+
+        for (node, data) in &client.backend.data {
+            for key in data.keys() {
+                assert_eq!(node, client.node_for(key).unwrap());
+            }
+        }
+        assert_eq!(3, client.backend.data.len());
+    }
+
+    #[test]
+    fn mget_should_keep_order() {
+        let mut client = client();
+        client.execute(Request::MSet { kvs: (0..10).map(kv).collect() }).unwrap();
+
+        let keys: Vec<String> = (0..10).rev().map(|i| format!("k{i}")).collect();
+        let res = client.execute(Request::MGet { keys }).unwrap();
+
+        let expected: Vec<String> = (0..10).rev().map(|i| format!("v{i}")).collect();
+        assert_eq!(expected, res.values);
+
+        // 每个节点只收到一次 MGet
+        assert_eq!(3, client.backend.count(|r| matches!(r, Request::MGet { .. })));
+    }
+
+    #[test]
+    fn mget_should_skip_missing_keys() {
+        let mut client = client();
+        client.execute(Request::MSet { kvs: vec![kv(1), kv(3), kv(5)] }).unwrap();
+
+        let keys = (0..6).map(|i| format!("k{i}")).collect();
+        let res = client.execute(Request::MGet { keys }).unwrap();
+        assert_eq!(vec!["v1", "v3", "v5"], res.values);
+    }
+
+    #[test]
+    fn del_should_be_split_by_node() {
+        let mut client = client();
+        client.execute(Request::MSet { kvs: (0..10).map(kv).collect() }).unwrap();
+
+        let keys = (0..10).map(|i| format!("k{i}")).collect();
+        let res = client.execute(Request::Del { keys }).unwrap();
+        assert_eq!(Response::default(), res);
+        assert!(client.backend.data.values().all(|data| data.is_empty()));
+    }
+
+    #[test]
+    fn throttled_request_should_be_retried() {
+        let mut client = client();
+        client.backend.throttled = 2;
+
+        let res = client.execute(Request::Set { kv: kv(1) }).unwrap();
+        assert_eq!(0, res.code);
+        assert_eq!(3, client.backend.requests.borrow().len());
+    }
+
+    #[test]
+    fn should_give_up_after_max_retries() {
+        let mut client = client().with_max_retries(1);
+        client.backend.throttled = 5;
+
+        let res = client.execute(Request::Get { key: String::from("k1") }).unwrap();
+        assert_eq!(429, res.code);
+        assert_eq!(2, client.backend.requests.borrow().len());
+    }
+
+    #[test]
+    fn error_should_be_returned() {
+        let mut client = client();
+        let res = client.execute(Request::RemoveNode { id: 1 }).unwrap();
+        assert_eq!(400, res.code);
+        assert_eq!("n1", client.backend.requests.borrow()[0].0);
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use bytes::BytesMut;
+use kv_core::codec::{Codec, Message};
+use kv_core::compress;
+use kv_core::domain::{Envelope, Request, Response, TraceContext};
+use kv_core::error::KvError;
+use kv_core::frame;
+
+/// 与单个 kv-server 节点之间的连接
+pub struct Connection {
+    stream: TcpStream,
+    buf: BytesMut,
+    // 与服务端协商后的压缩阈值
+    compression: Option<usize>,
+    // 请求的编码格式，响应的格式由帧头决定
+    codec: Codec,
+}
+
+impl Connection {
+    pub fn connect(addr: &str) -> Result<Self, KvError> {
+        let stream = TcpStream::connect(addr).map_err(io_error)?;
+
+        Ok(Self {
+            stream,
+            buf: BytesMut::with_capacity(1024),
+            compression: None,
+            codec: Codec::Json,
+        })
+    }
+
+    /// 之后的请求使用 codec 编码，旧版本的服务端只支持 JSON
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// 与服务端协商压缩，之后不小于 threshold 的请求和响应使用 lz4 压缩
+    ///
+    /// 服务端不支持或关闭了压缩时返回 false，连接继续以不压缩的方式使用。
+    pub fn hello(&mut self, threshold: usize) -> Result<bool, KvError> {
+        let res = self.call(&Request::Hello { compression: vec![compress::LZ4.to_string()] })?;
+        let enabled = res.code == 0 && res.values.iter().any(|c| c == compress::LZ4);
+        self.compression = enabled.then_some(threshold);
+        Ok(enabled)
+    }
+
+    /// 发送请求并等待响应
+    pub fn call(&mut self, request: &Request) -> Result<Response, KvError> {
+        // JSON 格式发送不带信封的请求，兼容旧版本的服务端
+        let payload = match self.codec {
+            Codec::Json => self.encode(request)?,
+            Codec::Protobuf => self.encode(&Envelope::from(request.clone()))?,
+        };
+        self.round_trip(&payload)
+    }
+
+    /// 同 call，附带调用方的链路追踪上下文，服务端的 span 会挂在该上下文下
+    pub fn call_traced(&mut self, request: &Request, trace: &TraceContext) -> Result<Response, KvError> {
+        let envelope = Envelope { request: request.clone(), trace: Some(trace.clone()) };
+        let payload = self.encode(&envelope)?;
+        self.round_trip(&payload)
+    }
+
+    fn encode<T: Message>(&self, value: &T) -> Result<Vec<u8>, KvError> {
+        let mut payload = vec![];
+        self.codec.encode(value, &mut payload)?;
+        Ok(payload)
+    }
+
+    fn round_trip(&mut self, payload: &[u8]) -> Result<Response, KvError> {
+        let mut out = BytesMut::with_capacity(frame::HEADER_LEN + payload.len());
+        frame::encode_with(payload, self.codec, self.compression, &mut out);
+        self.stream.write_all(&out).map_err(io_error)?;
+        self.recv()
+    }
+
+    /// 读取下一个响应，订阅后用于接收推送的消息
+    pub fn recv(&mut self) -> Result<Response, KvError> {
+        loop {
+            if let Some((codec, payload)) = frame::decode_with(&mut self.buf, frame::MAX_FRAME_SIZE)? {
+                return codec.decode(&payload);
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = self.stream.read(&mut chunk).map_err(io_error)?;
+            if n == 0 {
+                return Err(KvError::Internal(String::from("Connection closed by server.")));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> KvError {
+    KvError::Internal(e.to_string())
+}
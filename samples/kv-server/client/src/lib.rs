@@ -0,0 +1,9 @@
+pub mod backup;
+pub mod client;
+pub mod connection;
+pub mod ring;
+#[cfg(test)]
+mod testing;
+pub mod transfer;
+
+pub use client::KvClient;
@@ -1,3 +1,354 @@
-fn main() {
-    println!("Hello, world!");
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use kv_client::backup;
+use kv_client::transfer::{self, Event, Format};
+use kv_client::client::TcpBackend;
+use kv_client::KvClient;
+use kv_core::codec::Codec;
+use kv_core::domain::{Request, Response, ServerInfo, SlowLogEntry, KV};
+
+#[derive(Parser)]
+#[command(name = "kv-client", about = "kv-server 命令行客户端")]
+struct Cli {
+    /// kv-server 节点地址，多个节点用逗号分隔，key 按一致性哈希分布到各个节点
+    #[arg(short, long, value_delimiter = ',', default_value = "127.0.0.1:6736")]
+    nodes: Vec<String>,
+
+    /// 认证用户名，服务端按用户限流
+    #[arg(short, long, requires = "password")]
+    user: Option<String>,
+
+    #[arg(short, long)]
+    password: Option<String>,
+
+    /// 使用的命名空间
+    #[arg(long)]
+    db: Option<String>,
+
+    /// 与服务端协商压缩，较大的请求和响应使用 lz4 压缩
+    #[arg(long)]
+    compress: bool,
+
+    /// 使用 protobuf 编码请求和响应
+    #[arg(long)]
+    protobuf: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    Get { key: String },
+    #[command(name = "mget")]
+    MGet { keys: Vec<String> },
+    Set { key: String, value: String },
+    /// 参数格式为 key=value
+    #[command(name = "mset")]
+    MSet { kvs: Vec<String> },
+    Del { keys: Vec<String> },
+    /// 删除当前命名空间中的所有 key
+    #[command(name = "flushdb")]
+    FlushDb,
+    /// 发布消息，返回收到消息的订阅者数量
+    Publish { channel: String, message: String },
+    /// 订阅频道，持续输出收到的消息
+    Subscribe { channels: Vec<String> },
+    /// 按模式订阅频道，支持 `*` 和 `?` 通配符
+    #[command(name = "psubscribe")]
+    PSubscribe { patterns: Vec<String> },
+    /// 执行 Lua 脚本，例如 eval "return kv.get(KEYS[1])" --keys k1
+    Eval {
+        script: String,
+        #[arg(long, value_delimiter = ',')]
+        keys: Vec<String>,
+        #[arg(long, value_delimiter = ',')]
+        args: Vec<String>,
+    },
+    /// 执行已经缓存的脚本
+    #[command(name = "evalsha")]
+    EvalSha {
+        sha1: String,
+        #[arg(long, value_delimiter = ',')]
+        keys: Vec<String>,
+        #[arg(long, value_delimiter = ',')]
+        args: Vec<String>,
+    },
+    /// 查看每个节点的运行状态
+    Info,
+    /// 查看每个节点最近的慢请求
+    #[command(name = "slowlog")]
+    SlowLog {
+        #[arg(default_value_t = 10)]
+        count: usize,
+    },
+    /// 导出所有节点上有权限访问的数据，集群模式下只需要指定一个节点
+    Dump { file: PathBuf },
+    /// 导出当前命名空间中的所有 key，格式为 jsonl 或 csv
+    Export {
+        file: PathBuf,
+        #[arg(long, default_value = "jsonl")]
+        format: Format,
+        /// 每次 Scan、MGet 的 key 数量
+        #[arg(long, default_value_t = 1000)]
+        batch: usize,
+    },
+    /// 导入 export 导出的文件，格式错误的数据输出行号后跳过
+    Import {
+        file: PathBuf,
+        #[arg(long, default_value = "jsonl")]
+        format: Format,
+        /// 每次 MSet 的 key 数量
+        #[arg(long, default_value_t = 1000)]
+        batch: usize,
+        /// 从上次中断的位置继续导入，进度保存在 <file>.progress 中
+        #[arg(long)]
+        resume: bool,
+    },
+    /// 导入 dump 导出的文件，默认要求目标中没有数据
+    Restore {
+        file: PathBuf,
+        /// 合并到已有的数据中，相同的 key 被覆盖
+        #[arg(long)]
+        merge: bool,
+        /// 每次 MSet 写入的条数
+        #[arg(long, default_value_t = 1000)]
+        batch: usize,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut client = KvClient::with_backend(&cli.nodes, backend(&cli, cli.db.as_deref()));
+
+    let request = match cli.command {
+        Command::Get { key } => Request::Get { key },
+        Command::MGet { keys } => Request::MGet { keys },
+        Command::Set { key, value } => Request::Set { kv: KV { key, value } },
+        Command::MSet { kvs } => Request::MSet { kvs: kvs.iter().map(|s| parse_kv(s)).collect::<Result<_>>()? },
+        Command::Del { keys } => Request::Del { keys },
+        Command::FlushDb => Request::FlushDb,
+        Command::Publish { channel, message } => Request::Publish { channel, message },
+        Command::Eval { script, keys, args } => Request::Eval { script, keys, args },
+        Command::EvalSha { sha1, keys, args } => Request::EvalSha { sha1, keys, args },
+        Command::Subscribe { ref channels } => return subscribe(&cli, Request::Subscribe { channels: channels.clone() }),
+        Command::PSubscribe { ref patterns } => return subscribe(&cli, Request::PSubscribe { patterns: patterns.clone() }),
+        Command::Info => {
+            for node in client.nodes().to_vec() {
+                let response = client.execute_on(&node, Request::Info)?;
+                match &response.info {
+                    Some(info) => print_info(&node, info),
+                    None => print(&response),
+                }
+            }
+            return Ok(());
+        }
+        Command::SlowLog { count } => {
+            for node in client.nodes().to_vec() {
+                let response = client.execute_on(&node, Request::SlowLog { count })?;
+                match &response.slow_log {
+                    Some(entries) => print_slow_log(&node, entries),
+                    None => print(&response),
+                }
+            }
+            return Ok(());
+        }
+        Command::Dump { ref file } => {
+            // 导出所有命名空间，不切换到 --db
+            let backend = backend(&cli, None);
+            let connections = cli.nodes.iter().map(|node| backend.connect(node)).collect::<Result<Vec<_>, _>>()?;
+            let count = backup::dump(connections, BufWriter::new(File::create(file)?))?;
+            println!("Dumped {count} keys to {}.", file.display());
+            return Ok(());
+        }
+        Command::Export { file, format, batch } => {
+            let out = BufWriter::new(File::create(&file)?);
+            let count = transfer::export(&mut client, format, out, batch, |n| eprint!("\rExported {n} keys."))?;
+            eprintln!();
+            println!("Exported {count} keys to {}.", file.display());
+            return Ok(());
+        }
+        Command::Import { file, format, batch, resume } => return import(&mut client, &file, format, batch, resume),
+        Command::Restore { ref file, merge, batch } => return restore(&cli, &mut client, file, merge, batch),
+    };
+
+    let response = client.execute(request)?;
+    print(&response);
+
+    Ok(())
+}
+
+/// 每写入一批数据记录一次已经处理到的行号，中断后可以从该行之后继续
+fn import(client: &mut KvClient, file: &Path, format: Format, batch: usize, resume: bool) -> Result<()> {
+    let progress = PathBuf::from(format!("{}.progress", file.display()));
+    let skip = match resume {
+        true => std::fs::read_to_string(&progress).ok().and_then(|s| s.trim().parse().ok()).unwrap_or(0),
+        false => 0,
+    };
+    if skip > 0 {
+        println!("Resume from line {}.", skip + 1);
+    }
+
+    let mut malformed = 0;
+    let mut save_error = None;
+    let stats = transfer::import(client, format, BufReader::new(File::open(file)?), batch, skip, |event| match event {
+        Event::Malformed { line, reason } => {
+            malformed += 1;
+            eprintln!("\rline {line}: {reason}");
+        }
+        Event::Committed { line, imported } => {
+            if let Err(e) = std::fs::write(&progress, line.to_string()) {
+                save_error.get_or_insert(e);
+            }
+            eprint!("\rImported {imported} keys, {malformed} malformed.");
+        }
+    })?;
+    eprintln!();
+
+    if let Some(e) = save_error {
+        eprintln!("Save progress to {} failed: {e}", progress.display());
+    }
+    let _ = std::fs::remove_file(&progress);
+    println!("Imported {} keys, skipped {} malformed records.", stats.imported, stats.malformed);
+    Ok(())
+}
+
+fn backend(cli: &Cli, db: Option<&str>) -> TcpBackend {
+    let backend = match (&cli.user, &cli.password) {
+        (Some(user), Some(password)) => TcpBackend::with_auth(user, password),
+        _ => TcpBackend::default(),
+    };
+    let backend = if cli.compress { backend.with_compression() } else { backend };
+    let backend = if cli.protobuf { backend.with_codec(Codec::Protobuf) } else { backend };
+    match db {
+        Some(db) => backend.with_db(db),
+        None => backend,
+    }
+}
+
+/// 先完整校验文件，再按命名空间写入，校验失败时不写入任何数据
+fn restore(cli: &Cli, client: &mut KvClient, file: &Path, merge: bool, batch: usize) -> Result<()> {
+    let (header, count) = backup::verify(BufReader::new(File::open(file)?))?;
+    println!("Verified {count} keys dumped at {}.", header.created);
+
+    if !merge {
+        for node in client.nodes().to_vec() {
+            // Info 中 key 的数量可能大于实际的数量，对统计到的每个命名空间用 Scan 确认其中是否还有 key
+            let namespaces = client.execute_on(&node, Request::Info)?.info.map(|info| info.storage.namespaces).unwrap_or_default();
+            for ns in namespaces.keys() {
+                let mut scanner = KvClient::with_backend(&[&node], backend(cli, Some(ns)));
+                let response = check(scanner.execute_on(&node, Request::Scan { after: None, count: 1 })?)?;
+                if !response.values.is_empty() {
+                    return Err(anyhow!("Node {node} has keys in {ns}, use --merge to restore into existing data."));
+                }
+            }
+        }
+    }
+
+    let client_for = |ns: &str| KvClient::with_backend(&cli.nodes, backend(cli, Some(ns)));
+    let restored = backup::restore(BufReader::new(File::open(file)?), client_for, batch)?;
+    println!("Restored {restored} keys.");
+    Ok(())
+}
+
+/// 发布订阅只在单个节点的同一个命名空间内生效，与 publish 一样使用第一个节点和 --db 指定的命名空间
+fn subscribe(cli: &Cli, request: Request) -> Result<()> {
+    let node = cli.nodes.first().ok_or_else(|| anyhow!("No node configured."))?;
+    let mut conn = backend(cli, cli.db.as_deref()).connect(node)?;
+    check(conn.call(&request)?)?;
+
+    loop {
+        let response = check(conn.recv()?)?;
+        if let Some(message) = response.push {
+            match message.pattern {
+                Some(pattern) => println!("[{pattern}] {}: {}", message.channel, message.payload),
+                None => println!("{}: {}", message.channel, message.payload),
+            }
+        }
+    }
+}
+
+fn check(response: Response) -> Result<Response> {
+    if response.code != 0 {
+        return Err(anyhow!("({}) {} [{}]", response.code, response.message, response.request_id));
+    }
+    Ok(response)
+}
+
+fn parse_kv(s: &str) -> Result<KV> {
+    let (key, value) = s.split_once('=').ok_or_else(|| anyhow!("Invalid key value pair: {s}"))?;
+    Ok(KV { key: key.to_string(), value: value.to_string() })
+}
+
+fn print(response: &Response) {
+    if response.code != 0 {
+        // 带上请求 id，方便在服务端日志中定位
+        eprintln!("({}) {} [{}]", response.code, response.message, response.request_id);
+        return;
+    }
+
+    for value in &response.values {
+        println!("{value}");
+    }
+}
+
+fn print_info(node: &str, info: &ServerInfo) {
+    println!("# {node}");
+    println!("version:           {}", info.version);
+    println!("uptime:            {}s", info.uptime_secs);
+    println!("connected_clients: {}", info.connected_clients);
+    println!("total_commands:    {}", info.total_commands);
+
+    println!("commands:");
+    for (command, count) in &info.commands {
+        println!("  {command:<16} {count}");
+    }
+
+    println!("storage:");
+    println!("  engine:          {}", info.storage.engine);
+    println!("  keys:            {}", info.storage.keys);
+    println!("  memory_bytes:    {}", info.storage.memory_bytes);
+    for (ns, keys) in &info.storage.namespaces {
+        println!("  db.{:<13} {keys}", format!("{ns}:"));
+    }
+    if let Some(cache) = &info.storage.cache {
+        println!("  cache_keys:      {}", cache.keys);
+        println!("  cache_bytes:     {}/{}", cache.bytes, cache.capacity_bytes);
+        println!("  cache_hits:      {}", cache.hits);
+        println!("  cache_misses:    {}", cache.misses);
+        println!("  cache_hit_ratio: {:.4}", cache.hit_ratio());
+        println!("  cache_miss_ratio:{:.4}", cache.miss_ratio());
+    }
+    if let Some(compression) = &info.storage.compression {
+        println!("  compressed:      {}/{}", compression.compressed_bytes, compression.raw_bytes);
+        println!("  compress_ratio:  {:.2}", compression.ratio());
+    }
+
+    println!("persistence:");
+    println!("  last_snapshot:   {}", optional(info.persistence.last_snapshot));
+    println!("  wal_bytes:       {}", optional(info.persistence.wal_bytes));
+
+    if let Some(compression) = &info.wire_compression {
+        println!("wire:");
+        println!("  compressed:      {}/{}", compression.compressed_bytes, compression.raw_bytes);
+        println!("  compress_ratio:  {:.2}", compression.ratio());
+    }
+}
+
+fn print_slow_log(node: &str, entries: &[SlowLogEntry]) {
+    println!("# {node}");
+    for entry in entries {
+        println!(
+            "{:<6} {:<10} {:>10}us {:<21} {} {}",
+            entry.id, entry.timestamp, entry.duration_us, entry.client, entry.request_id, entry.request
+        );
+    }
+}
+
+fn optional(value: Option<u64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_else(|| String::from("-"))
 }
@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// 每个物理节点默认的虚拟节点数
+pub const DEFAULT_REPLICAS: usize = 160;
+
+/// 一致性哈希环
+///
+/// 每个节点在环上放置 `replicas` 个虚拟节点，key 落在顺时针方向的第一个虚拟节点上。
+/// 增删节点时只有相邻区间的 key 需要迁移。
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    replicas: usize,
+    ring: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLICAS)
+    }
+}
+
+impl HashRing {
+    pub fn new(replicas: usize) -> Self {
+        Self {
+            replicas: replicas.max(1),
+            ring: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: &str) {
+        if !self.nodes.insert(node.to_string()) {
+            return;
+        }
+
+        for i in 0..self.replicas {
+            self.ring.insert(hash(format!("{node}#{i}").as_bytes()), node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        if !self.nodes.remove(node) {
+            return;
+        }
+
+        self.ring.retain(|_, n| n != node);
+    }
+
+    /// key 所在的节点，环为空时返回 None
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let h = hash(key.as_bytes());
+
+        self.ring.range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// FNV-1a 再经过 splitmix64 打散，保证不同进程、不同版本下结果一致
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::ring::HashRing;
+
+    const KEYS: usize = 10_000;
+
+    fn ring(nodes: &[&str]) -> HashRing {
+        let mut ring = HashRing::default();
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    fn assign(ring: &HashRing) -> Vec<String> {
+        (0..KEYS).map(|i| ring.get(&format!("key-{i}")).unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn empty_ring() {
+        assert_eq!(None, HashRing::default().get("k1"));
+    }
+
+    #[test]
+    fn keys_should_be_balanced() {
+        let ring = ring(&["n1", "n2", "n3", "n4"]);
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for node in assign(&ring) {
+            *counts.entry(node).or_default() += 1;
+        }
+
+        for count in counts.values() {
+            // 理想值为 2500
+            assert!(*count > 1800 && *count < 3200, "unbalanced: {counts:?}");
+        }
+    }
+
+    #[test]
+    fn adding_node_should_remap_minimal_keys() {
+        let mut ring = ring(&["n1", "n2", "n3", "n4"]);
+        let before = assign(&ring);
+
+        ring.add("n5");
+        let after = assign(&ring);
+
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+
+        // 只有迁移到新节点的 key 发生变化，比例约为 1/5
+        assert!(moved.iter().all(|(_, a)| a.as_str() == "n5"));
+        assert!(moved.len() < KEYS * 3 / 10, "moved {} keys", moved.len());
+    }
+
+    #[test]
+    fn removing_node_should_remap_only_its_keys() {
+        let mut ring = ring(&["n1", "n2", "n3", "n4"]);
+        let before = assign(&ring);
+
+        ring.remove("n2");
+        let after = assign(&ring);
+
+        for (b, a) in before.iter().zip(&after) {
+            if b != "n2" {
+                assert_eq!(b, a);
+            } else {
+                assert_ne!("n2", a);
+            }
+        }
+    }
+}
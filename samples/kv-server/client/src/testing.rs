@@ -0,0 +1,83 @@
+//! 测试中使用的 Backend，模拟多个节点
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use kv_core::domain::{Request, Response};
+use kv_core::error::KvError;
+
+use crate::client::Backend;
+
+/// 每个节点一份有序存储，记录收到的请求
+#[derive(Default)]
+pub(crate) struct FakeBackend {
+    /// 节点 -> key -> value
+    pub data: HashMap<String, BTreeMap<String, String>>,
+    /// 按顺序记录的 (节点, 请求)，多个 FakeBackend 可以共用同一份记录
+    pub requests: Rc<RefCell<Vec<(String, Request)>>>,
+    /// 前几次请求返回 429
+    pub throttled: usize,
+}
+
+impl FakeBackend {
+    /// 请求记录到 requests 中
+    pub fn recording(requests: &Rc<RefCell<Vec<(String, Request)>>>) -> Self {
+        Self { requests: requests.clone(), ..Default::default() }
+    }
+
+    /// 节点上的数据
+    pub fn node(&mut self, node: &str) -> &mut BTreeMap<String, String> {
+        self.data.entry(node.to_string()).or_default()
+    }
+
+    /// 收到的满足条件的请求数
+    pub fn count(&self, predicate: impl Fn(&Request) -> bool) -> usize {
+        self.requests.borrow().iter().filter(|(_, r)| predicate(r)).count()
+    }
+}
+
+impl Backend for FakeBackend {
+    fn call(&mut self, node: &str, request: Request) -> Result<Response, KvError> {
+        self.requests.borrow_mut().push((node.to_string(), request.clone()));
+        if self.throttled > 0 {
+            self.throttled -= 1;
+            return Ok(Response::from(KvError::Throttled(1)));
+        }
+
+        let data = self.node(node);
+
+        let values = match request {
+            Request::Get { key } => data.get(&key).cloned().into_iter().collect(),
+            Request::MGet { keys } => keys.iter().filter_map(|k| data.get(k).cloned()).collect(),
+            Request::Set { kv } => {
+                data.insert(kv.key, kv.value);
+                vec![]
+            }
+            Request::MSet { kvs } => {
+                for kv in kvs {
+                    data.insert(kv.key, kv.value);
+                }
+                vec![]
+            }
+            Request::Del { keys } => {
+                for key in keys {
+                    data.remove(&key);
+                }
+                vec![]
+            }
+            Request::FlushDb => {
+                data.clear();
+                vec![]
+            }
+            Request::Scan { after, count } => data.keys()
+                .filter(|k| after.as_ref().is_none_or(|after| *k > after))
+                .take(count)
+                .cloned()
+                .collect(),
+            _ => return Ok(Response::from(KvError::InvalidCommand)),
+        };
+
+        Ok(Response::from(values))
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+use kv_core::domain::{Request, Response, KV};
+use kv_core::error::KvError;
+
+use crate::client::Backend;
+use crate::KvClient;
+
+/// 导入导出的文件格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// 每行一个 `{"key":"k1","value":"v1"}`
+    Jsonl,
+    /// 第一行为 `key,value`，之后每行一条数据
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown format {s}, expect jsonl or csv.")),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Jsonl => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// 导入过程中的事件，用于输出进度和记录断点
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// 无法解析的数据，已跳过
+    Malformed { line: u64, reason: String },
+    /// 一批数据已经写入，line 及之前的数据都已经处理完
+    Committed { line: u64, imported: u64 },
+}
+
+/// 导入结果
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImportStats {
+    pub imported: u64,
+    pub malformed: u64,
+}
+
+/// 通过 Scan 遍历每个节点上当前命名空间中的 key，每批 key 用一次 MGet 读取后写入 out，返回导出的条数
+///
+/// 每写完一批调用一次 progress，参数为已经导出的条数。
+pub fn export<B: Backend>(
+    client: &mut KvClient<B>,
+    format: Format,
+    out: impl Write,
+    batch: usize,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, KvError> {
+    let mut writer = Writer::new(format, out)?;
+    let mut count = 0;
+
+    for node in client.nodes().to_vec() {
+        let mut after = None;
+        loop {
+            let keys = check(client.execute_on(&node, Request::Scan { after: after.take(), count: batch.max(1) })?)?.values;
+            let Some(last) = keys.last().cloned() else {
+                break;
+            };
+
+            for kv in fetch(client, &node, keys)? {
+                writer.write(&kv)?;
+                count += 1;
+            }
+            progress(count);
+            after = Some(last);
+        }
+    }
+
+    writer.finish()?;
+    Ok(count)
+}
+
+/// 读取 input 中的数据，每 batch 条用一次 MSet 写入
+///
+/// 无法解析的数据通过 on_event 报告后跳过，不会中断导入。skip 为之前中断时最后一次 Committed 事件的行号，
+/// 该行及之前的数据不再写入。
+pub fn import<B: Backend>(
+    client: &mut KvClient<B>,
+    format: Format,
+    input: impl Read,
+    batch: usize,
+    skip: u64,
+    mut on_event: impl FnMut(&Event),
+) -> Result<ImportStats, KvError> {
+    let mut stats = ImportStats::default();
+    let mut kvs = vec![];
+    let mut last_line = skip;
+
+    for (line, record) in records(format, input) {
+        if line <= skip {
+            continue;
+        }
+
+        match record {
+            Ok(kv) => kvs.push(kv),
+            Err(Malformed::Io(e)) => return Err(e),
+            Err(Malformed::Record(reason)) => {
+                stats.malformed += 1;
+                on_event(&Event::Malformed { line, reason });
+            }
+        }
+        last_line = line;
+
+        if kvs.len() >= batch.max(1) {
+            commit(client, &mut kvs, last_line, &mut stats, &mut on_event)?;
+        }
+    }
+
+    commit(client, &mut kvs, last_line, &mut stats, &mut on_event)?;
+    Ok(stats)
+}
+
+fn commit<B: Backend>(
+    client: &mut KvClient<B>,
+    kvs: &mut Vec<KV>,
+    line: u64,
+    stats: &mut ImportStats,
+    on_event: &mut impl FnMut(&Event),
+) -> Result<(), KvError> {
+    if kvs.is_empty() {
+        return Ok(());
+    }
+
+    let count = kvs.len() as u64;
+    check(client.execute(Request::MSet { kvs: std::mem::take(kvs) })?)?;
+    stats.imported += count;
+    on_event(&Event::Committed { line, imported: stats.imported });
+    Ok(())
+}
+
+/// 读取 keys 对应的值，读取期间被删除的 key 不导出
+fn fetch<B: Backend>(client: &mut KvClient<B>, node: &str, keys: Vec<String>) -> Result<Vec<KV>, KvError> {
+    let values = check(client.execute_on(node, Request::MGet { keys: keys.clone() })?)?.values;
+    if values.len() == keys.len() {
+        return Ok(keys.into_iter().zip(values).map(|(key, value)| KV { key, value }).collect());
+    }
+
+    // 有 key 不存在时无法对齐位置，逐个读取
+    let mut kvs = vec![];
+    for key in keys {
+        let res = check(client.execute_on(node, Request::Get { key: key.clone() })?)?;
+        if let Some(value) = res.values.into_iter().next() {
+            kvs.push(KV { key, value });
+        }
+    }
+    Ok(kvs)
+}
+
+enum Writer<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Writer<W> {
+    fn new(format: Format, out: W) -> Result<Self, KvError> {
+        match format {
+            Format::Jsonl => Ok(Writer::Jsonl(out)),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer.write_record(["key", "value"]).map_err(csv_error)?;
+                Ok(Writer::Csv(Box::new(writer)))
+            }
+        }
+    }
+
+    fn write(&mut self, kv: &KV) -> Result<(), KvError> {
+        match self {
+            Writer::Jsonl(out) => {
+                let line = serde_json::to_string(kv).map_err(|e| KvError::Internal(e.to_string()))?;
+                writeln!(out, "{line}").map_err(io_error)
+            }
+            Writer::Csv(writer) => writer.write_record([&kv.key, &kv.value]).map_err(csv_error),
+        }
+    }
+
+    fn finish(self) -> Result<(), KvError> {
+        match self {
+            Writer::Jsonl(mut out) => out.flush().map_err(io_error),
+            Writer::Csv(mut writer) => writer.flush().map_err(io_error),
+        }
+    }
+}
+
+enum Malformed {
+    // 读取文件失败，无法继续
+    Io(KvError),
+    // 单条数据格式错误，可以跳过
+    Record(String),
+}
+
+/// 逐条读取数据，同时返回数据所在的行号（从 1 开始）
+fn records<'a>(format: Format, input: impl Read + 'a) -> Box<dyn Iterator<Item = (u64, Result<KV, Malformed>)> + 'a> {
+    match format {
+        Format::Jsonl => Box::new(jsonl_records(input)),
+        Format::Csv => Box::new(csv_records(input)),
+    }
+}
+
+fn jsonl_records(input: impl Read) -> impl Iterator<Item = (u64, Result<KV, Malformed>)> {
+    BufReader::new(input).lines()
+        .zip(1..)
+        .filter(|(line, _)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(line, no)| {
+            let record = match line {
+                Ok(line) => serde_json::from_str(&line).map_err(|e| Malformed::Record(e.to_string())),
+                Err(e) => Err(Malformed::Io(io_error(e))),
+            };
+            (no, record)
+        })
+}
+
+fn csv_records(input: impl Read) -> impl Iterator<Item = (u64, Result<KV, Malformed>)> {
+    let reader = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+
+    let mut last_line = 1;
+    reader.into_records().map(move |record| {
+        let line = match &record {
+            Ok(record) => record.position().map(|p| p.line()),
+            Err(e) => e.position().map(|p| p.line()),
+        };
+        // 没有位置信息时使用上一条数据的下一行
+        let line = line.unwrap_or(last_line + 1);
+        last_line = line;
+
+        let record = match record {
+            Ok(record) if record.len() == 2 => Ok(KV { key: record[0].to_string(), value: record[1].to_string() }),
+            Ok(record) => Err(Malformed::Record(format!("expect 2 fields, found {}", record.len()))),
+            Err(e) if e.is_io_error() => Err(Malformed::Io(csv_error(e))),
+            Err(e) => Err(Malformed::Record(e.to_string())),
+        };
+        (line, record)
+    })
+}
+
+fn check(res: Response) -> Result<Response, KvError> {
+    if res.code != 0 {
+        return Err(KvError::Internal(format!("({}) {}", res.code, res.message)));
+    }
+    Ok(res)
+}
+
+fn csv_error(e: csv::Error) -> KvError {
+    KvError::Internal(e.to_string())
+}
+
+fn io_error(e: std::io::Error) -> KvError {
+    KvError::Internal(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use kv_core::domain::Request;
+
+    use crate::testing::FakeBackend;
+    use crate::transfer::{export, import, Event, Format, ImportStats};
+    use crate::KvClient;
+
+    fn client() -> KvClient<FakeBackend> {
+        KvClient::with_backend(&["n1"], FakeBackend::default())
+    }
+
+    #[test]
+    fn exported_data_should_be_imported() {
+        for format in [Format::Jsonl, Format::Csv] {
+            let mut source = client();
+            let mut kvs = BTreeMap::new();
+            for i in 0..25 {
+                kvs.insert(format!("k{i:02}"), format!("v{i}"));
+            }
+            // 需要转义的内容
+            kvs.insert(String::from("k,\"quoted\""), String::from("line1\nline2"));
+            *source.backend.node("n1") = kvs.clone();
+
+            let mut out = vec![];
+            let mut progress = vec![];
+            assert_eq!(Ok(26), export(&mut source, format, &mut out, 10, |n| progress.push(n)));
+            assert_eq!(vec![10, 20, 26], progress, "{format}");
+
+            let mut target = client();
+            let stats = import(&mut target, format, out.as_slice(), 10, 0, |_| {}).unwrap();
+            assert_eq!(ImportStats { imported: 26, malformed: 0 }, stats);
+            assert_eq!(3, target.backend.count(|r| matches!(r, Request::MSet { .. })));
+            assert_eq!(&kvs, target.backend.node("n1"), "{format}");
+        }
+    }
+
+    #[test]
+    fn malformed_lines_should_be_reported() {
+        let input = "{\"key\":\"k1\",\"value\":\"v1\"}\nnot json\n\n{\"key\":\"k2\"}\n{\"key\":\"k3\",\"value\":\"v3\"}\n";
+        let mut events = vec![];
+        let stats = import(&mut client(), Format::Jsonl, input.as_bytes(), 10, 0, |e| events.push(e.clone())).unwrap();
+        assert_eq!(ImportStats { imported: 2, malformed: 2 }, stats);
+
+        let lines: Vec<u64> = events.iter()
+            .filter_map(|e| match e {
+                Event::Malformed { line, .. } => Some(*line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![2, 4], lines);
+        assert_eq!(Some(&Event::Committed { line: 5, imported: 2 }), events.last());
+
+        let input = "key,value\nk1,v1\nk2\n\"k3\",\"v\n3\"\nk4,v4,extra\nk5,v5\n";
+        let mut events = vec![];
+        let mut target = client();
+        let stats = import(&mut target, Format::Csv, input.as_bytes(), 10, 0, |e| events.push(e.clone())).unwrap();
+        assert_eq!(ImportStats { imported: 3, malformed: 2 }, stats);
+        assert!(matches!(&events[0], Event::Malformed { line: 3, reason } if reason.contains("2 fields")));
+        assert!(matches!(&events[1], Event::Malformed { line: 6, .. }));
+        assert_eq!(Some(&String::from("v\n3")), target.backend.node("n1").get("k3"));
+    }
+
+    #[test]
+    fn import_should_resume_after_committed_line() {
+        let input: String = (1..=5).map(|i| format!("{{\"key\":\"k{i}\",\"value\":\"v{i}\"}}\n")).collect();
+
+        let mut target = client();
+        let stats = import(&mut target, Format::Jsonl, input.as_bytes(), 2, 3, |_| {}).unwrap();
+        assert_eq!(2, stats.imported);
+        assert_eq!(vec!["k4", "k5"], target.backend.node("n1").keys().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,9 @@
+use crate::domain::{Request, Response};
+use crate::error::KvError;
+
+/// 向具体节点发送请求的方式
+///
+/// 客户端通过它把请求发往节点，服务端可以实现它在进程内处理请求，两者都不需要依赖对方。
+pub trait Backend {
+    fn call(&mut self, node: &str, request: Request) -> Result<Response, KvError>;
+}
@@ -0,0 +1,240 @@
+//! 消息的编码格式
+//!
+//! 默认使用 JSON，也可以使用 protobuf，schema 见 proto/kv_core.proto。帧头中的 [PROTOBUF](crate::frame::PROTOBUF)
+//! 标志说明 payload 的格式，服务端使用客户端最近一次请求的格式返回响应。
+
+pub mod proto;
+
+use prost::Message as _;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::KvError;
+
+/// payload 的编码格式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    Protobuf,
+}
+
+/// 可以使用两种格式编码的消息，protobuf 格式通过与生成的消息互相转换实现
+pub trait Message: Serialize + DeserializeOwned {
+    /// 对应的 protobuf 消息
+    type Proto: prost::Message + Default;
+
+    fn to_proto(&self) -> Self::Proto;
+
+    /// 缺少必需的字段时返回 InvalidCommand
+    fn from_proto(proto: Self::Proto) -> Result<Self, KvError>;
+}
+
+impl Codec {
+    /// 编码 value 并追加到 buf
+    pub fn encode<T: Message>(self, value: &T, buf: &mut Vec<u8>) -> Result<(), KvError> {
+        match self {
+            Codec::Json => serde_json::to_writer(buf, value).map_err(|e| KvError::Internal(e.to_string())),
+            Codec::Protobuf => Ok(value.to_proto().encode(buf)?),
+        }
+    }
+
+    /// 格式错误或缺少必需的字段时返回 InvalidCommand
+    pub fn decode<T: Message>(self, buf: &[u8]) -> Result<T, KvError> {
+        match self {
+            Codec::Json => serde_json::from_slice(buf).map_err(|_| KvError::InvalidCommand),
+            Codec::Protobuf => T::from_proto(T::Proto::decode(buf).map_err(|_| KvError::InvalidCommand)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::{btree_map, vec};
+    use proptest::option;
+    use proptest::prelude::*;
+
+    use crate::codec::{proto, Codec, Message};
+    use crate::domain::{
+        CacheInfo, CompressionInfo, Envelope, PersistenceInfo, PushMessage, Request, Response, ServerInfo, SlowLogEntry,
+        StorageInfo, TraceContext, KV,
+    };
+    use crate::error::KvError;
+
+    fn text() -> impl Strategy<Value = String> {
+        "\\PC{0,8}"
+    }
+
+    fn strings() -> impl Strategy<Value = Vec<String>> {
+        vec(text(), 0..4)
+    }
+
+    fn kv() -> impl Strategy<Value = KV> {
+        (text(), text()).prop_map(|(key, value)| KV { key, value })
+    }
+
+    fn request() -> impl Strategy<Value = Request> {
+        prop_oneof![
+            text().prop_map(|key| Request::Get { key }),
+            strings().prop_map(|keys| Request::MGet { keys }),
+            kv().prop_map(|kv| Request::Set { kv }),
+            vec(kv(), 0..4).prop_map(|kvs| Request::MSet { kvs }),
+            strings().prop_map(|keys| Request::Del { keys }),
+            (option::of(text()), any::<u32>()).prop_map(|(after, count)| Request::Scan { after, count: count as usize }),
+            (any::<u64>(), text(), text()).prop_map(|(id, addr, raft_addr)| Request::AddNode { id, addr, raft_addr }),
+            any::<u64>().prop_map(|id| Request::RemoveNode { id }),
+            (text(), text()).prop_map(|(username, password)| Request::Auth { username, password }),
+            strings().prop_map(|compression| Request::Hello { compression }),
+            text().prop_map(|db| Request::Select { db }),
+            Just(Request::FlushDb),
+            (text(), text()).prop_map(|(channel, message)| Request::Publish { channel, message }),
+            strings().prop_map(|channels| Request::Subscribe { channels }),
+            strings().prop_map(|patterns| Request::PSubscribe { patterns }),
+            (text(), strings(), strings()).prop_map(|(script, keys, args)| Request::Eval { script, keys, args }),
+            (text(), strings(), strings()).prop_map(|(sha1, keys, args)| Request::EvalSha { sha1, keys, args }),
+            Just(Request::Info),
+            any::<u32>().prop_map(|count| Request::SlowLog { count: count as usize }),
+            Just(Request::Dump),
+        ]
+    }
+
+    fn compression() -> impl Strategy<Value = CompressionInfo> {
+        (any::<u64>(), any::<u64>()).prop_map(|(raw_bytes, compressed_bytes)| CompressionInfo { raw_bytes, compressed_bytes })
+    }
+
+    fn info() -> impl Strategy<Value = ServerInfo> {
+        let counts = || btree_map(text(), any::<u64>(), 0..3);
+        let cache = (any::<u64>(), any::<u64>(), any::<u64>(), any::<u64>(), any::<u64>())
+            .prop_map(|(hits, misses, keys, bytes, capacity_bytes)| CacheInfo { hits, misses, keys, bytes, capacity_bytes });
+        let storage = (text(), any::<u64>(), any::<u64>(), counts(), option::of(cache), option::of(compression())).prop_map(
+            |(engine, keys, memory_bytes, namespaces, cache, compression)| StorageInfo {
+                engine,
+                keys,
+                memory_bytes,
+                namespaces,
+                cache,
+                compression,
+            },
+        );
+        let persistence = (option::of(any::<u64>()), option::of(any::<u64>()))
+            .prop_map(|(last_snapshot, wal_bytes)| PersistenceInfo { last_snapshot, wal_bytes });
+        (text(), any::<u64>(), counts(), storage, persistence, option::of(compression())).prop_map(
+            |(version, uptime_secs, commands, storage, persistence, wire_compression)| ServerInfo {
+                version,
+                uptime_secs,
+                connected_clients: uptime_secs / 2,
+                total_commands: commands.values().fold(0u64, |sum, n| sum.wrapping_add(*n)),
+                commands,
+                storage,
+                persistence,
+                wire_compression,
+            },
+        )
+    }
+
+    fn response() -> impl Strategy<Value = Response> {
+        let entry = (any::<u64>(), text(), any::<u64>(), text()).prop_map(|(id, request_id, timestamp, request)| SlowLogEntry {
+            id,
+            request_id,
+            timestamp,
+            duration_us: timestamp / 3,
+            client: String::from("127.0.0.1:6736"),
+            request,
+        });
+        let push = (text(), option::of(text()), text()).prop_map(|(channel, pattern, payload)| PushMessage { channel, pattern, payload });
+        (any::<u32>(), text(), strings(), option::of(info()), option::of(vec(entry, 0..3)), option::of(push), any::<bool>(), text())
+            .prop_map(|(code, message, values, info, slow_log, push, more, request_id)| Response {
+                code,
+                message,
+                values,
+                info,
+                slow_log,
+                push,
+                more,
+                request_id,
+            })
+    }
+
+    fn round_trip<T: Message + PartialEq + std::fmt::Debug>(value: &T) -> Result<(), TestCaseError> {
+        for codec in [Codec::Json, Codec::Protobuf] {
+            let mut buf = vec![];
+            codec.encode(value, &mut buf).unwrap();
+            prop_assert_eq!(value, &codec.decode::<T>(&buf).unwrap());
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn request_should_round_trip(request in request()) {
+            round_trip(&request)?;
+        }
+
+        #[test]
+        fn envelope_should_round_trip(request in request(), traceparent in option::of(text()), tracestate in option::of(text())) {
+            let trace = traceparent.map(|traceparent| TraceContext { traceparent, tracestate });
+            round_trip(&Envelope { request, trace })?;
+        }
+
+        #[test]
+        fn response_should_round_trip(response in response()) {
+            round_trip(&response)?;
+        }
+
+        #[test]
+        fn kv_should_round_trip(kv in kv()) {
+            round_trip(&kv)?;
+        }
+
+        /// 任意输入都不会 panic，只返回错误
+        #[test]
+        fn arbitrary_bytes_should_not_panic(bytes in vec(any::<u8>(), 0..256)) {
+            for codec in [Codec::Json, Codec::Protobuf] {
+                let _ = codec.decode::<Envelope>(&bytes);
+                let _ = codec.decode::<Response>(&bytes);
+            }
+        }
+
+        /// 截断或者修改编码后的请求不会 panic
+        #[test]
+        fn mutated_request_should_not_panic(request in request(), cut in any::<prop::sample::Index>(), flip in any::<(prop::sample::Index, u8)>()) {
+            let mut buf = vec![];
+            Codec::Protobuf.encode(&Envelope::from(request), &mut buf).unwrap();
+            if !buf.is_empty() {
+                let _ = Codec::Protobuf.decode::<Envelope>(&buf[..cut.index(buf.len())]);
+                let (i, mask) = flip;
+                let i = i.index(buf.len());
+                buf[i] ^= mask;
+                let _ = Codec::Protobuf.decode::<Envelope>(&buf);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_protobuf_should_be_rejected() {
+        // 没有命令
+        let envelope = prost::Message::encode_to_vec(&proto::Envelope { request: Some(proto::Request { command: None }), trace: None });
+        assert_eq!(Err(KvError::InvalidCommand), Codec::Protobuf.decode::<Envelope>(&envelope));
+        assert_eq!(Err(KvError::InvalidCommand), Codec::Protobuf.decode::<Envelope>(&[]));
+
+        // 截断的消息
+        let mut buf = vec![];
+        Codec::Protobuf.encode(&Envelope::from(Request::Get { key: String::from("k1") }), &mut buf).unwrap();
+        let err = Codec::Protobuf.decode::<Envelope>(&buf[..buf.len() - 1]).unwrap_err();
+        assert_eq!(KvError::InvalidCommand, err);
+        assert_eq!(400, Response::from(err).code);
+
+        assert_eq!(Err(KvError::InvalidCommand), Codec::Json.decode::<Envelope>(b"not json"));
+    }
+
+    #[test]
+    fn protobuf_should_be_smaller_than_json() {
+        let values = (0..100).map(|i| format!("value-{i}")).collect::<Vec<_>>();
+        let response = Response { values, request_id: String::from("b3e1"), ..Default::default() };
+
+        let (mut json, mut protobuf) = (vec![], vec![]);
+        Codec::Json.encode(&response, &mut json).unwrap();
+        Codec::Protobuf.encode(&response, &mut protobuf).unwrap();
+        assert!(protobuf.len() < json.len());
+    }
+}
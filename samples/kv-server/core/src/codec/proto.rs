@@ -0,0 +1,261 @@
+//! 由 proto/kv_core.proto 生成的消息，以及与 [crate::domain] 中类型的转换
+
+include!(concat!(env!("OUT_DIR"), "/kv_core.rs"));
+
+use crate::codec::Message;
+use crate::domain;
+use crate::error::KvError;
+
+use request::Command;
+
+impl Message for domain::Request {
+    type Proto = Request;
+
+    fn to_proto(&self) -> Request {
+        let command = match self.clone() {
+            domain::Request::Get { key } => Command::Get(request::Get { key }),
+            domain::Request::MGet { keys } => Command::Mget(request::MGet { keys }),
+            domain::Request::Set { kv } => Command::Set(request::Set { kv: Some(kv.into()) }),
+            domain::Request::MSet { kvs } => Command::Mset(request::MSet { kvs: kvs.into_iter().map(Kv::from).collect() }),
+            domain::Request::Del { keys } => Command::Del(request::Del { keys }),
+            domain::Request::Scan { after, count } => Command::Scan(request::Scan { after, count: count as u64 }),
+            domain::Request::AddNode { id, addr, raft_addr } => Command::AddNode(request::AddNode { id, addr, raft_addr }),
+            domain::Request::RemoveNode { id } => Command::RemoveNode(request::RemoveNode { id }),
+            domain::Request::Auth { username, password } => Command::Auth(request::Auth { username, password }),
+            domain::Request::Hello { compression } => Command::Hello(request::Hello { compression }),
+            domain::Request::Select { db } => Command::Select(request::Select { db }),
+            domain::Request::FlushDb => Command::FlushDb(request::FlushDb {}),
+            domain::Request::Publish { channel, message } => Command::Publish(request::Publish { channel, message }),
+            domain::Request::Subscribe { channels } => Command::Subscribe(request::Subscribe { channels }),
+            domain::Request::PSubscribe { patterns } => Command::Psubscribe(request::PSubscribe { patterns }),
+            domain::Request::Eval { script, keys, args } => Command::Eval(request::Eval { script, keys, args }),
+            domain::Request::EvalSha { sha1, keys, args } => Command::EvalSha(request::EvalSha { sha1, keys, args }),
+            domain::Request::Info => Command::Info(request::Info {}),
+            domain::Request::SlowLog { count } => Command::SlowLog(request::SlowLog { count: count as u64 }),
+            domain::Request::Dump => Command::Dump(request::Dump {}),
+        };
+        Request { command: Some(command) }
+    }
+
+    fn from_proto(proto: Request) -> Result<Self, KvError> {
+        let request = match proto.command.ok_or(KvError::InvalidCommand)? {
+            Command::Get(request::Get { key }) => domain::Request::Get { key },
+            Command::Mget(request::MGet { keys }) => domain::Request::MGet { keys },
+            Command::Set(request::Set { kv }) => domain::Request::Set { kv: kv.ok_or(KvError::InvalidCommand)?.into() },
+            Command::Mset(request::MSet { kvs }) => domain::Request::MSet { kvs: kvs.into_iter().map(domain::KV::from).collect() },
+            Command::Del(request::Del { keys }) => domain::Request::Del { keys },
+            Command::Scan(request::Scan { after, count }) => domain::Request::Scan { after, count: count as usize },
+            Command::AddNode(request::AddNode { id, addr, raft_addr }) => domain::Request::AddNode { id, addr, raft_addr },
+            Command::RemoveNode(request::RemoveNode { id }) => domain::Request::RemoveNode { id },
+            Command::Auth(request::Auth { username, password }) => domain::Request::Auth { username, password },
+            Command::Hello(request::Hello { compression }) => domain::Request::Hello { compression },
+            Command::Select(request::Select { db }) => domain::Request::Select { db },
+            Command::FlushDb(_) => domain::Request::FlushDb,
+            Command::Publish(request::Publish { channel, message }) => domain::Request::Publish { channel, message },
+            Command::Subscribe(request::Subscribe { channels }) => domain::Request::Subscribe { channels },
+            Command::Psubscribe(request::PSubscribe { patterns }) => domain::Request::PSubscribe { patterns },
+            Command::Eval(request::Eval { script, keys, args }) => domain::Request::Eval { script, keys, args },
+            Command::EvalSha(request::EvalSha { sha1, keys, args }) => domain::Request::EvalSha { sha1, keys, args },
+            Command::Info(_) => domain::Request::Info,
+            Command::SlowLog(request::SlowLog { count }) => domain::Request::SlowLog { count: count as usize },
+            Command::Dump(_) => domain::Request::Dump,
+        };
+        Ok(request)
+    }
+}
+
+impl Message for domain::Envelope {
+    type Proto = Envelope;
+
+    fn to_proto(&self) -> Envelope {
+        Envelope {
+            request: Some(self.request.to_proto()),
+            trace: self.trace.clone().map(|trace| TraceContext { traceparent: trace.traceparent, tracestate: trace.tracestate }),
+        }
+    }
+
+    fn from_proto(proto: Envelope) -> Result<Self, KvError> {
+        Ok(Self {
+            request: domain::Request::from_proto(proto.request.ok_or(KvError::InvalidCommand)?)?,
+            trace: proto.trace.map(|trace| domain::TraceContext { traceparent: trace.traceparent, tracestate: trace.tracestate }),
+        })
+    }
+}
+
+impl Message for domain::Response {
+    type Proto = Response;
+
+    fn to_proto(&self) -> Response {
+        let response = self.clone();
+        Response {
+            code: response.code,
+            message: response.message,
+            values: response.values,
+            info: response.info.map(ServerInfo::from),
+            slow_log: response.slow_log.map(|entries| SlowLogEntries { entries: entries.into_iter().map(SlowLogEntry::from).collect() }),
+            push: response.push.map(PushMessage::from),
+            more: response.more,
+            request_id: response.request_id,
+        }
+    }
+
+    fn from_proto(proto: Response) -> Result<Self, KvError> {
+        Ok(Self {
+            code: proto.code,
+            message: proto.message,
+            values: proto.values,
+            info: proto.info.map(domain::ServerInfo::from),
+            slow_log: proto.slow_log.map(|log| log.entries.into_iter().map(domain::SlowLogEntry::from).collect()),
+            push: proto.push.map(domain::PushMessage::from),
+            more: proto.more,
+            request_id: proto.request_id,
+        })
+    }
+}
+
+impl Message for domain::KV {
+    type Proto = Kv;
+
+    fn to_proto(&self) -> Kv {
+        self.clone().into()
+    }
+
+    fn from_proto(proto: Kv) -> Result<Self, KvError> {
+        Ok(proto.into())
+    }
+}
+
+impl From<domain::KV> for Kv {
+    fn from(kv: domain::KV) -> Self {
+        Self { key: kv.key, value: kv.value }
+    }
+}
+
+impl From<Kv> for domain::KV {
+    fn from(kv: Kv) -> Self {
+        Self { key: kv.key, value: kv.value }
+    }
+}
+
+impl From<domain::ServerInfo> for ServerInfo {
+    fn from(info: domain::ServerInfo) -> Self {
+        Self {
+            version: info.version,
+            uptime_secs: info.uptime_secs,
+            connected_clients: info.connected_clients,
+            total_commands: info.total_commands,
+            commands: info.commands,
+            storage: Some(info.storage.into()),
+            persistence: Some(PersistenceInfo {
+                last_snapshot: info.persistence.last_snapshot,
+                wal_bytes: info.persistence.wal_bytes,
+            }),
+            wire_compression: info.wire_compression.map(CompressionInfo::from),
+        }
+    }
+}
+
+/// storage 和 persistence 缺失时使用默认值
+impl From<ServerInfo> for domain::ServerInfo {
+    fn from(info: ServerInfo) -> Self {
+        let persistence = info.persistence.unwrap_or_default();
+        Self {
+            version: info.version,
+            uptime_secs: info.uptime_secs,
+            connected_clients: info.connected_clients,
+            total_commands: info.total_commands,
+            commands: info.commands,
+            storage: info.storage.map(domain::StorageInfo::from).unwrap_or_default(),
+            persistence: domain::PersistenceInfo { last_snapshot: persistence.last_snapshot, wal_bytes: persistence.wal_bytes },
+            wire_compression: info.wire_compression.map(domain::CompressionInfo::from),
+        }
+    }
+}
+
+impl From<domain::StorageInfo> for StorageInfo {
+    fn from(info: domain::StorageInfo) -> Self {
+        Self {
+            engine: info.engine,
+            keys: info.keys,
+            memory_bytes: info.memory_bytes,
+            namespaces: info.namespaces,
+            cache: info.cache.map(|cache| CacheInfo {
+                hits: cache.hits,
+                misses: cache.misses,
+                keys: cache.keys,
+                bytes: cache.bytes,
+                capacity_bytes: cache.capacity_bytes,
+            }),
+            compression: info.compression.map(CompressionInfo::from),
+        }
+    }
+}
+
+impl From<StorageInfo> for domain::StorageInfo {
+    fn from(info: StorageInfo) -> Self {
+        Self {
+            engine: info.engine,
+            keys: info.keys,
+            memory_bytes: info.memory_bytes,
+            namespaces: info.namespaces,
+            cache: info.cache.map(|cache| domain::CacheInfo {
+                hits: cache.hits,
+                misses: cache.misses,
+                keys: cache.keys,
+                bytes: cache.bytes,
+                capacity_bytes: cache.capacity_bytes,
+            }),
+            compression: info.compression.map(domain::CompressionInfo::from),
+        }
+    }
+}
+
+impl From<domain::CompressionInfo> for CompressionInfo {
+    fn from(info: domain::CompressionInfo) -> Self {
+        Self { raw_bytes: info.raw_bytes, compressed_bytes: info.compressed_bytes }
+    }
+}
+
+impl From<CompressionInfo> for domain::CompressionInfo {
+    fn from(info: CompressionInfo) -> Self {
+        Self { raw_bytes: info.raw_bytes, compressed_bytes: info.compressed_bytes }
+    }
+}
+
+impl From<domain::PushMessage> for PushMessage {
+    fn from(message: domain::PushMessage) -> Self {
+        Self { channel: message.channel, pattern: message.pattern, payload: message.payload }
+    }
+}
+
+impl From<PushMessage> for domain::PushMessage {
+    fn from(message: PushMessage) -> Self {
+        Self { channel: message.channel, pattern: message.pattern, payload: message.payload }
+    }
+}
+
+impl From<domain::SlowLogEntry> for SlowLogEntry {
+    fn from(entry: domain::SlowLogEntry) -> Self {
+        Self {
+            id: entry.id,
+            request_id: entry.request_id,
+            timestamp: entry.timestamp,
+            duration_us: entry.duration_us,
+            client: entry.client,
+            request: entry.request,
+        }
+    }
+}
+
+impl From<SlowLogEntry> for domain::SlowLogEntry {
+    fn from(entry: SlowLogEntry) -> Self {
+        Self {
+            id: entry.id,
+            request_id: entry.request_id,
+            timestamp: entry.timestamp,
+            duration_us: entry.duration_us,
+            client: entry.client,
+            request: entry.request,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! 值压缩
+//!
+//! 使用 lz4 的 block 格式，压缩后的数据为 4 字节大端序的原始长度加上压缩的数据。
+//! 存储引擎和网络协议都只压缩不小于阈值的数据，压缩后没有变小时保留原始数据。
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::domain::CompressionInfo;
+
+/// 压缩算法的名称，连接建立时用于协商
+pub const LZ4: &str = "lz4";
+
+/// 默认的压缩阈值，小的值压缩后通常不会变小
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// 压缩不小于 threshold 的数据，没有压缩时返回 None
+pub fn compress(data: &[u8], threshold: usize) -> Option<Vec<u8>> {
+    if data.len() < threshold || data.len() > u32::MAX as usize {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(4 + lz4_flex::block::get_maximum_output_size(data.len()));
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(lz4_flex::block::compress(data));
+    (out.len() < data.len()).then_some(out)
+}
+
+/// lz4 block 格式每个字节最多解压出 255 字节，用于在分配内存前检查声明的原始长度
+const MAX_RATIO: usize = 256;
+
+/// 解压 compress 的结果，数据损坏或原始长度超过 max_len 时返回 None
+pub fn decompress(data: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let (len, block) = data.split_first_chunk::<4>()?;
+    let len = u32::from_be_bytes(*len) as usize;
+    // 声明的长度不可能由这么短的数据解压出来时，不为它分配内存
+    if len > max_len || len > block.len().saturating_mul(MAX_RATIO) {
+        return None;
+    }
+    lz4_flex::block::decompress(block, len).ok().filter(|out| out.len() == len)
+}
+
+/// 累计压缩前后的字节数
+#[derive(Debug, Default)]
+pub struct Counter {
+    raw: AtomicU64,
+    stored: AtomicU64,
+}
+
+impl Counter {
+    /// raw 字节的数据实际占用 stored 字节，没有压缩时两者相等
+    pub fn record(&self, raw: usize, stored: usize) {
+        self.raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.stored.fetch_add(stored as u64, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> CompressionInfo {
+        CompressionInfo {
+            raw_bytes: self.raw.load(Ordering::Relaxed),
+            compressed_bytes: self.stored.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compress::{compress, decompress, Counter};
+
+    #[test]
+    fn compress_round_trip() {
+        let data = r#"{"name":"kv-server","tags":["a","b","c"]}"#.repeat(100);
+        let compressed = compress(data.as_bytes(), 1024).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(data.as_bytes(), decompress(&compressed, data.len()).unwrap());
+
+        // 小于阈值或者压缩后没有变小时不压缩
+        assert_eq!(None, compress(b"short", 1024));
+        assert_eq!(None, compress(b"abcdefgh", 0));
+
+        // 原始长度超过限制或者数据损坏
+        assert_eq!(None, decompress(&compressed, data.len() - 1));
+        assert_eq!(None, decompress(&compressed[..compressed.len() / 2], data.len()));
+        assert_eq!(None, decompress(b"ab", 100));
+
+        let counter = Counter::default();
+        counter.record(1000, 250);
+        counter.record(100, 100);
+        let info = counter.info();
+        assert_eq!((1100, 350), (info.raw_bytes, info.compressed_bytes));
+        assert!((info.ratio() - 1100.0 / 350.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn decompress_should_reject_forged_length() {
+        // 压缩比最高的数据也能解压
+        let zeros = vec![0u8; 1024 * 1024];
+        let compressed = compress(&zeros, 1024).unwrap();
+        assert_eq!(zeros, decompress(&compressed, zeros.len()).unwrap());
+
+        // 伪造的长度远超数据能解压出的长度，即使没有超过 max_len 也不分配内存
+        let mut forged = (16u32 * 1024 * 1024).to_be_bytes().to_vec();
+        forged.extend(&compressed[4..]);
+        assert_eq!(None, decompress(&forged, usize::MAX));
+        let mut forged = u32::MAX.to_be_bytes().to_vec();
+        forged.extend(b"\x1f\x00");
+        assert_eq!(None, decompress(&forged, usize::MAX));
+    }
+}
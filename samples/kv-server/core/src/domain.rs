@@ -1,28 +1,270 @@
-use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
 use crate::error::KvError;
 
-#[derive(Debug, Deserialize)]
+/// 连接默认使用的命名空间
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
     MGet { keys: Vec<String> },
     Set { kv: KV },
     MSet { kvs: Vec<KV> },
     Del { keys: Vec<String> },
+    /// 按字典序返回大于 after 的最多 count 个 key，after 为 None 时从头开始，用于遍历命名空间中的所有 key
+    Scan { after: Option<String>, count: usize },
+
+    // 集群管理命令
+    AddNode { id: u64, addr: String, raft_addr: String },
+    RemoveNode { id: u64 },
+
+    // 认证当前连接，认证后按用户限流
+    Auth { username: String, password: String },
+    /// 协商连接使用的压缩算法，compression 为客户端支持的算法，服务端返回选中的算法，不压缩时为空
+    Hello { compression: Vec<String> },
+
+    /// 切换当前连接的命名空间，不同命名空间的 key 互相独立
+    Select { db: String },
+    /// 删除当前命名空间中的所有 key
+    FlushDb,
+
+    // 发布订阅，订阅后连接进入推送模式，只能继续订阅
+    Publish { channel: String, message: String },
+    Subscribe { channels: Vec<String> },
+    /// 按模式订阅，支持 `*` 和 `?` 通配符
+    PSubscribe { patterns: Vec<String> },
+
+    /// 原子地执行 Lua 脚本，脚本中通过 KEYS、ARGV 访问参数，通过 kv.get/kv.set/kv.del 读写数据
+    Eval { script: String, keys: Vec<String>, args: Vec<String> },
+    /// 执行之前通过 Eval 缓存的脚本
+    EvalSha { sha1: String, keys: Vec<String>, args: Vec<String> },
+
+    // 运维命令
+    Info,
+    /// 查询最近的 count 条慢请求
+    SlowLog { count: usize },
+    /// 导出当前用户可以访问的所有数据，以多个 Response 分段返回，格式见 [crate::dump]
+    Dump,
+}
+
+/// 请求信封，在请求之外携带调用方的链路追踪上下文
+///
+/// 不带信封的请求同样可以解析，兼容旧版本的客户端。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "EnvelopeRepr")]
+pub struct Envelope {
+    pub request: Request,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvelopeRepr {
+    Envelope {
+        request: Request,
+        #[serde(default)]
+        trace: Option<TraceContext>,
+    },
+    Request(Request),
+}
+
+impl From<EnvelopeRepr> for Envelope {
+    fn from(repr: EnvelopeRepr) -> Self {
+        match repr {
+            EnvelopeRepr::Envelope { request, trace } => Self { request, trace },
+            EnvelopeRepr::Request(request) => Self { request, trace: None },
+        }
+    }
 }
 
-#[derive(Default, Debug, Deserialize)]
+impl From<Request> for Envelope {
+    fn from(request: Request) -> Self {
+        Self { request, trace: None }
+    }
+}
+
+/// W3C Trace Context，格式见 https://www.w3.org/TR/trace-context/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceContext {
+    pub traceparent: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Response {
     pub code: u32,
     pub message: String,
     pub values: Vec<String>,
+    /// Info 命令的结果
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<ServerInfo>,
+    /// SlowLog 命令的结果，按时间倒序
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_log: Option<Vec<SlowLogEntry>>,
+    /// 推送给订阅者的消息
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push: Option<PushMessage>,
+    /// 为 true 时后面还有属于同一个请求的 Response，用于分段返回 Dump 的结果
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub more: bool,
+    /// 服务端生成的请求 id，用于关联客户端与服务端的日志
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub request_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct KV {
     pub key: String,
     pub value: String,
 }
 
+/// 服务端运行状态
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub connected_clients: u64,
+    pub total_commands: u64,
+    /// 各命令的执行次数
+    pub commands: BTreeMap<String, u64>,
+    pub storage: StorageInfo,
+    pub persistence: PersistenceInfo,
+    /// 服务端发送的数据在网络上的压缩情况
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wire_compression: Option<CompressionInfo>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageInfo {
+    pub engine: String,
+    pub keys: u64,
+    pub memory_bytes: u64,
+    /// 各命名空间的 key 数量
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, u64>,
+    /// 分层存储的内存缓存，其他存储引擎为 None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheInfo>,
+    /// 存储引擎中值的压缩情况，没有开启压缩时为 None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionInfo>,
+}
+
+/// 内存缓存的命中情况
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheInfo {
+    pub hits: u64,
+    pub misses: u64,
+    /// 缓存中的 key 数量
+    pub keys: u64,
+    /// 缓存占用的字节数
+    pub bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+impl CacheInfo {
+    /// 命中率，没有读操作时为 0
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+
+    pub fn miss_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.misses as f64 / total as f64,
+        }
+    }
+}
+
+/// 压缩前后的字节数
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompressionInfo {
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionInfo {
+    /// 压缩率，即压缩前后字节数之比，没有数据时为 1
+    pub fn ratio(&self) -> f64 {
+        match self.compressed_bytes {
+            0 => 1.0,
+            compressed => self.raw_bytes as f64 / compressed as f64,
+        }
+    }
+}
+
+/// 发布到频道的消息
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushMessage {
+    pub channel: String,
+    /// 通过模式订阅收到时为匹配的模式
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    pub payload: String,
+}
+
+/// 一条慢请求记录
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowLogEntry {
+    /// 自增的记录 id
+    pub id: u64,
+    pub request_id: String,
+    /// 请求开始的时间，unix 时间戳（秒）
+    pub timestamp: u64,
+    pub duration_us: u64,
+    pub client: String,
+    /// 请求的摘要，只包含命令和部分 key
+    pub request: String,
+}
+
+/// 持久化状态，内存引擎没有持久化时各字段为空
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistenceInfo {
+    /// 最近一次快照的时间，unix 时间戳（秒）
+    pub last_snapshot: Option<u64>,
+    /// WAL 文件大小，单位为字节
+    pub wal_bytes: Option<u64>,
+}
+
+impl Request {
+    /// 命令名称，用于日志和统计指标
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::MGet { .. } => "mget",
+            Request::Set { .. } => "set",
+            Request::MSet { .. } => "mset",
+            Request::Del { .. } => "del",
+            Request::Scan { .. } => "scan",
+            Request::AddNode { .. } => "add_node",
+            Request::RemoveNode { .. } => "remove_node",
+            Request::Auth { .. } => "auth",
+            Request::Hello { .. } => "hello",
+            Request::Select { .. } => "select",
+            Request::FlushDb => "flushdb",
+            Request::Publish { .. } => "publish",
+            Request::Subscribe { .. } => "subscribe",
+            Request::PSubscribe { .. } => "psubscribe",
+            Request::Eval { .. } => "eval",
+            Request::EvalSha { .. } => "evalsha",
+            Request::Info => "info",
+            Request::SlowLog { .. } => "slowlog",
+            Request::Dump => "dump",
+        }
+    }
+
+    /// 是否为修改数据的命令，集群模式下这些命令需要经过 Raft 日志
+    pub fn is_write(&self) -> bool {
+        matches!(self, Request::Set { .. } | Request::MSet { .. } | Request::Del { .. } | Request::FlushDb)
+    }
+}
 
 impl From<Vec<String>> for Response {
     fn from(values: Vec<String>) -> Self {
@@ -33,17 +275,60 @@ impl From<Vec<String>> for Response {
     }
 }
 
+impl From<ServerInfo> for Response {
+    fn from(info: ServerInfo) -> Self {
+        Self {
+            info: Some(info),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PushMessage> for Response {
+    fn from(message: PushMessage) -> Self {
+        Self {
+            push: Some(message),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<SlowLogEntry>> for Response {
+    fn from(entries: Vec<SlowLogEntry>) -> Self {
+        Self {
+            slow_log: Some(entries),
+            ..Default::default()
+        }
+    }
+}
+
 impl From<KvError> for Response {
     fn from(err: KvError) -> Self {
         let code = match err {
             KvError::NotFound(_) => 404,
-            KvError::InvalidCommand => 400,
+            KvError::InvalidCommand | KvError::DecodeError(_) => 400,
+            KvError::NotLeader(_) => 307,
+            KvError::FrameTooLarge(_) => 413,
+            KvError::Unavailable(_) => 503,
+            KvError::Throttled(_) => 429,
+            KvError::AuthFailed => 401,
+            KvError::Forbidden(_) => 403,
+            KvError::SlowSubscriber(_) => 503,
+            KvError::ScriptError(_) => 422,
             _ => 500,
         };
 
+        // 重定向时把 leader 地址、限流时把建议的等待时间（毫秒）放在 values 中，方便客户端重试
+        let values = match &err {
+            KvError::NotLeader(Some(leader)) => vec![leader.clone()],
+            KvError::Throttled(retry_after_ms) => vec![retry_after_ms.to_string()],
+            _ => vec![],
+        };
+
         Self {
             code,
             message: err.to_string(),
+            values,
             ..Default::default()
         }
     }
@@ -73,6 +358,50 @@ impl From<Result<(), KvError>> for Response {
 
 #[cfg(test)]
 mod tests {
+    use crate::domain::{Envelope, Request, Response, TraceContext};
+    use crate::error::KvError;
+
     #[test]
     fn test() {}
+
+    #[test]
+    fn not_leader_should_redirect() {
+        let res = Response::from(KvError::NotLeader(Some(String::from("127.0.0.1:6737"))));
+        assert_eq!(307, res.code);
+        assert_eq!(vec![String::from("127.0.0.1:6737")], res.values);
+
+        let res = Response::from(KvError::NotLeader(None));
+        assert_eq!(307, res.code);
+        assert!(res.values.is_empty());
+    }
+
+    #[test]
+    fn throttled_should_carry_retry_after() {
+        let res = Response::from(KvError::Throttled(150));
+        assert_eq!(429, res.code);
+        assert_eq!(vec![String::from("150")], res.values);
+    }
+
+    #[test]
+    fn envelope_should_accept_bare_request() {
+        let get = Request::Get { key: String::from("k1") };
+        let json = serde_json::to_string(&get).unwrap();
+        assert_eq!(Envelope::from(get), serde_json::from_str(&json).unwrap());
+
+        let envelope: Envelope = serde_json::from_str(r#""Info""#).unwrap();
+        assert_eq!(Request::Info, envelope.request);
+    }
+
+    #[test]
+    fn envelope_should_carry_trace_context() {
+        let envelope = Envelope {
+            request: Request::Get { key: String::from("k1") },
+            trace: Some(TraceContext {
+                traceparent: String::from("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+                tracestate: None,
+            }),
+        };
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(envelope, serde_json::from_str(&json).unwrap());
+    }
 }
@@ -0,0 +1,258 @@
+//! 导出文件格式
+//!
+//! 文件为 UTF-8 文本，每行一个 JSON 对象：
+//!
+//! ```text
+//! {"format":"kv-dump","version":1,"created":1700000000}
+//! {"ns":"default","key":"k1","value":"v1"}
+//! {"count":1,"crc32":"a4ff8c0b"}
+//! ```
+//!
+//! 第一行为文件头，最后一行为文件尾，中间每行一条数据。文件尾记录数据的条数，
+//! 以及文件头和所有数据行（每行都包含结尾的 `\n`）的 CRC32 校验和。
+
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::KvError;
+
+/// 文件头中的格式名称
+pub const FORMAT: &str = "kv-dump";
+
+/// 当前的格式版本，只能读取不高于该版本的文件
+pub const VERSION: u32 = 1;
+
+/// 文件头
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub format: String,
+    pub version: u32,
+    /// 导出时间，unix 时间戳（秒）
+    pub created: u64,
+}
+
+/// 一条数据
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub ns: String,
+    pub key: String,
+    pub value: String,
+}
+
+/// 文件尾
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trailer {
+    pub count: u64,
+    pub crc32: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Record(Record),
+    Trailer(Trailer),
+}
+
+/// 按行生成导出文件，调用方负责把返回的行（不含 `\n`）写出
+pub struct DumpWriter {
+    hasher: crc32fast::Hasher,
+    count: u64,
+}
+
+impl DumpWriter {
+    /// 创建写入器，同时返回文件头
+    pub fn new(created: u64) -> (Self, String) {
+        let mut writer = Self { hasher: crc32fast::Hasher::new(), count: 0 };
+        let header = Header { format: FORMAT.to_string(), version: VERSION, created };
+        let line = writer.line(&header);
+        (writer, line)
+    }
+
+    pub fn record(&mut self, ns: &str, key: &str, value: &str) -> String {
+        self.count += 1;
+        self.line(&Record { ns: ns.to_string(), key: key.to_string(), value: value.to_string() })
+    }
+
+    /// 已经写入的数据条数
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// 结束写入，返回文件尾
+    pub fn finish(self) -> String {
+        let trailer = Trailer { count: self.count, crc32: format!("{:08x}", self.hasher.finalize()) };
+        serde_json::to_string(&trailer).unwrap_or_default()
+    }
+
+    fn line(&mut self, value: &impl Serialize) -> String {
+        // 只包含字符串和数字的结构体序列化不会失败
+        let line = serde_json::to_string(value).unwrap_or_default();
+        self.hasher.update(line.as_bytes());
+        self.hasher.update(b"\n");
+        line
+    }
+}
+
+/// 逐行读取导出文件，读到文件尾时校验条数和校验和
+///
+/// 数据在校验之前就会返回，需要保证完整性时先完整读取一遍，确认没有错误后再使用。
+pub struct DumpReader<I> {
+    lines: I,
+    header: Header,
+    hasher: crc32fast::Hasher,
+    count: u64,
+    // 当前行号，从 1 开始，用于错误信息
+    line_no: u64,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = Result<String, KvError>>> DumpReader<I> {
+    /// 读取并检查文件头
+    pub fn new(mut lines: I) -> Result<Self, KvError> {
+        let line = lines.next().ok_or_else(|| invalid(1, "empty dump"))??;
+        let header: Header = serde_json::from_str(&line).map_err(|e| invalid(1, e))?;
+        if header.format != FORMAT {
+            return Err(invalid(1, format!("unknown format {}", header.format)));
+        }
+        if header.version > VERSION {
+            return Err(invalid(1, format!("unsupported version {}", header.version)));
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+
+        Ok(Self { lines, header, hasher, count: 0, line_no: 1, finished: false })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn verify(&mut self, trailer: Trailer) -> Result<(), KvError> {
+        if trailer.count != self.count {
+            return Err(invalid(self.line_no, format!("expect {} records, found {}", trailer.count, self.count)));
+        }
+
+        let crc32 = format!("{:08x}", mem::take(&mut self.hasher).finalize());
+        if trailer.crc32 != crc32 {
+            return Err(invalid(self.line_no, format!("checksum mismatch, expect {}, found {crc32}", trailer.crc32)));
+        }
+
+        if self.lines.next().is_some() {
+            return Err(invalid(self.line_no + 1, "data after trailer"));
+        }
+        Ok(())
+    }
+}
+
+impl<I: Iterator<Item = Result<String, KvError>>> Iterator for DumpReader<I> {
+    type Item = Result<Record, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        self.line_no += 1;
+        let line = match self.lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                self.finished = true;
+                return Some(Err(e));
+            }
+            None => {
+                self.finished = true;
+                return Some(Err(invalid(self.line_no, "missing trailer, dump is truncated")));
+            }
+        };
+
+        match serde_json::from_str(&line) {
+            Ok(Line::Record(record)) => {
+                self.count += 1;
+                self.hasher.update(line.as_bytes());
+                self.hasher.update(b"\n");
+                Some(Ok(record))
+            }
+            Ok(Line::Trailer(trailer)) => {
+                self.finished = true;
+                self.verify(trailer).err().map(Err)
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(invalid(self.line_no, e)))
+            }
+        }
+    }
+}
+
+fn invalid(line_no: u64, reason: impl ToString) -> KvError {
+    KvError::InvalidDump(format!("line {line_no}: {}", reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{DumpReader, DumpWriter, Record};
+    use crate::error::KvError;
+
+    fn dump(records: &[(&str, &str, &str)]) -> Vec<String> {
+        let (mut writer, header) = DumpWriter::new(1700000000);
+        let mut lines = vec![header];
+        for (ns, key, value) in records {
+            lines.push(writer.record(ns, key, value));
+        }
+        lines.push(writer.finish());
+        lines
+    }
+
+    fn read(lines: Vec<String>) -> Result<Vec<Record>, KvError> {
+        DumpReader::new(lines.into_iter().map(Ok))?.collect()
+    }
+
+    #[test]
+    fn dump_should_round_trip() {
+        let lines = dump(&[("default", "k1", "v1"), ("team-a", "k\n2", "\"v2\"")]);
+        assert_eq!(r#"{"format":"kv-dump","version":1,"created":1700000000}"#, lines[0]);
+        assert_eq!(4, lines.len());
+
+        let reader = DumpReader::new(lines.into_iter().map(Ok)).unwrap();
+        assert_eq!(1700000000, reader.header().created);
+
+        let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(2, records.len());
+        assert_eq!(Record { ns: "team-a".into(), key: "k\n2".into(), value: "\"v2\"".into() }, records[1]);
+    }
+
+    #[test]
+    fn corrupted_dump_should_be_rejected() {
+        let lines = dump(&[("default", "k1", "v1"), ("default", "k2", "v2")]);
+
+        // 修改数据
+        let mut modified = lines.clone();
+        modified[1] = modified[1].replace("v1", "v9");
+        assert!(matches!(read(modified), Err(KvError::InvalidDump(msg)) if msg.contains("checksum")));
+
+        // 缺少数据
+        let mut missing = lines.clone();
+        missing.remove(2);
+        assert!(matches!(read(missing), Err(KvError::InvalidDump(msg)) if msg.contains("expect 2 records")));
+
+        // 缺少文件尾
+        let mut truncated = lines.clone();
+        truncated.pop();
+        assert!(matches!(read(truncated), Err(KvError::InvalidDump(msg)) if msg.contains("truncated")));
+
+        // 无法解析的行
+        let mut garbage = lines.clone();
+        garbage[2] = String::from("garbage");
+        assert_eq!(Err(KvError::InvalidDump(String::from("line 3: expected value at line 1 column 1"))), read(garbage));
+    }
+
+    #[test]
+    fn newer_version_should_be_rejected() {
+        let mut lines = dump(&[]);
+        lines[0] = lines[0].replace("\"version\":1", "\"version\":2");
+        assert!(matches!(read(lines), Err(KvError::InvalidDump(msg)) if msg.contains("unsupported version 2")));
+    }
+}
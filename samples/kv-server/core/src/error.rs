@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum KvError {
     #[error("Not found for {0}")]
     NotFound(String),
@@ -11,12 +11,66 @@ pub enum KvError {
     #[error("Cannot process command {0} with key: {1}. Error: {2}")]
     StorageError(&'static str, String, String),
 
-    // #[error("Failed to encode protobuf message")]
-    // EncodeError(#[from] prost::EncodeError),
-    //
-    // #[error("Failed to decode protobuf message")]
-    // DecodeError(#[from] prost::DecodeError),
+    #[error("Not leader, current leader: {0:?}")]
+    NotLeader(Option<String>),
+
+    #[error("Frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Too many requests, retry after {0} ms")]
+    Throttled(u64),
+
+    #[error("Authentication failed.")]
+    AuthFailed,
+
+    #[error("Permission denied for namespace {0}.")]
+    Forbidden(String),
+
+    #[error("Script error: {0}")]
+    ScriptError(String),
+
+    #[error("Subscriber too slow, {0} messages pending. Disconnected.")]
+    SlowSubscriber(usize),
+
+    #[error("Invalid dump: {0}")]
+    InvalidDump(String),
+
+    #[error("Integrity check failed: {0}")]
+    IntegrityError(String),
+
+    #[error("Failed to encode protobuf message")]
+    EncodeError(#[from] prost::EncodeError),
+
+    #[error("Failed to decode protobuf message")]
+    DecodeError(#[from] prost::DecodeError),
 
     #[error("Internal error: {0}")]
     Internal(String),
 }
+
+impl KvError {
+    /// 错误类型的名称，用于统计指标
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KvError::NotFound(_) => "not_found",
+            KvError::InvalidCommand => "invalid_command",
+            KvError::StorageError(..) => "storage_error",
+            KvError::NotLeader(_) => "not_leader",
+            KvError::FrameTooLarge(_) => "frame_too_large",
+            KvError::Unavailable(_) => "unavailable",
+            KvError::Throttled(_) => "throttled",
+            KvError::AuthFailed => "auth_failed",
+            KvError::Forbidden(_) => "forbidden",
+            KvError::SlowSubscriber(_) => "slow_subscriber",
+            KvError::ScriptError(_) => "script_error",
+            KvError::InvalidDump(_) => "invalid_dump",
+            KvError::IntegrityError(_) => "integrity_error",
+            KvError::EncodeError(_) => "encode_error",
+            KvError::DecodeError(_) => "decode_error",
+            KvError::Internal(_) => "internal",
+        }
+    }
+}
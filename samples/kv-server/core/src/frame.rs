@@ -0,0 +1,154 @@
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::codec::Codec;
+use crate::compress;
+use crate::error::KvError;
+
+/// 帧头长度：4 字节大端序的 payload 长度
+pub const HEADER_LEN: usize = 4;
+
+/// 帧头的最高位表示 payload 经过压缩，格式见 [crate::compress]
+pub const COMPRESSED: u32 = 1 << 31;
+
+/// 帧头的次高位表示 payload 使用 protobuf 编码，否则为 JSON，见 [crate::codec]
+pub const PROTOBUF: u32 = 1 << 30;
+
+const FLAGS: u32 = COMPRESSED | PROTOBUF;
+
+/// 默认的最大帧长度
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// 将 JSON 格式的 payload 编码为一个帧写入 dst
+pub fn encode(payload: &[u8], dst: &mut BytesMut) {
+    encode_with(payload, Codec::Json, None, dst);
+}
+
+/// 同 encode，payload 不小于 threshold 时压缩，返回写入 dst 的 payload 长度
+pub fn encode_compressed(payload: &[u8], threshold: usize, dst: &mut BytesMut) -> usize {
+    encode_with(payload, Codec::Json, Some(threshold), dst)
+}
+
+/// 同 encode_compressed，codec 为 payload 的编码格式，threshold 为 None 时不压缩
+pub fn encode_with(payload: &[u8], codec: Codec, threshold: Option<usize>, dst: &mut BytesMut) -> usize {
+    let mut flags = match codec {
+        Codec::Json => 0,
+        Codec::Protobuf => PROTOBUF,
+    };
+    let compressed = threshold.and_then(|threshold| compress::compress(payload, threshold));
+    let payload = match &compressed {
+        Some(compressed) => {
+            flags |= COMPRESSED;
+            compressed
+        }
+        None => payload,
+    };
+
+    dst.reserve(HEADER_LEN + payload.len());
+    dst.put_u32(payload.len() as u32 | flags);
+    dst.put_slice(payload);
+    payload.len()
+}
+
+/// 从 src 中取出一个完整的帧，数据不足时返回 None 并保留 src 中的数据
+///
+/// 帧头声明的长度超过 max_len 时返回 FrameTooLarge，此时连接上的数据已无法继续解析。
+/// 压缩的 payload 在这里解压，解压失败或解压后超过 max_len 时返回 InvalidCommand，该帧已被取出。
+pub fn decode(src: &mut BytesMut, max_len: usize) -> Result<Option<BytesMut>, KvError> {
+    Ok(decode_with(src, max_len)?.map(|(_, payload)| payload))
+}
+
+/// 同 decode，同时返回 payload 的编码格式
+pub fn decode_with(src: &mut BytesMut, max_len: usize) -> Result<Option<(Codec, BytesMut)>, KvError> {
+    let Some(header) = src.first_chunk::<HEADER_LEN>() else {
+        return Ok(None);
+    };
+
+    let header = u32::from_be_bytes(*header);
+    let len = (header & !FLAGS) as usize;
+    if len > max_len {
+        return Err(KvError::FrameTooLarge(len));
+    }
+
+    if src.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+
+    src.advance(HEADER_LEN);
+    let payload = src.split_to(len);
+    let codec = if header & PROTOBUF == 0 { Codec::Json } else { Codec::Protobuf };
+    if header & COMPRESSED == 0 {
+        return Ok(Some((codec, payload)));
+    }
+    match compress::decompress(&payload, max_len) {
+        Some(payload) => Ok(Some((codec, BytesMut::from(&payload[..])))),
+        None => Err(KvError::InvalidCommand),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::codec::Codec;
+    use crate::error::KvError;
+    use crate::frame::{decode, decode_with, encode, encode_compressed, encode_with, MAX_FRAME_SIZE};
+
+    #[test]
+    fn frame_round_trip() {
+        let mut buf = BytesMut::new();
+        encode(b"hello", &mut buf);
+        encode(b"world", &mut buf);
+
+        // 半个帧
+        let mut partial = buf.split_to(6);
+        assert_eq!(Ok(None), decode(&mut partial, MAX_FRAME_SIZE));
+        assert_eq!(6, partial.len());
+
+        partial.unsplit(buf);
+        assert_eq!(&b"hello"[..], &decode(&mut partial, MAX_FRAME_SIZE).unwrap().unwrap()[..]);
+        assert_eq!(&b"world"[..], &decode(&mut partial, MAX_FRAME_SIZE).unwrap().unwrap()[..]);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut buf = BytesMut::new();
+        encode(&[0u8; 100], &mut buf);
+
+        // 只收到帧头就能判断
+        let mut header = buf.split_to(4);
+        assert_eq!(Err(KvError::FrameTooLarge(100)), decode(&mut header, 99));
+    }
+
+    #[test]
+    fn compressed_frame_round_trip() {
+        let payload = "value".repeat(1000);
+        let mut buf = BytesMut::new();
+        let len = encode_compressed(payload.as_bytes(), 1024, &mut buf);
+        assert!(len < payload.len());
+        assert_eq!(len + 4, buf.len());
+        // 小于阈值时不压缩
+        assert_eq!(5, encode_compressed(b"hello", 1024, &mut buf));
+
+        assert_eq!(payload.as_bytes(), &decode(&mut buf, MAX_FRAME_SIZE).unwrap().unwrap()[..]);
+        assert_eq!(&b"hello"[..], &decode(&mut buf, MAX_FRAME_SIZE).unwrap().unwrap()[..]);
+
+        // 解压后超过最大长度
+        encode_compressed(payload.as_bytes(), 1024, &mut buf);
+        assert_eq!(Err(KvError::InvalidCommand), decode(&mut buf, 1000));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn frame_should_carry_codec() {
+        let payload = "value".repeat(1000);
+        let mut buf = BytesMut::new();
+        encode_with(b"hello", Codec::Protobuf, None, &mut buf);
+        encode_with(payload.as_bytes(), Codec::Protobuf, Some(1024), &mut buf);
+        encode(b"world", &mut buf);
+
+        assert_eq!(Some((Codec::Protobuf, BytesMut::from(&b"hello"[..]))), decode_with(&mut buf, MAX_FRAME_SIZE).unwrap());
+        assert_eq!(Some((Codec::Protobuf, BytesMut::from(payload.as_bytes()))), decode_with(&mut buf, MAX_FRAME_SIZE).unwrap());
+        assert_eq!(Some((Codec::Json, BytesMut::from(&b"world"[..]))), decode_with(&mut buf, MAX_FRAME_SIZE).unwrap());
+    }
+}
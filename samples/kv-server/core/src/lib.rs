@@ -1,2 +1,7 @@
+pub mod backend;
+pub mod codec;
+pub mod compress;
 pub mod domain;
+pub mod dump;
 pub mod error;
+pub mod frame;
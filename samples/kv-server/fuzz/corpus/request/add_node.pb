@@ -0,0 +1,2 @@
+
+$:"127.0.0.1:5001127.0.0.1:6001
\ No newline at end of file
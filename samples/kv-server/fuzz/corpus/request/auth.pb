@@ -0,0 +1,3 @@
+
+J
+adminsecret
\ No newline at end of file
@@ -0,0 +1,4 @@
+
+J
+adminsecret?
+700-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01kv=1
\ No newline at end of file
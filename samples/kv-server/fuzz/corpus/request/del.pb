@@ -0,0 +1,3 @@
+
+*
+k1
\ No newline at end of file
@@ -0,0 +1,3 @@
+
+
+return kv.get(KEYS[1])k1
\ No newline at end of file
@@ -0,0 +1,3 @@
+
+0-
+(a9993e364706816aba3e25717850c26c9cd0d89d1
\ No newline at end of file
@@ -0,0 +1,5 @@
+
+
+
+
+user:1
\ No newline at end of file
@@ -0,0 +1,3 @@
+
+R
+lz4
\ No newline at end of file
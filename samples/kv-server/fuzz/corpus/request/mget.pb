@@ -0,0 +1,5 @@
+
+
+
+k1
+k2
\ No newline at end of file
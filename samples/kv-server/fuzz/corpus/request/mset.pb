@@ -0,0 +1,6 @@
+
+"
+
+k1v1
+
+k2v2
\ No newline at end of file
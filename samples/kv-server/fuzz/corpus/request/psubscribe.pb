@@ -0,0 +1,4 @@
+
+
+z
+news.*
\ No newline at end of file
@@ -0,0 +1,4 @@
+
+j
+
+newshi
\ No newline at end of file
@@ -0,0 +1,2 @@
+
+B
\ No newline at end of file
@@ -0,0 +1,3 @@
+
+2
+k1d
\ No newline at end of file
@@ -0,0 +1,4 @@
+
+Z
+
+tenant-a
\ No newline at end of file
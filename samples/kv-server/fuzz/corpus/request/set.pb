@@ -0,0 +1,4 @@
+
+
+
+user:1{"name":"kv"}
\ No newline at end of file
@@ -0,0 +1,3 @@
+
+r
+news
\ No newline at end of file
@@ -0,0 +1,37 @@
+//! 帧解码：网络上收到的任意字节都不能导致 panic 或者超过最大帧长度的分配
+#![no_main]
+
+use bytes::BytesMut;
+use kv_core::domain::Envelope;
+use kv_core::error::KvError;
+use kv_core::frame;
+use libfuzzer_sys::fuzz_target;
+
+// 比默认值小，让帧头声明的长度更容易越界
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    // 与服务端的连接一样连续解析，直到数据不足或者无法继续
+    loop {
+        let len = buf.len();
+        match frame::decode_with(&mut buf, MAX_FRAME_SIZE) {
+            Ok(Some((codec, payload))) => {
+                assert!(payload.len() <= MAX_FRAME_SIZE);
+                if let Err(e) = codec.decode::<Envelope>(&payload) {
+                    assert_eq!(KvError::InvalidCommand, e);
+                }
+            }
+            Ok(None) => {
+                assert_eq!(len, buf.len());
+                break;
+            }
+            Err(KvError::FrameTooLarge(_)) => break,
+            // 解压失败的帧已被取出
+            Err(e) => {
+                assert_eq!(KvError::InvalidCommand, e);
+                assert!(buf.len() < len);
+            }
+        }
+    }
+});
@@ -0,0 +1,20 @@
+//! 请求解析：任意 payload 都不能导致 panic，无法解析时返回 InvalidCommand，能解析的请求重新编码后得到相同的请求
+#![no_main]
+
+use kv_core::codec::Codec;
+use kv_core::domain::Envelope;
+use kv_core::error::KvError;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for codec in [Codec::Json, Codec::Protobuf] {
+        match codec.decode::<Envelope>(data) {
+            Ok(envelope) => {
+                let mut buf = vec![];
+                codec.encode(&envelope, &mut buf).unwrap();
+                assert_eq!(Ok(envelope), codec.decode::<Envelope>(&buf));
+            }
+            Err(e) => assert_eq!(KvError::InvalidCommand, e),
+        }
+    }
+});
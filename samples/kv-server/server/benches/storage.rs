@@ -0,0 +1,58 @@
+//! 比较各存储引擎在不同负载下的吞吐量和延迟
+//!
+//! ```shell
+//! cargo bench -p kv-server --features bench --bench storage
+//! # 只运行名称包含参数的负载
+//! cargo bench -p kv-server --features bench --bench storage -- read-only
+//! ```
+
+use std::path::Path;
+
+use kv_server::bench::{self, Report, Workload};
+use kv_server::config::{BTreeConfig, LsmConfig};
+use kv_server::storage::btree::BTree;
+use kv_server::storage::lsm::Lsm;
+use kv_server::storage::memory::Memory;
+use kv_server::storage::tiered::Tiered;
+
+fn main() {
+    // cargo bench 会传入 --bench 等参数
+    let filters: Vec<String> = std::env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect();
+    let dir = std::env::temp_dir().join(format!("kv-bench-{}", std::process::id()));
+
+    println!("{}", Report::header());
+    for workload in Workload::standard() {
+        if !filters.is_empty() && !filters.iter().any(|filter| workload.name.contains(filter.as_str())) {
+            continue;
+        }
+
+        // 每个负载使用新的数据；持久化引擎不 fsync，只比较数据结构本身
+        println!("{}", bench::run(&Memory::new(), &workload).unwrap());
+
+        let path = dir.join(workload.name).join("lsm");
+        let lsm = Lsm::open(&LsmConfig { path: path.clone(), sync: false, ..LsmConfig::default() }).unwrap();
+        println!("{}", bench::run(&lsm, &workload).unwrap());
+        drop(lsm);
+
+        // 缓存约为初始数据的十分之一，热点 key 在内存中
+        let path = dir.join(workload.name).join("tiered");
+        let cache_bytes = (workload.keys * workload.value_bytes / 10) as u64;
+        let tiered = Tiered::new(Lsm::open(&LsmConfig { path, sync: false, ..LsmConfig::default() }).unwrap(), cache_bytes);
+        println!("{}", bench::run(&tiered, &workload).unwrap());
+        drop(tiered);
+
+        let path = dir.join(workload.name).join("data.btree");
+        let btree = BTree::open(&BTreeConfig { path, sync: false, ..BTreeConfig::default() }).unwrap();
+        println!("{}", bench::run(&btree, &workload).unwrap());
+        drop(btree);
+
+        remove(&dir.join(workload.name));
+    }
+    remove(&dir);
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_dir_all(path) {
+        eprintln!("Failed to remove {}: {e}", path.display());
+    }
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // 使用内置的 protoc，构建环境不需要安装 protobuf
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_prost_build::compile_protos("proto/kv.proto").unwrap();
+}
@@ -0,0 +1,65 @@
+use std::time::Instant;
+
+use kv_core::domain::{PersistenceInfo, ServerInfo, StorageInfo};
+
+use crate::metrics::Metrics;
+use crate::storage::Storage;
+
+/// 汇总服务端运行状态，供 Info 命令返回
+pub fn info(storage: &impl Storage, metrics: &Metrics, started: Instant) -> ServerInfo {
+    let commands = metrics.command_counts();
+    let stats = storage.stats();
+
+    ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: started.elapsed().as_secs(),
+        connected_clients: metrics.active_connections(),
+        total_commands: commands.values().sum(),
+        commands,
+        storage: StorageInfo {
+            engine: storage.name().to_string(),
+            keys: stats.keys,
+            memory_bytes: stats.memory_bytes,
+            namespaces: stats.namespaces,
+            cache: stats.cache,
+            compression: stats.compression,
+        },
+        persistence: PersistenceInfo {
+            last_snapshot: stats.last_snapshot,
+            wal_bytes: stats.wal_bytes,
+        },
+        wire_compression: metrics.wire_compression_info(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::admin;
+    use crate::metrics::Metrics;
+    use crate::storage::memory::Memory;
+    use crate::storage::Storage;
+
+    #[test]
+    fn info_should_summarize_server() {
+        let store = Memory::new();
+        store.set("team-a", String::from("k1"), String::from("v1")).unwrap();
+
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        metrics.observe_request("set", Duration::from_micros(10), None);
+        metrics.observe_request("get", Duration::from_micros(10), None);
+        metrics.observe_request("get", Duration::from_micros(10), None);
+
+        let info = admin::info(&store, &metrics, Instant::now());
+
+        assert_eq!(1, info.connected_clients);
+        assert_eq!(3, info.total_commands);
+        assert_eq!(Some(&2), info.commands.get("get"));
+        assert_eq!("memory", info.storage.engine);
+        assert_eq!(1, info.storage.keys);
+        assert_eq!(Some(&1), info.storage.namespaces.get("team-a"));
+        assert_eq!(None, info.persistence.wal_bytes);
+    }
+}
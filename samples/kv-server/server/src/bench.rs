@@ -0,0 +1,292 @@
+//! 存储引擎的基准测试
+//!
+//! 请求通过 request_handler::handle 执行，与服务端处理请求的路径相同，只是不经过网络和序列化。
+//! 先写入 keys 个 key，再由 threads 个线程共执行 ops 个请求，统计吞吐量和延迟的分位数。
+//! 各存储引擎的对比见 `benches/storage.rs`。
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kv_core::domain::{Request, KV};
+use kv_core::error::KvError;
+
+use crate::request_handler;
+use crate::storage::Storage;
+
+const NAMESPACE: &str = "bench";
+/// 写入初始数据时每个 MSet 的 key 数量
+const LOAD_BATCH: usize = 1000;
+const SCAN_COUNT: usize = 10;
+
+/// 请求访问的 key 的分布
+#[derive(Debug, Clone, Copy)]
+pub enum Distribution {
+    /// 每个 key 的概率相同
+    Uniform,
+    /// 少数 key 被频繁访问，参数越大越集中，通常为 0.99
+    Zipf(f64),
+    /// 按顺序依次访问
+    Sequential,
+    /// 最近写入的 key 被频繁访问，写操作写入新的 key
+    Latest,
+}
+
+/// 负载
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub name: &'static str,
+    /// 初始数据的 key 数量
+    pub keys: usize,
+    pub value_bytes: usize,
+    pub ops: usize,
+    pub threads: usize,
+    /// Get 的比例
+    pub read_ratio: f64,
+    /// Scan 的比例，其余为 Set
+    pub scan_ratio: f64,
+    pub distribution: Distribution,
+}
+
+impl Workload {
+    /// 与 YCSB 类似的一组负载
+    pub fn standard() -> Vec<Workload> {
+        let base = Workload {
+            name: "",
+            keys: 100_000,
+            value_bytes: 100,
+            ops: 200_000,
+            threads: 4,
+            read_ratio: 0.0,
+            scan_ratio: 0.0,
+            distribution: Distribution::Zipf(0.99),
+        };
+        vec![
+            Workload { name: "update-heavy", read_ratio: 0.5, ..base.clone() },
+            Workload { name: "read-mostly", read_ratio: 0.95, ..base.clone() },
+            Workload { name: "read-only", read_ratio: 1.0, ..base.clone() },
+            Workload { name: "read-only-uniform", read_ratio: 1.0, distribution: Distribution::Uniform, ..base.clone() },
+            Workload { name: "read-latest", read_ratio: 0.95, distribution: Distribution::Latest, ..base.clone() },
+            Workload { name: "short-scans", scan_ratio: 0.95, ops: 50_000, ..base.clone() },
+            Workload { name: "sequential-writes", distribution: Distribution::Sequential, ..base.clone() },
+            Workload { name: "large-values", read_ratio: 0.9, keys: 10_000, value_bytes: 16 * 1024, ops: 50_000, ..base },
+        ]
+    }
+}
+
+/// 一个存储引擎执行一个负载的结果
+#[derive(Debug)]
+pub struct Report {
+    pub engine: &'static str,
+    pub workload: &'static str,
+    pub ops: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl Report {
+    pub fn header() -> String {
+        format!("{:<20} {:<8} {:>12} {:>10} {:>10}", "workload", "engine", "ops/s", "p50(us)", "p99(us)")
+    }
+
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<20} {:<8} {:>12.0} {:>10.1} {:>10.1}",
+            self.workload,
+            self.engine,
+            self.ops_per_sec(),
+            self.p50.as_secs_f64() * 1e6,
+            self.p99.as_secs_f64() * 1e6,
+        )
+    }
+}
+
+/// 写入初始数据并执行负载，storage 应该是空的
+pub fn run<S: Storage + Sync>(storage: &S, workload: &Workload) -> Result<Report, KvError> {
+    let value = "x".repeat(workload.value_bytes);
+    for start in (0..workload.keys).step_by(LOAD_BATCH) {
+        let kvs = (start..workload.keys.min(start + LOAD_BATCH)).map(|i| KV { key: key(i), value: value.clone() }).collect();
+        request_handler::handle(Request::MSet { kvs }, NAMESPACE, storage)?;
+    }
+
+    let zipf = match workload.distribution {
+        Distribution::Zipf(theta) => Some(Zipf::new(workload.keys, theta)),
+        Distribution::Latest => Some(Zipf::new(workload.keys, 0.99)),
+        _ => None,
+    };
+    let inserted = AtomicUsize::new(workload.keys);
+    let sequence = AtomicUsize::new(0);
+    let latencies = Mutex::new(Vec::with_capacity(workload.ops));
+    let error = Mutex::new(None);
+
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..workload.threads {
+            let ops = workload.ops / workload.threads + usize::from(t < workload.ops % workload.threads);
+            let (zipf, inserted, sequence, latencies, error, value) = (&zipf, &inserted, &sequence, &latencies, &error, &value);
+            scope.spawn(move || {
+                let mut rng = Rng::new(t as u64 + 1);
+                let mut local = Vec::with_capacity(ops);
+                for _ in 0..ops {
+                    let dice = rng.next_f64();
+                    let write = dice >= workload.read_ratio + workload.scan_ratio;
+                    let index = match workload.distribution {
+                        Distribution::Uniform => rng.next() as usize % workload.keys,
+                        Distribution::Zipf(_) => scatter(zipf.as_ref().unwrap().sample(&mut rng), workload.keys),
+                        Distribution::Sequential => sequence.fetch_add(1, Ordering::Relaxed) % workload.keys,
+                        Distribution::Latest if write => inserted.fetch_add(1, Ordering::Relaxed),
+                        Distribution::Latest => {
+                            let latest = inserted.load(Ordering::Relaxed);
+                            latest - 1 - zipf.as_ref().unwrap().sample(&mut rng).min(latest - 1)
+                        }
+                    };
+
+                    let request = if dice < workload.read_ratio {
+                        Request::Get { key: key(index) }
+                    } else if !write {
+                        Request::Scan { after: Some(key(index)), count: SCAN_COUNT }
+                    } else {
+                        Request::Set { kv: KV { key: key(index), value: value.clone() } }
+                    };
+
+                    let start = Instant::now();
+                    if let Err(e) = request_handler::handle(request, NAMESPACE, storage) {
+                        error.lock().unwrap().get_or_insert(e);
+                        return;
+                    }
+                    local.push(start.elapsed());
+                }
+                latencies.lock().unwrap().extend(local);
+            });
+        }
+    });
+    let elapsed = started.elapsed();
+
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut latencies = latencies.into_inner().unwrap();
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies.get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1))).copied().unwrap_or_default();
+
+    Ok(Report {
+        engine: storage.name(),
+        workload: workload.name,
+        ops: latencies.len(),
+        elapsed,
+        p50: percentile(0.5),
+        p99: percentile(0.99),
+    })
+}
+
+fn key(index: usize) -> String {
+    format!("user{index:010}")
+}
+
+/// 把排名打散到整个 key 空间，热点 key 不集中在相邻的位置
+fn scatter(rank: usize, keys: usize) -> usize {
+    (rank as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) as usize % keys
+}
+
+/// 按 Zipf 分布生成 [0, n) 中的排名，0 的概率最大
+struct Zipf {
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize, theta: f64) -> Self {
+        let mut sum = 0.0;
+        let mut cdf: Vec<_> = (1..=n)
+            .map(|i| {
+                sum += 1.0 / (i as f64).powf(theta);
+                sum
+            })
+            .collect();
+        cdf.iter_mut().for_each(|p| *p /= sum);
+        Self { cdf }
+    }
+
+    fn sample(&self, rng: &mut Rng) -> usize {
+        let u = rng.next_f64();
+        self.cdf.partition_point(|p| *p < u).min(self.cdf.len() - 1)
+    }
+}
+
+/// splitmix64，不需要密码学强度，只要求快且可以复现
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1) 中的均匀分布
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bench::{run, Distribution, Rng, Workload, Zipf};
+    use crate::storage::memory::Memory;
+    use crate::storage::Storage;
+
+    fn workload(distribution: Distribution) -> Workload {
+        Workload {
+            name: "test",
+            keys: 100,
+            value_bytes: 8,
+            ops: 1000,
+            threads: 3,
+            read_ratio: 0.5,
+            scan_ratio: 0.1,
+            distribution,
+        }
+    }
+
+    #[test]
+    fn workload_should_run_all_ops() {
+        for distribution in [Distribution::Uniform, Distribution::Zipf(0.99), Distribution::Sequential] {
+            let store = Memory::new();
+            let report = run(&store, &workload(distribution)).unwrap();
+            assert_eq!(1000, report.ops);
+            assert!(report.p50 <= report.p99);
+            // 只更新已有的 key
+            assert_eq!(100, store.stats().keys);
+        }
+
+        // 写操作写入新的 key
+        let store = Memory::new();
+        run(&store, &workload(Distribution::Latest)).unwrap();
+        assert!(store.stats().keys > 100);
+    }
+
+    #[test]
+    fn zipf_should_favor_low_ranks() {
+        let zipf = Zipf::new(1000, 0.99);
+        let mut rng = Rng::new(1);
+        let samples: Vec<_> = (0..10_000).map(|_| zipf.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|rank| *rank < 1000));
+        // 前 1% 的 key 占了相当大比例的访问
+        assert!(samples.iter().filter(|rank| **rank < 10).count() > 2500);
+    }
+}
@@ -0,0 +1,291 @@
+pub(crate) mod raft;
+pub(crate) mod transport;
+#[cfg(test)]
+mod simulation;
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+
+use kv_core::domain::{Request, Response};
+use kv_core::error::KvError;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::cluster::raft::{Command, ConfChange, Member, Message, RaftNode, Role};
+use crate::cluster::transport::Transport;
+
+enum Event {
+    Propose(Command, oneshot::Sender<Result<Response, KvError>>),
+    ConfChange(ConfChange, oneshot::Sender<Result<Response, KvError>>),
+    Message(Message),
+}
+
+/// 集群模式下的 Raft 节点句柄，可以在多个连接之间共享
+///
+/// Raft 状态机运行在单独的任务中，写命令提交后才应用到本地存储。
+#[derive(Clone)]
+pub struct Cluster {
+    tx: mpsc::Sender<Event>,
+    // 等待提交的最长时间
+    proposal_timeout: Duration,
+}
+
+impl Cluster {
+    /// 启动 Raft 驱动任务，`apply` 用于把已提交的写命令应用到本地存储中对应的命名空间
+    ///
+    /// 已提交的命令在单独的任务中按日志顺序应用，执行时间较长的脚本不会阻塞心跳和选举。
+    /// 写命令超过 proposal_timeout 没有提交时返回 Unavailable。
+    pub fn spawn<T, F, Fut>(node: RaftNode, transport: T, tick: Duration, proposal_timeout: Duration, apply: F) -> Self
+    where
+        T: Transport,
+        F: Fn(String, Request) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Response, KvError>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1024);
+        let (applier, committed) = mpsc::unbounded_channel();
+        tokio::spawn(run(node, transport, tick, applier, rx));
+        tokio::spawn(apply_committed(apply, committed));
+        Self { tx, proposal_timeout }
+    }
+
+    /// 将客户端请求转换为 Raft 日志，提交并应用后返回结果
+    pub async fn handle(&self, ns: &str, request: Request) -> Result<Response, KvError> {
+        let (tx, rx) = oneshot::channel();
+
+        let event = match request {
+            Request::AddNode { id, addr, raft_addr } => {
+                Event::ConfChange(ConfChange::AddNode { id, member: Member { addr, raft_addr } }, tx)
+            }
+            Request::RemoveNode { id } => Event::ConfChange(ConfChange::RemoveNode { id }, tx),
+            request => Event::Propose(Command::Write { ns: ns.to_string(), request }, tx),
+        };
+
+        if self.tx.send(event).await.is_err() {
+            return Err(KvError::Internal(String::from("Raft node stopped.")));
+        }
+
+        // 超时的请求结果未知，之后仍然可能被提交
+        match tokio::time::timeout(self.proposal_timeout, rx).await {
+            Ok(result) => result.unwrap_or_else(|_| Err(KvError::Internal(String::from("Raft node stopped.")))),
+            Err(_) => Err(KvError::Unavailable(String::from("Proposal was not committed in time."))),
+        }
+    }
+
+    /// 投递从其他节点收到的消息
+    pub async fn receive(&self, msg: Message) {
+        let _ = self.tx.send(Event::Message(msg)).await;
+    }
+}
+
+/// 已提交的日志命令，以及等待应用结果的回调
+type Committed = (Command, Option<oneshot::Sender<Result<Response, KvError>>>);
+
+async fn apply_committed<F, Fut>(apply: F, mut committed: mpsc::UnboundedReceiver<Committed>)
+where
+    F: Fn(String, Request) -> Fut,
+    Fut: Future<Output = Result<Response, KvError>>,
+{
+    while let Some((command, tx)) = committed.recv().await {
+        let response = match command {
+            Command::Write { ns, request } => apply(ns, request).await,
+            _ => Ok(Response::default()),
+        };
+        if let Some(tx) = tx {
+            let _ = tx.send(response);
+        }
+    }
+}
+
+async fn run<T>(mut node: RaftNode, transport: T, tick: Duration, applier: mpsc::UnboundedSender<Committed>, mut rx: mpsc::Receiver<Event>)
+where
+    T: Transport,
+{
+    let mut ticker = tokio::time::interval(tick);
+    // 等待提交的请求：index -> (term, 回调)
+    let mut pending: BTreeMap<u64, (u64, oneshot::Sender<Result<Response, KvError>>)> = BTreeMap::new();
+    let mut role = Role::Follower;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                node.tick();
+                // 超时返回的请求不再等待结果
+                pending.retain(|_, (_, tx)| !tx.is_closed());
+            }
+            event = rx.recv() => match event {
+                Some(Event::Propose(command, tx)) => match node.propose(command) {
+                    Ok((index, term)) => {
+                        pending.insert(index, (term, tx));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                },
+                Some(Event::ConfChange(change, tx)) => match node.propose_conf_change(change) {
+                    Ok((index, term)) => {
+                        pending.insert(index, (term, tx));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                },
+                Some(Event::Message(msg)) => node.step(msg),
+                None => break,
+            },
+        }
+
+        if node.role() != role {
+            role = node.role();
+            info!("Raft node {} became {:?} at term {}.", node.id(), role, node.term());
+        }
+
+        for msg in node.take_messages() {
+            match node.members().get(&msg.to) {
+                Some(member) => transport.send(&member.raft_addr, msg),
+                None => warn!("Drop raft message to unknown node {}.", msg.to),
+            }
+        }
+
+        for entry in node.take_committed() {
+            let tx = match pending.remove(&entry.index) {
+                Some((term, tx)) if term == entry.term => Some(tx),
+                // 同一位置的日志被新 leader 覆盖，原请求没有被提交
+                Some((_, tx)) => {
+                    let _ = tx.send(Err(KvError::NotLeader(node.leader_addr())));
+                    None
+                }
+                None => None,
+            };
+            if applier.send((entry.command, tx)).is_err() {
+                return;
+            }
+        }
+
+        // 失去 leader 身份后，未提交的请求结果未知，让客户端去新 leader 重试
+        if !node.is_leader() && !pending.is_empty() {
+            for (_, (_, tx)) in std::mem::take(&mut pending) {
+                let _ = tx.send(Err(KvError::NotLeader(node.leader_addr())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use kv_core::domain::{Request, Response, DEFAULT_NAMESPACE, KV};
+    use kv_core::error::KvError;
+
+    use crate::cluster::raft::{Member, Message, RaftConfig, RaftNode};
+    use crate::cluster::transport::Transport;
+    use crate::cluster::Cluster;
+    use crate::request_handler;
+    use crate::storage::memory::Memory;
+    use crate::storage::Storage;
+
+    /// 进程内的传输层，按地址把消息投递给对应的节点
+    #[derive(Clone, Default)]
+    struct ChannelTransport {
+        nodes: Arc<Mutex<HashMap<String, Cluster>>>,
+    }
+
+    impl Transport for ChannelTransport {
+        fn send(&self, addr: &str, msg: Message) {
+            if let Some(cluster) = self.nodes.lock().unwrap().get(addr).cloned() {
+                tokio::spawn(async move { cluster.receive(msg).await });
+            }
+        }
+    }
+
+    /// 启动 3 个节点
+    fn start(transport: &ChannelTransport) -> (Vec<Cluster>, Vec<Arc<Memory>>) {
+        let members: BTreeMap<_, _> = (1..=3)
+            .map(|id| (id, Member { addr: format!("client-{id}"), raft_addr: format!("raft-{id}") }))
+            .collect();
+
+        let mut clusters = vec![];
+        let mut stores = vec![];
+
+        for id in 1..=3 {
+            let store = Arc::new(Memory::new());
+            let node = RaftNode::new(id, members.clone(), RaftConfig { seed: 7, ..Default::default() });
+
+            let apply_store = store.clone();
+            let cluster = Cluster::spawn(node, transport.clone(), Duration::from_millis(5), Duration::from_secs(5), move |ns, req| {
+                let store = apply_store.clone();
+                async move { request_handler::handle(req, &ns, store.as_ref()) }
+            });
+
+            transport.nodes.lock().unwrap().insert(format!("raft-{id}"), cluster.clone());
+            clusters.push(cluster);
+            stores.push(store);
+        }
+        (clusters, stores)
+    }
+
+    /// 选举完成前可能被重定向，轮流重试，返回 leader 的下标
+    async fn write(clusters: &[Cluster], request: Request) -> usize {
+        for _ in 0..200 {
+            for (i, cluster) in clusters.iter().enumerate() {
+                if cluster.handle(DEFAULT_NAMESPACE, request.clone()).await == Ok(Response::default()) {
+                    return i;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Write was not applied.");
+    }
+
+    fn set(key: &str, value: &str) -> Request {
+        Request::Set { kv: KV { key: key.to_string(), value: value.to_string() } }
+    }
+
+    #[tokio::test]
+    async fn write_should_be_applied_on_all_nodes() {
+        let transport = ChannelTransport::default();
+        let (clusters, stores) = start(&transport);
+        write(&clusters, set("k1", "v1")).await;
+
+        // follower 在下一次心跳后应用
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for store in &stores {
+            assert_eq!(Ok(vec![String::from("v1")]), store.get(DEFAULT_NAMESPACE, "k1"));
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_write_should_fail_when_leader_loses_quorum() {
+        let transport = ChannelTransport::default();
+        let (clusters, _) = start(&transport);
+        let leader = write(&clusters, set("k1", "v1")).await;
+
+        // 断开所有节点之间的网络，leader 在选举超时后退位，等待中的写命令返回 NotLeader
+        transport.nodes.lock().unwrap().clear();
+        let res = tokio::time::timeout(Duration::from_secs(1), clusters[leader].handle(DEFAULT_NAMESPACE, set("k2", "v2"))).await;
+        assert!(matches!(res, Ok(Err(KvError::NotLeader(_)))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn write_should_time_out() {
+        let members = BTreeMap::from([(1, Member { addr: String::from("client-1"), raft_addr: String::from("raft-1") })]);
+        let node = RaftNode::new(1, members, RaftConfig::default());
+        // 应用一直不结束，写命令等到超时
+        let cluster = Cluster::spawn(node, ChannelTransport::default(), Duration::from_millis(5), Duration::from_millis(100), |_, _| {
+            std::future::pending()
+        });
+
+        let mut res = Err(KvError::NotLeader(None));
+        for _ in 0..100 {
+            res = cluster.handle(DEFAULT_NAMESPACE, set("k1", "v1")).await;
+            if !matches!(res, Err(KvError::NotLeader(_))) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(res, Err(KvError::Unavailable(_))), "{res:?}");
+    }
+}
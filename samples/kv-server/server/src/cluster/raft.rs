@@ -0,0 +1,607 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use kv_core::domain::Request;
+use kv_core::error::KvError;
+use serde::{Deserialize, Serialize};
+
+pub type NodeId = u64;
+
+/// 单次 AppendEntries 最多携带的日志条数
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+/// 集群成员的地址信息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    /// 对客户端提供服务的地址，用于 leader 重定向
+    pub addr: String,
+    /// 节点间 Raft 通信的地址
+    pub raft_addr: String,
+}
+
+/// 写入 Raft 日志的命令
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// leader 当选后写入的空日志，用于提交之前任期的日志
+    Noop,
+    /// 在命名空间 ns 中执行的写命令
+    Write { ns: String, request: Request },
+    /// 成员变更后完整的成员列表，新加入的节点由此得到集群的全部成员
+    Members(BTreeMap<NodeId, Member>),
+}
+
+/// 成员变更，每次只能增加或删除一个节点
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfChange {
+    AddNode { id: NodeId, member: Member },
+    RemoveNode { id: NodeId },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: u64,
+    pub body: MessageBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessageBody {
+    RequestVote { last_log_index: u64, last_log_term: u64 },
+    Vote { granted: bool },
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// 失败时 match_index 为 follower 给出的回退提示
+    AppendResponse { success: bool, match_index: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// 选举超时的最小 tick 数，实际超时在 [election_ticks, 2 * election_ticks) 之间随机
+    pub election_ticks: u32,
+    /// leader 发送心跳的间隔 tick 数
+    pub heartbeat_ticks: u32,
+    /// 随机数种子，相同的种子得到相同的选举超时序列
+    pub seed: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            seed: 0,
+        }
+    }
+}
+
+/// Raft 状态机
+///
+/// 不做任何 IO：由调用方驱动 `tick` 和 `step`，再通过 `take_messages` 取出待发送的消息，
+/// 通过 `take_committed` 取出已提交的日志。这样可以在测试中确定性地模拟网络。
+///
+/// term、投票和日志只保存在内存中，节点重启后需要先移出集群再重新加入。
+/// 日志没有快照和截断：所有日志一直保留在内存中，随写命令的数量无限增长，
+/// 新加入或重新加入的节点从第 1 条日志开始回放。
+///
+/// leader 在一个选举超时内没有收到多数节点的响应时退为 follower（check quorum），
+/// 不会在网络分区的少数派一侧一直认为自己是 leader。follower 在选举超时内收到过 leader 的消息时
+/// 不响应投票请求，保证新 leader 只能在旧 leader 退位之后选出，本地读不会读到过期的 leader 的数据。
+#[derive(Debug)]
+pub struct RaftNode {
+    id: NodeId,
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+
+    // log[0] 是占位用的哨兵，保证 log[i].index == i；已经应用的日志也不会删除
+    log: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+
+    // 成员变更在日志追加时即生效，日志被截断时从初始成员重新计算
+    initial_members: BTreeMap<NodeId, Member>,
+    members: BTreeMap<NodeId, Member>,
+    // 最近一次成员变更日志的位置
+    conf_index: u64,
+
+    votes: BTreeSet<NodeId>,
+    // leader 上各节点距离上一次响应的 tick 数
+    acked: BTreeMap<NodeId, u32>,
+    next_index: BTreeMap<NodeId, u64>,
+    match_index: BTreeMap<NodeId, u64>,
+
+    config: RaftConfig,
+    elapsed: u32,
+    timeout: u32,
+    rng: u64,
+
+    outbox: Vec<Message>,
+}
+
+impl RaftNode {
+    /// 创建节点。新加入集群的节点 `members` 传空即可，它会从 leader 的日志中得到成员信息，
+    /// 不在成员列表中的节点不会发起选举。
+    pub fn new(id: NodeId, members: BTreeMap<NodeId, Member>, config: RaftConfig) -> Self {
+        let sentinel = Entry { term: 0, index: 0, command: Command::Noop };
+        let rng = (config.seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1;
+
+        let mut node = Self {
+            id,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: vec![sentinel],
+            commit_index: 0,
+            last_applied: 0,
+            initial_members: members.clone(),
+            members,
+            conf_index: 0,
+            votes: BTreeSet::new(),
+            acked: BTreeMap::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            config,
+            elapsed: 0,
+            timeout: 0,
+            rng,
+            outbox: vec![],
+        };
+        node.timeout = node.random_timeout();
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    #[cfg(test)]
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn members(&self) -> &BTreeMap<NodeId, Member> {
+        &self.members
+    }
+
+    /// leader 对客户端服务的地址
+    pub fn leader_addr(&self) -> Option<String> {
+        self.leader
+            .and_then(|id| self.members.get(&id))
+            .map(|m| m.addr.clone())
+    }
+
+    /// 逻辑时钟前进一步
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+
+        match self.role {
+            Role::Leader => {
+                let election_ticks = self.config.election_ticks;
+                let mut active = BTreeSet::from([self.id]);
+                for (id, ticks) in self.acked.iter_mut() {
+                    *ticks += 1;
+                    if *ticks < election_ticks {
+                        active.insert(*id);
+                    }
+                }
+                if !self.has_quorum(&active) {
+                    self.become_follower(self.term, None);
+                    return;
+                }
+                if self.elapsed >= self.config.heartbeat_ticks {
+                    self.elapsed = 0;
+                    self.broadcast_append();
+                }
+            }
+            _ => {
+                if self.elapsed >= self.timeout && self.members.contains_key(&self.id) {
+                    self.campaign();
+                }
+            }
+        }
+    }
+
+    /// 提交一条命令，返回日志的 (index, term)。非 leader 返回 NotLeader 以便客户端重定向。
+    pub fn propose(&mut self, command: Command) -> Result<(u64, u64), KvError> {
+        if self.role != Role::Leader {
+            return Err(KvError::NotLeader(self.leader_addr()));
+        }
+
+        let index = self.append(command);
+        self.broadcast_append();
+        self.maybe_commit();
+
+        Ok((index, self.term))
+    }
+
+    /// 提交一次成员变更
+    pub fn propose_conf_change(&mut self, change: ConfChange) -> Result<(u64, u64), KvError> {
+        if self.role != Role::Leader {
+            return Err(KvError::NotLeader(self.leader_addr()));
+        }
+
+        // 同一时间只允许一个未提交的成员变更
+        if self.conf_index > self.commit_index {
+            return Err(KvError::Internal(String::from("Another membership change is in progress.")));
+        }
+
+        let mut members = self.members.clone();
+        match change {
+            ConfChange::AddNode { id, member } => members.insert(id, member),
+            ConfChange::RemoveNode { id } => members.remove(&id),
+        };
+
+        self.propose(Command::Members(members))
+    }
+
+    /// 处理一条收到的消息
+    pub fn step(&mut self, msg: Message) {
+        if msg.to != self.id {
+            return;
+        }
+
+        // 忽略已被移出集群的节点发起的选举，避免它们不断用更高的 term 打断集群
+        if matches!(msg.body, MessageBody::RequestVote { .. }) && !self.members.contains_key(&msg.from) {
+            return;
+        }
+
+        // 当前 leader 仍然有效时忽略投票请求，也不更新 term
+        if matches!(msg.body, MessageBody::RequestVote { .. }) && msg.term > self.term && self.in_lease() {
+            return;
+        }
+
+        if msg.term > self.term {
+            let leader = match msg.body {
+                MessageBody::AppendEntries { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader);
+        }
+
+        if msg.term < self.term {
+            // 过期的请求直接拒绝，让对方更新 term
+            match msg.body {
+                MessageBody::RequestVote { .. } => self.send(msg.from, MessageBody::Vote { granted: false }),
+                MessageBody::AppendEntries { .. } => {
+                    self.send(msg.from, MessageBody::AppendResponse { success: false, match_index: 0 })
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match msg.body {
+            MessageBody::RequestVote { last_log_index, last_log_term } => {
+                let can_vote = self.voted_for.is_none() || self.voted_for == Some(msg.from);
+                let up_to_date = (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = can_vote && up_to_date;
+
+                if granted {
+                    self.voted_for = Some(msg.from);
+                    self.elapsed = 0;
+                }
+                self.send(msg.from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.has_quorum(&self.votes) {
+                        self.become_leader();
+                    }
+                }
+            }
+            MessageBody::AppendEntries { prev_log_index, prev_log_term, entries, leader_commit } => {
+                self.role = Role::Follower;
+                self.leader = Some(msg.from);
+                self.elapsed = 0;
+                self.handle_append(msg.from, prev_log_index, prev_log_term, entries, leader_commit);
+            }
+            MessageBody::AppendResponse { success, match_index } => {
+                if self.role == Role::Leader {
+                    self.acked.insert(msg.from, 0);
+                    self.handle_append_response(msg.from, success, match_index);
+                }
+            }
+        }
+    }
+
+    /// 取出待发送的消息
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// 取出已提交但还未应用的日志
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        let from = self.last_applied as usize + 1;
+        let to = self.commit_index as usize;
+        self.last_applied = self.commit_index;
+
+        if from > to {
+            return vec![];
+        }
+        self.log[from..=to].to_vec()
+    }
+
+    fn handle_append(
+        &mut self,
+        leader: NodeId,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) {
+        if prev_log_index > self.last_index() {
+            let match_index = self.last_index();
+            self.send(leader, MessageBody::AppendResponse { success: false, match_index });
+            return;
+        }
+
+        if self.term_at(prev_log_index) != prev_log_term {
+            self.send(leader, MessageBody::AppendResponse { success: false, match_index: prev_log_index - 1 });
+            return;
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+
+        for entry in entries {
+            let index = entry.index as usize;
+            if index < self.log.len() {
+                if self.log[index].term == entry.term {
+                    continue;
+                }
+                // 冲突的日志及其之后的日志都要删除
+                self.log.truncate(index);
+                if self.conf_index >= entry.index {
+                    self.recompute_members();
+                }
+            }
+
+            if let Command::Members(members) = &entry.command {
+                self.update_members(entry.index, members.clone());
+            }
+            self.log.push(entry);
+        }
+
+        let commit = leader_commit.min(last_new);
+        if commit > self.commit_index {
+            self.commit_index = commit;
+        }
+
+        self.send(leader, MessageBody::AppendResponse { success: true, match_index: last_new });
+    }
+
+    fn handle_append_response(&mut self, from: NodeId, success: bool, match_index: u64) {
+        if !self.members.contains_key(&from) {
+            return;
+        }
+
+        if success {
+            let matched = self.match_index.entry(from).or_insert(0);
+            *matched = (*matched).max(match_index);
+            let next = *matched + 1;
+            self.next_index.insert(from, next);
+
+            self.maybe_commit();
+
+            if next <= self.last_index() {
+                self.send_append(from);
+            }
+        } else {
+            let next = self.next_index.get(&from).copied().unwrap_or(1);
+            let next = next.saturating_sub(1).min(match_index + 1).max(1);
+            self.next_index.insert(from, next);
+            self.send_append(from);
+        }
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes = BTreeSet::from([self.id]);
+        self.elapsed = 0;
+        self.timeout = self.random_timeout();
+
+        if self.has_quorum(&self.votes) {
+            self.become_leader();
+            return;
+        }
+
+        let (last_log_index, last_log_term) = (self.last_index(), self.last_term());
+        for peer in self.peers() {
+            self.send(peer, MessageBody::RequestVote { last_log_index, last_log_term });
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.elapsed = 0;
+        self.timeout = self.random_timeout();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.acked = self.peers().into_iter().map(|id| (id, 0)).collect();
+
+        let next = self.last_index() + 1;
+        self.next_index = self.peers().into_iter().map(|id| (id, next)).collect();
+        self.match_index = self.peers().into_iter().map(|id| (id, 0)).collect();
+
+        self.append(Command::Noop);
+        self.broadcast_append();
+        self.maybe_commit();
+    }
+
+    fn append(&mut self, command: Command) -> u64 {
+        let index = self.last_index() + 1;
+        if let Command::Members(members) = &command {
+            self.update_members(index, members.clone());
+        }
+        self.log.push(Entry { term: self.term, index, command });
+        index
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index.get(&peer).copied().unwrap_or(self.last_index() + 1);
+        let prev_log_index = next - 1;
+        let prev_log_term = self.term_at(prev_log_index);
+
+        let entries = self.log[next as usize..]
+            .iter()
+            .take(MAX_ENTRIES_PER_APPEND)
+            .cloned()
+            .collect();
+
+        self.send(peer, MessageBody::AppendEntries {
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+        });
+    }
+
+    fn maybe_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            // 只能通过计数提交当前任期的日志
+            if self.term_at(index) != self.term {
+                break;
+            }
+
+            let acked: BTreeSet<NodeId> = self.match_index.iter()
+                .filter(|(_, matched)| **matched >= index)
+                .map(|(id, _)| *id)
+                .chain(std::iter::once(self.id))
+                .collect();
+
+            if self.has_quorum(&acked) {
+                self.commit_index = index;
+                break;
+            }
+        }
+
+        // leader 被移出集群，在变更提交后退位
+        if self.role == Role::Leader && !self.members.contains_key(&self.id) && self.conf_index <= self.commit_index {
+            self.become_follower(self.term, None);
+        }
+    }
+
+    fn update_members(&mut self, index: u64, members: BTreeMap<NodeId, Member>) {
+        if self.role == Role::Leader {
+            let next = self.last_index() + 1;
+            for id in members.keys().filter(|id| **id != self.id) {
+                if !self.next_index.contains_key(id) {
+                    self.next_index.insert(*id, next);
+                    self.match_index.insert(*id, 0);
+                    // 与当选时的成员一样，新加入的节点有一个选举超时的时间做出第一次响应
+                    self.acked.insert(*id, 0);
+                }
+            }
+            self.next_index.retain(|id, _| members.contains_key(id));
+            self.match_index.retain(|id, _| members.contains_key(id));
+            self.acked.retain(|id, _| members.contains_key(id));
+        }
+
+        self.members = members;
+        self.conf_index = index;
+    }
+
+    fn recompute_members(&mut self) {
+        let last = self.log.iter().rev().find_map(|e| match &e.command {
+            Command::Members(members) => Some((e.index, members.clone())),
+            _ => None,
+        });
+
+        let (index, members) = last.unwrap_or_else(|| (0, self.initial_members.clone()));
+        self.update_members(index, members);
+    }
+
+    /// leader 自身，或者在选举超时内收到过 leader 消息的 follower
+    fn in_lease(&self) -> bool {
+        match self.role {
+            Role::Leader => true,
+            Role::Follower => self.leader.is_some() && self.elapsed < self.config.election_ticks,
+            Role::Candidate => false,
+        }
+    }
+
+    fn has_quorum(&self, voters: &BTreeSet<NodeId>) -> bool {
+        let count = voters.iter().filter(|id| self.members.contains_key(id)).count();
+        count > self.members.len() / 2
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.members.keys().copied().filter(|id| *id != self.id).collect()
+    }
+
+    fn send(&mut self, to: NodeId, body: MessageBody) {
+        self.outbox.push(Message { from: self.id, to, term: self.term, body });
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64 - 1
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|e| e.term).unwrap_or(0)
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        self.log.get(index as usize).map(|e| e.term).unwrap_or(0)
+    }
+
+    /// xorshift64，保证相同种子下结果确定
+    fn random_timeout(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let ticks = self.config.election_ticks.max(1);
+        ticks + (self.rng % ticks as u64) as u32
+    }
+}
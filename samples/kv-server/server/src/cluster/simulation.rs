@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use kv_core::domain::{Request, Response, DEFAULT_NAMESPACE, KV};
+use kv_core::error::KvError;
+
+use crate::cluster::raft::{Command, ConfChange, Member, Message, NodeId, RaftConfig, RaftNode};
+use crate::request_handler;
+use crate::storage::memory::Memory;
+
+/// 进程内的多节点模拟，网络故障由随机数种子决定，每次运行结果相同
+pub struct Simulation {
+    nodes: BTreeMap<NodeId, RaftNode>,
+    stores: BTreeMap<NodeId, Memory>,
+    network: VecDeque<Message>,
+    // 被隔离的节点，与其他节点之间的消息全部丢弃
+    isolated: BTreeSet<NodeId>,
+    // 丢包率，百分比
+    drop_rate: u64,
+    rng: u64,
+    seed: u64,
+}
+
+impl Simulation {
+    pub fn new(size: u64, seed: u64) -> Self {
+        let members: BTreeMap<NodeId, Member> = (1..=size).map(|id| (id, member(id))).collect();
+
+        let mut sim = Self {
+            nodes: BTreeMap::new(),
+            stores: BTreeMap::new(),
+            network: VecDeque::new(),
+            isolated: BTreeSet::new(),
+            drop_rate: 0,
+            rng: seed | 1,
+            seed,
+        };
+
+        for id in 1..=size {
+            sim.start_node(id, members.clone());
+        }
+        sim
+    }
+
+    /// 启动一个新节点，新加入的节点 members 为空
+    pub fn start_node(&mut self, id: NodeId, members: BTreeMap<NodeId, Member>) {
+        let config = RaftConfig { seed: self.seed, ..Default::default() };
+        self.nodes.insert(id, RaftNode::new(id, members, config));
+        self.stores.insert(id, Memory::new());
+    }
+
+    pub fn node(&self, id: NodeId) -> &RaftNode {
+        &self.nodes[&id]
+    }
+
+    pub fn store(&self, id: NodeId) -> &Memory {
+        &self.stores[&id]
+    }
+
+    pub fn set_drop_rate(&mut self, percent: u64) {
+        self.drop_rate = percent;
+    }
+
+    pub fn isolate(&mut self, id: NodeId) {
+        self.isolated.insert(id);
+    }
+
+    pub fn heal(&mut self) {
+        self.isolated.clear();
+    }
+
+    /// 当前任期最高的 leader
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes.values()
+            .filter(|n| n.is_leader() && !self.isolated.contains(&n.id()))
+            .max_by_key(|n| n.term())
+            .map(|n| n.id())
+    }
+
+    /// 运行若干个 tick，每个 tick 后投递所有在途消息
+    pub fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for node in self.nodes.values_mut() {
+                node.tick();
+            }
+            self.deliver();
+        }
+    }
+
+    /// 运行直到选出 leader
+    pub fn elect(&mut self) -> NodeId {
+        for _ in 0..1000 {
+            if let Some(id) = self.leader() {
+                return id;
+            }
+            self.run(1);
+        }
+        panic!("No leader elected.");
+    }
+
+    pub fn propose(&mut self, id: NodeId, command: Command) -> Result<(u64, u64), KvError> {
+        let res = self.nodes.get_mut(&id).unwrap().propose(command);
+        self.deliver();
+        res
+    }
+
+    pub fn conf_change(&mut self, id: NodeId, change: ConfChange) -> Result<(u64, u64), KvError> {
+        let res = self.nodes.get_mut(&id).unwrap().propose_conf_change(change);
+        self.deliver();
+        res
+    }
+
+    /// 向 leader 写入一个值
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(u64, u64), KvError> {
+        let leader = self.elect();
+        let kv = KV { key: key.to_string(), value: value.to_string() };
+        self.propose(leader, write(Request::Set { kv }))
+    }
+
+    fn deliver(&mut self) {
+        loop {
+            self.collect_messages();
+            let Some(msg) = self.network.pop_front() else { break };
+
+            let cut = self.isolated.contains(&msg.from) || self.isolated.contains(&msg.to);
+            if cut || self.random() % 100 < self.drop_rate {
+                continue;
+            }
+
+            if let Some(node) = self.nodes.get_mut(&msg.to) {
+                node.step(msg);
+            }
+        }
+        self.apply_committed();
+    }
+
+    fn collect_messages(&mut self) {
+        for node in self.nodes.values_mut() {
+            self.network.extend(node.take_messages());
+        }
+    }
+
+    fn apply_committed(&mut self) {
+        for (id, node) in self.nodes.iter_mut() {
+            for entry in node.take_committed() {
+                if let Command::Write { ns, request } = entry.command {
+                    let response = request_handler::handle(request, &ns, &self.stores[id]);
+                    assert_eq!(Ok(Response::default()), response);
+                }
+            }
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+pub fn write(request: Request) -> Command {
+    Command::Write { ns: DEFAULT_NAMESPACE.to_string(), request }
+}
+
+pub fn member(id: NodeId) -> Member {
+    Member { addr: format!("127.0.0.1:{}", 6735 + id), raft_addr: format!("127.0.0.1:{}", 7735 + id) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use kv_core::domain::{Request, DEFAULT_NAMESPACE, KV};
+    use kv_core::error::KvError;
+
+    use crate::cluster::raft::{Command, ConfChange, RaftConfig, Role};
+    use crate::cluster::simulation::{member, write, Simulation};
+    use crate::storage::Storage;
+
+    fn get(sim: &Simulation, id: u64, key: &str) -> Vec<String> {
+        sim.store(id).get(DEFAULT_NAMESPACE, key).unwrap()
+    }
+
+    #[test]
+    fn should_elect_single_leader() {
+        let mut sim = Simulation::new(3, 1);
+        let leader = sim.elect();
+
+        sim.run(50);
+        assert_eq!(Some(leader), sim.leader());
+
+        let leaders = (1..=3).filter(|id| sim.node(*id).role() == Role::Leader).count();
+        assert_eq!(1, leaders);
+    }
+
+    #[test]
+    fn write_should_replicate_to_all_nodes() {
+        let mut sim = Simulation::new(5, 2);
+        sim.set("k1", "v1").unwrap();
+        sim.run(5);
+
+        for id in 1..=5 {
+            assert_eq!(vec![String::from("v1")], get(&sim, id, "k1"));
+        }
+    }
+
+    #[test]
+    fn follower_should_redirect_to_leader() {
+        let mut sim = Simulation::new(3, 3);
+        let leader = sim.elect();
+        sim.run(5);
+
+        let follower = (1..=3).find(|id| *id != leader).unwrap();
+        let res = sim.propose(follower, Command::Noop);
+        assert_eq!(Err(KvError::NotLeader(Some(member(leader).addr))), res);
+    }
+
+    #[test]
+    fn minority_should_not_commit() {
+        let mut sim = Simulation::new(3, 4);
+        let leader = sim.elect();
+        sim.run(5);
+
+        // leader 与其他节点隔离后仍然可以接受写入，但无法提交
+        sim.isolate(leader);
+        let (index, _) = sim.propose(leader, write(set("k1", "lost"))).unwrap();
+        // 一个选举超时内没有收到多数节点的响应，旧 leader 退位，不再接受写入
+        sim.run(RaftConfig::default().election_ticks as usize);
+        assert_ne!(Role::Leader, sim.node(leader).role());
+        assert!(sim.propose(leader, Command::Noop).is_err());
+        sim.run(100);
+        assert!(sim.node(leader).commit_index() < index);
+        assert!(get(&sim, leader, "k1").is_empty());
+
+        // 多数派选出新的 leader 并继续服务
+        let new_leader = sim.elect();
+        assert_ne!(leader, new_leader);
+        sim.set("k1", "v1").unwrap();
+        sim.run(5);
+
+        // 恢复网络后旧 leader 未提交的日志被覆盖
+        sim.heal();
+        sim.run(50);
+        for id in 1..=3 {
+            assert_eq!(vec![String::from("v1")], get(&sim, id, "k1"));
+        }
+    }
+
+    #[test]
+    fn should_converge_with_lossy_network() {
+        let mut sim = Simulation::new(5, 5);
+        sim.set_drop_rate(30);
+
+        let mut accepted = 0;
+        for i in 0..20 {
+            // 丢包时 leader 可能发生切换，被接受的写入也可能没有提交而被覆盖
+            if sim.set(&format!("k{i}"), &format!("v{i}")).is_ok() {
+                accepted += 1;
+            }
+            sim.run(10);
+        }
+        assert!(accepted > 0);
+
+        sim.set_drop_rate(0);
+        let leader = sim.elect();
+        sim.propose(leader, Command::Noop).unwrap();
+        sim.run(50);
+
+        let committed = (0..20).filter(|i| !get(&sim, leader, &format!("k{i}")).is_empty()).count();
+        assert!(committed > 0);
+
+        // 所有节点的数据一致
+        for i in 0..20 {
+            let key = format!("k{i}");
+            let expected = get(&sim, leader, &key);
+            for id in 1..=5 {
+                assert_eq!(expected, get(&sim, id, &key));
+            }
+        }
+    }
+
+    #[test]
+    fn should_add_and_remove_nodes() {
+        let mut sim = Simulation::new(3, 6);
+        sim.set("k1", "v1").unwrap();
+        sim.run(5);
+
+        // 新节点以空成员列表启动，由 leader 同步日志
+        sim.start_node(4, BTreeMap::new());
+        let leader = sim.elect();
+        sim.conf_change(leader, ConfChange::AddNode { id: 4, member: member(4) }).unwrap();
+        sim.run(20);
+
+        assert_eq!(4, sim.node(leader).members().len());
+        assert_eq!(4, sim.node(4).members().len());
+        assert_eq!(vec![String::from("v1")], get(&sim, 4, "k1"));
+
+        // 上一个成员变更未提交前不允许新的变更
+        sim.isolate(4);
+        sim.isolate(leader);
+        sim.conf_change(leader, ConfChange::RemoveNode { id: 4 }).unwrap();
+        assert!(sim.conf_change(leader, ConfChange::RemoveNode { id: 3 }).is_err());
+        sim.heal();
+        sim.run(50);
+
+        // 移除当前 leader，剩余节点选出新的 leader
+        let leader = sim.elect();
+        sim.conf_change(leader, ConfChange::RemoveNode { id: leader }).unwrap();
+        sim.run(50);
+
+        assert_ne!(Role::Leader, sim.node(leader).role());
+        let new_leader = sim.elect();
+        assert_ne!(leader, new_leader);
+        assert_eq!(2, sim.node(new_leader).members().len());
+
+        sim.set("k2", "v2").unwrap();
+        sim.run(5);
+        for id in sim.node(new_leader).members().keys().copied().collect::<Vec<_>>() {
+            assert_eq!(vec![String::from("v2")], get(&sim, id, "k2"));
+        }
+    }
+
+    #[test]
+    fn added_node_should_not_demote_leader_before_first_response() {
+        let mut sim = Simulation::new(1, 7);
+        let leader = sim.elect();
+        sim.set("k1", "v1").unwrap();
+
+        // 新节点的第一次响应还没有到达
+        sim.start_node(2, BTreeMap::new());
+        sim.isolate(2);
+        sim.conf_change(leader, ConfChange::AddNode { id: 2, member: member(2) }).unwrap();
+        sim.run(1);
+        assert_eq!(Role::Leader, sim.node(leader).role());
+
+        sim.heal();
+        sim.run(20);
+        assert_eq!(Role::Leader, sim.node(leader).role());
+        assert_eq!(vec![String::from("v1")], get(&sim, 2, "k1"));
+
+        // 一直没有响应时仍然在一个选举超时后退位
+        sim.isolate(2);
+        sim.run(RaftConfig::default().election_ticks as usize);
+        assert_ne!(Role::Leader, sim.node(leader).role());
+    }
+
+    fn set(key: &str, value: &str) -> Request {
+        Request::Set { kv: KV { key: key.to_string(), value: value.to_string() } }
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+use kv_core::error::KvError;
+use kv_core::frame;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use crate::cluster::raft::Message;
+use crate::cluster::Cluster;
+use crate::serializer;
+
+/// 每个对端节点待发送消息的队列长度，队列满时直接丢弃，由 Raft 重传保证可靠
+const PEER_QUEUE_SIZE: usize = 1024;
+
+/// 节点间的消息传输
+pub trait Transport: Send + Sync + 'static {
+    /// 发送消息，不保证送达
+    fn send(&self, addr: &str, msg: Message);
+}
+
+/// 基于 TCP 的传输层，每个对端节点一个发送任务
+#[derive(Default)]
+pub struct TcpTransport {
+    peers: Mutex<HashMap<String, mpsc::Sender<Message>>>,
+}
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, addr: &str, msg: Message) {
+        let mut peers = self.peers.lock().unwrap();
+
+        let tx = peers.entry(addr.to_string()).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(PEER_QUEUE_SIZE);
+            tokio::spawn(send_loop(addr.to_string(), rx));
+            tx
+        });
+
+        if tx.try_send(msg).is_err() {
+            debug!("Raft queue to {addr} is full, drop message.");
+        }
+    }
+}
+
+async fn send_loop(addr: String, mut rx: mpsc::Receiver<Message>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut buf = BytesMut::new();
+
+    while let Some(msg) = rx.recv().await {
+        if stream.is_none() {
+            match TcpStream::connect(&addr).await {
+                Ok(s) => stream = Some(s),
+                Err(e) => {
+                    // 连接失败时丢弃消息，下一条消息再重连
+                    debug!("Connect to raft peer {addr} failed: {e:?}");
+                    continue;
+                }
+            }
+        }
+
+        buf.clear();
+        if let Err(e) = serializer::encode(&msg, &mut buf) {
+            error!("Encode raft message failed: {e:?}");
+            continue;
+        }
+
+        if let Some(s) = stream.as_mut() {
+            if let Err(e) = s.write_all_buf(&mut buf).await {
+                warn!("Write to raft peer {addr} failed: {e:?}");
+                stream = None;
+            }
+        }
+    }
+}
+
+/// 监听其他节点发来的 Raft 消息
+pub async fn serve(addr: &str, cluster: Cluster) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Raft listening on: {addr}");
+
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        let cluster = cluster.clone();
+
+        tokio::spawn(async move {
+            let mut buf = BytesMut::with_capacity(4096);
+            loop {
+                match socket.read_buf(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(_) => loop {
+                        match serializer::decode::<Message>(&mut buf, frame::MAX_FRAME_SIZE) {
+                            Ok(Some(msg)) => cluster.receive(msg).await,
+                            Ok(None) => break,
+                            Err(e @ KvError::FrameTooLarge(_)) => {
+                                warn!("Invalid raft message from {peer}: {e:?}");
+                                return;
+                            }
+                            Err(e) => warn!("Invalid raft message from {peer}: {e:?}"),
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Read from raft peer {peer} failed: {e:?}");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,595 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use kv_core::{compress, frame};
+use serde::Deserialize;
+
+use crate::cluster::raft::NodeId;
+
+/// 配置文件路径的环境变量
+pub const CONFIG_ENV: &str = "KV_SERVER_CONFIG";
+
+/// 服务端配置，从 TOML 文件加载
+#[derive(Debug, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_addr")]
+    pub addr: String,
+
+    /// 不配置时以单机模式运行
+    pub cluster: Option<ClusterConfig>,
+
+    /// 配置后通过 HTTP 暴露 Prometheus 指标
+    pub metrics: Option<MetricsConfig>,
+
+    /// 配置后开启 HTTP 网关
+    pub http: Option<HttpConfig>,
+
+    /// 配置后开启 gRPC 服务
+    pub grpc: Option<GrpcConfig>,
+
+    /// 配置后通过 OTLP 导出链路追踪数据
+    pub tracing: Option<TracingConfig>,
+
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// 可以通过 Auth 命令认证的用户
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    #[serde(default)]
+    pub slow_log: SlowLogConfig,
+
+    #[serde(default)]
+    pub pubsub: PubSubConfig,
+
+    #[serde(default)]
+    pub script: ScriptConfig,
+
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    #[serde(default)]
+    pub compression: CompressionConfig,
+}
+
+/// 存储引擎，通过 engine 字段选择
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "engine", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// 内存存储，进程退出后数据丢失
+    #[default]
+    Memory,
+    /// LSM 存储，数据保存在 path 目录中
+    Lsm(LsmConfig),
+    /// B+ 树存储，数据保存在 path 文件中
+    Btree(BTreeConfig),
+    /// 分层存储，热点 key 缓存在内存中，所有数据保存在 disk 中
+    Tiered(TieredConfig),
+}
+
+/// LSM 存储引擎
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LsmConfig {
+    /// 数据目录
+    pub path: PathBuf,
+    /// memtable 超过该大小后写成 SSTable
+    pub memtable_bytes: usize,
+    /// 每次写入后 fsync WAL。关闭后进程崩溃不丢数据，但断电可能丢失最近的写入
+    pub sync: bool,
+    /// SSTable 中数据块的大小
+    pub block_bytes: usize,
+    /// 合并产生的 SSTable 的大小
+    pub table_bytes: u64,
+    /// 第 0 层的 SSTable 数量达到该值后合并到第 1 层
+    pub l0_tables: usize,
+    /// 第 1 层的大小上限，之后每层是上一层的 10 倍
+    pub level_bytes: u64,
+    /// SSTable 中不小于该长度的值使用 lz4 压缩，不配置时不压缩
+    pub compress_threshold: Option<usize>,
+    /// 配置后 WAL 和 SSTable 加密后写入
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("data"),
+            memtable_bytes: 4 * 1024 * 1024,
+            sync: true,
+            block_bytes: 4 * 1024,
+            table_bytes: 2 * 1024 * 1024,
+            l0_tables: 4,
+            level_bytes: 10 * 1024 * 1024,
+            compress_threshold: None,
+            encryption: None,
+        }
+    }
+}
+
+/// B+ 树存储引擎
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BTreeConfig {
+    /// 数据文件
+    pub path: PathBuf,
+    /// 每次提交时 fsync。关闭后进程崩溃不丢数据，但断电可能导致数据文件损坏
+    pub sync: bool,
+    /// 不小于该长度的值使用 lz4 压缩，不配置时不压缩
+    pub compress_threshold: Option<usize>,
+    /// 配置后除元数据页以外的页面加密后写入
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl Default for BTreeConfig {
+    fn default() -> Self {
+        Self { path: PathBuf::from("data.btree"), sync: true, compress_threshold: None, encryption: None }
+    }
+}
+
+/// 静态数据加密的密钥，key_file 和 key_env 二选一
+///
+/// 密钥为 64 个十六进制字符（32 字节），多个密钥用换行或逗号分隔。第一个密钥用于加密，
+/// 其余为轮换前的旧密钥，只用于解密，数据用新密钥重写后可以去掉。
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// 保存密钥的文件
+    pub key_file: Option<PathBuf>,
+    /// 保存密钥的环境变量
+    pub key_env: Option<String>,
+}
+
+/// 分层存储
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TieredConfig {
+    /// 内存缓存的大小上限，超过后按 LRU 淘汰
+    pub cache_bytes: u64,
+    pub disk: DiskConfig,
+}
+
+impl Default for TieredConfig {
+    fn default() -> Self {
+        Self { cache_bytes: 256 * 1024 * 1024, disk: DiskConfig::default() }
+    }
+}
+
+/// 分层存储使用的持久化引擎
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "engine", rename_all = "lowercase")]
+pub enum DiskConfig {
+    Lsm(LsmConfig),
+    Btree(BTreeConfig),
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        DiskConfig::Lsm(LsmConfig::default())
+    }
+}
+
+/// 网络传输的压缩，客户端通过 Hello 命令开启后才会压缩
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// 为 false 时拒绝客户端的压缩请求
+    pub enabled: bool,
+    /// 不小于该长度的响应才压缩
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self { enabled: true, threshold: compress::DEFAULT_THRESHOLD }
+    }
+}
+
+/// Lua 脚本的执行限制
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScriptConfig {
+    /// 最多执行的虚拟机指令数
+    pub max_instructions: u64,
+    /// 最长执行时间。集群模式下各节点需要得到相同的结果，只检查指令数，不检查时间
+    pub timeout_ms: u64,
+    /// 虚拟机最多使用的内存
+    pub memory_bytes: usize,
+    /// 最多缓存的脚本数量
+    pub max_cached: usize,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            max_instructions: 10_000_000,
+            timeout_ms: 1000,
+            memory_bytes: 16 * 1024 * 1024,
+            max_cached: 1024,
+        }
+    }
+}
+
+/// 发布订阅
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PubSubConfig {
+    /// 每个订阅者最多积压的消息数，超过后断开订阅者的连接
+    pub queue_size: usize,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self { queue_size: 1024 }
+    }
+}
+
+/// 慢请求日志
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SlowLogConfig {
+    /// 处理时间超过该值的请求被记录，单位为微秒
+    pub threshold_us: u64,
+    /// 最多保留的记录数，超过后丢弃最早的记录；为 0 时关闭慢请求日志
+    pub max_len: usize,
+}
+
+impl Default for SlowLogConfig {
+    fn default() -> Self {
+        Self {
+            threshold_us: 10_000,
+            max_len: 128,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    /// argon2 的 PHC 格式哈希，通过 `kv-server hash-password` 生成，不保存明文密码
+    #[serde(deserialize_with = "crate::password::deserialize")]
+    pub password_hash: String,
+    /// 可以访问的命名空间，不配置时可以访问所有命名空间
+    #[serde(default)]
+    pub namespaces: Option<Vec<String>>,
+}
+
+/// 限流配置，未配置的维度不限流
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitConfig {
+    /// 整个服务端共享的限制
+    pub global: Option<Quota>,
+    /// 每个客户端 IP 的限制
+    pub per_ip: Option<Quota>,
+    /// 每个认证用户的限制
+    pub per_user: Option<Quota>,
+}
+
+/// 每秒允许的请求数和请求字节数，同时也是允许的突发量
+#[derive(Debug, Clone, Deserialize)]
+pub struct Quota {
+    pub requests_per_sec: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+}
+
+/// 连接相关的资源限制
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// 最大并发连接数，超过后新连接收到 503 并被关闭
+    pub max_connections: usize,
+    /// 连接上没有未完成的请求时，超过该时间没有数据则断开
+    pub idle_timeout_ms: u64,
+    /// 收到请求的第一个字节后，超过该时间仍未收到完整的请求则断开
+    pub read_timeout_ms: u64,
+    /// 写回响应的超时时间，客户端一直不读取数据时断开连接
+    pub write_timeout_ms: u64,
+    /// 单个请求帧的最大长度
+    pub max_frame_size: usize,
+    /// 单个连接待写回数据的上限，超过后先写回客户端再继续处理请求
+    pub max_buffer_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            idle_timeout_ms: 300_000,
+            read_timeout_ms: 30_000,
+            write_timeout_ms: 30_000,
+            max_frame_size: frame::MAX_FRAME_SIZE,
+            max_buffer_size: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "default_metrics_addr")]
+    pub addr: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HttpConfig {
+    #[serde(default = "default_http_addr")]
+    pub addr: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrpcConfig {
+    #[serde(default = "default_grpc_addr")]
+    pub addr: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TracingConfig {
+    /// OTLP gRPC 接收端地址
+    #[serde(default = "default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+/// 集群模式的配置
+///
+/// Raft 日志只保存在内存中，没有快照和截断，会随写命令的数量一直增长，
+/// 新节点加入时需要从头回放所有日志；写入量大的部署需要定期导出数据，用新的集群重建。
+#[derive(Debug, Deserialize)]
+pub struct ClusterConfig {
+    /// 当前节点的 id
+    pub id: NodeId,
+    /// 节点间 Raft 通信的监听地址
+    pub raft_addr: String,
+    /// 集群初始成员，包括当前节点；新加入集群的节点留空
+    #[serde(default)]
+    pub members: Vec<MemberConfig>,
+    #[serde(default = "default_tick_ms")]
+    pub tick_ms: u64,
+    #[serde(default = "default_election_ticks")]
+    pub election_ticks: u32,
+    #[serde(default = "default_heartbeat_ticks")]
+    pub heartbeat_ticks: u32,
+    /// 写命令等待提交的最长时间，超时返回 503，写入结果未知
+    #[serde(default = "default_proposal_timeout_ms")]
+    pub proposal_timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberConfig {
+    pub id: NodeId,
+    pub addr: String,
+    pub raft_addr: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: default_addr(),
+            cluster: None,
+            metrics: None,
+            http: None,
+            grpc: None,
+            tracing: None,
+            limits: LimitsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            users: vec![],
+            slow_log: SlowLogConfig::default(),
+            pubsub: PubSubConfig::default(),
+            script: ScriptConfig::default(),
+            storage: StorageConfig::default(),
+            compression: CompressionConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 依次从命令行第一个参数、环境变量 KV_SERVER_CONFIG 中获取配置文件路径，都没有时使用默认配置
+    pub fn load() -> Result<Self> {
+        let path = std::env::args().nth(1)
+            .or_else(|| std::env::var(CONFIG_ENV).ok());
+
+        match path {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+}
+
+fn default_addr() -> String {
+    String::from("127.0.0.1:6736")
+}
+
+fn default_metrics_addr() -> String {
+    String::from("127.0.0.1:9736")
+}
+
+fn default_http_addr() -> String {
+    String::from("127.0.0.1:8736")
+}
+
+fn default_grpc_addr() -> String {
+    String::from("127.0.0.1:5736")
+}
+
+fn default_otlp_endpoint() -> String {
+    String::from("http://127.0.0.1:4317")
+}
+
+fn default_service_name() -> String {
+    String::from("kv-server")
+}
+
+fn default_tick_ms() -> u64 {
+    50
+}
+
+fn default_election_ticks() -> u32 {
+    10
+}
+
+fn default_heartbeat_ticks() -> u32 {
+    2
+}
+
+fn default_proposal_timeout_ms() -> u64 {
+    3000
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{BTreeConfig, DiskConfig, ServerConfig, StorageConfig};
+
+    #[test]
+    fn parse_cluster_config() {
+        let config: ServerConfig = toml::from_str(r#"
+            addr = "127.0.0.1:6737"
+
+            [cluster]
+            id = 1
+            raft_addr = "127.0.0.1:7737"
+            members = [
+                { id = 1, addr = "127.0.0.1:6737", raft_addr = "127.0.0.1:7737" },
+                { id = 2, addr = "127.0.0.1:6738", raft_addr = "127.0.0.1:7738" },
+            ]
+        "#).unwrap();
+
+        let cluster = config.cluster.unwrap();
+        assert_eq!(1, cluster.id);
+        assert_eq!(2, cluster.members.len());
+        assert_eq!(50, cluster.tick_ms);
+        assert_eq!(3000, cluster.proposal_timeout_ms);
+        assert!(config.metrics.is_none());
+    }
+
+    #[test]
+    fn parse_metrics_config() {
+        let config: ServerConfig = toml::from_str("[metrics]").unwrap();
+        assert_eq!("127.0.0.1:9736", config.metrics.unwrap().addr);
+    }
+
+    #[test]
+    fn parse_http_config() {
+        let config: ServerConfig = toml::from_str("[http]").unwrap();
+        assert_eq!("127.0.0.1:8736", config.http.unwrap().addr);
+        assert!(ServerConfig::default().http.is_none());
+    }
+
+    #[test]
+    fn parse_grpc_config() {
+        let config: ServerConfig = toml::from_str("[grpc]\naddr = \"0.0.0.0:5737\"").unwrap();
+        assert_eq!("0.0.0.0:5737", config.grpc.unwrap().addr);
+        assert!(ServerConfig::default().grpc.is_none());
+    }
+
+    #[test]
+    fn parse_tracing_config() {
+        let config: ServerConfig = toml::from_str("[tracing]\notlp_endpoint = \"http://collector:4317\"").unwrap();
+        let tracing = config.tracing.unwrap();
+        assert_eq!("http://collector:4317", tracing.otlp_endpoint);
+        assert_eq!("kv-server", tracing.service_name);
+    }
+
+    #[test]
+    fn parse_limits_config() {
+        let config: ServerConfig = toml::from_str("[limits]\nmax_connections = 10").unwrap();
+        assert_eq!(10, config.limits.max_connections);
+        assert_eq!(300_000, config.limits.idle_timeout_ms);
+    }
+
+    #[test]
+    fn parse_rate_limit_config() {
+        let config: ServerConfig = toml::from_str(&format!(r#"
+            [rate_limit.per_ip]
+            requests_per_sec = 100
+
+            [rate_limit.per_user]
+            requests_per_sec = 50
+            bytes_per_sec = 1048576
+
+            [[users]]
+            name = "alice"
+            password_hash = "{}"
+        "#, crate::password::weak_hash("secret"))).unwrap();
+
+        assert!(config.rate_limit.global.is_none());
+        assert_eq!(Some(100.0), config.rate_limit.per_ip.unwrap().requests_per_sec);
+        assert_eq!(Some(1048576.0), config.rate_limit.per_user.unwrap().bytes_per_sec);
+        assert_eq!("alice", config.users[0].name);
+
+        // 不接受明文密码
+        assert!(toml::from_str::<ServerConfig>("[[users]]\nname = \"alice\"\npassword_hash = \"secret\"").is_err());
+    }
+
+    #[test]
+    fn parse_storage_config() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert!(matches!(config.storage, StorageConfig::Memory));
+
+        let config: ServerConfig = toml::from_str("[storage]\nengine = \"lsm\"\npath = \"/var/lib/kv\"\nsync = false").unwrap();
+        let StorageConfig::Lsm(lsm) = config.storage else {
+            panic!("expected lsm storage");
+        };
+        assert_eq!("/var/lib/kv", lsm.path.to_str().unwrap());
+        assert!(!lsm.sync);
+        assert_eq!(4, lsm.l0_tables);
+        assert_eq!(None, lsm.compress_threshold);
+
+        let config: ServerConfig = toml::from_str("[storage]\nengine = \"btree\"").unwrap();
+        let StorageConfig::Btree(btree) = config.storage else {
+            panic!("expected btree storage");
+        };
+        assert_eq!("data.btree", btree.path.to_str().unwrap());
+        assert!(btree.sync);
+
+        let config: ServerConfig = toml::from_str("[storage]\nengine = \"btree\"\ncompress_threshold = 256").unwrap();
+        assert!(matches!(config.storage, StorageConfig::Btree(BTreeConfig { compress_threshold: Some(256), .. })));
+
+        let toml = "[storage]\nengine = \"tiered\"\ncache_bytes = 1024\n[storage.disk]\nengine = \"btree\"\nsync = false";
+        let config: ServerConfig = toml::from_str(toml).unwrap();
+        let StorageConfig::Tiered(tiered) = config.storage else {
+            panic!("expected tiered storage");
+        };
+        assert_eq!(1024, tiered.cache_bytes);
+        assert!(matches!(tiered.disk, DiskConfig::Btree(BTreeConfig { sync: false, encryption: None, .. })));
+    }
+
+    #[test]
+    fn parse_encryption_config() {
+        let config: ServerConfig = toml::from_str("[storage]\nengine = \"lsm\"\n[storage.encryption]\nkey_file = \"/etc/kv/key\"").unwrap();
+        let StorageConfig::Lsm(lsm) = config.storage else {
+            panic!("expected lsm storage");
+        };
+        let encryption = lsm.encryption.unwrap();
+        assert_eq!("/etc/kv/key", encryption.key_file.unwrap().to_str().unwrap());
+        assert_eq!(None, encryption.key_env);
+
+        let toml = "[storage]\nengine = \"tiered\"\n[storage.disk]\nengine = \"btree\"\n[storage.disk.encryption]\nkey_env = \"KV_KEY\"";
+        let config: ServerConfig = toml::from_str(toml).unwrap();
+        let StorageConfig::Tiered(tiered) = config.storage else {
+            panic!("expected tiered storage");
+        };
+        let DiskConfig::Btree(btree) = tiered.disk else {
+            panic!("expected btree storage");
+        };
+        assert_eq!(Some(String::from("KV_KEY")), btree.encryption.unwrap().key_env);
+    }
+
+    #[test]
+    fn parse_compression_config() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert!(config.compression.enabled);
+        assert_eq!(1024, config.compression.threshold);
+
+        let config: ServerConfig = toml::from_str("[compression]\nenabled = false").unwrap();
+        assert!(!config.compression.enabled);
+    }
+}
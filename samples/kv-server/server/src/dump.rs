@@ -0,0 +1,127 @@
+use std::vec;
+
+use kv_core::domain::{Response, KV};
+use kv_core::dump::DumpWriter;
+
+use crate::storage::Cursor;
+
+/// 每段数据的大致字节数
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// 每次从游标读取的条数
+const BATCH_ENTRIES: usize = 256;
+
+/// 分段返回的导出数据
+///
+/// 第一段为文件头，最后一段以文件尾结束，每段的 values 为导出文件中连续的若干行。
+/// 除最后一段外 Response 的 more 都为 true。数据从游标中分批读取，内存中只保留当前的一批。
+/// 读取出错时最后一段为错误，不再有文件尾。
+pub struct DumpStream {
+    // 写完文件尾或者出错后为 None
+    writer: Option<DumpWriter>,
+    cursor: Box<dyn Cursor>,
+    batch: vec::IntoIter<(String, KV)>,
+}
+
+impl DumpStream {
+    /// cursor 为存储引擎的游标，同时返回第一段
+    pub fn new(cursor: Box<dyn Cursor>, created: u64) -> (Self, Response) {
+        let (writer, header) = DumpWriter::new(created);
+        let stream = Self { writer: Some(writer), cursor, batch: vec![].into_iter() };
+        (stream, chunk(vec![header], true))
+    }
+
+    /// 下一段数据，全部返回后为 None
+    pub fn next_chunk(&mut self) -> Option<Response> {
+        let writer = self.writer.as_mut()?;
+
+        let mut lines = vec![];
+        let mut size = 0;
+        loop {
+            let (ns, kv) = match self.batch.next() {
+                Some(entry) => entry,
+                None => match self.cursor.next_batch(BATCH_ENTRIES) {
+                    Ok(batch) if batch.is_empty() => break,
+                    Ok(batch) => {
+                        self.batch = batch.into_iter();
+                        continue;
+                    }
+                    Err(e) => {
+                        self.writer = None;
+                        return Some(Response::from(e));
+                    }
+                },
+            };
+            let line = writer.record(&ns, &kv.key, &kv.value);
+            size += line.len();
+            lines.push(line);
+            if size >= CHUNK_BYTES {
+                return Some(chunk(lines, true));
+            }
+        }
+
+        lines.push(self.writer.take()?.finish());
+        Some(chunk(lines, false))
+    }
+}
+
+fn chunk(lines: Vec<String>, more: bool) -> Response {
+    Response { more, ..Response::from(lines) }
+}
+
+#[cfg(test)]
+mod tests {
+    use kv_core::domain::KV;
+    use kv_core::dump::DumpReader;
+    use kv_core::error::KvError;
+
+    use crate::dump::DumpStream;
+    use crate::storage::memory::Memory;
+    use crate::storage::{Cursor, Storage};
+
+    #[test]
+    fn dump_should_be_split_into_chunks() {
+        let value = "v".repeat(1024);
+        let store = Memory::new();
+        store.mset("default", (0..200).map(|i| KV { key: format!("k{i:03}"), value: value.clone() }).collect()).unwrap();
+
+        let (mut stream, first) = DumpStream::new(store.snapshot().unwrap(), 1700000000);
+        let mut responses = vec![first];
+        while let Some(response) = stream.next_chunk() {
+            responses.push(response);
+        }
+
+        // 文件头、4 段数据，最后一段只有最后一个 Response 没有后续
+        assert_eq!(5, responses.len());
+        assert!(responses[..4].iter().all(|r| r.more));
+        assert!(!responses[4].more);
+
+        let lines = responses.into_iter().flat_map(|r| r.values).map(Ok);
+        let records = DumpReader::new(lines).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(200, records.len());
+        assert_eq!("k199", records[199].key);
+    }
+
+    /// 读取一批后出错的游标
+    struct Failing(bool);
+
+    impl Cursor for Failing {
+        fn next_batch(&mut self, _: usize) -> Result<Vec<(String, KV)>, KvError> {
+            if std::mem::replace(&mut self.0, true) {
+                return Err(KvError::Internal(String::from("broken")));
+            }
+            Ok(vec![(String::from("default"), KV { key: String::from("k1"), value: String::from("v1") })])
+        }
+    }
+
+    #[test]
+    fn dump_should_end_with_error() {
+        let (mut stream, first) = DumpStream::new(Box::new(Failing(false)), 1700000000);
+        assert!(first.more);
+
+        let last = stream.next_chunk().unwrap();
+        assert_eq!(500, last.code);
+        assert!(!last.more);
+        assert!(stream.next_chunk().is_none());
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{bail, Result};
+use kv_core::backend::Backend;
+use kv_core::domain::{Request, Response};
+use kv_core::error::KvError;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::ServerConfig;
+use crate::session::Session;
+use crate::storage::memory::Memory;
+use crate::storage::Storage;
+use crate::SharedServer;
+
+/// 进程内的 kv-server，不监听端口，可以直接作为 KvClient 的 Backend 使用
+///
+/// ```ignore
+/// let mut client = KvClient::with_backend(&["embedded"], Embedded::new()?);
+/// client.execute(Request::Set { kv: KV { key: "k1".into(), value: "v1".into() } })?;
+/// ```
+///
+/// 内部使用单线程的 tokio 运行时同步等待结果，不能在异步代码中使用，异步代码直接调用 [SharedServer::call]。
+/// 所有请求共用一个会话，Auth、Select 对之后的请求都生效。
+pub struct Embedded<Store = Memory> {
+    server: SharedServer<Store>,
+    session: Session,
+    runtime: Runtime,
+}
+
+impl Embedded<Memory> {
+    /// 使用内存存储和默认配置
+    pub fn new() -> Result<Self> {
+        Self::with_config(Memory::new(), &ServerConfig::default())
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> Embedded<Store> {
+    /// 不支持集群模式
+    pub fn with_config(storage: Store, config: &ServerConfig) -> Result<Self> {
+        if config.cluster.is_some() {
+            bail!("Embedded server does not support cluster mode.");
+        }
+
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let server = {
+            let _guard = runtime.enter();
+            SharedServer::new(storage, config)
+        };
+
+        Ok(Self { server, session: Session::local(), runtime })
+    }
+
+    /// 处理一个请求，Dump 只返回第一段，其余部分通过 [Embedded::session] 取出
+    pub fn execute(&mut self, request: Request) -> Response {
+        self.runtime.block_on(self.server.call(request, &mut self.session))
+    }
+
+    pub fn server(&self) -> &SharedServer<Store> {
+        &self.server
+    }
+
+    pub fn session(&mut self) -> &mut Session {
+        &mut self.session
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> Backend for Embedded<Store> {
+    fn call(&mut self, _node: &str, request: Request) -> Result<Response, KvError> {
+        Ok(self.execute(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kv_client::KvClient;
+    use kv_core::domain::{Request, KV};
+    use kv_core::dump::DumpReader;
+
+    use crate::config::ServerConfig;
+    use crate::embedded::Embedded;
+    use crate::storage::memory::Memory;
+
+    fn kv(key: &str, value: &str) -> KV {
+        KV { key: key.to_string(), value: value.to_string() }
+    }
+
+    #[test]
+    fn client_should_use_embedded_server() {
+        let mut client = KvClient::with_backend(&["embedded"], Embedded::new().unwrap());
+
+        client.execute(Request::MSet { kvs: vec![kv("k1", "v1"), kv("k2", "v2")] }).unwrap();
+        let res = client.execute(Request::MGet { keys: vec![String::from("k1"), String::from("k2")] }).unwrap();
+        assert_eq!(vec![String::from("v1"), String::from("v2")], res.values);
+
+        let script = String::from("return kv.get(KEYS[1]) .. ARGV[1]");
+        let res = client.execute(Request::Eval { script, keys: vec![String::from("k1")], args: vec![String::from("!")] }).unwrap();
+        assert_eq!(vec![String::from("v1!")], res.values);
+
+        // 会话在请求之间保持
+        client.execute(Request::Select { db: String::from("team-a") }).unwrap();
+        let res = client.execute(Request::Get { key: String::from("k1") }).unwrap();
+        assert!(res.values.is_empty());
+        assert!(!res.request_id.is_empty());
+    }
+
+    #[test]
+    fn dump_should_be_read_by_chunks() {
+        let mut embedded = Embedded::new().unwrap();
+        embedded.execute(Request::Set { kv: kv("k1", "v1") });
+
+        let first = embedded.execute(Request::Dump);
+        assert!(first.more);
+        let mut lines = first.values;
+        while let Some(chunk) = embedded.session().next_chunk() {
+            lines.extend(chunk.values);
+        }
+
+        let records = DumpReader::new(lines.into_iter().map(Ok)).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(1, records.len());
+    }
+
+    #[test]
+    fn cluster_mode_should_be_rejected() {
+        let config: ServerConfig = toml::from_str(r#"
+            [cluster]
+            id = 1
+            raft_addr = "127.0.0.1:7736"
+            members = []
+        "#).unwrap();
+        assert!(Embedded::with_config(Memory::new(), &config).is_err());
+    }
+}
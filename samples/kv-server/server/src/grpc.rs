@@ -0,0 +1,444 @@
+//! gRPC 前端，接口定义见 proto/kv.proto，其他语言可以用同一个文件生成客户端
+//!
+//! 与 HTTP 网关一样，每个调用转换为一个 Request，在新的 session 中经过 [SharedServer::call] 处理，
+//! Response 的 code 转换为 gRPC 状态码。认证和命名空间通过 metadata 中的 `authorization`、`kv-db` 传递。
+//!
+//! 消息的编码长度计入限流的字节数。同时处理的调用数不超过 `max_connections`，超过时返回
+//! RESOURCE_EXHAUSTED；单个调用的处理时间不超过 `read_timeout_ms`。
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use kv_core::domain::{PushMessage, Request, Response, KV};
+use kv_core::error::KvError;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Code, Status};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tracing::info;
+
+use crate::http::basic_auth;
+use crate::session::Session;
+use crate::storage::Storage;
+use crate::watch::KeyEvent;
+use crate::SharedServer;
+
+use pb::kv_server::{Kv, KvServer};
+use pb::watch_event::Kind;
+use pb::{DelRequest, GetRequest, MGetRequest, MSetRequest, Message, Reply, SetRequest, SubscribeRequest, WatchEvent, WatchRequest};
+
+/// 由 proto/kv.proto 生成的消息、服务端和客户端
+pub mod pb {
+    tonic::include_proto!("kv");
+}
+
+/// 选择命名空间的 metadata
+pub const DB_METADATA: &str = "kv-db";
+
+struct KvService<Store> {
+    server: SharedServer<Store>,
+}
+
+impl<Store> KvService<Store>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    /// 根据 metadata 创建 session，通过 remote_addr 识别客户端
+    async fn session<T>(&self, request: &tonic::Request<T>) -> Result<Session, Status> {
+        let addr = request.remote_addr().unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let metadata = request.metadata();
+        let session = match credentials(metadata).and_then(|credentials| Ok((credentials, db(metadata)?))) {
+            Ok((credentials, db)) => self.server.session(addr, credentials, db).await,
+            Err(e) => Err(e),
+        };
+        session.map_err(|e| status(Response::from(e)))
+    }
+
+    async fn call<T: prost::Message>(&self, request: tonic::Request<T>, into: impl FnOnce(T) -> Request) -> Result<Response, Status> {
+        let mut session = self.session(&request).await?;
+        let size = request.get_ref().encoded_len();
+        let response = self.server.call_sized(into(request.into_inner()), &mut session, size).await;
+        match response.code {
+            0 => Ok(response),
+            _ => Err(status(response)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<Store> Kv for KvService<Store>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    async fn get(&self, request: tonic::Request<GetRequest>) -> Result<tonic::Response<Reply>, Status> {
+        let key = request.get_ref().key.clone();
+        let response = self.call(request, |r| Request::Get { key: r.key }).await?;
+        // key 不存在时返回 NOT_FOUND
+        if response.values.is_empty() {
+            return Err(status(Response { request_id: response.request_id, ..Response::from(KvError::NotFound(key)) }));
+        }
+        Ok(reply(response))
+    }
+
+    async fn m_get(&self, request: tonic::Request<MGetRequest>) -> Result<tonic::Response<Reply>, Status> {
+        self.call(request, |r| Request::MGet { keys: r.keys }).await.map(reply)
+    }
+
+    async fn set(&self, request: tonic::Request<SetRequest>) -> Result<tonic::Response<Reply>, Status> {
+        if request.get_ref().kv.is_none() {
+            return Err(status(Response::from(KvError::InvalidCommand)));
+        }
+        self.call(request, |r| Request::Set { kv: r.kv.unwrap_or_default().into() }).await.map(reply)
+    }
+
+    async fn m_set(&self, request: tonic::Request<MSetRequest>) -> Result<tonic::Response<Reply>, Status> {
+        self.call(request, |r| Request::MSet { kvs: r.kvs.into_iter().map(KV::from).collect() }).await.map(reply)
+    }
+
+    async fn del(&self, request: tonic::Request<DelRequest>) -> Result<tonic::Response<Reply>, Status> {
+        self.call(request, |r| Request::Del { keys: r.keys }).await.map(reply)
+    }
+
+    type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
+
+    /// 订阅当前命名空间中 key 的变更，在客户端取消调用后结束
+    async fn watch(&self, request: tonic::Request<WatchRequest>) -> Result<tonic::Response<Self::WatchStream>, Status> {
+        let session = self.session(&request).await?;
+        let WatchRequest { keys, prefixes } = request.into_inner();
+        let mut watch = self.server.watch(&session, keys, prefixes).map_err(|e| status(Response::from(e)))?;
+
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = watch.recv() => event.map(WatchEvent::from).map_err(|e| status(Response::from(e))),
+                    _ = tx.closed() => break,
+                };
+                // 订阅者跟不上时发送错误后结束
+                let end = event.is_err();
+                if tx.send(event).await.is_err() || end {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Message, Status>>;
+
+    /// 转换为 Subscribe 和 PSubscribe，订阅在客户端取消调用后结束
+    async fn subscribe(&self, request: tonic::Request<SubscribeRequest>) -> Result<tonic::Response<Self::SubscribeStream>, Status> {
+        let mut session = self.session(&request).await?;
+        let SubscribeRequest { channels, patterns } = request.into_inner();
+        let mut requests = vec![];
+        if !channels.is_empty() {
+            requests.push(Request::Subscribe { channels });
+        }
+        if !patterns.is_empty() {
+            requests.push(Request::PSubscribe { patterns });
+        }
+        if requests.is_empty() {
+            return Err(status(Response::from(KvError::InvalidCommand)));
+        }
+
+        for request in requests {
+            let response = self.server.call(request, &mut session).await;
+            if response.code != 0 {
+                return Err(status(response));
+            }
+        }
+        let mut subscription = session.subscription.take().ok_or_else(|| Status::internal("subscription not created"))?;
+
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    message = subscription.recv() => message.map(Message::from).map_err(|e| status(Response::from(e))),
+                    _ = tx.closed() => break,
+                };
+                let end = message.is_err();
+                if tx.send(message).await.is_err() || end {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+impl From<pb::Kv> for KV {
+    fn from(kv: pb::Kv) -> Self {
+        Self { key: kv.key, value: kv.value }
+    }
+}
+
+impl From<KeyEvent> for WatchEvent {
+    fn from(event: KeyEvent) -> Self {
+        match event {
+            KeyEvent::Set { key, value } => Self { kind: Kind::Set.into(), key, value: Some(value) },
+            KeyEvent::Del { key } => Self { kind: Kind::Del.into(), key, value: None },
+            KeyEvent::Flush => Self { kind: Kind::Flush.into(), key: String::new(), value: None },
+        }
+    }
+}
+
+impl From<PushMessage> for Message {
+    fn from(message: PushMessage) -> Self {
+        Self { channel: message.channel, pattern: message.pattern, payload: message.payload }
+    }
+}
+
+/// 解析 metadata 中的 `authorization: Basic <base64(username:password)>`
+fn credentials(metadata: &MetadataMap) -> Result<Option<(String, String)>, KvError> {
+    metadata
+        .get("authorization")
+        .map(|value| value.to_str().map_err(|_| KvError::AuthFailed).and_then(basic_auth))
+        .transpose()
+}
+
+fn db(metadata: &MetadataMap) -> Result<Option<String>, KvError> {
+    metadata
+        .get(DB_METADATA)
+        .map(|value| value.to_str().map(String::from).map_err(|_| KvError::InvalidCommand))
+        .transpose()
+}
+
+fn reply(response: Response) -> tonic::Response<Reply> {
+    tonic::Response::new(Reply { values: response.values, request_id: response.request_id })
+}
+
+/// Response 的 code 转换为 gRPC 状态码，重定向时 leader 地址放在 `kv-leader` 中，限流时建议的等待时间（毫秒）
+/// 放在 `kv-retry-after-ms` 中
+fn status(response: Response) -> Status {
+    let code = match response.code {
+        400 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        307 | 503 => Code::Unavailable,
+        413 | 429 => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+
+    let mut status = Status::new(code, response.message);
+    let metadata = status.metadata_mut();
+    let mut insert = |key: &'static str, value: &str| {
+        if let Ok(value) = value.parse() {
+            metadata.insert(key, value);
+        }
+    };
+    if !response.request_id.is_empty() {
+        insert("kv-request-id", &response.request_id);
+    }
+    match (response.code, response.values.first()) {
+        (307, Some(leader)) => insert("kv-leader", leader),
+        (429, Some(retry_after_ms)) => insert("kv-retry-after-ms", retry_after_ms),
+        _ => {}
+    }
+    status
+}
+
+/// 启动 gRPC 服务，请求大小受 max_frame_size 限制，并发调用数和处理时间按 limits 限制
+pub async fn serve<Store>(addr: &str, server: SharedServer<Store>) -> anyhow::Result<()>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!("gRPC listening on: {addr}");
+    serve_with_listener(listener, server).await
+}
+
+async fn serve_with_listener<Store>(listener: TcpListener, server: SharedServer<Store>) -> anyhow::Result<()>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let limits = server.shared.limits.clone();
+    let service = KvServer::new(KvService { server }).max_decoding_message_size(limits.max_frame_size);
+    // 超过并发数时 load_shed 直接拒绝，不排队等待
+    Server::builder()
+        .load_shed(true)
+        .timeout(Duration::from_millis(limits.read_timeout_ms))
+        .layer(GlobalConcurrencyLimitLayer::new(limits.max_connections))
+        .add_service(service)
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use kv_core::domain::Request;
+    use tokio::net::TcpListener;
+    use tonic::transport::Channel;
+    use tonic::{Code, Status};
+
+    use crate::config::{Quota, RateLimitConfig, ServerConfig, UserConfig};
+    use crate::grpc::pb::kv_client::KvClient;
+    use crate::grpc::pb::watch_event::Kind;
+    use crate::grpc::pb::{DelRequest, GetRequest, Kv, MGetRequest, MSetRequest, SetRequest, SubscribeRequest, WatchRequest};
+    use crate::grpc::{serve_with_listener, DB_METADATA};
+    use crate::session::Session;
+    use crate::storage::memory::Memory;
+    use crate::SharedServer;
+
+    async fn start(config: ServerConfig) -> (KvClient<Channel>, SharedServer) {
+        let server = SharedServer::new(Memory::new(), &config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_with_listener(listener, server.clone()));
+
+        (KvClient::connect(format!("http://{addr}")).await.unwrap(), server)
+    }
+
+    fn kv(key: &str, value: &str) -> Kv {
+        Kv { key: key.to_string(), value: value.to_string() }
+    }
+
+    /// 带上认证和命名空间的 metadata
+    fn request<T>(message: T, auth: &str, db: &str) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        let credentials = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, auth);
+        request.metadata_mut().insert("authorization", format!("Basic {credentials}").parse().unwrap());
+        request.metadata_mut().insert(DB_METADATA, db.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn calls_should_map_to_requests() {
+        let (mut client, _) = start(ServerConfig::default()).await;
+
+        let reply = client.set(SetRequest { kv: Some(kv("k1", "v1")) }).await.unwrap().into_inner();
+        assert!(!reply.request_id.is_empty());
+        let reply = client.get(GetRequest { key: String::from("k1") }).await.unwrap().into_inner();
+        assert_eq!(vec![String::from("v1")], reply.values);
+
+        let status = client.get(GetRequest { key: String::from("missing") }).await.unwrap_err();
+        assert_eq!(Code::NotFound, status.code());
+        assert!(status.metadata().get("kv-request-id").is_some());
+
+        client.m_set(MSetRequest { kvs: vec![kv("k2", "v2"), kv("k3", "v3")] }).await.unwrap();
+        let keys = vec![String::from("k1"), String::from("k2"), String::from("k3")];
+        let reply = client.m_get(MGetRequest { keys }).await.unwrap().into_inner();
+        assert_eq!(vec!["v1", "v2", "v3"], reply.values);
+
+        client.del(DelRequest { keys: vec![String::from("k1")] }).await.unwrap();
+        let status = client.get(GetRequest { key: String::from("k1") }).await.unwrap_err();
+        assert_eq!(Code::NotFound, status.code());
+
+        let status = client.set(SetRequest { kv: None }).await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, status.code());
+    }
+
+    #[tokio::test]
+    async fn status_should_be_mapped_from_errors() {
+        let config = ServerConfig {
+            users: vec![UserConfig {
+                name: String::from("alice"),
+                password_hash: crate::password::weak_hash("secret"),
+                namespaces: Some(vec![String::from("team-a")]),
+            }],
+            ..Default::default()
+        };
+        let (mut client, _) = start(config).await;
+
+        let code = |result: Result<tonic::Response<_>, Status>| result.map(|_| Code::Ok).unwrap_or_else(|s| s.code());
+        let set = |auth: &str, db: &str| request(SetRequest { kv: Some(kv("k1", "v1")) }, auth, db);
+        assert_eq!(Code::Unauthenticated, code(client.set(set("alice:wrong", "team-a")).await));
+        assert_eq!(Code::PermissionDenied, code(client.set(set("alice:secret", "team-b")).await));
+        assert_eq!(Code::InvalidArgument, code(client.set(set("alice:secret", "")).await));
+        assert_eq!(Code::Ok, code(client.set(set("alice:secret", "team-a")).await));
+
+        let reply = client.get(request(GetRequest { key: String::from("k1") }, "alice:secret", "team-a")).await.unwrap();
+        assert_eq!(vec![String::from("v1")], reply.into_inner().values);
+        // 不同命名空间的数据互相隔离
+        assert_eq!(Code::NotFound, code(client.get(GetRequest { key: String::from("k1") }).await));
+    }
+
+    #[tokio::test]
+    async fn request_bytes_should_be_throttled() {
+        let quota = Quota { requests_per_sec: None, bytes_per_sec: Some(100.0) };
+        let config = ServerConfig { rate_limit: RateLimitConfig { per_ip: Some(quota), ..Default::default() }, ..Default::default() };
+        let (mut client, _) = start(config).await;
+
+        let set = || SetRequest { kv: Some(kv("k1", &"x".repeat(80))) };
+        client.set(set()).await.unwrap();
+        let status = client.set(set()).await.unwrap_err();
+        assert_eq!(Code::ResourceExhausted, status.code());
+        assert!(status.metadata().get("kv-retry-after-ms").is_some());
+    }
+
+    #[tokio::test]
+    async fn watch_should_stream_key_changes() {
+        let (mut client, server) = start(ServerConfig::default()).await;
+
+        let status = client.watch(WatchRequest::default()).await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, status.code());
+
+        let watch = WatchRequest { keys: vec![String::from("k1")], prefixes: vec![String::from("user:")] };
+        let mut stream = client.watch(watch).await.unwrap().into_inner();
+
+        client.set(SetRequest { kv: Some(kv("k2", "v2")) }).await.unwrap();
+        client.set(SetRequest { kv: Some(kv("k1", "v1")) }).await.unwrap();
+        client.m_set(MSetRequest { kvs: vec![kv("k3", "v3"), kv("user:1", "alice")] }).await.unwrap();
+        // 脚本的写入同样通知
+        let mut session = Session::local();
+        let script = String::from("kv.set(KEYS[1], ARGV[1])");
+        let eval = Request::Eval { script, keys: vec![String::from("user:2")], args: vec![String::from("bob")] };
+        assert_eq!(0, server.call(eval, &mut session).await.code);
+        client.del(DelRequest { keys: vec![String::from("k1"), String::from("k2")] }).await.unwrap();
+        assert_eq!(0, server.call(Request::FlushDb, &mut session).await.code);
+
+        let mut events = vec![];
+        for _ in 0..5 {
+            let event = stream.message().await.unwrap().unwrap();
+            events.push((event.kind(), event.key, event.value));
+        }
+        let expected = vec![
+            (Kind::Set, String::from("k1"), Some(String::from("v1"))),
+            (Kind::Set, String::from("user:1"), Some(String::from("alice"))),
+            (Kind::Set, String::from("user:2"), Some(String::from("bob"))),
+            (Kind::Del, String::from("k1"), None),
+            (Kind::Flush, String::new(), None),
+        ];
+        assert_eq!(expected, events);
+    }
+
+    #[tokio::test]
+    async fn subscribe_should_stream_published_messages() {
+        let (mut client, server) = start(ServerConfig::default()).await;
+
+        let status = client.subscribe(SubscribeRequest::default()).await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, status.code());
+
+        let subscribe = SubscribeRequest { channels: vec![String::from("news.sport")], patterns: vec![String::from("news.*")] };
+        let mut stream = client.subscribe(subscribe).await.unwrap().into_inner();
+
+        let mut session = Session::local();
+        let publish = Request::Publish { channel: String::from("news.sport"), message: String::from("goal") };
+        assert_eq!(vec!["2"], server.call(publish, &mut session).await.values);
+
+        let event = stream.message().await.unwrap().unwrap();
+        assert_eq!(("news.sport", None, "goal"), (event.channel.as_str(), event.pattern.as_deref(), event.payload.as_str()));
+        let event = stream.message().await.unwrap().unwrap();
+        assert_eq!(Some("news.*"), event.pattern.as_deref());
+
+        // 客户端取消后订阅结束
+        drop(stream);
+        let publish = Request::Publish { channel: String::from("news.sport"), message: String::from("again") };
+        let mut received = vec![];
+        for _ in 0..50 {
+            received = server.call(publish.clone(), &mut session).await.values;
+            if received == ["0"] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(vec!["0"], received);
+    }
+}
@@ -0,0 +1,382 @@
+//! HTTP 网关，供只能使用 HTTP 的工具读写数据
+//!
+//! 每个路由转换为一个 Request，与 TCP 连接上的请求一样经过 [SharedServer::call] 处理（限流、命名空间权限、
+//! 集群模式下的复制），最终由 request_handler::handle 读写存储。响应为 JSON 格式的 Response，
+//! HTTP 状态码与 Response 的 code 相同，成功时为 200。
+//!
+//! HTTP 请求之间没有连接状态，每个请求使用独立的 session：通过 Basic 认证登录，通过 `?db=` 选择命名空间。
+//! 请求的 key 和请求体的字节数计入限流。同时处理的请求数不超过 `max_connections`，超过时返回 503；
+//! 单个请求从开始接收请求体到返回响应不超过 `read_timeout_ms`，超时返回 503。
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::error_handling::HandleErrorLayer;
+use axum::BoxError;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, FromRequestParts, Path, Query, State};
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Json};
+use axum::routing::{get, post};
+use axum::Router;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use kv_core::domain::{Request, Response, KV};
+use kv_core::error::KvError;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tower::ServiceBuilder;
+use tracing::info;
+
+use crate::config::LimitsConfig;
+use crate::storage::Storage;
+use crate::SharedServer;
+
+/// `POST /kv/_mget` 的请求体
+#[derive(Debug, Deserialize)]
+struct Keys {
+    keys: Vec<String>,
+}
+
+/// `POST /kv/_mset` 的请求体
+#[derive(Debug, Deserialize)]
+struct Kvs {
+    kvs: Vec<KV>,
+}
+
+/// URL 中的查询参数
+#[derive(Debug, Deserialize)]
+struct Params {
+    db: Option<String>,
+}
+
+/// 发起请求的客户端：地址、Basic 认证的用户名和密码以及选择的命名空间
+struct Caller {
+    addr: SocketAddr,
+    credentials: Option<Result<(String, String), KvError>>,
+    db: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = (StatusCode, Json<Response>);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| reply(Response::from(KvError::Internal(e.to_string()))))?;
+        let Query(params) = Query::<Params>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| reply(Response::from(KvError::InvalidCommand)))?;
+        let credentials = parts.headers.get(AUTHORIZATION).map(header_auth);
+        Ok(Self { addr, credentials, db: params.db })
+    }
+}
+
+impl Caller {
+    /// 在新的 session 中认证、选择命名空间后执行请求，size 为请求的字节数
+    async fn call<Store>(self, server: &SharedServer<Store>, request: Request, size: usize) -> Response
+    where
+        Store: Storage + Send + Sync + 'static,
+    {
+        let session = match self.credentials.transpose() {
+            Ok(credentials) => server.session(self.addr, credentials, self.db).await,
+            Err(e) => Err(e),
+        };
+        match session {
+            Ok(mut session) => server.call_sized(request, &mut session, size).await,
+            Err(e) => Response::from(e),
+        }
+    }
+}
+
+/// 解析 `Authorization: Basic <base64(username:password)>`，gRPC 的 metadata 使用相同的格式
+pub(crate) fn basic_auth(value: &str) -> Result<(String, String), KvError> {
+    let encoded = value.strip_prefix("Basic ").ok_or(KvError::AuthFailed)?;
+    let decoded = STANDARD.decode(encoded.trim()).map_err(|_| KvError::AuthFailed)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| KvError::AuthFailed)?;
+    let (username, password) = decoded.split_once(':').ok_or(KvError::AuthFailed)?;
+    Ok((username.to_string(), password.to_string()))
+}
+
+fn header_auth(value: &HeaderValue) -> Result<(String, String), KvError> {
+    value.to_str().map_err(|_| KvError::AuthFailed).and_then(basic_auth)
+}
+
+/// HTTP 状态码与 Response 的 code 相同
+fn reply(response: Response) -> (StatusCode, Json<Response>) {
+    let status = match response.code {
+        0 => StatusCode::OK,
+        code => u16::try_from(code).ok().and_then(|code| StatusCode::from_u16(code).ok()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    (status, Json(response))
+}
+
+/// 限流时在 Retry-After 中给出建议的等待时间（秒）
+fn respond(response: Response) -> impl IntoResponse {
+    let retry_after = (response.code == 429)
+        .then(|| response.values.first()?.parse::<u64>().ok())
+        .flatten()
+        .map(|ms| [(RETRY_AFTER, ms.div_ceil(1000).to_string())]);
+    (retry_after, reply(response))
+}
+
+/// 以 `_` 开头的 key 是保留的路径，返回 InvalidCommand
+fn check_key(key: &str) -> Result<(), KvError> {
+    if key.starts_with('_') {
+        return Err(KvError::InvalidCommand);
+    }
+    Ok(())
+}
+
+/// 请求体解析失败时返回 InvalidCommand
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, KvError> {
+    serde_json::from_slice(body).map_err(|_| KvError::InvalidCommand)
+}
+
+async fn get_key<Store>(State(server): State<SharedServer<Store>>, caller: Caller, Path(key): Path<String>) -> impl IntoResponse
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    if let Err(e) = check_key(&key) {
+        return respond(Response::from(e));
+    }
+    let size = key.len();
+    let mut response = caller.call(&server, Request::Get { key: key.clone() }, size).await;
+    // key 不存在时返回 404
+    if response.code == 0 && response.values.is_empty() {
+        response = Response { request_id: response.request_id, ..Response::from(KvError::NotFound(key)) };
+    }
+    respond(response)
+}
+
+async fn put_key<Store>(State(server): State<SharedServer<Store>>, caller: Caller, Path(key): Path<String>, value: String) -> impl IntoResponse
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    if let Err(e) = check_key(&key) {
+        return respond(Response::from(e));
+    }
+    let size = key.len() + value.len();
+    respond(caller.call(&server, Request::Set { kv: KV { key, value } }, size).await)
+}
+
+async fn delete_key<Store>(State(server): State<SharedServer<Store>>, caller: Caller, Path(key): Path<String>) -> impl IntoResponse
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    if let Err(e) = check_key(&key) {
+        return respond(Response::from(e));
+    }
+    let size = key.len();
+    respond(caller.call(&server, Request::Del { keys: vec![key] }, size).await)
+}
+
+async fn mget<Store>(State(server): State<SharedServer<Store>>, caller: Caller, body: Bytes) -> impl IntoResponse
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    respond(match parse::<Keys>(&body) {
+        Ok(Keys { keys }) => caller.call(&server, Request::MGet { keys }, body.len()).await,
+        Err(e) => Response::from(e),
+    })
+}
+
+async fn mset<Store>(State(server): State<SharedServer<Store>>, caller: Caller, body: Bytes) -> impl IntoResponse
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    respond(match parse::<Kvs>(&body) {
+        Ok(Kvs { kvs }) => caller.call(&server, Request::MSet { kvs }, body.len()).await,
+        Err(e) => Response::from(e),
+    })
+}
+
+/// 请求体最大为 max_frame_size 字节，并发请求数和处理时间按 limits 限制
+///
+/// 以 `_` 开头的 key 保留给批量操作，不能通过 `/kv/{key}` 读写。
+pub fn router<Store>(server: SharedServer<Store>, limits: &LimitsConfig) -> Router
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let limit = ServiceBuilder::new()
+        .layer(HandleErrorLayer::new(overloaded))
+        .load_shed()
+        .concurrency_limit(limits.max_connections)
+        .timeout(Duration::from_millis(limits.read_timeout_ms));
+
+    Router::new()
+        .route("/kv/_mget", post(mget::<Store>))
+        .route("/kv/_mset", post(mset::<Store>))
+        .route("/kv/:key", get(get_key::<Store>).put(put_key::<Store>).delete(delete_key::<Store>))
+        .layer(DefaultBodyLimit::max(limits.max_frame_size))
+        .with_state(server)
+        .layer(limit)
+}
+
+/// 并发请求过多或者处理超时
+async fn overloaded(e: BoxError) -> (StatusCode, Json<Response>) {
+    let message = if e.is::<tower::timeout::error::Elapsed>() { "Request timed out." } else { "Too many requests in flight." };
+    reply(Response::from(KvError::Unavailable(message.to_string())))
+}
+
+/// 启动 HTTP 网关
+pub async fn serve<Store>(addr: &str, server: SharedServer<Store>) -> anyhow::Result<()>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let limits = server.shared.limits.clone();
+    let app = router(server, &limits);
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("HTTP gateway listening on: {addr}");
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use kv_core::domain::Response;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::{LimitsConfig, Quota, RateLimitConfig, ServerConfig, UserConfig};
+    use crate::http::router;
+    use crate::storage::memory::Memory;
+    use crate::SharedServer;
+
+    async fn start(config: ServerConfig) -> SocketAddr {
+        let server = SharedServer::new(Memory::new(), &config);
+        let app = router(server, &config.limits);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await });
+        addr
+    }
+
+    /// 发送一个 HTTP/1.1 请求，返回状态码和解析后的 Response
+    async fn send(addr: SocketAddr, method: &str, path: &str, auth: Option<&str>, body: &str) -> (u16, Response) {
+        let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nContent-Length: {}\r\n", body.len());
+        if let Some(credentials) = auth {
+            request += &format!("Authorization: Basic {}\r\n", STANDARD.encode(credentials));
+        }
+        request += "\r\n";
+        request += body;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).await.unwrap();
+
+        let status = raw.split(' ').nth(1).unwrap().parse().unwrap();
+        let (_, body) = raw.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap_or_else(|e| panic!("invalid body {body:?}: {e}")))
+    }
+
+    #[tokio::test]
+    async fn routes_should_map_to_requests() {
+        let addr = start(ServerConfig::default()).await;
+
+        let (status, res) = send(addr, "PUT", "/kv/k1", None, "v1").await;
+        assert_eq!((200, 0), (status, res.code));
+        assert!(!res.request_id.is_empty());
+
+        let (status, res) = send(addr, "GET", "/kv/k1", None, "").await;
+        assert_eq!(200, status);
+        assert_eq!(vec![String::from("v1")], res.values);
+
+        let (status, res) = send(addr, "GET", "/kv/missing", None, "").await;
+        assert_eq!((404, 404), (status, res.code));
+
+        let body = r#"{"kvs":[{"key":"k2","value":"v2"},{"key":"k3","value":"v3"}]}"#;
+        assert_eq!(200, send(addr, "POST", "/kv/_mset", None, body).await.0);
+        let (status, res) = send(addr, "POST", "/kv/_mget", None, r#"{"keys":["k1","k2","k3"]}"#).await;
+        assert_eq!(200, status);
+        assert_eq!(vec!["v1", "v2", "v3"], res.values);
+
+        assert_eq!(200, send(addr, "DELETE", "/kv/k1", None, "").await.0);
+        assert_eq!(404, send(addr, "GET", "/kv/k1", None, "").await.0);
+
+        // 请求体格式错误
+        let (status, res) = send(addr, "POST", "/kv/_mget", None, r#"{"key":"k1"}"#).await;
+        assert_eq!((400, 400), (status, res.code));
+
+        // 以 _ 开头的 key 保留给批量操作
+        let (status, res) = send(addr, "PUT", "/kv/_other", None, "v").await;
+        assert_eq!((400, 400), (status, res.code));
+    }
+
+    #[tokio::test]
+    async fn request_bytes_should_be_throttled() {
+        let quota = Quota { requests_per_sec: None, bytes_per_sec: Some(100.0) };
+        let addr = start(ServerConfig { rate_limit: RateLimitConfig { per_ip: Some(quota), ..Default::default() }, ..Default::default() }).await;
+
+        let value = "x".repeat(80);
+        assert_eq!(200, send(addr, "PUT", "/kv/k1", None, &value).await.0);
+        assert_eq!(429, send(addr, "PUT", "/kv/k1", None, &value).await.0);
+    }
+
+    #[tokio::test]
+    async fn slow_request_should_time_out() {
+        let addr = start(ServerConfig { limits: LimitsConfig { read_timeout_ms: 100, ..Default::default() }, ..Default::default() }).await;
+
+        // 声明了请求体但一直不发送
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("PUT /kv/k1 HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nContent-Length: 10\r\n\r\nv");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).await.unwrap();
+        assert!(raw.starts_with("HTTP/1.1 503"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn body_should_be_limited() {
+        let addr = start(ServerConfig { limits: LimitsConfig { max_frame_size: 16, ..Default::default() }, ..Default::default() }).await;
+
+        assert_eq!(200, send(addr, "PUT", "/kv/k1", None, "short").await.0);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("PUT /kv/k1 HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nContent-Length: 64\r\n\r\n{}", "x".repeat(64));
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).await.unwrap();
+        assert!(raw.starts_with("HTTP/1.1 413"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_require_permission() {
+        let config = ServerConfig {
+            users: vec![UserConfig {
+                name: String::from("alice"),
+                password_hash: crate::password::weak_hash("secret"),
+                namespaces: Some(vec![String::from("team-a")]),
+            }],
+            ..Default::default()
+        };
+        let addr = start(config).await;
+
+        let alice = Some("alice:secret");
+        assert_eq!(401, send(addr, "PUT", "/kv/k1?db=team-a", Some("alice:wrong"), "v1").await.0);
+        assert_eq!(401, send(addr, "GET", "/kv/k1?db=team-a", Some("alice"), "").await.0);
+        assert_eq!(403, send(addr, "PUT", "/kv/k1?db=team-b", alice, "v1").await.0);
+        assert_eq!(400, send(addr, "PUT", "/kv/k1?db=", alice, "v1").await.0);
+
+        assert_eq!(200, send(addr, "PUT", "/kv/k1?db=team-a", alice, "v1").await.0);
+        let (status, res) = send(addr, "GET", "/kv/k1?db=team-a", alice, "").await;
+        assert_eq!(200, status);
+        assert_eq!(vec![String::from("v1")], res.values);
+
+        // 不同命名空间的数据互相隔离
+        assert_eq!(404, send(addr, "GET", "/kv/k1", None, "").await.0);
+    }
+}
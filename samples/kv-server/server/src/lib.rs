@@ -0,0 +1,1393 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use crate::storage::btree::BTree;
+use crate::storage::lsm::Lsm;
+use crate::storage::memory::Memory;
+use crate::storage::tiered::Tiered;
+use crate::storage::{Cursor, Storage};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+use anyhow::Result;
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::WriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time;
+use kv_core::codec::Message;
+use kv_core::compress;
+use kv_core::domain::{Envelope, PushMessage, Request, Response, TraceContext, DEFAULT_NAMESPACE, KV};
+use kv_core::error::KvError;
+use crate::cluster::Cluster;
+use crate::cluster::raft::{Member, RaftConfig, RaftNode};
+use crate::cluster::transport::{self, TcpTransport};
+use crate::dump::DumpStream;
+use crate::config::{ClusterConfig, CompressionConfig, DiskConfig, LimitsConfig, ServerConfig, StorageConfig, UserConfig};
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::pubsub::{PubSub, Subscription};
+use crate::script::Scripts;
+use crate::serializer::Format;
+use crate::session::Session;
+use crate::slow_log::SlowLog;
+use crate::watch::{KeyWatch, Observed, Watches};
+
+mod admin;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod cluster;
+pub mod config;
+mod dump;
+pub mod embedded;
+pub mod grpc;
+mod http;
+mod metrics;
+pub mod password;
+mod pubsub;
+mod rate_limit;
+mod request_handler;
+mod script;
+pub mod session;
+mod slow_log;
+pub mod storage;
+mod serializer;
+mod telemetry;
+mod watch;
+
+/// 实际的 Server 类
+struct Server<Store> {
+    storage: Store,
+    metrics: Metrics,
+    started: Instant,
+    limits: LimitsConfig,
+    rate_limiter: RateLimiter,
+    slow_log: SlowLog,
+    pubsub: Arc<PubSub>,
+    watches: Arc<Watches>,
+    scripts: Scripts,
+    // 脚本执行时持有写锁，其他读写存储的请求持有读锁，保证脚本的原子性
+    script_lock: tokio::sync::RwLock<()>,
+    // 用户名 -> 用户配置
+    users: HashMap<String, UserConfig>,
+    compression: CompressionConfig,
+}
+
+
+/// kv-server 的入口，可以通过 [serve] 监听端口，也可以通过 [SharedServer::call] 在进程内调用
+pub struct SharedServer<Store = Memory> {
+    // 多线程共享
+    shared: Arc<Server<Store>>,
+    // 集群模式下写命令需要经过 Raft
+    cluster: Option<Cluster>,
+}
+
+
+impl<Store: Storage + Send + Sync + 'static> SharedServer<Store> {
+    pub fn new(storage: Store, config: &ServerConfig) -> Self {
+        let server = Server {
+            storage,
+            metrics: Metrics::new(),
+            started: Instant::now(),
+            limits: config.limits.clone(),
+            rate_limiter: RateLimiter::new(&config.rate_limit),
+            slow_log: SlowLog::new(&config.slow_log),
+            pubsub: Arc::new(PubSub::new(&config.pubsub)),
+            watches: Arc::new(Watches::new(&config.pubsub)),
+            scripts: Scripts::new(&config.script),
+            script_lock: tokio::sync::RwLock::new(()),
+            users: config.users.iter().map(|u| (u.name.clone(), u.clone())).collect(),
+            compression: config.compression.clone(),
+        };
+
+        let mut server = Self {
+            shared: Arc::new(server),
+            cluster: None,
+        };
+
+        if let Some(cluster_config) = &config.cluster {
+            server.cluster = Some(server.spawn_cluster(cluster_config));
+        }
+        server
+    }
+
+    /// 在进程内处理一个请求，不经过网络。session 保存连接的状态，多个请求之间复用同一个 session
+    ///
+    /// Dump 只返回第一段，其余部分通过 [Session::next_chunk] 取出。
+    pub async fn call(&self, request: Request, session: &mut Session) -> Response {
+        self.handle_request(request, None, session, 0).await
+    }
+
+    /// 同 [SharedServer::call]，网关收到的请求按 size 字节计入限流
+    pub(crate) async fn call_sized(&self, request: Request, session: &mut Session, size: usize) -> Response {
+        self.handle_request(request, None, session, size).await
+    }
+
+    #[instrument(skip_all, fields(peer = %addr))]
+    async fn handle_connection(&self, mut socket: TcpStream, addr: SocketAddr) {
+        let (mut reader, mut writer) = socket.split();
+        let mut buf = BytesMut::with_capacity(1024);
+        let mut out = BytesMut::with_capacity(1024);
+        let limits = &self.shared.limits;
+        let metrics = &self.shared.metrics;
+        let mut session = Session::new(addr);
+        // 收到未完成请求的第一个字节后，必须在该时间之前收到完整的请求，逐字节发送也不能延长
+        let mut deadline: Option<time::Instant> = None;
+        metrics.connection_opened();
+
+        loop {
+            // 没有未完成的请求时使用空闲超时，收到部分请求后等到读超时的截止时间
+            let deadline_at = deadline.unwrap_or_else(|| time::Instant::now() + Duration::from_millis(limits.idle_timeout_ms));
+
+            let format = session.format();
+            let read = match session.subscription.as_mut() {
+                // 推送模式下同时等待新的请求和推送的消息，不使用空闲超时
+                Some(subscription) if buf.is_empty() => tokio::select! {
+                    res = reader.read_buf(&mut buf) => Ok(res),
+                    push = subscription.recv() => {
+                        if !self.push(push, subscription, format, &mut writer, &mut out, addr).await {
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                _ => time::timeout_at(deadline_at, reader.read_buf(&mut buf)).await,
+            };
+
+            match read {
+                Ok(Ok(0)) => {
+                    trace!("Read data from {addr} finished.");
+                    break;
+                }
+                Ok(Ok(n)) => {
+                    trace!("Read data from {addr}, data size = {n}.");
+                    metrics.bytes_in(n);
+                }
+                Ok(Err(e)) => {
+                    error!("Read data from {addr} failed: {e:?}");
+                    break;
+                }
+                Err(_) => {
+                    debug!("Client {addr} timed out.");
+                    break;
+                }
+            }
+
+            // 一次读取可能包含多个请求
+            let mut closing = false;
+            loop {
+                let remaining = buf.len();
+                let response = match serializer::decode_message::<Envelope>(&mut buf, limits.max_frame_size) {
+                    Ok(Some((codec, Envelope { request, trace }))) => {
+                        deadline = None;
+                        session.codec = codec;
+                        self.handle_request(request, trace, &mut session, remaining - buf.len()).await
+                    }
+                    Ok(None) => break,
+                    // 帧长度非法时后续数据无法再解析，返回错误后关闭连接
+                    Err(e @ KvError::FrameTooLarge(_)) => {
+                        closing = true;
+                        Response::from(e)
+                    }
+                    Err(e) => Response::from(e),
+                };
+
+                if let Err(e) = self.encode(&response, session.format(), &mut out) {
+                    error!("Encode response to {addr} failed: {e:?}");
+                }
+
+                if let Some(mut dump) = session.dump.take() {
+                    if !self.stream(&mut dump, &response.request_id, session.format(), &mut writer, &mut out, addr).await {
+                        closing = true;
+                        break;
+                    }
+                }
+
+                if closing {
+                    break;
+                }
+
+                // 待写回的数据过多时先写回。客户端不读取时在这里等待，不再处理新的请求
+                if out.len() >= limits.max_buffer_size && !self.flush(&mut writer, &mut out, addr).await {
+                    closing = true;
+                    break;
+                }
+            }
+
+            if !self.flush(&mut writer, &mut out, addr).await || closing {
+                break;
+            }
+
+            if buf.is_empty() {
+                deadline = None;
+            } else if deadline.is_none() {
+                deadline = Some(time::Instant::now() + Duration::from_millis(limits.read_timeout_ms));
+            }
+        }
+
+        metrics.connection_closed();
+
+        trace!("Client {:?} disconnected.", addr);
+    }
+
+    /// 写回推送的消息以及队列中已经到达的消息，订阅者积压过多或写回失败时返回 false
+    async fn push(
+        &self,
+        push: Result<PushMessage, KvError>,
+        subscription: &mut Subscription,
+        format: Format,
+        writer: &mut WriteHalf<'_>,
+        out: &mut BytesMut,
+        addr: SocketAddr,
+    ) -> bool {
+        let mut message = match push {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Disconnect subscriber {addr}: {e}");
+                if self.encode(&Response::from(e), format, out).is_ok() {
+                    self.flush(writer, out, addr).await;
+                }
+                return false;
+            }
+        };
+
+        loop {
+            if let Err(e) = self.encode(&Response::from(message), format, out) {
+                error!("Encode message to {addr} failed: {e:?}");
+            }
+            match subscription.try_recv() {
+                Some(next) if out.len() < self.shared.limits.max_buffer_size => message = next,
+                _ => break,
+            }
+        }
+
+        self.flush(writer, out, addr).await
+    }
+
+    /// 分段写回 Dump 的结果，缓冲区满时先写回，写回失败时返回 false
+    async fn stream(
+        &self,
+        dump: &mut DumpStream,
+        request_id: &str,
+        format: Format,
+        writer: &mut WriteHalf<'_>,
+        out: &mut BytesMut,
+        addr: SocketAddr,
+    ) -> bool {
+        while let Some(mut chunk) = dump.next_chunk() {
+            chunk.request_id = request_id.to_string();
+            if let Err(e) = self.encode(&chunk, format, out) {
+                error!("Encode dump to {addr} failed: {e:?}");
+            }
+            if out.len() >= self.shared.limits.max_buffer_size && !self.flush(writer, out, addr).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 按连接的编码格式和协商的压缩阈值编码消息，开启压缩时统计压缩前后的字节数
+    fn encode<T: Message>(&self, value: &T, format: Format, out: &mut BytesMut) -> Result<(), KvError> {
+        let (raw, len) = serializer::encode_compressed(value, format, out)?;
+        if format.compression.is_some() {
+            self.shared.metrics.wire_compression(raw, len);
+        }
+        Ok(())
+    }
+
+    /// 写回缓冲区中的数据，失败或超时返回 false
+    async fn flush(&self, writer: &mut WriteHalf<'_>, out: &mut BytesMut, addr: SocketAddr) -> bool {
+        if out.is_empty() {
+            return true;
+        }
+
+        self.shared.metrics.bytes_out(out.len());
+        let timeout = Duration::from_millis(self.shared.limits.write_timeout_ms);
+
+        match time::timeout(timeout, writer.write_all_buf(out)).await {
+            Ok(Ok(_)) => {
+                trace!("Write data to {addr} finished.");
+                true
+            }
+            Ok(Err(e)) => {
+                error!("Write data to {addr} failed: {e:?}");
+                false
+            }
+            Err(_) => {
+                warn!("Write data to {addr} timed out.");
+                false
+            }
+        }
+    }
+
+    /// 处理一个请求，size 为请求在网络上的字节数
+    ///
+    /// 客户端传入 trace 时，请求的 span 挂在客户端的 span 下，否则挂在连接的 span 下。
+    async fn handle_request(&self, request: Request, trace: Option<TraceContext>, session: &mut Session, size: usize) -> Response {
+        let req_id = Uuid::new_v4();
+
+        let span = info_span!("handle_request", command = request.name(), request_id = %req_id);
+        if let Some(trace) = &trace {
+            if let Err(e) = span.set_parent(telemetry::extract(trace)) {
+                debug!("{req_id} - set trace parent failed: {e:?}");
+            }
+        }
+
+        self.process(req_id, request, session, size).instrument(span).await
+    }
+
+    async fn process(&self, req_id: Uuid, request: Request, session: &mut Session, size: usize) -> Response {
+        debug!("{req_id} - request = {:?}", request);
+
+        let command = request.name();
+        // 只有开启慢日志时才生成摘要
+        let summary = self.shared.slow_log.enabled().then(|| slow_log::summarize(&request));
+        let started = SystemTime::now();
+        let start = Instant::now();
+
+        // TODO: 发送 on_received 事件
+        let result = match self.shared.rate_limiter.check(session.addr.ip(), session.user.as_deref(), size) {
+            Err(e) => Err(e),
+            Ok(_) => self.execute(request, session).await,
+        };
+
+        let elapsed = start.elapsed();
+        self.shared.metrics.observe_request(command, elapsed, result.as_ref().err());
+        if let Some(summary) = summary {
+            self.shared.slow_log.record(req_id.to_string(), summary, session.addr, started, elapsed);
+        }
+
+        let mut response = Response::from(result);
+        response.request_id = req_id.to_string();
+
+        debug!("{req_id} - response = {:?}", response);
+
+        // TODO: 发送 on_executed 事件
+
+        response
+    }
+
+    async fn execute(&self, request: Request, session: &mut Session) -> Result<Response, KvError> {
+        // 推送模式下只能继续订阅
+        if session.subscription.is_some() && !matches!(request, Request::Subscribe { .. } | Request::PSubscribe { .. }) {
+            return Err(KvError::InvalidCommand);
+        }
+
+        // EvalSha 在当前节点解析为脚本，集群模式下以 Eval 的形式复制到其他节点
+        let request = match request {
+            Request::EvalSha { sha1, keys, args } => Request::Eval { script: self.shared.scripts.get(&sha1)?, keys, args },
+            request => request,
+        };
+
+        if uses_namespace(&request) {
+            self.shared.check_namespace(session.user.as_deref(), &session.db)?;
+        }
+        if is_admin(&request) {
+            self.shared.check_admin(session.user.as_deref())?;
+        }
+
+        match (&self.cluster, request) {
+            // 集群模式下写命令提交到 Raft 日志后再应用，读命令直接读本地存储
+            (Some(cluster), request) if is_replicated(&request) => cluster.handle(&session.db, request).await,
+            (_, Request::Auth { username, password }) => self.auth(session, username, password).await,
+            (_, Request::Hello { compression }) => {
+                let config = &self.shared.compression;
+                let enabled = config.enabled && compression.iter().any(|c| c == compress::LZ4);
+                session.compression = enabled.then_some(config.threshold);
+                Ok(Response::from(if enabled { vec![compress::LZ4.to_string()] } else { vec![] }))
+            }
+            (_, Request::Select { db }) => {
+                if db.is_empty() {
+                    return Err(KvError::InvalidCommand);
+                }
+                self.shared.check_namespace(session.user.as_deref(), &db)?;
+                session.db = db;
+                Ok(Response::default())
+            }
+            (_, Request::Info) => {
+                let mut info = admin::info(&self.shared.storage, &self.shared.metrics, self.shared.started);
+                // 受限的用户只能看到有权限的命名空间
+                if self.shared.check_admin(session.user.as_deref()).is_err() {
+                    info.storage.namespaces.retain(|ns, _| self.shared.check_namespace(session.user.as_deref(), ns).is_ok());
+                    info.storage.keys = info.storage.namespaces.values().sum();
+                }
+                Ok(info.into())
+            }
+            (_, Request::SlowLog { count }) => Ok(self.shared.slow_log.latest(count).into()),
+            (_, Request::Dump) => {
+                let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+                let (dump, response) = DumpStream::new(self.shared.snapshot(session.user.as_deref()).await?, created);
+                session.dump = Some(dump);
+                Ok(response)
+            }
+            (_, Request::Publish { channel, message }) => {
+                let received = self.shared.pubsub.publish(&session.db, &channel, &message);
+                Ok(Response::from(vec![received.to_string()]))
+            }
+            (_, Request::Subscribe { channels }) => {
+                let db = session.db.clone();
+                self.subscription(session).subscribe(&db, &channels);
+                Ok(Response::from(channels))
+            }
+            (_, Request::PSubscribe { patterns }) => {
+                let db = session.db.clone();
+                self.subscription(session).psubscribe(&db, &patterns);
+                Ok(Response::from(patterns))
+            }
+            (_, request) => self.shared.apply(request, &session.db, true).await,
+        }
+    }
+
+    /// HTTP、gRPC 网关的请求没有连接状态，每个请求使用新的 session，有用户名和密码时先认证，再选择命名空间
+    ///
+    /// 命名空间的权限在执行读写命令时检查。
+    async fn session(&self, addr: SocketAddr, credentials: Option<(String, String)>, db: Option<String>) -> Result<Session, KvError> {
+        let mut session = Session::new(addr);
+        if let Some((username, password)) = credentials {
+            self.auth(&mut session, username, password).await?;
+        }
+        if let Some(db) = db {
+            if db.is_empty() {
+                return Err(KvError::InvalidCommand);
+            }
+            session.db = db;
+        }
+        Ok(session)
+    }
+
+    /// 订阅 session 当前命名空间中的 keys 以及以 prefixes 开头的 key 的变更，至少指定一个
+    pub(crate) fn watch(&self, session: &Session, keys: Vec<String>, prefixes: Vec<String>) -> Result<KeyWatch, KvError> {
+        if keys.is_empty() && prefixes.is_empty() {
+            return Err(KvError::InvalidCommand);
+        }
+        self.shared.check_namespace(session.user.as_deref(), &session.db)?;
+        Ok(self.shared.watches.watch(&session.db, keys, prefixes))
+    }
+
+    fn subscription<'a>(&self, session: &'a mut Session) -> &'a mut Subscription {
+        session.subscription.get_or_insert_with(|| self.shared.pubsub.subscription())
+    }
+
+    /// 校验密码的哈希计算量较大，在阻塞线程池中执行
+    async fn auth(&self, session: &mut Session, username: String, password: String) -> Result<Response, KvError> {
+        let user = self.shared.users.get(&username);
+        // 用户不存在时同样校验一次，响应时间不暴露用户是否存在
+        let hash = user.or_else(|| self.shared.users.values().next()).map(|u| u.password_hash.clone());
+        let verified = match hash {
+            Some(hash) => tokio::task::spawn_blocking(move || password::verify(&hash, &password))
+                .await
+                .map_err(|e| KvError::Internal(e.to_string()))?,
+            None => false,
+        };
+
+        if verified && user.is_some() {
+            session.user = Some(username);
+            Ok(Response::default())
+        } else {
+            warn!("Authentication failed for {username} from {}.", session.addr);
+            Err(KvError::AuthFailed)
+        }
+    }
+
+    /// 启动 Raft 节点，已提交的写命令应用到本地存储
+    fn spawn_cluster(&self, config: &ClusterConfig) -> Cluster {
+        let members = config.members.iter()
+            .map(|m| (m.id, Member { addr: m.addr.clone(), raft_addr: m.raft_addr.clone() }))
+            .collect();
+        let raft_config = RaftConfig {
+            election_ticks: config.election_ticks,
+            heartbeat_ticks: config.heartbeat_ticks,
+            seed: Uuid::new_v4().as_u64_pair().0,
+        };
+        let node = RaftNode::new(config.id, members, raft_config);
+
+        let shared = self.shared.clone();
+        Cluster::spawn(
+            node,
+            TcpTransport::new(),
+            Duration::from_millis(config.tick_ms),
+            Duration::from_millis(config.proposal_timeout_ms),
+            move |ns, request| {
+                let shared = shared.clone();
+                async move { shared.apply(request, &ns, false).await }
+            },
+        )
+    }
+}
+
+/// 读写数据以及发布订阅的命令，在连接当前的命名空间中执行
+fn uses_namespace(request: &Request) -> bool {
+    request.is_write()
+        || matches!(
+            request,
+            Request::Get { .. } | Request::MGet { .. } | Request::Scan { .. } | Request::Eval { .. }
+                | Request::Publish { .. } | Request::Subscribe { .. } | Request::PSubscribe { .. }
+        )
+}
+
+/// 管理集群或者返回所有命名空间数据的命令，只有不受命名空间限制的用户可以执行
+fn is_admin(request: &Request) -> bool {
+    matches!(request, Request::AddNode { .. } | Request::RemoveNode { .. } | Request::SlowLog { .. })
+}
+
+fn is_replicated(request: &Request) -> bool {
+    request.is_write() || matches!(request, Request::AddNode { .. } | Request::RemoveNode { .. } | Request::Eval { .. })
+}
+
+impl<Store: Storage + Send + Sync + 'static> Server<Store> {
+    /// 在命名空间 ns 中读写存储，timeout 为 false 时脚本不检查执行时间
+    ///
+    /// 脚本在阻塞线程池中执行，执行期间其他请求异步等待读锁，不占用运行时的工作线程。
+    async fn apply(self: &Arc<Self>, request: Request, ns: &str, timeout: bool) -> Result<Response, KvError> {
+        match request {
+            Request::Eval { script, keys, args } => {
+                let server = self.clone();
+                let ns = ns.to_string();
+                tokio::task::spawn_blocking(move || {
+                    let _guard = server.script_lock.blocking_write();
+                    server.scripts.load(&script);
+                    // 写入成功后通知订阅了这些 key 的 Watch
+                    let storage = Observed::new(&server.storage, &server.watches);
+                    server.scripts.eval(&script, &ns, keys, args, &storage, timeout).map(Response::from)
+                })
+                .await
+                .map_err(|e| KvError::Internal(e.to_string()))?
+            }
+            request => {
+                let _guard = self.script_lock.read().await;
+                request_handler::handle(request, ns, &Observed::new(&self.storage, &self.watches))
+            }
+        }
+    }
+
+    /// 固定当前的版本，返回只读取当前用户可以访问的命名空间的游标
+    async fn snapshot(self: &Arc<Self>, user: Option<&str>) -> Result<Box<dyn Cursor>, KvError> {
+        let _guard = self.script_lock.read().await;
+        let cursor = self.storage.snapshot()?;
+        Ok(Box::new(Accessible { server: self.clone(), user: user.map(String::from), cursor }))
+    }
+}
+
+/// 跳过用户不能访问的命名空间的游标
+struct Accessible<Store> {
+    server: Arc<Server<Store>>,
+    user: Option<String>,
+    cursor: Box<dyn Cursor>,
+}
+
+impl<Store: Storage + Send + Sync + 'static> Cursor for Accessible<Store> {
+    fn next_batch(&mut self, count: usize) -> Result<Vec<(String, KV)>, KvError> {
+        loop {
+            let mut batch = self.cursor.next_batch(count)?;
+            if batch.is_empty() {
+                return Ok(batch);
+            }
+            batch.retain(|(ns, _)| self.server.check_namespace(self.user.as_deref(), ns).is_ok());
+            if !batch.is_empty() {
+                return Ok(batch);
+            }
+        }
+    }
+}
+
+impl<Store: Storage> Server<Store> {
+    /// 没有配置用户时所有连接都不受限制；配置了用户时，只有认证为没有配置 namespaces 的用户才不受限制
+    fn check_admin(&self, user: Option<&str>) -> Result<(), KvError> {
+        let unrestricted = match user.and_then(|u| self.users.get(u)) {
+            Some(user) => user.namespaces.is_none(),
+            None => self.users.is_empty(),
+        };
+
+        if unrestricted {
+            Ok(())
+        } else {
+            Err(KvError::Forbidden(String::from("*")))
+        }
+    }
+
+    /// 认证用户只能访问配置的命名空间；配置了用户时，未认证的连接只能访问默认命名空间
+    fn check_namespace(&self, user: Option<&str>, ns: &str) -> Result<(), KvError> {
+        let allowed = match user.and_then(|u| self.users.get(u)) {
+            Some(user) => user.namespaces.as_ref().is_none_or(|allowed| allowed.iter().any(|n| n == ns)),
+            None => self.users.is_empty() || ns == DEFAULT_NAMESPACE,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvError::Forbidden(ns.to_string()))
+        }
+    }
+}
+
+/// 实现 Clone trait
+impl<Storage> Clone for SharedServer<Storage> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            cluster: self.cluster.clone(),
+        }
+    }
+}
+
+/// 接受客户端连接，超过最大连接数时拒绝新连接
+pub async fn serve<Store>(listener: TcpListener, server: SharedServer<Store>)
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(server.shared.limits.max_connections));
+
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // 文件句柄耗尽等错误，稍后重试
+                error!("Accept failed: {e:?}");
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        trace!("Client {:?} connected.", addr);
+
+        let Ok(permit) = semaphore.clone().try_acquire_owned() else {
+            warn!("Too many connections, reject {addr}.");
+            tokio::spawn(reject(socket));
+            continue;
+        };
+
+        let svr = server.clone();
+
+        tokio::spawn(async move {
+            svr.handle_connection(socket, addr).await;
+            drop(permit);
+        });
+    }
+}
+
+async fn reject(mut socket: TcpStream) {
+    let mut out = BytesMut::new();
+    let response = Response::from(KvError::Unavailable(String::from("Too many connections.")));
+    if serializer::encode(&response, &mut out).is_ok() {
+        let _ = time::timeout(Duration::from_secs(1), socket.write_all_buf(&mut out)).await;
+    }
+}
+
+/// 按配置启动 kv-server，收到 ctrl-c 后退出
+pub async fn run(config: ServerConfig) -> Result<()> {
+    let provider = telemetry::init(config.tracing.as_ref())?;
+
+    match &config.storage {
+        StorageConfig::Memory => start(SharedServer::new(Memory::new(), &config), &config).await?,
+        StorageConfig::Lsm(lsm) => start(SharedServer::new(Lsm::open(lsm)?, &config), &config).await?,
+        StorageConfig::Btree(btree) => start(SharedServer::new(BTree::open(btree)?, &config), &config).await?,
+        StorageConfig::Tiered(tiered) => match &tiered.disk {
+            DiskConfig::Lsm(lsm) => {
+                start(SharedServer::new(Tiered::new(Lsm::open(lsm)?, tiered.cache_bytes), &config), &config).await?
+            }
+            DiskConfig::Btree(btree) => {
+                start(SharedServer::new(Tiered::new(BTree::open(btree)?, tiered.cache_bytes), &config), &config).await?
+            }
+        },
+    }
+
+    // 发送缓存中尚未导出的 span
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            error!("Shutdown tracer provider failed: {e:?}");
+        }
+    }
+    Ok(())
+}
+
+async fn start<Store: Storage + Send + Sync + 'static>(server: SharedServer<Store>, config: &ServerConfig) -> Result<()> {
+    if let (Some(cluster_config), Some(cluster)) = (&config.cluster, server.cluster.clone()) {
+        let raft_addr = cluster_config.raft_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = transport::serve(&raft_addr, cluster).await {
+                error!("Raft listener stopped: {e:?}");
+            }
+        });
+    }
+
+    if let Some(metrics_config) = &config.metrics {
+        let addr = metrics_config.addr.clone();
+        let svr = server.clone();
+        tokio::spawn(async move {
+            let render = move || svr.shared.metrics.render(&svr.shared.storage.stats());
+            if let Err(e) = metrics::serve(&addr, render).await {
+                error!("Metrics listener stopped: {e:?}");
+            }
+        });
+    }
+
+    if let Some(http_config) = &config.http {
+        let addr = http_config.addr.clone();
+        let svr = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(&addr, svr).await {
+                error!("HTTP listener stopped: {e:?}");
+            }
+        });
+    }
+
+    if let Some(grpc_config) = &config.grpc {
+        let addr = grpc_config.addr.clone();
+        let svr = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(&addr, svr).await {
+                error!("gRPC listener stopped: {e:?}");
+            }
+        });
+    }
+
+    let addr = &config.addr;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on: {addr}");
+
+    tokio::select! {
+        _ = serve(listener, server) => {}
+        _ = tokio::signal::ctrl_c() => info!("Shutting down."),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::BytesMut;
+    use kv_client::client::TcpBackend;
+    use kv_core::codec::Codec;
+    use kv_core::compress;
+    use kv_core::domain::{Envelope, Request, Response, TraceContext, KV};
+    use kv_core::dump::{DumpReader, Record};
+    use kv_core::frame;
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::{CompressionConfig, LimitsConfig, PubSubConfig, Quota, RateLimitConfig, ScriptConfig, ServerConfig, SlowLogConfig, UserConfig};
+    use crate::storage::memory::Memory;
+    use crate::serializer::Format;
+    use crate::{password, serializer, serve, SharedServer};
+
+    async fn start(limits: LimitsConfig) -> (SocketAddr, SharedServer) {
+        start_with(ServerConfig { limits, ..Default::default() }).await
+    }
+
+    async fn start_with(config: ServerConfig) -> (SocketAddr, SharedServer) {
+        let server = SharedServer::new(Memory::new(), &config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, server.clone()));
+
+        (addr, server)
+    }
+
+    async fn call(stream: &mut TcpStream, request: &Request) -> Option<Response> {
+        let mut out = BytesMut::new();
+        serializer::encode(request, &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        read_response(stream).await
+    }
+
+    /// 每次只读取一帧，推送模式下连续到达的多条消息不会被丢弃
+    async fn read_response(stream: &mut TcpStream) -> Option<Response> {
+        read_frame(stream).await.map(|(_, response)| response)
+    }
+
+    /// 同 read_response，同时返回响应是否经过压缩
+    async fn read_frame(stream: &mut TcpStream) -> Option<(bool, Response)> {
+        read_message(stream).await.map(|(compressed, _, response)| (compressed, response))
+    }
+
+    /// 同 read_frame，同时返回响应的编码格式
+    async fn read_message(stream: &mut TcpStream) -> Option<(bool, Codec, Response)> {
+        let mut header = [0u8; frame::HEADER_LEN];
+        stream.read_exact(&mut header).await.ok()?;
+        let compressed = u32::from_be_bytes(header) & frame::COMPRESSED != 0;
+        let len = (u32::from_be_bytes(header) & !(frame::COMPRESSED | frame::PROTOBUF)) as usize;
+
+        let mut buf = BytesMut::zeroed(frame::HEADER_LEN + len);
+        buf[..frame::HEADER_LEN].copy_from_slice(&header);
+        stream.read_exact(&mut buf[frame::HEADER_LEN..]).await.unwrap();
+        let (codec, response) = serializer::decode_message(&mut buf, frame::MAX_FRAME_SIZE).unwrap()?;
+        Some((compressed, codec, response))
+    }
+
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 16];
+        matches!(tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await, Ok(Ok(0) | Err(_)))
+    }
+
+    #[tokio::test]
+    async fn should_reject_when_too_many_connections() {
+        let (addr, _) = start(LimitsConfig { max_connections: 1, ..Default::default() }).await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let res = call(&mut first, &Request::Get { key: String::from("k1") }).await.unwrap();
+        assert_eq!(0, res.code);
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let res = read_response(&mut second).await.unwrap();
+        assert_eq!(503, res.code);
+
+        // 第一个连接关闭后可以建立新连接
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let res = call(&mut third, &Request::Get { key: String::from("k1") }).await.unwrap();
+        assert_eq!(0, res.code);
+    }
+
+    #[tokio::test]
+    async fn idle_connection_should_be_closed() {
+        let (addr, server) = start(LimitsConfig { idle_timeout_ms: 100, ..Default::default() }).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(is_closed(&mut stream).await);
+        assert_eq!(0, server.shared.metrics.active_connections());
+    }
+
+    #[tokio::test]
+    async fn partial_request_should_time_out() {
+        let (addr, _) = start(LimitsConfig { read_timeout_ms: 100, ..Default::default() }).await;
+
+        // 只发送帧头，不发送 payload
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&10u32.to_be_bytes()).await.unwrap();
+        assert!(is_closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn trickled_request_should_time_out() {
+        let (addr, server) = start(LimitsConfig { read_timeout_ms: 300, ..Default::default() }).await;
+
+        // 每次只发送一个字节，间隔小于读超时，整个请求的时间超过读超时后仍然断开
+        let mut out = BytesMut::new();
+        serializer::encode(&Request::Get { key: String::from("k1") }, &mut out).unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut sent = 0;
+        for byte in out.iter().take(out.len() - 1) {
+            if stream.write_all(&[*byte]).await.is_err() {
+                break;
+            }
+            sent += 1;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            if server.shared.metrics.active_connections() == 0 {
+                break;
+            }
+        }
+        // 大约在发送第 6 个字节时断开，不会等到发送完
+        assert!(sent < out.len() - 1, "sent {sent} bytes");
+        assert!(is_closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_should_be_rejected() {
+        let (addr, _) = start(LimitsConfig { max_frame_size: 1024, ..Default::default() }).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&(1024u32 * 1024).to_be_bytes()).await.unwrap();
+
+        let res = read_response(&mut stream).await.unwrap();
+        assert_eq!(413, res.code);
+        assert!(is_closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn client_not_reading_should_be_disconnected() {
+        let limits = LimitsConfig { write_timeout_ms: 200, ..Default::default() };
+        let (addr, server) = start(limits).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let kv = KV { key: String::from("k1"), value: "v".repeat(64 * 1024) };
+        call(&mut stream, &Request::Set { kv }).await.unwrap();
+
+        // 只发送请求不读取响应，服务端写超时后断开连接
+        let mut out = BytesMut::new();
+        for _ in 0..1000 {
+            serializer::encode(&Request::Get { key: String::from("k1") }, &mut out).unwrap();
+        }
+        stream.write_all_buf(&mut out).await.unwrap();
+
+        for _ in 0..50 {
+            if server.shared.metrics.active_connections() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Connection is not closed.");
+    }
+
+    #[tokio::test]
+    async fn authenticated_user_should_be_throttled() {
+        let config = ServerConfig {
+            rate_limit: RateLimitConfig {
+                per_user: Some(Quota { requests_per_sec: Some(1.0), bytes_per_sec: None }),
+                ..Default::default()
+            },
+            users: vec![UserConfig { name: String::from("alice"), password_hash: password::weak_hash("secret"), namespaces: None }],
+            ..Default::default()
+        };
+        let (addr, _) = start_with(config).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let auth = |password: &str| Request::Auth { username: String::from("alice"), password: password.to_string() };
+        assert_eq!(401, call(&mut stream, &auth("wrong")).await.unwrap().code);
+        assert_eq!(0, call(&mut stream, &auth("secret")).await.unwrap().code);
+
+        // 认证后的第一个请求消耗掉令牌
+        let get = Request::Get { key: String::from("k1") };
+        assert_eq!(0, call(&mut stream, &get).await.unwrap().code);
+
+        let res = call(&mut stream, &get).await.unwrap();
+        assert_eq!(429, res.code);
+        assert!(res.values[0].parse::<u64>().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn slow_requests_should_be_logged_with_request_id() {
+        // 阈值为 0，所有请求都会被记录
+        let config = ServerConfig { slow_log: SlowLogConfig { threshold_us: 0, max_len: 2 }, ..Default::default() };
+        let (addr, _) = start_with(config).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let set = Request::Set { kv: KV { key: String::from("k1"), value: String::from("v1") } };
+        let set_res = call(&mut stream, &set).await.unwrap();
+        let get_res = call(&mut stream, &Request::Get { key: String::from("k1") }).await.unwrap();
+        assert!(!set_res.request_id.is_empty());
+        assert_ne!(set_res.request_id, get_res.request_id);
+
+        let res = call(&mut stream, &Request::SlowLog { count: 10 }).await.unwrap();
+        let entries = res.slow_log.unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!(get_res.request_id, entries[0].request_id);
+        assert_eq!("get k1", entries[0].request);
+        assert_eq!(set_res.request_id, entries[1].request_id);
+        assert_eq!("set k1", entries[1].request);
+        assert_eq!(stream.local_addr().unwrap().to_string(), entries[0].client);
+    }
+
+    #[tokio::test]
+    async fn compression_should_be_negotiated() {
+        let (addr, _) = start(LimitsConfig::default()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let hello = |algorithm: &str| Request::Hello { compression: vec![algorithm.to_string()] };
+        let value = r#"{"user":"alice","roles":["admin"]}"#.repeat(100);
+        let get = Request::Get { key: String::from("k1") };
+
+        // 协商之前不压缩，不支持的算法不开启压缩
+        let set = Request::Set { kv: KV { key: String::from("k1"), value: value.clone() } };
+        assert_eq!(0, call(&mut stream, &set).await.unwrap().code);
+        assert!(call(&mut stream, &hello("zstd")).await.unwrap().values.is_empty());
+        let mut out = BytesMut::new();
+        serializer::encode(&get, &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        assert!(!read_frame(&mut stream).await.unwrap().0);
+
+        assert_eq!(vec![compress::LZ4.to_string()], call(&mut stream, &hello(compress::LZ4)).await.unwrap().values);
+
+        // 压缩的请求和响应
+        let set = Request::Set { kv: KV { key: String::from("k2"), value: value.clone() } };
+        let (raw, len) = serializer::encode_compressed(&set, Format { compression: Some(1024), ..Default::default() }, &mut out).unwrap();
+        assert!(len < raw);
+        stream.write_all_buf(&mut out).await.unwrap();
+        assert_eq!(0, read_response(&mut stream).await.unwrap().code);
+
+        serializer::encode(&Request::Get { key: String::from("k2") }, &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        let (compressed, res) = read_frame(&mut stream).await.unwrap();
+        assert!(compressed);
+        assert_eq!(vec![value], res.values);
+
+        // 小的响应不压缩
+        serializer::encode(&Request::Get { key: String::from("k3") }, &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        assert!(!read_frame(&mut stream).await.unwrap().0);
+
+        let info = call(&mut stream, &Request::Info).await.unwrap().info.unwrap();
+        assert!(info.wire_compression.unwrap().ratio() > 1.0);
+    }
+
+    #[tokio::test]
+    async fn responses_should_follow_request_codec() {
+        let (addr, _) = start(LimitsConfig::default()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let value = "v".repeat(4096);
+        let protobuf = |compression| Format { codec: Codec::Protobuf, compression };
+
+        let mut out = BytesMut::new();
+        let set = Envelope::from(Request::Set { kv: KV { key: String::from("k1"), value: value.clone() } });
+        serializer::encode_compressed(&set, protobuf(None), &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        let (_, codec, res) = read_message(&mut stream).await.unwrap();
+        assert_eq!((Codec::Protobuf, 0), (codec, res.code));
+        assert!(!res.request_id.is_empty());
+
+        // 同一个连接上可以切换格式，压缩同样适用于 protobuf
+        assert_eq!(vec![compress::LZ4.to_string()], call(&mut stream, &Request::Hello { compression: vec![compress::LZ4.to_string()] }).await.unwrap().values);
+        serializer::encode_compressed(&Envelope::from(Request::Get { key: String::from("k1") }), protobuf(Some(1024)), &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        let (compressed, codec, res) = read_message(&mut stream).await.unwrap();
+        assert_eq!((true, Codec::Protobuf), (compressed, codec));
+        assert_eq!(vec![value], res.values);
+
+        // 格式错误的 protobuf 请求返回 400，连接继续可用
+        frame::encode_with(b"\xff\xff\xff", Codec::Protobuf, None, &mut out);
+        stream.write_all_buf(&mut out).await.unwrap();
+        assert_eq!(400, read_response(&mut stream).await.unwrap().code);
+        serializer::encode(&Request::Get { key: String::from("k2") }, &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        let (_, codec, res) = read_message(&mut stream).await.unwrap();
+        assert_eq!((Codec::Json, 0), (codec, res.code));
+    }
+
+    #[tokio::test]
+    async fn compression_can_be_disabled() {
+        let config = ServerConfig { compression: CompressionConfig { enabled: false, threshold: 0 }, ..Default::default() };
+        let (addr, _) = start_with(config).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let hello = Request::Hello { compression: vec![compress::LZ4.to_string()] };
+        let res = call(&mut stream, &hello).await.unwrap();
+        assert_eq!(0, res.code);
+        assert!(res.values.is_empty());
+    }
+
+    // 使用单线程运行时，服务端任务与测试在同一线程上，可以使用线程局部的 subscriber
+    #[tokio::test]
+    async fn get_should_export_span_tree() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (addr, _) = start(LimitsConfig::default()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(0, call(&mut stream, &Request::Get { key: String::from("k1") }).await.unwrap().code);
+
+        // 带有客户端 trace context 的请求
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let envelope = Envelope {
+            request: Request::Get { key: String::from("k2") },
+            trace: Some(TraceContext { traceparent: traceparent.to_string(), tracestate: None }),
+        };
+        let mut out = BytesMut::new();
+        serializer::encode(&envelope, &mut out).unwrap();
+        stream.write_all_buf(&mut out).await.unwrap();
+        assert_eq!(0, read_response(&mut stream).await.unwrap().code);
+
+        // 关闭连接后连接的 span 结束
+        drop(stream);
+        let mut spans = vec![];
+        for _ in 0..100 {
+            spans = exporter.get_finished_spans().unwrap();
+            if spans.iter().any(|s| s.name == "handle_connection") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let find = |name: &str| spans.iter().filter(|s| s.name == name).collect::<Vec<_>>();
+        let connection = find("handle_connection");
+        let requests = find("handle_request");
+        let handles = find("request_handler::handle");
+        let gets = find("storage::get");
+        assert_eq!(1, connection.len());
+        assert_eq!(2, requests.len());
+        assert_eq!(2, handles.len());
+        assert_eq!(2, gets.len());
+
+        // handle_connection -> handle_request -> request_handler::handle -> storage::get
+        let connection_id = connection[0].span_context.span_id();
+        let local = requests.iter().find(|s| s.parent_span_id == connection_id).unwrap();
+        let handle = handles.iter().find(|s| s.parent_span_id == local.span_context.span_id()).unwrap();
+        assert!(gets.iter().any(|s| s.parent_span_id == handle.span_context.span_id()));
+
+        // 客户端传入的 trace context 作为父 span
+        let remote = requests.iter().find(|s| s.parent_span_is_remote).unwrap();
+        assert_eq!(TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(), remote.span_context.trace_id());
+        assert_eq!(SpanId::from_hex("00f067aa0ba902b7").unwrap(), remote.parent_span_id);
+    }
+
+    #[tokio::test]
+    async fn subscriber_should_receive_published_messages() {
+        let (addr, _) = start(LimitsConfig::default()).await;
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+
+        let subscribe = Request::Subscribe { channels: vec![String::from("news.sport")] };
+        assert_eq!(0, call(&mut subscriber, &subscribe).await.unwrap().code);
+        let psubscribe = Request::PSubscribe { patterns: vec![String::from("news.*")] };
+        assert_eq!(0, call(&mut subscriber, &psubscribe).await.unwrap().code);
+
+        let publish = Request::Publish { channel: String::from("news.sport"), message: String::from("goal") };
+        assert_eq!(vec![String::from("2")], call(&mut publisher, &publish).await.unwrap().values);
+
+        let first = read_response(&mut subscriber).await.unwrap().push.unwrap();
+        let second = read_response(&mut subscriber).await.unwrap().push.unwrap();
+        assert_eq!(("news.sport", "goal"), (first.channel.as_str(), first.payload.as_str()));
+        assert_eq!(None, first.pattern);
+        assert_eq!(Some(String::from("news.*")), second.pattern);
+
+        // 推送模式下不能执行其他命令
+        let res = call(&mut subscriber, &Request::Get { key: String::from("k1") }).await.unwrap();
+        assert_eq!(400, res.code);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_be_disconnected() {
+        let config = ServerConfig { pubsub: PubSubConfig { queue_size: 4 }, ..Default::default() };
+        let (addr, server) = start_with(config).await;
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+
+        let subscribe = Request::Subscribe { channels: vec![String::from("c1")] };
+        assert_eq!(0, call(&mut subscriber, &subscribe).await.unwrap().code);
+
+        // 订阅者不读取，socket 缓冲区写满后消息在队列中积压
+        let payload = "x".repeat(1 << 20);
+        let mut lagged = false;
+        for _ in 0..200 {
+            if server.shared.pubsub.publish("default", "c1", &payload) == 0 {
+                lagged = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(lagged);
+
+        // 读完已经推送的消息后收到错误，然后连接被关闭
+        loop {
+            let res = read_response(&mut subscriber).await.unwrap();
+            if res.push.is_none() {
+                assert_eq!(503, res.code);
+                break;
+            }
+        }
+        assert!(is_closed(&mut subscriber).await);
+    }
+
+    #[tokio::test]
+    async fn script_should_be_evaluated_by_sha() {
+        let (addr, _) = start(LimitsConfig::default()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let script = "local v = (tonumber(kv.get(KEYS[1])) or 0) + 1; kv.set(KEYS[1], tostring(v)); return v";
+        let eval = Request::Eval { script: script.to_string(), keys: vec![String::from("counter")], args: vec![] };
+        assert_eq!(vec![String::from("1")], call(&mut stream, &eval).await.unwrap().values);
+
+        let evalsha = |sha1: &str| Request::EvalSha { sha1: sha1.to_string(), keys: vec![String::from("counter")], args: vec![] };
+        let sha1 = crate::script::Scripts::new(&Default::default()).load(script);
+        assert_eq!(vec![String::from("2")], call(&mut stream, &evalsha(&sha1)).await.unwrap().values);
+        assert_eq!(404, call(&mut stream, &evalsha("0000")).await.unwrap().code);
+
+        let res = call(&mut stream, &Request::Eval { script: String::from("error('boom')"), keys: vec![], args: vec![] }).await;
+        assert_eq!(422, res.unwrap().code);
+    }
+
+    #[tokio::test]
+    async fn script_should_not_block_runtime() {
+        let script = ScriptConfig { timeout_ms: 500, max_instructions: u64::MAX, ..Default::default() };
+        let (addr, _) = start_with(ServerConfig { script, ..Default::default() }).await;
+
+        let mut scripted = TcpStream::connect(addr).await.unwrap();
+        let eval = Request::Eval { script: String::from("while true do end"), keys: vec![], args: vec![] };
+        let running = tokio::spawn(async move { call(&mut scripted, &eval).await.unwrap().code });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 测试使用单线程运行时，脚本执行期间其他连接的不访问存储的请求仍然可以处理
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(0, call(&mut other, &Request::Info).await.unwrap().code);
+        assert!(start.elapsed() < Duration::from_millis(300), "{:?}", start.elapsed());
+
+        assert_eq!(422, running.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_be_isolated() {
+        let user = |name: &str, namespaces: Option<Vec<String>>| UserConfig {
+            name: name.to_string(),
+            password_hash: password::weak_hash("secret"),
+            namespaces,
+        };
+        let config = ServerConfig {
+            users: vec![user("alice", Some(vec![String::from("team-a")])), user("admin", None)],
+            ..Default::default()
+        };
+        let (addr, _) = start_with(config).await;
+
+        let auth = |name: &str| Request::Auth { username: name.to_string(), password: String::from("secret") };
+        let select = |db: &str| Request::Select { db: db.to_string() };
+        let set = |value: &str| Request::Set { kv: KV { key: String::from("k1"), value: value.to_string() } };
+        let get = Request::Get { key: String::from("k1") };
+
+        // 未认证的连接只能访问默认命名空间
+        let mut anonymous = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(0, call(&mut anonymous, &set("default")).await.unwrap().code);
+        assert_eq!(403, call(&mut anonymous, &select("team-a")).await.unwrap().code);
+
+        let mut alice = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(0, call(&mut alice, &auth("alice")).await.unwrap().code);
+        assert_eq!(0, call(&mut alice, &select("team-a")).await.unwrap().code);
+        assert_eq!(0, call(&mut alice, &set("a")).await.unwrap().code);
+        assert_eq!(vec![String::from("a")], call(&mut alice, &get).await.unwrap().values);
+        assert_eq!(403, call(&mut alice, &select("team-b")).await.unwrap().code);
+
+        // 切换用户后不能继续访问没有权限的命名空间
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(0, call(&mut admin, &auth("admin")).await.unwrap().code);
+        assert_eq!(0, call(&mut admin, &select("team-a")).await.unwrap().code);
+        assert_eq!(0, call(&mut admin, &Request::FlushDb).await.unwrap().code);
+        assert_eq!(0, call(&mut admin, &auth("alice")).await.unwrap().code);
+        assert_eq!(403, call(&mut admin, &select("default")).await.unwrap().code);
+
+        assert!(call(&mut alice, &get).await.unwrap().values.is_empty());
+        assert_eq!(vec![String::from("default")], call(&mut anonymous, &get).await.unwrap().values);
+
+        let info = call(&mut anonymous, &Request::Info).await.unwrap().info.unwrap();
+        assert_eq!(Some(&1), info.storage.namespaces.get("default"));
+        assert_eq!(None, info.storage.namespaces.get("team-a"));
+    }
+
+    #[tokio::test]
+    async fn restricted_users_should_not_see_other_namespaces() {
+        let user = |name: &str, namespaces: Option<Vec<String>>| UserConfig {
+            name: name.to_string(),
+            password_hash: password::weak_hash("secret"),
+            namespaces,
+        };
+        let config = ServerConfig {
+            users: vec![user("alice", Some(vec![String::from("team-a")])), user("admin", None)],
+            slow_log: SlowLogConfig { threshold_us: 0, max_len: 16 },
+            ..Default::default()
+        };
+        let (addr, _) = start_with(config).await;
+        let auth = |name: &str| Request::Auth { username: name.to_string(), password: String::from("secret") };
+        let select = |db: &str| Request::Select { db: db.to_string() };
+
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        call(&mut admin, &auth("admin")).await.unwrap();
+        call(&mut admin, &select("team-b")).await.unwrap();
+        call(&mut admin, &Request::Set { kv: KV { key: String::from("secret-key"), value: String::from("v") } }).await.unwrap();
+
+        let mut alice = TcpStream::connect(addr).await.unwrap();
+        call(&mut alice, &auth("alice")).await.unwrap();
+        call(&mut alice, &select("team-a")).await.unwrap();
+        call(&mut alice, &Request::Set { kv: KV { key: String::from("k1"), value: String::from("v") } }).await.unwrap();
+        let mut anonymous = TcpStream::connect(addr).await.unwrap();
+
+        // 管理命令只有不受限制的用户可以执行，未认证的连接同样不可以
+        let add_node = Request::AddNode { id: 9, addr: String::from("127.0.0.1:1"), raft_addr: String::from("127.0.0.1:2") };
+        for stream in [&mut alice, &mut anonymous] {
+            assert_eq!(403, call(stream, &Request::SlowLog { count: 10 }).await.unwrap().code);
+            assert_eq!(403, call(stream, &add_node).await.unwrap().code);
+            assert_eq!(403, call(stream, &Request::RemoveNode { id: 9 }).await.unwrap().code);
+        }
+        let entries = call(&mut admin, &Request::SlowLog { count: 16 }).await.unwrap().slow_log.unwrap();
+        assert!(entries.iter().any(|e| e.request.contains("secret-key")));
+
+        // Info 只包含有权限的命名空间
+        let info = call(&mut alice, &Request::Info).await.unwrap().info.unwrap();
+        assert_eq!(vec![String::from("team-a")], info.storage.namespaces.keys().cloned().collect::<Vec<_>>());
+        assert_eq!(1, info.storage.keys);
+        let info = call(&mut admin, &Request::Info).await.unwrap().info.unwrap();
+        assert_eq!(2, info.storage.keys);
+
+        // 发布订阅的频道按命名空间隔离
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        call(&mut subscriber, &auth("alice")).await.unwrap();
+        call(&mut subscriber, &select("team-a")).await.unwrap();
+        call(&mut subscriber, &Request::PSubscribe { patterns: vec![String::from("*")] }).await.unwrap();
+        let publish = Request::Publish { channel: String::from("news"), message: String::from("m") };
+        assert_eq!(vec![String::from("0")], call(&mut admin, &publish).await.unwrap().values);
+        assert_eq!(vec![String::from("1")], call(&mut alice, &publish).await.unwrap().values);
+        assert_eq!(vec![String::from("0")], call(&mut anonymous, &publish).await.unwrap().values);
+        assert_eq!("news", read_response(&mut subscriber).await.unwrap().push.unwrap().channel);
+    }
+
+    #[tokio::test]
+    async fn client_should_subscribe_in_selected_namespace() {
+        let (addr, _) = start_with(ServerConfig::default()).await;
+
+        // 与 kv-client --db team --protobuf --compress subscribe 建立连接的方式相同
+        let subscriber = tokio::task::spawn_blocking(move || {
+            let backend = TcpBackend::default().with_compression().with_codec(Codec::Protobuf).with_db("team");
+            let mut conn = backend.connect(&addr.to_string()).unwrap();
+            assert_eq!(0, conn.call(&Request::Subscribe { channels: vec![String::from("news")] }).unwrap().code);
+            conn
+        });
+        let mut subscriber = subscriber.await.unwrap();
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        let publish = |message: &str| Request::Publish { channel: String::from("news"), message: message.to_string() };
+        assert_eq!(vec![String::from("0")], call(&mut publisher, &publish("default")).await.unwrap().values);
+        call(&mut publisher, &Request::Select { db: String::from("team") }).await.unwrap();
+        assert_eq!(vec![String::from("1")], call(&mut publisher, &publish("team")).await.unwrap().values);
+
+        let push = tokio::task::spawn_blocking(move || subscriber.recv().unwrap()).await.unwrap().push.unwrap();
+        assert_eq!(("news", "team"), (push.channel.as_str(), push.payload.as_str()));
+    }
+
+    #[tokio::test]
+    async fn dump_should_stream_accessible_namespaces() {
+        let user = |name: &str, namespaces: Option<Vec<String>>| UserConfig {
+            name: name.to_string(),
+            password_hash: password::weak_hash("secret"),
+            namespaces,
+        };
+        let config = ServerConfig {
+            users: vec![user("alice", Some(vec![String::from("team-a")])), user("admin", None)],
+            ..Default::default()
+        };
+        let (addr, _) = start_with(config).await;
+
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        let auth = |name: &str| Request::Auth { username: name.to_string(), password: String::from("secret") };
+        call(&mut admin, &auth("admin")).await.unwrap();
+        let kvs = (0..2000).map(|i| KV { key: format!("k{i:04}"), value: "v".repeat(100) }).collect();
+        assert_eq!(0, call(&mut admin, &Request::MSet { kvs }).await.unwrap().code);
+        call(&mut admin, &Request::Select { db: String::from("team-a") }).await.unwrap();
+        call(&mut admin, &Request::Set { kv: KV { key: String::from("k1"), value: String::from("a1") } }).await.unwrap();
+
+        // 读取所有分段，每段都带有同一个请求 id
+        async fn dump(stream: &mut TcpStream) -> Vec<Record> {
+            let first = call(stream, &Request::Dump).await.unwrap();
+            let request_id = first.request_id.clone();
+            let mut more = first.more;
+            let mut lines = first.values;
+            while more {
+                let chunk = read_response(stream).await.unwrap();
+                assert_eq!(request_id, chunk.request_id);
+                more = chunk.more;
+                lines.extend(chunk.values);
+            }
+            DumpReader::new(lines.into_iter().map(Ok)).unwrap().collect::<Result<_, _>>().unwrap()
+        }
+
+        let records = dump(&mut admin).await;
+        assert_eq!(2001, records.len());
+        assert_eq!(("default", "k0000"), (records[0].ns.as_str(), records[0].key.as_str()));
+        assert_eq!(("team-a", "a1"), (records[2000].ns.as_str(), records[2000].value.as_str()));
+
+        // 只导出有权限的命名空间
+        let mut alice = TcpStream::connect(addr).await.unwrap();
+        call(&mut alice, &auth("alice")).await.unwrap();
+        let records = dump(&mut alice).await;
+        assert_eq!(1, records.len());
+        assert_eq!("team-a", records[0].ns);
+
+        // 导出后连接可以继续使用
+        call(&mut alice, &Request::Select { db: String::from("team-a") }).await.unwrap();
+        let res = call(&mut alice, &Request::Get { key: String::from("k1") }).await.unwrap();
+        assert_eq!(vec![String::from("a1")], res.values);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use axum::routing::get;
+use axum::Router;
+use kv_core::domain::CompressionInfo;
+use kv_core::error::KvError;
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::storage::StorageStats;
+use tokio::net::TcpListener;
+use tracing::info;
+
+/// 服务端的统计指标
+///
+/// 指标始终在进程内收集，是否通过 HTTP `/metrics` 暴露由配置决定。
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    errors: IntCounterVec,
+    connections: IntGauge,
+    bytes_in: IntCounter,
+    bytes_out: IntCounter,
+    keys: IntGauge,
+    memory_bytes: IntGauge,
+    cache_hits: IntGauge,
+    cache_misses: IntGauge,
+    cache_hit_ratio: Gauge,
+    compression_ratio: Gauge,
+    wire_raw_bytes: IntCounter,
+    wire_compressed_bytes: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("kv_requests_total", "Total number of requests by command."),
+            &["command"],
+        ).unwrap();
+        // kv 命令通常在微秒级完成，从 50us 开始按 2 倍递增
+        let latency = HistogramVec::new(
+            HistogramOpts::new("kv_request_duration_seconds", "Request latency by command.")
+                .buckets(exponential_buckets(0.00005, 2.0, 16).unwrap()),
+            &["command"],
+        ).unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("kv_errors_total", "Total number of errors by kind."),
+            &["kind"],
+        ).unwrap();
+        let connections = IntGauge::new("kv_active_connections", "Number of active client connections.").unwrap();
+        let bytes_in = IntCounter::new("kv_bytes_in_total", "Total bytes read from clients.").unwrap();
+        let bytes_out = IntCounter::new("kv_bytes_out_total", "Total bytes written to clients.").unwrap();
+        let keys = IntGauge::new("kv_storage_keys", "Number of keys in the storage engine.").unwrap();
+        let memory_bytes = IntGauge::new("kv_storage_memory_bytes", "Approximate memory used by the storage engine.").unwrap();
+        // 分层存储的缓存，其他存储引擎始终为 0
+        let cache_hits = IntGauge::new("kv_storage_cache_hits", "Reads served from the in-memory cache.").unwrap();
+        let cache_misses = IntGauge::new("kv_storage_cache_misses", "Reads that missed the in-memory cache.").unwrap();
+        let cache_hit_ratio = Gauge::new("kv_storage_cache_hit_ratio", "Ratio of reads served from the in-memory cache.").unwrap();
+        // 没有开启存储压缩时始终为 0
+        let compression_ratio = Gauge::new("kv_storage_compression_ratio", "Ratio of raw to compressed value bytes in storage.").unwrap();
+        // 只统计协商了压缩的连接
+        let wire_raw_bytes = IntCounter::new("kv_wire_uncompressed_bytes_total", "Response bytes before compression.").unwrap();
+        let wire_compressed_bytes = IntCounter::new("kv_wire_compressed_bytes_total", "Response bytes after compression.").unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry.register(Box::new(bytes_in.clone())).unwrap();
+        registry.register(Box::new(bytes_out.clone())).unwrap();
+        registry.register(Box::new(keys.clone())).unwrap();
+        registry.register(Box::new(memory_bytes.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry.register(Box::new(cache_hit_ratio.clone())).unwrap();
+        registry.register(Box::new(compression_ratio.clone())).unwrap();
+        registry.register(Box::new(wire_raw_bytes.clone())).unwrap();
+        registry.register(Box::new(wire_compressed_bytes.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            latency,
+            errors,
+            connections,
+            bytes_in,
+            bytes_out,
+            keys,
+            memory_bytes,
+            cache_hits,
+            cache_misses,
+            cache_hit_ratio,
+            compression_ratio,
+            wire_raw_bytes,
+            wire_compressed_bytes,
+        }
+    }
+
+    /// 记录一次请求的耗时和结果
+    pub fn observe_request(&self, command: &str, elapsed: Duration, error: Option<&KvError>) {
+        self.requests.with_label_values(&[command]).inc();
+        self.latency.with_label_values(&[command]).observe(elapsed.as_secs_f64());
+
+        if let Some(err) = error {
+            self.errors.with_label_values(&[err.kind()]).inc();
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.inc();
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.dec();
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.connections.get().max(0) as u64
+    }
+
+    /// 各命令的执行次数
+    pub fn command_counts(&self) -> BTreeMap<String, u64> {
+        self.requests.collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter_map(|m| {
+                let command = m.get_label().iter().find(|l| l.get_name() == "command")?;
+                Some((command.get_value().to_string(), m.get_counter().get_value() as u64))
+            })
+            .collect()
+    }
+
+    pub fn bytes_in(&self, n: usize) {
+        self.bytes_in.inc_by(n as u64);
+    }
+
+    pub fn bytes_out(&self, n: usize) {
+        self.bytes_out.inc_by(n as u64);
+    }
+
+    /// 开启压缩的连接上发送了 raw 字节的 payload，压缩后为 compressed 字节
+    pub fn wire_compression(&self, raw: usize, compressed: usize) {
+        self.wire_raw_bytes.inc_by(raw as u64);
+        self.wire_compressed_bytes.inc_by(compressed as u64);
+    }
+
+    /// 网络传输的压缩情况，没有连接开启压缩时为 None
+    pub fn wire_compression_info(&self) -> Option<CompressionInfo> {
+        let info = CompressionInfo { raw_bytes: self.wire_raw_bytes.get(), compressed_bytes: self.wire_compressed_bytes.get() };
+        (info.raw_bytes > 0).then_some(info)
+    }
+
+    /// 以 Prometheus 文本格式输出，存储引擎的统计在输出时更新
+    pub fn render(&self, stats: &StorageStats) -> String {
+        self.keys.set(stats.keys as i64);
+        self.memory_bytes.set(stats.memory_bytes as i64);
+        if let Some(cache) = &stats.cache {
+            self.cache_hits.set(cache.hits as i64);
+            self.cache_misses.set(cache.misses as i64);
+            self.cache_hit_ratio.set(cache.hit_ratio());
+        }
+        if let Some(compression) = &stats.compression {
+            self.compression_ratio.set(compression.ratio());
+        }
+
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// 启动 HTTP 服务，在 `/metrics` 上输出 `render` 的结果
+pub async fn serve<F>(addr: &str, render: F) -> anyhow::Result<()>
+where
+    F: Fn() -> String + Clone + Send + Sync + 'static,
+{
+    let app = Router::new().route("/metrics", get(move || {
+        let render = render.clone();
+        async move { render() }
+    }));
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics listening on: {addr}");
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use kv_core::domain::{CacheInfo, CompressionInfo};
+    use kv_core::error::KvError;
+
+    use crate::metrics::Metrics;
+    use crate::storage::StorageStats;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        metrics.observe_request("get", Duration::from_micros(80), None);
+        metrics.observe_request("get", Duration::from_micros(120), Some(&KvError::NotFound(String::from("k1"))));
+        metrics.connection_opened();
+        metrics.bytes_in(10);
+        metrics.bytes_out(20);
+        assert_eq!(None, metrics.wire_compression_info());
+        metrics.wire_compression(400, 100);
+
+        let cache = CacheInfo { hits: 3, misses: 1, ..Default::default() };
+        let compression = CompressionInfo { raw_bytes: 300, compressed_bytes: 100 };
+        let stats = StorageStats { keys: 3, memory_bytes: 128, cache: Some(cache), compression: Some(compression), ..Default::default() };
+        let text = metrics.render(&stats);
+
+        assert!(text.contains(r#"kv_requests_total{command="get"} 2"#));
+        assert!(text.contains(r#"kv_request_duration_seconds_count{command="get"} 2"#));
+        assert!(text.contains(r#"kv_errors_total{kind="not_found"} 1"#));
+        assert!(text.contains("kv_active_connections 1"));
+        assert!(text.contains("kv_bytes_in_total 10"));
+        assert!(text.contains("kv_bytes_out_total 20"));
+        assert!(text.contains("kv_storage_keys 3"));
+        assert!(text.contains("kv_storage_memory_bytes 128"));
+        assert!(text.contains("kv_storage_cache_hits 3"));
+        assert!(text.contains("kv_storage_cache_hit_ratio 0.75"));
+        assert!(text.contains("kv_storage_compression_ratio 3"));
+        assert!(text.contains("kv_wire_uncompressed_bytes_total 400"));
+        assert!(text.contains("kv_wire_compressed_bytes_total 100"));
+        assert_eq!(Some(4.0), metrics.wire_compression_info().map(|info| info.ratio()));
+
+        assert_eq!(1, metrics.active_connections());
+        assert_eq!(Some(&2), metrics.command_counts().get("get"));
+    }
+}
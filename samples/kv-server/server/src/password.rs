@@ -0,0 +1,54 @@
+//! 用户密码的哈希与校验
+//!
+//! 配置文件中只保存 argon2 的 PHC 格式哈希（`$argon2id$v=19$...`），通过 `kv-server hash-password` 生成。
+//! 校验时由 argon2 在常量时间内比较哈希值。
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Deserializer};
+
+/// 使用默认参数和随机盐生成密码的哈希
+pub fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// 密码与哈希是否匹配，哈希格式错误时返回 false
+pub fn verify(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// 读取配置时检查哈希的格式，配置错误时启动失败，而不是所有认证都失败
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let hash = String::deserialize(deserializer)?;
+    PasswordHash::new(&hash).map_err(|e| serde::de::Error::custom(format!("invalid password_hash: {e}")))?;
+    Ok(hash)
+}
+
+/// 测试中使用的低成本哈希，避免每个测试都花费默认参数的计算时间
+#[cfg(test)]
+pub(crate) fn weak_hash(password: &str) -> String {
+    let params = argon2::Params::new(argon2::Params::MIN_M_COST, 1, 1, None).unwrap();
+    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+    argon2.hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng)).unwrap().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::password::{hash, verify, weak_hash};
+
+    #[test]
+    fn password_should_be_verified_against_hash() {
+        let hashed = hash("secret").unwrap();
+        assert!(hashed.starts_with("$argon2id$"));
+        assert!(verify(&hashed, "secret"));
+        assert!(!verify(&hashed, "Secret"));
+
+        // 盐是随机的，同一个密码的哈希不同
+        assert_ne!(hashed, hash("secret").unwrap());
+        assert!(verify(&weak_hash("secret"), "secret"));
+        assert!(!verify("secret", "secret"));
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use kv_core::domain::PushMessage;
+use kv_core::error::KvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+use crate::config::PubSubConfig;
+
+/// 订阅者的发送端
+#[derive(Clone)]
+struct Subscriber {
+    tx: mpsc::Sender<PushMessage>,
+    // 队列满时通知连接断开
+    lagged: Arc<Notify>,
+}
+
+impl Subscriber {
+    fn send(&self, message: PushMessage) -> bool {
+        match self.tx.try_send(message) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                self.lagged.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// (命名空间, 频道或模式)
+type Topic = (String, String);
+
+#[derive(Default)]
+struct State {
+    // (命名空间, 频道) -> 订阅者 id -> 订阅者
+    channels: HashMap<Topic, HashMap<u64, Subscriber>>,
+    // (命名空间, 模式) -> 订阅者 id -> 订阅者
+    patterns: HashMap<Topic, HashMap<u64, Subscriber>>,
+}
+
+/// 发布订阅，消息只投递给当前节点上同一命名空间中的订阅者
+pub struct PubSub {
+    queue_size: usize,
+    next_id: AtomicU64,
+    state: RwLock<State>,
+}
+
+impl PubSub {
+    pub fn new(config: &PubSubConfig) -> Self {
+        Self {
+            queue_size: config.queue_size.max(1),
+            next_id: AtomicU64::new(0),
+            state: RwLock::new(State::default()),
+        }
+    }
+
+    /// 创建一个订阅，连接关闭时 drop 订阅即可取消所有频道
+    pub fn subscription(self: &Arc<Self>) -> Subscription {
+        let (tx, rx) = mpsc::channel(self.queue_size);
+        Subscription {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            pubsub: self.clone(),
+            subscriber: Subscriber { tx, lagged: Arc::new(Notify::new()) },
+            rx,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    /// 在命名空间 ns 中发布消息，返回收到消息的订阅者数量
+    pub fn publish(&self, ns: &str, channel: &str, payload: &str) -> usize {
+        let state = self.state.read().unwrap();
+        let mut received = 0;
+
+        if let Some(subscribers) = state.channels.get(&(ns.to_string(), channel.to_string())) {
+            for subscriber in subscribers.values() {
+                let message = PushMessage { channel: channel.to_string(), pattern: None, payload: payload.to_string() };
+                if subscriber.send(message) {
+                    received += 1;
+                }
+            }
+        }
+
+        let matched = state.patterns.iter().filter(|((n, p), _)| n == ns && glob_match(p, channel));
+        for ((_, pattern), subscribers) in matched {
+            for subscriber in subscribers.values() {
+                let message = PushMessage {
+                    channel: channel.to_string(),
+                    pattern: Some(pattern.clone()),
+                    payload: payload.to_string(),
+                };
+                if subscriber.send(message) {
+                    received += 1;
+                }
+            }
+        }
+
+        received
+    }
+}
+
+/// 一个连接上的所有订阅
+pub struct Subscription {
+    id: u64,
+    pubsub: Arc<PubSub>,
+    subscriber: Subscriber,
+    rx: mpsc::Receiver<PushMessage>,
+    channels: HashSet<Topic>,
+    patterns: HashSet<Topic>,
+}
+
+impl Subscription {
+    /// 订阅命名空间 ns 中的频道
+    pub fn subscribe(&mut self, ns: &str, channels: &[String]) {
+        let mut state = self.pubsub.state.write().unwrap();
+        for channel in channels {
+            let topic = (ns.to_string(), channel.clone());
+            if self.channels.insert(topic.clone()) {
+                state.channels.entry(topic).or_default().insert(self.id, self.subscriber.clone());
+            }
+        }
+    }
+
+    /// 订阅命名空间 ns 中匹配模式的频道
+    pub fn psubscribe(&mut self, ns: &str, patterns: &[String]) {
+        let mut state = self.pubsub.state.write().unwrap();
+        for pattern in patterns {
+            let topic = (ns.to_string(), pattern.clone());
+            if self.patterns.insert(topic.clone()) {
+                state.patterns.entry(topic).or_default().insert(self.id, self.subscriber.clone());
+            }
+        }
+    }
+
+    /// 等待下一条消息，积压的消息超过队列大小时返回 SlowSubscriber
+    pub async fn recv(&mut self) -> Result<PushMessage, KvError> {
+        tokio::select! {
+            biased;
+            _ = self.subscriber.lagged.notified() => Err(KvError::SlowSubscriber(self.pubsub.queue_size)),
+            // 订阅自己持有发送端，队列不会被关闭
+            message = self.rx.recv() => message.ok_or_else(|| KvError::Internal(String::from("Subscription closed."))),
+        }
+    }
+
+    /// 取出已经到达的消息，不等待
+    pub fn try_recv(&mut self) -> Option<PushMessage> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.pubsub.state.write().unwrap();
+        for channel in &self.channels {
+            remove(&mut state.channels, channel, self.id);
+        }
+        for pattern in &self.patterns {
+            remove(&mut state.patterns, pattern, self.id);
+        }
+    }
+}
+
+fn remove(map: &mut HashMap<Topic, HashMap<u64, Subscriber>>, topic: &Topic, id: u64) {
+    if let Some(subscribers) = map.get_mut(topic) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            map.remove(topic);
+        }
+    }
+}
+
+/// 通配符匹配，`*` 匹配任意个字符，`?` 匹配一个字符
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // 最近一个 `*` 的位置，以及它当前匹配到的文本位置
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            // 回溯，让 `*` 多匹配一个字符
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use kv_core::domain::PushMessage;
+    use kv_core::error::KvError;
+
+    use crate::config::PubSubConfig;
+    use crate::pubsub::{glob_match, PubSub};
+
+    fn channels(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn glob_should_match() {
+        assert!(glob_match("news.*", "news.sport"));
+        assert!(glob_match("news.*", "news."));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(!glob_match("news.*", "weather.today"));
+        assert!(!glob_match("h?llo", "hllo"));
+    }
+
+    #[tokio::test]
+    async fn message_should_be_delivered_to_subscribers() {
+        let pubsub = Arc::new(PubSub::new(&PubSubConfig::default()));
+        let mut first = pubsub.subscription();
+        let mut second = pubsub.subscription();
+        first.subscribe("default", &channels(&["news.sport"]));
+        second.psubscribe("default", &channels(&["news.*"]));
+
+        assert_eq!(2, pubsub.publish("default", "news.sport", "goal"));
+        assert_eq!(1, pubsub.publish("default", "news.weather", "sunny"));
+        assert_eq!(0, pubsub.publish("default", "other", "ignored"));
+        // 其他命名空间中的同名频道互不影响
+        assert_eq!(0, pubsub.publish("team-a", "news.sport", "hidden"));
+
+        let message = PushMessage { channel: String::from("news.sport"), pattern: None, payload: String::from("goal") };
+        assert_eq!(Ok(message), first.recv().await);
+        assert!(first.try_recv().is_none());
+
+        assert_eq!(Some(String::from("news.*")), second.recv().await.unwrap().pattern);
+        assert_eq!("sunny", second.recv().await.unwrap().payload);
+    }
+
+    #[tokio::test]
+    async fn dropped_subscription_should_be_removed() {
+        let pubsub = Arc::new(PubSub::new(&PubSubConfig::default()));
+        let mut subscription = pubsub.subscription();
+        subscription.subscribe("default", &channels(&["c1", "c1"]));
+        subscription.psubscribe("default", &channels(&["c*"]));
+        assert_eq!(2, pubsub.publish("default", "c1", "m1"));
+
+        drop(subscription);
+        assert_eq!(0, pubsub.publish("default", "c1", "m2"));
+        assert!(pubsub.state.read().unwrap().channels.is_empty());
+        assert!(pubsub.state.read().unwrap().patterns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_be_reported() {
+        let pubsub = Arc::new(PubSub::new(&PubSubConfig { queue_size: 2 }));
+        let mut subscription = pubsub.subscription();
+        subscription.subscribe("default", &channels(&["c1"]));
+
+        assert_eq!(1, pubsub.publish("default", "c1", "m1"));
+        assert_eq!(1, pubsub.publish("default", "c1", "m2"));
+        assert_eq!(0, pubsub.publish("default", "c1", "m3"));
+
+        assert_eq!(Err(KvError::SlowSubscriber(2)), subscription.recv().await);
+    }
+}
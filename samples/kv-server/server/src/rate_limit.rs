@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use kv_core::error::KvError;
+
+use crate::config::{Quota, RateLimitConfig};
+
+/// 每种维度最多跟踪的客户端数量，超过后清理长时间未使用的令牌桶
+const MAX_TRACKED: usize = 10_000;
+const IDLE_EXPIRE: Duration = Duration::from_secs(60);
+
+/// 令牌桶，容量为一秒的速率
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { rate, tokens: rate, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// 令牌不足时返回需要等待的时间。超过容量的请求按容量计算，避免永远无法通过
+    fn wait_time(&self, n: f64) -> Option<Duration> {
+        let n = n.min(self.rate);
+        if self.tokens >= n {
+            return None;
+        }
+        Some(Duration::from_secs_f64((n - self.tokens) / self.rate))
+    }
+
+    fn take(&mut self, n: f64) {
+        self.tokens -= n.min(self.rate);
+    }
+}
+
+/// 一个维度上的请求数和字节数限制
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(quota: &Quota, now: Instant) -> Self {
+        Self {
+            requests: quota.requests_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            bytes: quota.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    fn wait_time(&mut self, bytes: usize, now: Instant) -> Option<Duration> {
+        let requests = self.requests.as_mut().and_then(|b| {
+            b.refill(now);
+            b.wait_time(1.0)
+        });
+        let bytes = self.bytes.as_mut().and_then(|b| {
+            b.refill(now);
+            b.wait_time(bytes as f64)
+        });
+        requests.max(bytes)
+    }
+
+    fn take(&mut self, bytes: usize) {
+        if let Some(b) = self.requests.as_mut() {
+            b.take(1.0);
+        }
+        if let Some(b) = self.bytes.as_mut() {
+            b.take(bytes as f64);
+        }
+    }
+
+    fn idle(&self, now: Instant) -> bool {
+        [&self.requests, &self.bytes].into_iter()
+            .flatten()
+            .all(|b| now.saturating_duration_since(b.last) > IDLE_EXPIRE)
+    }
+}
+
+/// 按客户端分组的令牌桶
+struct Group<K> {
+    quota: Quota,
+    buckets: HashMap<K, Buckets>,
+}
+
+impl<K: Eq + Hash> Group<K> {
+    fn get(&mut self, key: K, now: Instant) -> &mut Buckets {
+        if self.buckets.len() >= MAX_TRACKED {
+            self.buckets.retain(|_, b| !b.idle(now));
+        }
+
+        let quota = &self.quota;
+        self.buckets.entry(key).or_insert_with(|| Buckets::new(quota, now))
+    }
+}
+
+struct State {
+    global: Option<Buckets>,
+    per_ip: Option<Group<IpAddr>>,
+    per_user: Option<Group<String>>,
+}
+
+/// 全局、按客户端 IP、按认证用户三个维度的限流
+///
+/// 请求需要同时满足所有维度的限制，任何一个维度不满足时都不消耗令牌。
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        let state = State {
+            global: config.global.as_ref().map(|q| Buckets::new(q, now)),
+            per_ip: config.per_ip.clone().map(|quota| Group { quota, buckets: HashMap::new() }),
+            per_user: config.per_user.clone().map(|quota| Group { quota, buckets: HashMap::new() }),
+        };
+
+        Self { state: Mutex::new(state) }
+    }
+
+    /// 检查并消耗令牌，被限流时返回 Throttled 及建议的重试等待时间
+    pub fn check(&self, ip: IpAddr, user: Option<&str>, bytes: usize) -> Result<(), KvError> {
+        self.check_at(ip, user, bytes, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, user: Option<&str>, bytes: usize, now: Instant) -> Result<(), KvError> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        let mut scopes: Vec<&mut Buckets> = vec![];
+        if let Some(global) = state.global.as_mut() {
+            scopes.push(global);
+        }
+        if let Some(group) = state.per_ip.as_mut() {
+            scopes.push(group.get(ip, now));
+        }
+        if let (Some(group), Some(user)) = (state.per_user.as_mut(), user) {
+            scopes.push(group.get(user.to_string(), now));
+        }
+
+        let wait = scopes.iter_mut()
+            .filter_map(|b| b.wait_time(bytes, now))
+            .max();
+
+        if let Some(wait) = wait {
+            // 向上取整，避免客户端按提示重试时仍然被限流
+            let retry_after_ms = wait.as_micros().div_ceil(1000) as u64;
+            return Err(KvError::Throttled(retry_after_ms));
+        }
+
+        for bucket in scopes {
+            bucket.take(bytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use kv_core::error::KvError;
+
+    use crate::config::{Quota, RateLimitConfig};
+    use crate::rate_limit::RateLimiter;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn quota(requests: f64) -> Option<Quota> {
+        Some(Quota { requests_per_sec: Some(requests), bytes_per_sec: None })
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        for _ in 0..10_000 {
+            assert!(limiter.check(ip("127.0.0.1"), None, 1024).is_ok());
+        }
+    }
+
+    #[test]
+    fn requests_should_be_limited_per_ip() {
+        let limiter = RateLimiter::new(&RateLimitConfig { per_ip: quota(10.0), ..Default::default() });
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.check_at(ip("10.0.0.1"), None, 0, now).is_ok());
+        }
+        assert_eq!(Err(KvError::Throttled(100)), limiter.check_at(ip("10.0.0.1"), None, 0, now));
+
+        // 其他客户端不受影响
+        assert!(limiter.check_at(ip("10.0.0.2"), None, 0, now).is_ok());
+
+        // 100ms 后补充一个令牌
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.check_at(ip("10.0.0.1"), None, 0, later).is_ok());
+        assert!(limiter.check_at(ip("10.0.0.1"), None, 0, later).is_err());
+    }
+
+    #[test]
+    fn bytes_should_be_limited() {
+        let config = RateLimitConfig {
+            global: Some(Quota { requests_per_sec: None, bytes_per_sec: Some(1000.0) }),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(&config);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(ip("10.0.0.1"), None, 600, now).is_ok());
+        assert_eq!(Err(KvError::Throttled(200)), limiter.check_at(ip("10.0.0.2"), None, 600, now));
+
+        // 超过容量的请求在令牌桶满时可以通过
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(ip("10.0.0.1"), None, 5000, later).is_ok());
+    }
+
+    #[test]
+    fn users_should_be_limited_separately() {
+        let config = RateLimitConfig { per_ip: quota(100.0), per_user: quota(2.0), ..Default::default() };
+        let limiter = RateLimiter::new(&config);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(ip("10.0.0.1"), Some("alice"), 0, now).is_ok());
+        assert!(limiter.check_at(ip("10.0.0.2"), Some("alice"), 0, now).is_ok());
+        assert!(limiter.check_at(ip("10.0.0.3"), Some("alice"), 0, now).is_err());
+        assert!(limiter.check_at(ip("10.0.0.3"), Some("bob"), 0, now).is_ok());
+
+        // 未认证的连接只受 IP 限制
+        assert!(limiter.check_at(ip("10.0.0.3"), None, 0, now).is_ok());
+    }
+
+    #[test]
+    fn rejected_request_should_not_consume_tokens() {
+        let config = RateLimitConfig { global: quota(100.0), per_ip: quota(1.0), ..Default::default() };
+        let limiter = RateLimiter::new(&config);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(ip("10.0.0.1"), None, 0, now).is_ok());
+        for _ in 0..200 {
+            assert!(limiter.check_at(ip("10.0.0.1"), None, 0, now).is_err());
+        }
+
+        // 全局令牌没有被被拒绝的请求消耗
+        for i in 0..99 {
+            assert!(limiter.check_at(ip(&format!("10.0.1.{i}")), None, 0, now).is_ok());
+        }
+    }
+}
@@ -1,19 +1,64 @@
-use kv_core::domain::Request::{Del, Get, MGet, MSet, Set};
+use kv_core::domain::Request::{
+    AddNode, Auth, Del, Dump, Eval, EvalSha, FlushDb, Get, Hello, Info, MGet, MSet, PSubscribe, Publish, RemoveNode, Scan, Select,
+    Set, SlowLog, Subscribe,
+};
 use kv_core::domain::{Request, Response, KV};
+use kv_core::error::KvError;
+use tracing::{info_span, instrument};
 
 use crate::storage::Storage;
 
+/// Scan 每次最多返回的 key 数量
+pub const MAX_SCAN_COUNT: usize = 10_000;
+
 /// process request
-pub fn handle(request: Request, storage: &impl Storage) -> Response {
-    let res = match request {
-        Get { key } => storage.get(&key),
-        MGet { keys } => storage.mget(&keys),
-        Set { kv: KV { key, value, } } => storage.set(key, value),
-        MSet { kvs } => storage.mset(kvs),
-        Del { keys } => storage.del(&keys),
-    };
-
-    Response::from(res)
+///
+/// 返回 KvError 而不是直接转换为 Response，调用方可以按错误类型统计。ns 为连接当前的命名空间
+#[instrument(name = "request_handler::handle", skip_all, fields(command = request.name(), ns))]
+pub fn handle(request: Request, ns: &str, storage: &impl Storage) -> Result<Response, KvError> {
+    // 存储调用单独记录 span，区分存储引擎与其他环节的耗时
+    let engine = storage.name();
+
+    let values = match request {
+        Get { key } => info_span!("storage::get", engine).in_scope(|| storage.get(ns, &key)),
+        MGet { keys } => info_span!("storage::mget", engine).in_scope(|| storage.mget(ns, &keys)),
+        Set { kv: KV { key, value, } } => info_span!("storage::set", engine).in_scope(|| storage.set(ns, key, value)),
+        MSet { kvs } => info_span!("storage::mset", engine).in_scope(|| storage.mset(ns, kvs)),
+        Del { keys } => info_span!("storage::del", engine).in_scope(|| storage.del(ns, &keys)),
+        Scan { after, count } => {
+            info_span!("storage::scan", engine).in_scope(|| storage.scan(ns, after.as_deref(), count.min(MAX_SCAN_COUNT)))
+        }
+        FlushDb => info_span!("storage::flush", engine).in_scope(|| storage.flush(ns)),
+        // 集群管理命令只能在集群模式下处理
+        AddNode { .. } | RemoveNode { .. } => Err(KvError::InvalidCommand),
+        // 连接相关的命令和运维命令由 SharedServer 处理
+        Auth { .. } | Hello { .. } | Select { .. } | Info | SlowLog { .. } | Dump => Err(KvError::InvalidCommand),
+        Publish { .. } | Subscribe { .. } | PSubscribe { .. } => Err(KvError::InvalidCommand),
+        // 脚本需要缓存和执行限制，由 SharedServer 处理
+        Eval { .. } | EvalSha { .. } => Err(KvError::InvalidCommand),
+    }?;
+
+    Ok(Response::from(values))
 }
 
+#[cfg(test)]
+mod tests {
+    use kv_core::domain::{Request, KV};
+
+    use crate::request_handler::{handle, MAX_SCAN_COUNT};
+    use crate::storage::memory::Memory;
 
+    #[test]
+    fn scan_should_be_limited() {
+        let storage = Memory::new();
+        let kvs = (0..MAX_SCAN_COUNT + 10).map(|i| KV { key: format!("k{i:05}"), value: String::from("v") }).collect();
+        handle(Request::MSet { kvs }, "default", &storage).unwrap();
+
+        let res = handle(Request::Scan { after: None, count: usize::MAX }, "default", &storage).unwrap();
+        assert_eq!(MAX_SCAN_COUNT, res.values.len());
+
+        let after = res.values.last().cloned();
+        let res = handle(Request::Scan { after, count: usize::MAX }, "default", &storage).unwrap();
+        assert_eq!(10, res.values.len());
+    }
+}
@@ -0,0 +1,332 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use kv_core::domain::KV;
+use kv_core::error::KvError;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Value};
+use sha1::{Digest, Sha1};
+
+use crate::config::ScriptConfig;
+use crate::storage::Storage;
+
+/// 每执行多少条指令检查一次限制
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Lua 脚本的缓存和执行
+///
+/// 每次执行都使用新的 Lua 虚拟机，只开放 table、string、math 标准库，没有 io、os 等访问外部环境的能力。
+/// 脚本中的写操作先保存在缓冲区中，执行成功后才写入存储，出错时不产生任何修改。
+pub struct Scripts {
+    config: ScriptConfig,
+    cache: Mutex<Cache>,
+}
+
+/// 缓存满时淘汰最久没有使用的脚本，客户端收到 NotFound 后可以重新用 Eval 执行
+#[derive(Default)]
+struct Cache {
+    tick: u64,
+    /// sha1 -> (最近使用的序号, 脚本)
+    scripts: HashMap<String, (u64, String)>,
+    /// 最近使用的序号 -> sha1，第一个是最久没有使用的
+    order: BTreeMap<u64, String>,
+}
+
+impl Cache {
+    /// 更新最近使用的时间
+    fn touch(&mut self, sha: &str) -> Option<String> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (last, script) = self.scripts.get_mut(sha)?;
+        let sha = self.order.remove(last).unwrap();
+        self.order.insert(tick, sha);
+        *last = tick;
+        Some(script.clone())
+    }
+
+    fn insert(&mut self, sha: String, script: String, capacity: usize) {
+        if self.touch(&sha).is_some() {
+            return;
+        }
+        while self.scripts.len() >= capacity.max(1) {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.scripts.remove(&oldest);
+        }
+        self.order.insert(self.tick, sha.clone());
+        self.scripts.insert(sha, (self.tick, script));
+    }
+}
+
+impl Scripts {
+    pub fn new(config: &ScriptConfig) -> Self {
+        Self {
+            config: config.clone(),
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// 缓存脚本，返回脚本的 sha1
+    pub fn load(&self, script: &str) -> String {
+        let sha = sha1_hex(script);
+        self.cache.lock().unwrap().insert(sha.clone(), script.to_string(), self.config.max_cached);
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Result<String, KvError> {
+        self.cache.lock().unwrap()
+            .touch(&sha.to_ascii_lowercase())
+            .ok_or_else(|| KvError::NotFound(format!("script {sha}")))
+    }
+
+    /// 执行脚本，返回值转换为字符串列表
+    ///
+    /// `timeout` 为 false 时只限制指令数和内存。集群模式下各节点应用同一个脚本，
+    /// 需要得到相同的结果，不能使用与机器快慢有关的时间限制。
+    pub fn eval(&self, script: &str, ns: &str, keys: Vec<String>, args: Vec<String>, storage: &impl Storage, timeout: bool)
+        -> Result<Vec<String>, KvError> {
+        let deadline = timeout.then(|| Instant::now() + Duration::from_millis(self.config.timeout_ms));
+        let overlay = Overlay { ns, storage, writes: RefCell::new(BTreeMap::new()) };
+
+        let values = self.run(script, keys, args, &overlay, deadline)
+            .map_err(|e| KvError::ScriptError(e.to_string()))?;
+
+        overlay.commit()?;
+        Ok(values)
+    }
+
+    fn run<S: Storage>(
+        &self,
+        script: &str,
+        keys: Vec<String>,
+        args: Vec<String>,
+        overlay: &Overlay<S>,
+        deadline: Option<Instant>,
+    ) -> mlua::Result<Vec<String>> {
+        let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())?;
+        lua.set_memory_limit(self.config.memory_bytes)?;
+
+        // 随机数在各节点上结果不同
+        let math: mlua::Table = lua.globals().get("math")?;
+        math.set("random", Value::Nil)?;
+        math.set("randomseed", Value::Nil)?;
+
+        let max_instructions = self.config.max_instructions;
+        let executed = Cell::new(0u64);
+        lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |_, _| {
+            executed.set(executed.get() + HOOK_INTERVAL as u64);
+            if executed.get() > max_instructions {
+                return Err(mlua::Error::runtime("Script exceeded instruction limit."));
+            }
+            if deadline.is_some_and(|d| Instant::now() > d) {
+                return Err(mlua::Error::runtime("Script exceeded time limit."));
+            }
+            Ok(())
+        });
+
+        lua.globals().set("KEYS", keys)?;
+        lua.globals().set("ARGV", args)?;
+
+        lua.scope(|scope| {
+            let kv = lua.create_table()?;
+            kv.set("get", scope.create_function(|_, key: String| overlay.get(&key).map_err(mlua::Error::external))?)?;
+            kv.set("set", scope.create_function(|_, (key, value): (String, String)| {
+                overlay.set(key, value);
+                Ok(())
+            })?)?;
+            kv.set("del", scope.create_function(|_, key: String| overlay.del(key).map_err(mlua::Error::external))?)?;
+            lua.globals().set("kv", kv)?;
+
+            let value: Value = lua.load(script).set_name("script").eval()?;
+            to_values(value)
+        })
+    }
+}
+
+/// 脚本执行期间的写缓冲区，读取时先看本次脚本中的写入
+struct Overlay<'a, S> {
+    ns: &'a str,
+    storage: &'a S,
+    // key -> 写入的值，None 表示删除
+    writes: RefCell<BTreeMap<String, Option<String>>>,
+}
+
+impl<S: Storage> Overlay<'_, S> {
+    fn get(&self, key: &str) -> Result<Option<String>, KvError> {
+        if let Some(value) = self.writes.borrow().get(key) {
+            return Ok(value.clone());
+        }
+        Ok(self.storage.get(self.ns, key)?.into_iter().next())
+    }
+
+    fn set(&self, key: String, value: String) {
+        self.writes.borrow_mut().insert(key, Some(value));
+    }
+
+    /// 返回 key 删除前是否存在
+    fn del(&self, key: String) -> Result<bool, KvError> {
+        let existed = self.get(&key)?.is_some();
+        self.writes.borrow_mut().insert(key, None);
+        Ok(existed)
+    }
+
+    /// 脚本执行成功后统一写入
+    fn commit(self) -> Result<(), KvError> {
+        let mut sets = vec![];
+        let mut dels = vec![];
+        for (key, value) in self.writes.into_inner() {
+            match value {
+                Some(value) => sets.push(KV { key, value }),
+                None => dels.push(key),
+            }
+        }
+        if !sets.is_empty() {
+            self.storage.mset(self.ns, sets)?;
+        }
+        if !dels.is_empty() {
+            self.storage.del(self.ns, &dels)?;
+        }
+        Ok(())
+    }
+}
+
+/// 脚本返回值：nil 为空，数组按顺序转换，其他值转换为一个字符串
+fn to_values(value: Value) -> mlua::Result<Vec<String>> {
+    match value {
+        Value::Nil => Ok(vec![]),
+        Value::Table(table) => table.sequence_values::<Value>().map(|v| v.and_then(to_string)).collect(),
+        value => Ok(vec![to_string(value)?]),
+    }
+}
+
+fn to_string(value: Value) -> mlua::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.to_str()?.to_string()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        value => Err(mlua::Error::runtime(format!("Unsupported return value: {}", value.type_name()))),
+    }
+}
+
+fn sha1_hex(script: &str) -> String {
+    hex::encode(Sha1::digest(script.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use kv_core::error::KvError;
+
+    use crate::config::ScriptConfig;
+    use crate::script::Scripts;
+    use crate::storage::memory::Memory;
+    use crate::storage::Storage;
+
+    const NS: &str = "default";
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn eval(scripts: &Scripts, script: &str, keys: &[&str], args: &[&str], storage: &Memory) -> Result<Vec<String>, KvError> {
+        scripts.eval(script, NS, strings(keys), strings(args), storage, true)
+    }
+
+    #[test]
+    fn script_should_read_and_write() {
+        let scripts = Scripts::new(&ScriptConfig::default());
+        let storage = Memory::new();
+        storage.set(NS, String::from("counter"), String::from("41")).unwrap();
+
+        let script = r#"
+            local n = tonumber(kv.get(KEYS[1])) + tonumber(ARGV[1])
+            kv.set(KEYS[1], tostring(n))
+            return kv.get(KEYS[1])
+        "#;
+        assert_eq!(Ok(strings(&["42"])), eval(&scripts, script, &["counter"], &["1"], &storage));
+        assert_eq!(Ok(strings(&["42"])), storage.get(NS, "counter"));
+
+        let script = "local old = kv.get(KEYS[1]); kv.del(KEYS[1]); return {old, kv.get(KEYS[1]) == nil}";
+        assert_eq!(Ok(strings(&["42", "true"])), eval(&scripts, script, &["counter"], &[], &storage));
+        assert_eq!(Ok(vec![]), storage.get(NS, "counter"));
+    }
+
+    #[test]
+    fn failed_script_should_not_write() {
+        let scripts = Scripts::new(&ScriptConfig::default());
+        let storage = Memory::new();
+
+        let script = "kv.set('k1', 'v1'); error('boom')";
+        let res = eval(&scripts, script, &[], &[], &storage);
+        assert!(matches!(res, Err(KvError::ScriptError(msg)) if msg.contains("boom")));
+        assert_eq!(Ok(vec![]), storage.get(NS, "k1"));
+    }
+
+    #[test]
+    fn script_should_be_sandboxed() {
+        let scripts = Scripts::new(&ScriptConfig::default());
+        let storage = Memory::new();
+
+        for script in ["return os.time()", "return io.open('/etc/passwd')", "return math.random()", "require('os')"] {
+            assert!(matches!(eval(&scripts, script, &[], &[], &storage), Err(KvError::ScriptError(_))), "{script}");
+        }
+    }
+
+    #[test]
+    fn endless_script_should_be_stopped() {
+        let config = ScriptConfig { max_instructions: 100_000, ..Default::default() };
+        let scripts = Scripts::new(&config);
+        let storage = Memory::new();
+
+        let res = eval(&scripts, "kv.set('k1', 'v1'); while true do end", &[], &[], &storage);
+        assert!(matches!(res, Err(KvError::ScriptError(msg)) if msg.contains("instruction limit")));
+        assert_eq!(Ok(vec![]), storage.get(NS, "k1"));
+
+        let config = ScriptConfig { timeout_ms: 10, max_instructions: u64::MAX, ..Default::default() };
+        let scripts = Scripts::new(&config);
+        let res = eval(&scripts, "while true do end", &[], &[], &storage);
+        assert!(matches!(res, Err(KvError::ScriptError(msg)) if msg.contains("time limit")));
+    }
+
+    #[test]
+    fn memory_should_be_limited() {
+        let config = ScriptConfig { memory_bytes: 1024 * 1024, max_instructions: u64::MAX, ..Default::default() };
+        let scripts = Scripts::new(&config);
+        let storage = Memory::new();
+
+        let res = eval(&scripts, "local t = {} for i = 1, 1e7 do t[i] = i end", &[], &[], &storage);
+        assert!(matches!(res, Err(KvError::ScriptError(msg)) if msg.contains("memory")));
+    }
+
+    #[test]
+    fn script_should_be_cached_by_sha() {
+        let config = ScriptConfig { max_cached: 1, ..Default::default() };
+        let scripts = Scripts::new(&config);
+
+        let sha = scripts.load("return 1");
+        assert_eq!("e0e1f9fabfc9d4800c877a703b823ac0578ff8db", sha);
+        assert_eq!(Ok(String::from("return 1")), scripts.get(&sha.to_uppercase()));
+
+        // 超过缓存数量后淘汰旧的脚本
+        scripts.load("return 2");
+        assert!(matches!(scripts.get(&sha), Err(KvError::NotFound(_))));
+    }
+
+    #[test]
+    fn least_recently_used_script_should_be_evicted() {
+        let config = ScriptConfig { max_cached: 2, ..Default::default() };
+        let scripts = Scripts::new(&config);
+
+        let first = scripts.load("return 1");
+        let second = scripts.load("return 2");
+        // 使用过的脚本不会被淘汰
+        scripts.get(&first).unwrap();
+        let third = scripts.load("return 3");
+
+        assert!(scripts.get(&first).is_ok());
+        assert!(matches!(scripts.get(&second), Err(KvError::NotFound(_))));
+        assert!(scripts.get(&third).is_ok());
+    }
+}
@@ -1 +1,116 @@
+use bytes::BytesMut;
+use kv_core::codec::{Codec, Message};
+use kv_core::error::KvError;
+use kv_core::frame;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
+/// 连接上响应的编码格式和压缩阈值
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Format {
+    pub codec: Codec,
+    /// 为 None 时不压缩
+    pub compression: Option<usize>,
+}
+
+/// 从缓冲区中解析出一个完整的消息，数据不足时返回 Ok(None)
+pub fn decode<T: DeserializeOwned>(buf: &mut BytesMut, max_frame: usize) -> Result<Option<T>, KvError> {
+    match frame::decode(buf, max_frame)? {
+        Some(payload) => serde_json::from_slice(&payload)
+            .map(Some)
+            .map_err(|_| KvError::InvalidCommand),
+        None => Ok(None),
+    }
+}
+
+/// 同 decode，按帧头中的标志使用 JSON 或 protobuf 解析，同时返回 payload 的编码格式
+pub fn decode_message<T: Message>(buf: &mut BytesMut, max_frame: usize) -> Result<Option<(Codec, T)>, KvError> {
+    match frame::decode_with(buf, max_frame)? {
+        Some((codec, payload)) => codec.decode(&payload).map(|value| Some((codec, value))),
+        None => Ok(None),
+    }
+}
+
+/// 将消息序列化为帧写入缓冲区
+pub fn encode<T: Serialize>(value: &T, buf: &mut BytesMut) -> Result<(), KvError> {
+    let payload = serde_json::to_vec(value)
+        .map_err(|e| KvError::Internal(e.to_string()))?;
+    frame::encode(&payload, buf);
+    Ok(())
+}
+
+/// 同 encode，按 format 选择编码格式，压缩不小于阈值的消息，返回压缩前后 payload 的长度
+pub fn encode_compressed<T: Message>(value: &T, format: Format, buf: &mut BytesMut) -> Result<(usize, usize), KvError> {
+    let mut payload = vec![];
+    format.codec.encode(value, &mut payload)?;
+    let len = frame::encode_with(&payload, format.codec, format.compression, buf);
+    Ok((payload.len(), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use kv_core::codec::Codec;
+    use kv_core::domain::{Envelope, Request, KV};
+    use kv_core::error::KvError;
+    use kv_core::frame;
+
+    use crate::serializer::{decode, decode_message, encode, encode_compressed, Format};
+
+    #[test]
+    fn request_round_trip() {
+        let req = Request::Set { kv: KV { key: String::from("k1"), value: String::from("v1") } };
+
+        let mut buf = BytesMut::new();
+        encode(&req, &mut buf).unwrap();
+
+        assert_eq!(Ok(Some(req)), decode::<Request>(&mut buf, frame::MAX_FRAME_SIZE));
+        assert_eq!(Ok(None), decode::<Request>(&mut buf, frame::MAX_FRAME_SIZE));
+    }
+
+    #[test]
+    fn invalid_payload_should_be_consumed() {
+        let mut buf = BytesMut::new();
+        frame::encode(b"not json", &mut buf);
+        encode(&Request::Get { key: String::from("k1") }, &mut buf).unwrap();
+
+        assert_eq!(Err(KvError::InvalidCommand), decode::<Request>(&mut buf, frame::MAX_FRAME_SIZE));
+        assert_eq!(Ok(Some(Request::Get { key: String::from("k1") })), decode::<Request>(&mut buf, frame::MAX_FRAME_SIZE));
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let req = Request::Set { kv: KV { key: String::from("k1"), value: "v".repeat(4096) } };
+
+        let mut buf = BytesMut::new();
+        let (raw, compressed) = encode_compressed(&req, Format { codec: Codec::Json, compression: Some(1024) }, &mut buf).unwrap();
+        assert!(compressed < raw);
+        assert_eq!(compressed + frame::HEADER_LEN, buf.len());
+        assert_eq!(Ok(Some(req)), decode::<Request>(&mut buf, frame::MAX_FRAME_SIZE));
+    }
+
+    #[test]
+    fn codec_should_follow_frame() {
+        let envelope = Envelope::from(Request::Set { kv: KV { key: String::from("k1"), value: "v".repeat(4096) } });
+
+        let mut buf = BytesMut::new();
+        for format in [
+            Format { codec: Codec::Protobuf, compression: None },
+            Format { codec: Codec::Protobuf, compression: Some(1024) },
+            Format { codec: Codec::Json, compression: None },
+        ] {
+            encode_compressed(&envelope, format, &mut buf).unwrap();
+        }
+
+        for codec in [Codec::Protobuf, Codec::Protobuf, Codec::Json] {
+            assert_eq!(Ok(Some((codec, envelope.clone()))), decode_message::<Envelope>(&mut buf, frame::MAX_FRAME_SIZE));
+        }
+        assert_eq!(Ok(None), decode_message::<Envelope>(&mut buf, frame::MAX_FRAME_SIZE));
+
+        // 格式错误的帧已被取出，不影响后续的帧
+        frame::encode_with(b"\xff\xff", Codec::Protobuf, None, &mut buf);
+        encode_compressed(&envelope, Format::default(), &mut buf).unwrap();
+        assert_eq!(Err(KvError::InvalidCommand), decode_message::<Envelope>(&mut buf, frame::MAX_FRAME_SIZE));
+        assert_eq!(Ok(Some((Codec::Json, envelope))), decode_message::<Envelope>(&mut buf, frame::MAX_FRAME_SIZE));
+    }
+}
@@ -0,0 +1,58 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use kv_core::codec::Codec;
+use kv_core::domain::{Response, DEFAULT_NAMESPACE};
+
+use crate::dump::DumpStream;
+use crate::pubsub::Subscription;
+use crate::serializer::Format;
+
+/// 单个客户端连接的状态
+pub struct Session {
+    pub addr: SocketAddr,
+    /// 通过 Auth 命令认证的用户
+    pub user: Option<String>,
+    /// 通过 Select 命令选择的命名空间
+    pub db: String,
+    /// 订阅了频道后连接进入推送模式
+    pub subscription: Option<Subscription>,
+    /// 还没有返回的导出数据
+    pub dump: Option<DumpStream>,
+    /// 通过 Hello 命令协商的压缩阈值，为 None 时不压缩响应
+    pub compression: Option<usize>,
+    /// 客户端最近一次请求的编码格式，响应使用相同的格式
+    pub codec: Codec,
+}
+
+impl Session {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            user: None,
+            db: DEFAULT_NAMESPACE.to_string(),
+            subscription: None,
+            dump: None,
+            compression: None,
+            codec: Codec::Json,
+        }
+    }
+
+    /// 写回响应使用的编码格式和压缩阈值
+    pub(crate) fn format(&self) -> Format {
+        Format { codec: self.codec, compression: self.compression }
+    }
+
+    /// 进程内调用使用的会话，地址为 127.0.0.1:0
+    pub fn local() -> Self {
+        Self::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    /// 取出分段返回的结果中还没有返回的部分，全部取出后为 None
+    pub fn next_chunk(&mut self) -> Option<Response> {
+        let chunk = self.dump.as_mut()?.next_chunk();
+        if chunk.is_none() {
+            self.dump = None;
+        }
+        chunk
+    }
+}
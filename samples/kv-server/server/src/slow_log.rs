@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use kv_core::domain::{Request, SlowLogEntry};
+
+use crate::config::SlowLogConfig;
+
+/// 摘要中最多保留的 key 数量
+const MAX_SUMMARY_KEYS: usize = 8;
+
+/// 慢请求日志，保存在固定大小的环形缓冲区中
+pub struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+impl SlowLog {
+    pub fn new(config: &SlowLogConfig) -> Self {
+        Self {
+            threshold: Duration::from_micros(config.threshold_us),
+            max_len: config.max_len,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_len > 0
+    }
+
+    /// 处理时间超过阈值时记录
+    pub fn record(&self, request_id: String, summary: String, client: SocketAddr, started: SystemTime, duration: Duration) {
+        if !self.enabled() || duration < self.threshold {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        if state.entries.len() >= self.max_len {
+            state.entries.pop_front();
+        }
+        state.entries.push_back(SlowLogEntry {
+            id,
+            request_id,
+            timestamp: started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            duration_us: duration.as_micros() as u64,
+            client: client.to_string(),
+            request: summary,
+        });
+    }
+
+    /// 最近的 count 条记录，最新的在前
+    pub fn latest(&self, count: usize) -> Vec<SlowLogEntry> {
+        let state = self.state.lock().unwrap();
+        state.entries.iter().rev().take(count).cloned().collect()
+    }
+}
+
+/// 请求的摘要：命令名加上部分 key，不包含 value，避免慢日志占用过多内存
+pub fn summarize(request: &Request) -> String {
+    let keys: Vec<&str> = match request {
+        Request::Get { key } => vec![key],
+        Request::MGet { keys } | Request::Del { keys } => keys.iter().map(String::as_str).collect(),
+        Request::Eval { keys, .. } | Request::EvalSha { keys, .. } => keys.iter().map(String::as_str).collect(),
+        Request::Set { kv } => vec![&kv.key],
+        Request::MSet { kvs } => kvs.iter().map(|kv| kv.key.as_str()).collect(),
+        _ => vec![],
+    };
+
+    let mut summary = String::from(request.name());
+    for key in keys.iter().take(MAX_SUMMARY_KEYS) {
+        summary.push(' ');
+        summary.push_str(key);
+    }
+    if keys.len() > MAX_SUMMARY_KEYS {
+        summary.push_str(&format!(" ... ({} more)", keys.len() - MAX_SUMMARY_KEYS));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use kv_core::domain::{Request, KV};
+
+    use crate::config::SlowLogConfig;
+    use crate::slow_log::{summarize, SlowLog};
+
+    fn record(log: &SlowLog, request_id: &str, duration_us: u64) {
+        let client = "127.0.0.1:5000".parse().unwrap();
+        let duration = Duration::from_micros(duration_us);
+        log.record(request_id.to_string(), String::from("get k1"), client, SystemTime::now(), duration);
+    }
+
+    #[test]
+    fn only_slow_requests_should_be_recorded() {
+        let log = SlowLog::new(&SlowLogConfig { threshold_us: 1000, max_len: 10 });
+        record(&log, "fast", 999);
+        record(&log, "slow", 1000);
+
+        let entries = log.latest(10);
+        assert_eq!(1, entries.len());
+        assert_eq!("slow", entries[0].request_id);
+        assert_eq!(1000, entries[0].duration_us);
+        assert_eq!("127.0.0.1:5000", entries[0].client);
+    }
+
+    #[test]
+    fn oldest_entries_should_be_dropped() {
+        let log = SlowLog::new(&SlowLogConfig { threshold_us: 0, max_len: 3 });
+        for i in 0..5 {
+            record(&log, &format!("r{i}"), 10);
+        }
+
+        let ids: Vec<u64> = log.latest(10).iter().map(|e| e.id).collect();
+        assert_eq!(vec![4, 3, 2], ids);
+        assert_eq!(2, log.latest(2).len());
+    }
+
+    #[test]
+    fn disabled_when_max_len_is_zero() {
+        let log = SlowLog::new(&SlowLogConfig { threshold_us: 0, max_len: 0 });
+        record(&log, "r1", 10);
+        assert!(log.latest(10).is_empty());
+    }
+
+    #[test]
+    fn summary_should_not_contain_values() {
+        let kvs = (0..10).map(|i| KV { key: format!("k{i}"), value: String::from("secret") }).collect();
+        let summary = summarize(&Request::MSet { kvs });
+        assert_eq!("mset k0 k1 k2 k3 k4 k5 k6 k7 ... (2 more)", summary);
+    }
+}
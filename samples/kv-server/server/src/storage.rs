@@ -1,85 +1,206 @@
-pub(crate) mod memory;
+pub mod btree;
+#[cfg(any(test, feature = "testing"))]
+pub mod conformance;
+mod crypto;
+pub mod lsm;
+pub mod memory;
+pub mod tiered;
+#[cfg(test)]
+mod model;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
 
-use kv_core::domain::KV;
+use kv_core::domain::{CacheInfo, CompressionInfo, KV};
 use kv_core::error::KvError;
 
+/// [Cursor::read_to_end] 每批读取的条数
+const READ_BATCH: usize = 1024;
+
+/// 存储引擎的统计信息
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StorageStats {
+    /// key 的数量
+    pub keys: u64,
+    /// 各命名空间的 key 数量
+    pub namespaces: BTreeMap<String, u64>,
+    /// keys 和 namespaces 是近似值，可能大于实际的数量，但不会小于
+    pub approximate: bool,
+    /// 估算的内存占用，单位为字节
+    pub memory_bytes: u64,
+    /// 最近一次快照的时间，unix 时间戳（秒），没有持久化时为 None
+    pub last_snapshot: Option<u64>,
+    /// WAL 文件大小，没有持久化时为 None
+    pub wal_bytes: Option<u64>,
+    /// 内存缓存的命中情况，没有缓存时为 None
+    pub cache: Option<CacheInfo>,
+    /// 值的压缩情况，没有开启压缩时为 None
+    pub compression: Option<CompressionInfo>,
+}
+
+/// 存储引擎，数据按 (命名空间, key) 存储，不同命名空间的 key 互相独立
 pub trait Storage {
-    fn get(&self, key: &str) -> Result<Vec<String>, KvError>;
+    /// 存储引擎名称
+    fn name(&self) -> &'static str;
 
-    fn mget(&self, keys: &[String]) -> Result<Vec<String>, KvError>;
+    fn get(&self, ns: &str, key: &str) -> Result<Vec<String>, KvError>;
 
-    fn set(&self, key: String, value: String) -> Result<Vec<String>, KvError>;
+    fn mget(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError>;
 
-    fn mset(&self, kvs: Vec<KV>) -> Result<Vec<String>, KvError>;
+    fn set(&self, ns: &str, key: String, value: String) -> Result<Vec<String>, KvError>;
 
-    fn del(&self, keys: &[String]) -> Result<Vec<String>, KvError>;
-}
+    fn mset(&self, ns: &str, kvs: Vec<KV>) -> Result<Vec<String>, KvError>;
 
-#[cfg(test)]
-mod tests {
-    use crate::storage::{memory, Storage};
+    fn del(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError>;
 
-    fn common_operation_test(store: impl Storage) {
-        // empty
+    /// 删除命名空间中的所有 key
+    fn flush(&self, ns: &str) -> Result<Vec<String>, KvError>;
 
-        // get
-        let res = store.get("k1");
-        assert_eq!(Ok(vec![]), res);
+    /// 按字典序返回命名空间中大于 after 的最多 count 个 key，after 为 None 时从头开始
+    fn scan(&self, ns: &str, after: Option<&str>, count: usize) -> Result<Vec<String>, KvError>;
 
-        // mget
-        let res = store.mget(&[String::from("k1"), String::from("k2")]);
-        assert_eq!(Ok(vec![]), res);
+    /// 固定当前的版本，返回按 (命名空间, key) 的顺序读取所有数据的游标，用于导出
+    fn snapshot(&self) -> Result<Box<dyn Cursor>, KvError>;
 
-        // 插入单个值
+    fn stats(&self) -> StorageStats;
+}
 
-        // set
-        let res = store.set(String::from("k1"), String::from("v1"));
-        assert!(res.is_ok());
+/// 存储引擎某一时刻的版本上的游标
+///
+/// 游标固定了创建时的版本，之后的写操作对它不可见；分批读取，读取期间不持有存储引擎的锁。
+pub trait Cursor: Send {
+    /// 接下来的最多 count 条数据，按 (命名空间, key) 排序，读完后返回空
+    fn next_batch(&mut self, count: usize) -> Result<Vec<(String, KV)>, KvError>;
+
+    /// 读取剩余的所有数据
+    fn read_to_end(&mut self) -> Result<Vec<(String, KV)>, KvError> {
+        let mut entries = vec![];
+        loop {
+            let batch = self.next_batch(READ_BATCH)?;
+            if batch.is_empty() {
+                return Ok(entries);
+            }
+            entries.extend(batch);
+        }
+    }
+}
 
-        // get
-        let res = store.get("k1");
-        assert!(res.is_ok());
-        assert_eq!(vec![String::from("v1")], res.unwrap());
+/// 文件内容不符合格式
+pub(crate) fn corrupted(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
 
-        // mget
-        let res = store.mget(&[String::from("k1"), String::from("k2")]);
-        assert!(res.is_ok());
-        assert_eq!(vec![String::from("v1")], res.unwrap());
+/// 加密的数据解密失败：被篡改、损坏或者使用了未配置的密钥
+#[derive(Debug)]
+pub(crate) struct IntegrityError(String);
 
-        // 插入多个值
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-        // set
-        let res = store.set(String::from("k2"), String::from("v2"));
-        assert!(res.is_ok());
+impl std::error::Error for IntegrityError {}
 
-        // get
-        let res = store.get("k1");
-        assert!(res.is_ok());
-        assert_eq!(vec![String::from("v1")], res.unwrap());
-        let res = store.get("k2");
-        assert!(res.is_ok());
-        assert_eq!(vec![String::from("v2")], res.unwrap());
+pub(crate) fn integrity_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, IntegrityError(message))
+}
 
-        // mget
-        let res = store.mget(&[String::from("k1"), String::from("k2")]);
-        assert!(res.is_ok());
-        assert_eq!(vec![String::from("v1"), String::from("v2")], res.unwrap());
+/// 持久化引擎的 IO 错误转换为 KvError，解密失败转换为 IntegrityError
+pub(crate) fn storage_error(command: &'static str, key: &str) -> impl FnOnce(io::Error) -> KvError {
+    let key = key.to_string();
+    move |e| match e.get_ref().and_then(|inner| inner.downcast_ref::<IntegrityError>()) {
+        Some(IntegrityError(message)) => KvError::IntegrityError(message.clone()),
+        None => KvError::StorageError(command, key, e.to_string()),
+    }
+}
 
-        // 删除
-        let res = store.del(&[String::from("k1"), String::from("k2")]);
-        assert!(res.is_ok());
+#[cfg(test)]
+mod tests {
+    use kv_core::domain::KV;
+    use uuid::Uuid;
 
-        let res = store.get("k1");
-        assert_eq!(Ok(vec![]), res);
+    use crate::config::{BTreeConfig, EncryptionConfig, LsmConfig};
+    use crate::storage::{btree, conformance, lsm, memory, tiered, Storage, StorageStats};
 
-        // mget
-        let res = store.mget(&[String::from("k1"), String::from("k2")]);
-        assert_eq!(Ok(vec![]), res);
+    #[test]
+    fn test_memory_storage() {
+        conformance::run(memory::Memory::new)
     }
 
     #[test]
-    fn test_memory_storage() {
-        let store = memory::Memory::new();
-        common_operation_test(store)
+    fn empty_mset_should_not_create_namespace() {
+        let storage = memory::Memory::new();
+        storage.mset("team-a", vec![]).unwrap();
+        assert!(storage.stats().namespaces.is_empty());
+    }
+
+    #[test]
+    fn memory_stats_should_follow_writes() {
+        let storage = memory::Memory::new();
+        let entry = |key: &str, value: &str| (key.len() + value.len() + std::mem::size_of::<(String, String)>()) as u64;
+
+        storage.set("a", "k1".to_string(), "v1".to_string()).unwrap();
+        storage.mset("b", vec![KV { key: "k2".to_string(), value: "v2".to_string() }]).unwrap();
+        storage.set("a", "k1".to_string(), "longer".to_string()).unwrap();
+        assert_eq!(entry("k1", "longer") + entry("k2", "v2"), storage.stats().memory_bytes);
+
+        storage.del("a", &["k1".to_string(), "missing".to_string()]).unwrap();
+        assert_eq!(entry("k2", "v2"), storage.stats().memory_bytes);
+        storage.flush("b").unwrap();
+        assert_eq!(StorageStats::default(), storage.stats());
+    }
+
+    #[test]
+    fn test_lsm_storage() {
+        let key_file = std::env::temp_dir().join(format!("kv-lsm-{}.key", Uuid::new_v4()));
+        std::fs::write(&key_file, "33".repeat(32)).unwrap();
+        // 较小的 memtable 和层大小，让检查过程中发生多次写入 SSTable 和合并
+        let config = LsmConfig {
+            path: std::env::temp_dir().join(format!("kv-lsm-{}", Uuid::new_v4())),
+            memtable_bytes: 64 * 1024,
+            sync: false,
+            block_bytes: 1024,
+            table_bytes: 64 * 1024,
+            l0_tables: 2,
+            level_bytes: 256 * 1024,
+            compress_threshold: Some(64),
+            encryption: Some(EncryptionConfig { key_file: Some(key_file.clone()), key_env: None }),
+        };
+
+        conformance::run_persistent(|| lsm::Lsm::open(&config).unwrap());
+        std::fs::remove_dir_all(&config.path).unwrap();
+        std::fs::remove_file(&key_file).unwrap();
+    }
+
+    #[test]
+    fn test_tiered_storage() {
+        // 缓存只能放下少量 key，检查过程中不断淘汰
+        conformance::run(|| tiered::Tiered::new(memory::Memory::new(), 4096));
+
+        let dir = std::env::temp_dir().join(format!("kv-tiered-{}", Uuid::new_v4()));
+        let config = BTreeConfig { path: dir.join("data.btree"), sync: false, compress_threshold: None, encryption: None };
+        conformance::run_persistent(|| tiered::Tiered::new(btree::BTree::open(&config).unwrap(), 64 * 1024));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_btree_storage() {
+        let dir = std::env::temp_dir().join(format!("kv-btree-{}", Uuid::new_v4()));
+        let config = BTreeConfig { path: dir.join("data.btree"), sync: false, compress_threshold: None, encryption: None };
+        conformance::run_persistent(|| btree::BTree::open(&config).unwrap());
+
+        // 同时开启压缩和加密
+        let key_file = dir.join("data.key");
+        std::fs::write(&key_file, "33".repeat(32)).unwrap();
+        let config = BTreeConfig {
+            path: dir.join("encrypted.btree"),
+            compress_threshold: Some(64),
+            encryption: Some(EncryptionConfig { key_file: Some(key_file), key_env: None }),
+            ..config
+        };
+        conformance::run_persistent(|| btree::BTree::open(&config).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
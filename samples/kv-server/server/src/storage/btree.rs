@@ -0,0 +1,1056 @@
+//! 基于内存映射文件的 B+ 树存储引擎，适合读多写少的场景
+//!
+//! 数据文件按 4KB 分页，前两页为元数据页，交替写入，记录事务号、目录树的根节点、已经使用的页数以及是否已经加密。
+//! 目录树记录每个命名空间的 B+ 树的根节点和 key 数量。
+//!
+//! 写操作由一个写事务完成：修改的页面先复制到空闲页（copy-on-write），写入文件并 sync 后，
+//! 再写入另一个元数据页，元数据页写入成功即提交，最后在写锁下替换当前的根节点。读操作持有读锁，
+//! 直接在映射的内存中查找，不复制页面。提交之前的版本不会被覆盖，写入过程中崩溃时打开后
+//! 使用校验和正确、事务号最大的元数据页，回到最后一次提交的状态。
+//!
+//! 事务中不再使用的页面在下一个事务中才能重新使用，此时读取旧版本的读操作都已经结束。
+//! 导出使用的游标引用某个旧版本时，之后的事务释放的页面要等这个版本不再被引用后才能重新使用。
+//! 空闲页不持久化，打开时遍历所有的树重新计算。
+//!
+//! 每个值前有 1 字节的标记，配置了 compress_threshold 时较大的值使用 lz4 压缩后写入。
+//!
+//! 配置了 encryption 时，除元数据页以外的页面使用当前密钥加密后写入，读取时解密到内存中。
+//! 打开时用当前密钥重写旧密钥加密或者没有加密的页面，之后旧密钥可以从配置中去掉。B+ 树没有后台合并，
+//! 页面只在写事务中复制，重写在打开时分批完成。全部重写后在元数据页中记录，之后没有加密的页面视为被篡改。
+//!
+//! sync 为 false 时不等待数据写入磁盘，进程崩溃不会丢失数据，断电可能导致文件损坏。
+//! 写入出错后拒绝之后的写操作，重新打开后回到最后一次提交的状态。
+
+mod page;
+mod txn;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use kv_core::compress::{self, Counter};
+use kv_core::domain::KV;
+use kv_core::error::KvError;
+use memmap2::Mmap;
+use tracing::{error, info};
+
+use crate::config::BTreeConfig;
+use crate::storage::btree::page::{binary_search, child_index, ItemRef, PageId, PageView, PAGE_CAPACITY, PAGE_SIZE};
+use crate::storage::btree::txn::{decode_namespace, Txn};
+use crate::storage::crypto::{self, Cipher};
+use crate::storage::{corrupted, integrity_error, storage_error, Cursor, Storage, StorageStats};
+
+const MAGIC: &[u8; 8] = b"kvbtree\0";
+const VERSION: u32 = 4;
+/// 版本 3 的元数据页没有 flags
+const VERSION_3: u32 = 3;
+/// 元数据页 flags：所有页面都已经加密
+const ENCRYPTED: u32 = 1;
+/// 元数据页之后的第一页
+const FIRST_PAGE: PageId = 2;
+/// 文件每次至少扩大的页数
+const MIN_GROWTH: u64 = 256;
+/// 换密钥时每个事务最多重写的页数
+const REWRITE_BATCH: usize = 1024;
+
+/// 值的标记：原始数据
+const PLAIN: u8 = 0;
+/// 值的标记：lz4 压缩的数据
+const LZ4: u8 = 1;
+
+pub struct BTree {
+    sync: bool,
+    compress_threshold: Option<usize>,
+    /// 写入的值压缩前后的字节数
+    compression: Counter,
+    cipher: Option<Arc<Cipher>>,
+    current: RwLock<Arc<Snapshot>>,
+    writer: Mutex<Writer>,
+}
+
+/// 提交的版本，文件扩大后重新映射，旧版本继续使用原来的映射
+#[derive(Clone)]
+pub struct Snapshot {
+    map: Arc<Mmap>,
+    meta: Meta,
+    cipher: Option<Arc<Cipher>>,
+}
+
+struct Writer {
+    file: File,
+    /// 可以重新使用的页面
+    free: Vec<PageId>,
+    /// 被游标引用的旧版本
+    pinned: Vec<Weak<Snapshot>>,
+    /// 事务释放的页面和释放前的版本的事务号，这个版本以及更早的版本不再被引用后才能重新使用
+    pending: VecDeque<(u64, Vec<PageId>)>,
+    failed: Option<String>,
+}
+
+/// 游标固定的版本，按名称顺序读取每个命名空间的树
+struct BTreeCursor {
+    snapshot: Arc<Snapshot>,
+    namespaces: VecDeque<(String, PageId)>,
+    // 当前命名空间中上一次读到的 key
+    after: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Meta {
+    txn: u64,
+    catalog: PageId,
+    /// 已经使用的页数，包括元数据页
+    pages: u64,
+    /// 提交时间，unix 时间戳（秒）
+    committed_at: u64,
+    /// 所有页面都已经加密，之后拒绝没有加密的页面
+    encrypted: bool,
+}
+
+impl Meta {
+    const SIZE: usize = 56;
+    const SIZE_3: usize = 52;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend(MAGIC);
+        buf.extend(VERSION.to_be_bytes());
+        buf.extend((PAGE_SIZE as u32).to_be_bytes());
+        for field in [self.txn, self.catalog, self.pages, self.committed_at] {
+            buf.extend(field.to_be_bytes());
+        }
+        buf.extend(if self.encrypted { ENCRYPTED } else { 0 }.to_be_bytes());
+        buf.extend(crc32fast::hash(&buf).to_be_bytes());
+        buf
+    }
+
+    /// 写入不完整或格式不符时返回 None
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let version = u32::from_be_bytes(buf.get(8..12)?.try_into().unwrap());
+        let size = match version {
+            VERSION => Self::SIZE,
+            VERSION_3 => Self::SIZE_3,
+            _ => return None,
+        };
+        let buf = buf.get(..size)?;
+        let (body, crc) = buf.split_at(size - 4);
+        if crc32fast::hash(body).to_be_bytes() != crc || &body[..8] != MAGIC {
+            return None;
+        }
+        let u32_at = |pos: usize| u32::from_be_bytes(body[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_be_bytes(body[pos..pos + 8].try_into().unwrap());
+        if u32_at(12) != PAGE_SIZE as u32 {
+            return None;
+        }
+        let flags = if version == VERSION { u32_at(48) } else { 0 };
+        Some(Self {
+            txn: u64_at(16),
+            catalog: u64_at(24),
+            pages: u64_at(32),
+            committed_at: u64_at(40),
+            encrypted: flags & ENCRYPTED != 0,
+        })
+    }
+}
+
+impl BTree {
+    pub fn open(config: &BTreeConfig) -> io::Result<Self> {
+        let cipher = config.encryption.as_ref().map(Cipher::load).transpose()?.map(Arc::new);
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&config.path)?;
+        if file.metadata()?.len() == 0 {
+            let meta = Meta { txn: 0, catalog: 0, pages: FIRST_PAGE, committed_at: 0, encrypted: false };
+            file.set_len(FIRST_PAGE * PAGE_SIZE as u64)?;
+            write_meta(&mut file, &meta)?;
+            file.sync_all()?;
+        }
+
+        let map = map(&file)?;
+        if map.len() % PAGE_SIZE != 0 || map.len() < FIRST_PAGE as usize * PAGE_SIZE {
+            return Err(corrupted(format!("invalid data file size {}", map.len())));
+        }
+        let meta = [0, 1]
+            .into_iter()
+            .filter_map(|slot| Meta::decode(&map[slot * PAGE_SIZE..]))
+            .max_by_key(|meta| meta.txn)
+            .ok_or_else(|| corrupted("no valid meta page".to_string()))?;
+        if meta.pages as usize * PAGE_SIZE > map.len() {
+            return Err(corrupted(format!("meta page references {} pages beyond end of file", meta.pages)));
+        }
+        if meta.encrypted && cipher.is_none() {
+            return Err(corrupted(String::from("storage is encrypted, but no encryption key is configured")));
+        }
+
+        let snapshot = Snapshot { map: Arc::new(map), meta, cipher: cipher.clone() };
+        let free = snapshot.unreachable()?;
+        info!("Opened btree storage at {}: txn {}, {} pages, {} free", config.path.display(), meta.txn, meta.pages, free.len());
+
+        let store = Self {
+            sync: config.sync,
+            compress_threshold: config.compress_threshold,
+            compression: Counter::default(),
+            cipher,
+            current: RwLock::new(Arc::new(snapshot)),
+            writer: Mutex::new(Writer { file, free, pinned: vec![], pending: VecDeque::new(), failed: None }),
+        };
+        if let Some(cipher) = &store.cipher {
+            store.reencrypt(cipher.current())?;
+        }
+        Ok(store)
+    }
+
+    /// 用当前密钥重写其他密钥加密或者没有加密的页面，分成多个事务，每个事务复制的页数有上限
+    fn reencrypt(&self, current: u32) -> io::Result<()> {
+        let mut total = 0;
+        loop {
+            let mut rewritten = 0;
+            self.write(|txn| {
+                let mut budget = REWRITE_BATCH;
+                let changed = txn.rewrite_all(current, &mut budget)?;
+                rewritten = REWRITE_BATCH - budget;
+                Ok(changed)
+            })?;
+            if rewritten == 0 {
+                break;
+            }
+            total += rewritten;
+        }
+
+        // 空闲页中仍然是旧密钥加密或者没有加密的数据，打开过程中没有读操作引用它们，直接清零
+        let mut writer = self.writer.lock().unwrap();
+        let Writer { file, free, .. } = &mut *writer;
+        if total > 0 {
+            for id in free.iter() {
+                file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+                file.write_all(&[0; PAGE_SIZE])?;
+            }
+            file.sync_data()?;
+            info!("Re-encrypted {total} pages with the current key, cleared {} free pages.", free.len());
+        }
+
+        // 所有页面都已经加密，提交一个只修改元数据页的事务记录下来
+        let meta = self.current.read().unwrap().meta;
+        if !meta.encrypted {
+            let meta = Meta { txn: meta.txn + 1, committed_at: now(), encrypted: true, ..meta };
+            write_meta(file, &meta)?;
+            file.sync_data()?;
+            let mut current = self.current.write().unwrap();
+            *current = Arc::new(Snapshot { meta, ..Snapshot::clone(&current) });
+        }
+        Ok(())
+    }
+
+    /// 在写事务中执行 f，f 返回 false 表示没有修改
+    fn write(&self, f: impl FnOnce(&mut Txn) -> io::Result<bool>) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(reason) = &writer.failed {
+            return Err(io::Error::other(format!("storage is read-only after an earlier error: {reason}")));
+        }
+        let result = self.write_locked(&mut writer, f);
+        if let Err(e) = &result {
+            error!("Write failed, storage is read-only until restart: {e}");
+            writer.failed = Some(e.to_string());
+        }
+        result
+    }
+
+    fn write_locked(&self, writer: &mut Writer, f: impl FnOnce(&mut Txn) -> io::Result<bool>) -> io::Result<()> {
+        writer.release();
+        // 只有持有 writer 的线程会替换 current，事务执行期间 current 不变
+        let current = self.current.read().unwrap().clone();
+        let mut txn = Txn::new(&current, &mut writer.free);
+        if !f(&mut txn)? {
+            return Ok(());
+        }
+        let commit = txn.finish()?;
+
+        let file_pages = current.map.len() as u64 / PAGE_SIZE as u64;
+        let grow = commit.page_count > file_pages;
+        if grow {
+            let pages = commit.page_count.max(file_pages + file_pages.clamp(MIN_GROWTH, 16 * 1024));
+            writer.file.set_len(pages * PAGE_SIZE as u64)?;
+        }
+        for (id, page) in commit.pages {
+            writer.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+            writer.file.write_all(&self.seal(id, page))?;
+        }
+        if self.sync {
+            writer.file.sync_data()?;
+        }
+
+        // 元数据页写入后提交生效
+        let meta = Meta {
+            txn: current.meta.txn + 1,
+            catalog: commit.catalog,
+            pages: commit.page_count,
+            committed_at: now(),
+            encrypted: current.meta.encrypted,
+        };
+        write_meta(&mut writer.file, &meta)?;
+        if self.sync {
+            writer.file.sync_data()?;
+        }
+
+        let map = if grow { Arc::new(map(&writer.file)?) } else { current.map.clone() };
+        let snapshot = Arc::new(Snapshot { map, meta, cipher: current.cipher.clone() });
+        drop(current);
+        // 等待读取旧版本的读操作结束
+        let old = mem::replace(&mut *self.current.write().unwrap(), snapshot);
+        if Arc::strong_count(&old) > 1 {
+            writer.pinned.push(Arc::downgrade(&old));
+        }
+        writer.pending.push_back((old.meta.txn, commit.freed));
+        Ok(())
+    }
+
+    /// 加密页面，没有配置加密时末尾补 0
+    fn seal(&self, id: PageId, mut page: Vec<u8>) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(page, &id.to_be_bytes()),
+            None => {
+                page.resize(PAGE_SIZE, 0);
+                page
+            }
+        }
+    }
+
+    /// 加上标记，超过压缩阈值的值压缩后写入
+    fn encode_value(&self, value: String) -> Vec<u8> {
+        let Some(threshold) = self.compress_threshold else {
+            return [&[PLAIN], value.as_bytes()].concat();
+        };
+        let compressed = compress::compress(value.as_bytes(), threshold);
+        self.compression.record(value.len(), compressed.as_ref().map_or(value.len(), Vec::len));
+        match compressed {
+            Some(compressed) => [&[LZ4], &compressed[..]].concat(),
+            None => [&[PLAIN], value.as_bytes()].concat(),
+        }
+    }
+}
+
+impl Writer {
+    /// 没有游标引用的版本释放的页面可以重新使用
+    fn release(&mut self) {
+        self.pinned.retain(|snapshot| snapshot.strong_count() > 0);
+        let oldest = self.pinned.iter().filter_map(Weak::upgrade).map(|snapshot| snapshot.meta.txn).min();
+        while let Some((txn, _)) = self.pending.front() {
+            if oldest.is_some_and(|oldest| oldest <= *txn) {
+                break;
+            }
+            let (_, pages) = self.pending.pop_front().unwrap();
+            self.free.extend(pages);
+        }
+    }
+}
+
+fn map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: 数据文件只由本进程通过 Writer 修改，已经映射的页面在读操作结束前不会被覆盖
+    unsafe { Mmap::map(file) }
+}
+
+fn write_meta(file: &mut File, meta: &Meta) -> io::Result<()> {
+    file.seek(SeekFrom::Start((meta.txn % 2) * PAGE_SIZE as u64))?;
+    file.write_all(&meta.encode())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn string(bytes: Cow<'_, [u8]>) -> io::Result<String> {
+    String::from_utf8(bytes.into_owned()).map_err(|e| corrupted(format!("invalid utf-8: {e}")))
+}
+
+/// 解码带标记的值
+fn value(bytes: Cow<'_, [u8]>) -> io::Result<String> {
+    match bytes.first() {
+        Some(&PLAIN) => string(Cow::Borrowed(&bytes[1..])),
+        Some(&LZ4) => compress::decompress(&bytes[1..], u32::MAX as usize)
+            .ok_or_else(|| corrupted("invalid compressed value".to_string()))
+            .and_then(|bytes| string(Cow::Owned(bytes))),
+        tag => Err(corrupted(format!("invalid value tag {tag:?}"))),
+    }
+}
+
+impl Snapshot {
+    fn raw(&self, id: PageId) -> io::Result<&[u8]> {
+        if id < FIRST_PAGE || id >= self.meta.pages {
+            return Err(corrupted(format!("page {id} out of range")));
+        }
+        let start = id as usize * PAGE_SIZE;
+        Ok(&self.map[start..start + PAGE_SIZE])
+    }
+
+    /// 页面的内容，没有加密时直接引用映射的内存，加密时解密后返回
+    fn page(&self, id: PageId) -> io::Result<Cow<'_, [u8]>> {
+        let raw = self.raw(id)?;
+        if crypto::key_id(raw) == Some(0) {
+            // 完成加密后出现的明文页面是被替换的
+            if self.meta.encrypted {
+                return Err(integrity_error(format!("page {id} is not encrypted")));
+            }
+            return Ok(Cow::Borrowed(&raw[..PAGE_CAPACITY]));
+        }
+        let Some(cipher) = &self.cipher else {
+            return Err(corrupted(format!("page {id} is encrypted, but no encryption key is configured")));
+        };
+        cipher.decrypt(raw.to_vec(), &id.to_be_bytes())
+            .map(Cow::Owned)
+            .map_err(|e| integrity_error(format!("page {id}: {e}")))
+    }
+
+    /// 页面加密使用的密钥 id，0 表示没有加密
+    fn key_id(&self, id: PageId) -> io::Result<u32> {
+        Ok(crypto::key_id(self.raw(id)?).unwrap_or_default())
+    }
+
+    /// 溢出页链表中的下一页
+    fn next_overflow(&self, id: PageId) -> io::Result<PageId> {
+        Ok(PageView::new(&self.page(id)?).overflow()?.0)
+    }
+
+    /// 读取溢出页链表中的数据
+    fn overflow(&self, mut page: PageId, len: u32) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(len as usize);
+        while page != 0 && buf.len() < len as usize {
+            let bytes = self.page(page)?;
+            let (next, data) = PageView::new(&bytes).overflow()?;
+            buf.extend_from_slice(data);
+            page = next;
+        }
+        if buf.len() != len as usize {
+            return Err(corrupted(format!("overflow chain has {} bytes, expected {len}", buf.len())));
+        }
+        Ok(buf)
+    }
+
+    fn bytes<'a>(&'a self, item: ItemRef<'a>) -> io::Result<Cow<'a, [u8]>> {
+        match item {
+            ItemRef::Inline(bytes) => Ok(Cow::Borrowed(bytes)),
+            ItemRef::Overflow { page, len } => Ok(Cow::Owned(self.overflow(page, len)?)),
+        }
+    }
+
+    /// 叶子页中第一个大于等于 key 的位置
+    fn search_leaf(&self, page: PageView<'_>, key: &[u8]) -> io::Result<Result<usize, usize>> {
+        binary_search(page.count(), |i| Ok(self.bytes(page.leaf_entry(i)?.0)?.as_ref().cmp(key)))
+    }
+
+    fn search_branch(&self, page: PageView<'_>, key: &[u8]) -> io::Result<usize> {
+        binary_search(page.count(), |i| Ok(self.bytes(page.branch_key(i)?)?.as_ref().cmp(key))).map(child_index)
+    }
+
+    fn lookup(&self, root: PageId, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut id = root;
+        while id != 0 {
+            let bytes = self.page(id)?;
+            let page = PageView::new(&bytes);
+            if page.is_leaf() {
+                return match self.search_leaf(page, key)? {
+                    Ok(i) => Ok(Some(self.bytes(page.leaf_entry(i)?.1)?.into_owned())),
+                    Err(_) => Ok(None),
+                };
+            }
+            id = page.branch_child(self.search_branch(page, key)?)?;
+        }
+        Ok(None)
+    }
+
+    fn namespace(&self, ns: &str) -> io::Result<Option<(PageId, u64)>> {
+        match self.lookup(self.meta.catalog, ns.as_bytes())? {
+            Some(value) => decode_namespace(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn get(&self, ns: &str, key: &str) -> io::Result<Option<String>> {
+        let Some((root, _)) = self.namespace(ns)? else {
+            return Ok(None);
+        };
+        self.lookup(root, key.as_bytes())?.map(|bytes| value(Cow::Owned(bytes))).transpose()
+    }
+
+    /// 按顺序访问大于 after 的条目，f 返回 false 时停止
+    fn walk(&self, id: PageId, after: Option<&[u8]>, f: &mut dyn FnMut(&[u8], ItemRef<'_>) -> io::Result<bool>) -> io::Result<bool> {
+        if id == 0 {
+            return Ok(true);
+        }
+        let bytes = self.page(id)?;
+        let page = PageView::new(&bytes);
+        if page.is_leaf() {
+            let start = match after {
+                Some(after) => match self.search_leaf(page, after)? {
+                    Ok(i) => i + 1,
+                    Err(i) => i,
+                },
+                None => 0,
+            };
+            for i in start..page.count() {
+                let (key, value) = page.leaf_entry(i)?;
+                if !f(&self.bytes(key)?, value)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+
+        let start = after.map_or(Ok(0), |after| self.search_branch(page, after))?;
+        for i in start..=page.count() {
+            if !self.walk(page.branch_child(i)?, after, f)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// 所有命名空间的名称、根节点和 key 数量
+    fn namespaces(&self) -> io::Result<Vec<(String, PageId, u64)>> {
+        let mut namespaces = vec![];
+        self.walk(self.meta.catalog, None, &mut |ns, value| {
+            let (root, count) = decode_namespace(&self.bytes(value)?)?;
+            namespaces.push((string(Cow::Borrowed(ns))?, root, count));
+            Ok(true)
+        })?;
+        Ok(namespaces)
+    }
+
+    /// 没有被任何树引用的页面
+    fn unreachable(&self) -> io::Result<Vec<PageId>> {
+        let mut used = vec![false; self.meta.pages as usize];
+        self.mark(self.meta.catalog, &mut used)?;
+        for (_, root, _) in self.namespaces()? {
+            self.mark(root, &mut used)?;
+        }
+        Ok((FIRST_PAGE..self.meta.pages).rev().filter(|id| !used[*id as usize]).collect())
+    }
+
+    fn mark(&self, id: PageId, used: &mut [bool]) -> io::Result<()> {
+        if id == 0 {
+            return Ok(());
+        }
+        let bytes = self.page(id)?;
+        let page = PageView::new(&bytes);
+        if used[id as usize] {
+            return Err(corrupted(format!("page {id} referenced twice")));
+        }
+        used[id as usize] = true;
+
+        let mut items = vec![];
+        if page.is_leaf() {
+            for i in 0..page.count() {
+                let (key, value) = page.leaf_entry(i)?;
+                items.extend([key, value]);
+            }
+        } else {
+            for i in 0..page.count() {
+                items.push(page.branch_key(i)?);
+            }
+            for i in 0..=page.count() {
+                self.mark(page.branch_child(i)?, used)?;
+            }
+        }
+        for item in items {
+            if let ItemRef::Overflow { mut page, .. } = item {
+                while page != 0 {
+                    let next = self.next_overflow(page)?;
+                    used[page as usize] = true;
+                    page = next;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for BTree {
+    fn name(&self) -> &'static str {
+        "btree"
+    }
+
+    fn get(&self, ns: &str, key: &str) -> Result<Vec<String>, KvError> {
+        let current = self.current.read().unwrap();
+        let value = current.get(ns, key).map_err(storage_error("get", key))?;
+        Ok(value.into_iter().collect())
+    }
+
+    fn mget(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let current = self.current.read().unwrap();
+        let mut res = vec![];
+        for key in keys {
+            res.extend(current.get(ns, key).map_err(storage_error("mget", key))?);
+        }
+        Ok(res)
+    }
+
+    fn set(&self, ns: &str, key: String, value: String) -> Result<Vec<String>, KvError> {
+        self.mset(ns, vec![KV { key, value }]).map_err(|e| match e {
+            KvError::StorageError(_, key, message) => KvError::StorageError("set", key, message),
+            e => e,
+        })
+    }
+
+    fn mset(&self, ns: &str, kvs: Vec<KV>) -> Result<Vec<String>, KvError> {
+        let error = storage_error("mset", kvs.first().map_or("", |kv| kv.key.as_str()));
+        self.write(|txn| {
+            if kvs.is_empty() {
+                return Ok(false);
+            }
+            let (mut root, mut count) = txn.namespace(ns)?.unwrap_or((0, 0));
+            for kv in kvs {
+                let value = self.encode_value(kv.value);
+                let (new_root, existed) = txn.insert(root, kv.key.as_bytes(), page::Item::Inline(value))?;
+                root = new_root;
+                if !existed {
+                    count += 1;
+                }
+            }
+            txn.set_namespace(ns, root, count)?;
+            Ok(true)
+        })
+        .map_err(error)?;
+        Ok(vec![])
+    }
+
+    fn del(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let error = storage_error("del", keys.first().map_or("", String::as_str));
+        self.write(|txn| {
+            let Some((mut root, mut count)) = txn.namespace(ns)? else {
+                return Ok(false);
+            };
+            let mut changed = false;
+            for key in keys {
+                if txn.get(root, key.as_bytes())?.is_some() {
+                    root = txn.remove(root, key.as_bytes())?;
+                    count -= 1;
+                    changed = true;
+                }
+            }
+            if changed {
+                txn.set_namespace(ns, root, count)?;
+            }
+            Ok(changed)
+        })
+        .map_err(error)?;
+        Ok(vec![])
+    }
+
+    fn flush(&self, ns: &str) -> Result<Vec<String>, KvError> {
+        self.write(|txn| {
+            let Some((root, _)) = txn.namespace(ns)? else {
+                return Ok(false);
+            };
+            txn.free_tree(root)?;
+            txn.set_namespace(ns, 0, 0)?;
+            Ok(true)
+        })
+        .map_err(storage_error("flushdb", ""))?;
+        Ok(vec![])
+    }
+
+    fn scan(&self, ns: &str, after: Option<&str>, count: usize) -> Result<Vec<String>, KvError> {
+        let error = storage_error("scan", after.unwrap_or_default());
+        let current = self.current.read().unwrap();
+        let mut keys = vec![];
+        let result = current.namespace(ns).and_then(|namespace| match namespace {
+            Some((root, _)) if count > 0 => current.walk(root, after.map(str::as_bytes), &mut |key, _| {
+                keys.push(string(Cow::Borrowed(key))?);
+                Ok(keys.len() < count)
+            }),
+            _ => Ok(true),
+        });
+        result.map_err(error)?;
+        Ok(keys)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cursor>, KvError> {
+        let snapshot = self.current.read().unwrap().clone();
+        let namespaces = snapshot.namespaces().map_err(storage_error("dump", ""))?;
+        let namespaces = namespaces.into_iter().map(|(ns, root, _)| (ns, root)).collect();
+        Ok(Box::new(BTreeCursor { snapshot, namespaces, after: None }))
+    }
+
+    fn stats(&self) -> StorageStats {
+        let current = self.current.read().unwrap();
+        let namespaces: BTreeMap<_, _> = match current.namespaces() {
+            Ok(namespaces) => namespaces.into_iter().map(|(ns, _, count)| (ns, count)).collect(),
+            Err(e) => {
+                error!("Failed to read namespaces: {e}");
+                Default::default()
+            }
+        };
+
+        StorageStats {
+            keys: namespaces.values().sum(),
+            namespaces,
+            approximate: false,
+            memory_bytes: current.map.len() as u64,
+            last_snapshot: Some(current.meta.committed_at).filter(|t| *t > 0),
+            wal_bytes: None,
+            cache: None,
+            compression: self.compress_threshold.map(|_| self.compression.info()),
+        }
+    }
+}
+
+impl Cursor for BTreeCursor {
+    fn next_batch(&mut self, count: usize) -> Result<Vec<(String, KV)>, KvError> {
+        let mut entries = vec![];
+        while entries.len() < count {
+            let Some((ns, root)) = self.namespaces.front().cloned() else {
+                break;
+            };
+            let snapshot = &self.snapshot;
+            let mut last = None;
+            let finished = snapshot.walk(root, self.after.as_deref(), &mut |key, item| {
+                if entries.len() >= count {
+                    return Ok(false);
+                }
+                let value = value(snapshot.bytes(item)?)?;
+                entries.push((ns.clone(), KV { key: string(Cow::Borrowed(key))?, value }));
+                last = Some(key.to_vec());
+                Ok(true)
+            });
+
+            if finished.map_err(storage_error("dump", ""))? {
+                self.namespaces.pop_front();
+                self.after = None;
+            } else {
+                self.after = last;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use kv_core::domain::KV;
+    use uuid::Uuid;
+
+    use kv_core::error::KvError;
+
+    use crate::config::{BTreeConfig, EncryptionConfig};
+    use crate::storage::btree::page::PAGE_SIZE;
+    use crate::storage::btree::{BTree, Meta, FIRST_PAGE, MAGIC};
+    use crate::storage::{crypto, storage_error, Storage};
+
+    fn config() -> BTreeConfig {
+        let path = std::env::temp_dir().join(format!("kv-btree-{}", Uuid::new_v4())).join("data.btree");
+        BTreeConfig { path, sync: false, compress_threshold: None, encryption: None }
+    }
+
+    fn cleanup(path: &Path) {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    fn pages(store: &BTree) -> u64 {
+        store.current.read().unwrap().meta.pages
+    }
+
+    #[test]
+    fn torn_meta_should_fall_back_to_previous_commit() {
+        let config = config();
+        let store = BTree::open(&config).unwrap();
+        store.set("default", "k".to_string(), "v1".to_string()).unwrap();
+        store.set("default", "k".to_string(), "v2".to_string()).unwrap();
+        drop(store);
+
+        // 第 2 个事务写入第 0 页，模拟元数据页写入不完整
+        let mut file = OpenOptions::new().write(true).open(&config.path).unwrap();
+        file.seek(SeekFrom::Start(20)).unwrap();
+        file.write_all(&[0xff; 8]).unwrap();
+        drop(file);
+
+        let store = BTree::open(&config).unwrap();
+        assert_eq!(vec!["v1".to_string()], store.get("default", "k").unwrap());
+        // 回退后继续写入
+        store.set("default", "k".to_string(), "v3".to_string()).unwrap();
+        drop(store);
+        assert_eq!(vec!["v3".to_string()], BTree::open(&config).unwrap().get("default", "k").unwrap());
+
+        // 两个元数据页都损坏时无法打开
+        let mut file = OpenOptions::new().write(true).open(&config.path).unwrap();
+        for slot in 0..2 {
+            file.seek(SeekFrom::Start(slot * PAGE_SIZE as u64 + 20)).unwrap();
+            file.write_all(&[0xff; 8]).unwrap();
+        }
+        drop(file);
+        assert!(BTree::open(&config).is_err());
+        cleanup(&config.path);
+    }
+
+    #[test]
+    fn random_writes_should_match_btreemap() {
+        // key 和 value 的长度跨过溢出页的阈值，大量写入和删除让节点反复分裂与合并
+        let config = config();
+        let store = BTree::open(&config).unwrap();
+        let mut model = BTreeMap::new();
+        let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng
+        };
+
+        for round in 0..3000 {
+            let key = format!("{:0width$}", next() % 500, width = [4, 40, 600][(next() % 3) as usize]);
+            match next() % 4 {
+                0 => {
+                    store.del("default", std::slice::from_ref(&key)).unwrap();
+                    model.remove(&key);
+                }
+                _ => {
+                    let value = "v".repeat([1, 300, 5000][(next() % 3) as usize]);
+                    store.set("default", key.clone(), value.clone()).unwrap();
+                    model.insert(key, value);
+                }
+            }
+
+            if round % 500 == 499 {
+                let expected: Vec<_> = model.iter().map(|(k, v)| ("default".to_string(), KV { key: k.clone(), value: v.clone() })).collect();
+                assert_eq!(expected, store.snapshot().unwrap().read_to_end().unwrap());
+                assert_eq!(model.len() as u64, store.stats().keys);
+                let after = model.keys().nth(model.len() / 2).cloned();
+                let scanned = store.scan("default", after.as_deref(), 10).unwrap();
+                let expected: Vec<_> = model.keys().skip(model.len() / 2 + 1).take(10).cloned().collect();
+                assert_eq!(expected, scanned);
+            }
+        }
+
+        // 重新打开后重建的空闲页与提交的树一致
+        drop(store);
+        let store = BTree::open(&config).unwrap();
+        let keys: Vec<_> = model.keys().cloned().collect();
+        store.del("default", &keys).unwrap();
+        assert!(store.snapshot().unwrap().read_to_end().unwrap().is_empty());
+        assert_eq!(0, store.current.read().unwrap().meta.catalog);
+        cleanup(&config.path);
+    }
+
+    #[test]
+    fn freed_pages_should_be_reused() {
+        let config = config();
+        let store = BTree::open(&config).unwrap();
+        let big = "x".repeat(10_000);
+        for i in 0..2000 {
+            store.set("default", format!("key-{:04}", i % 200), big.clone()).unwrap();
+        }
+        let used = pages(&store);
+        // 覆盖写入不会让文件无限增长
+        for i in 0..2000 {
+            store.set("default", format!("key-{:04}", i % 200), big.clone()).unwrap();
+        }
+        assert_eq!(used, pages(&store));
+
+        // 删除所有 key 后重新写入同样多的数据，使用删除时释放的页面
+        let keys: Vec<_> = (0..200).map(|i| format!("key-{i:04}")).collect();
+        store.del("default", &keys).unwrap();
+        assert_eq!(0, store.stats().keys);
+        for key in &keys {
+            store.set("other", key.clone(), big.clone()).unwrap();
+        }
+        assert_eq!(used, pages(&store));
+
+        // 重新打开后空闲页不变
+        drop(store);
+        let store = BTree::open(&config).unwrap();
+        store.flush("other").unwrap();
+        for key in &keys {
+            store.set("default", key.clone(), big.clone()).unwrap();
+        }
+        assert_eq!(used, pages(&store));
+        assert_eq!(200, store.stats().keys);
+        cleanup(&config.path);
+    }
+
+    #[test]
+    fn pinned_pages_should_not_be_reused() {
+        let config = config();
+        let store = BTree::open(&config).unwrap();
+        let big = "x".repeat(10_000);
+        let keys: Vec<_> = (0..200).map(|i| format!("key-{i:04}")).collect();
+        for key in &keys {
+            store.set("default", key.clone(), big.clone()).unwrap();
+        }
+        let expected = store.snapshot().unwrap().read_to_end().unwrap();
+
+        // 游标引用的版本释放的页面在游标读完之前不会被覆盖
+        let mut cursor = store.snapshot().unwrap();
+        let mut actual = cursor.next_batch(10).unwrap();
+        let used = pages(&store);
+        store.del("default", &keys).unwrap();
+        for key in &keys {
+            store.set("default", key.clone(), "y".repeat(10_000)).unwrap();
+        }
+        assert!(pages(&store) > used);
+        actual.extend(cursor.read_to_end().unwrap());
+        assert_eq!(expected, actual);
+
+        // 游标释放后页面重新使用
+        drop(cursor);
+        let used = pages(&store);
+        for key in &keys {
+            store.set("default", key.clone(), big.clone()).unwrap();
+        }
+        assert_eq!(used, pages(&store));
+        cleanup(&config.path);
+    }
+
+    #[test]
+    fn large_values_should_be_compressed() {
+        let config = BTreeConfig { compress_threshold: Some(64), ..config() };
+        let store = BTree::open(&config).unwrap();
+        let big = r#"{"id":1,"tags":["a","b"]}"#.repeat(1000);
+        for i in 0..100 {
+            store.set("default", format!("key-{i:03}"), big.clone()).unwrap();
+        }
+        store.set("default", "small".to_string(), "v".to_string()).unwrap();
+        // 不压缩时需要几百页溢出页
+        assert!(pages(&store) < 100);
+
+        let compression = store.stats().compression.unwrap();
+        assert_eq!(100 * big.len() as u64 + 1, compression.raw_bytes);
+        assert!(compression.ratio() > 10.0);
+
+        // 关闭压缩后重新打开，已经压缩的值仍然可以读取
+        drop(store);
+        let store = BTree::open(&BTreeConfig { compress_threshold: None, ..config.clone() }).unwrap();
+        assert_eq!(vec![big], store.get("default", "key-042").unwrap());
+        assert_eq!(vec!["v".to_string()], store.get("default", "small").unwrap());
+        assert_eq!(101, store.snapshot().unwrap().read_to_end().unwrap().len());
+        assert_eq!(None, store.stats().compression);
+        cleanup(&config.path);
+    }
+
+    #[test]
+    fn pages_should_be_reencrypted_on_open() {
+        const ENV: &str = "KV_TEST_BTREE_ENCRYPTION_KEY";
+        let (old, new) = ("11".repeat(32), "22".repeat(32));
+        let config = config();
+        let encrypted = BTreeConfig {
+            encryption: Some(EncryptionConfig { key_file: None, key_env: Some(ENV.to_string()) }),
+            ..config.clone()
+        };
+        // 所有页面使用的密钥 id
+        let keys = |store: &BTree| {
+            let current = store.current.read().unwrap();
+            (FIRST_PAGE..current.meta.pages).map(|id| current.key_id(id).unwrap()).collect::<Vec<_>>()
+        };
+
+        let store = BTree::open(&config).unwrap();
+        let big = "secret-".repeat(1000);
+        for i in 0..300 {
+            store.set(if i % 2 == 0 { "a" } else { "b" }, format!("key-{i:03}"), big.clone()).unwrap();
+        }
+        let expected = store.snapshot().unwrap().read_to_end().unwrap();
+        drop(store);
+        assert!(String::from_utf8_lossy(&std::fs::read(&config.path).unwrap()).contains("secret-"));
+
+        // 开启加密后重写所有页面，没有加密的旧页面被清零；轮换密钥后同样如此
+        for keys_env in [old.clone(), format!("{new}\n{old}")] {
+            std::env::set_var(ENV, &keys_env);
+            let store = BTree::open(&encrypted).unwrap();
+            let current = store.cipher.as_ref().unwrap().current();
+            assert!(keys(&store).iter().all(|key| *key == current || *key == 0), "pages left with an old key");
+            assert_eq!(expected, store.snapshot().unwrap().read_to_end().unwrap());
+            store.set("a", "after".to_string(), "rotation".to_string()).unwrap();
+            store.del("a", &["after".to_string()]).unwrap();
+        }
+        assert!(!String::from_utf8_lossy(&std::fs::read(&config.path).unwrap()).contains("secret-"));
+
+        // 旧密钥已经不再需要；没有密钥或者只有旧密钥时无法打开
+        std::env::set_var(ENV, &new);
+        assert_eq!(expected, BTree::open(&encrypted).unwrap().snapshot().unwrap().read_to_end().unwrap());
+        assert!(BTree::open(&config).is_err());
+        std::env::set_var(ENV, &old);
+        let err = BTree::open(&encrypted).err().unwrap();
+        assert!(matches!(storage_error("open", "")(err), KvError::IntegrityError(message) if message.contains("unknown encryption key")));
+
+        // 修改目录树根节点中的一个字节
+        std::env::set_var(ENV, &new);
+        let catalog = BTree::open(&encrypted).unwrap().current.read().unwrap().meta.catalog;
+        let mut file = OpenOptions::new().read(true).write(true).open(&config.path).unwrap();
+        let mut page = vec![0; PAGE_SIZE];
+        file.seek(SeekFrom::Start(catalog * PAGE_SIZE as u64)).unwrap();
+        std::io::Read::read_exact(&mut file, &mut page).unwrap();
+        assert_ne!(Some(0), crypto::key_id(&page));
+        page[10] ^= 1;
+        file.seek(SeekFrom::Start(catalog * PAGE_SIZE as u64)).unwrap();
+        file.write_all(&page).unwrap();
+        drop(file);
+        let err = BTree::open(&encrypted).err().unwrap();
+        assert!(matches!(storage_error("open", "")(err), KvError::IntegrityError(message) if message.contains(&format!("page {catalog}"))));
+        cleanup(&config.path);
+    }
+
+    fn read_page(path: &Path, id: u64) -> Vec<u8> {
+        let mut file = OpenOptions::new().read(true).open(path).unwrap();
+        let mut page = vec![0; PAGE_SIZE];
+        file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)).unwrap();
+        std::io::Read::read_exact(&mut file, &mut page).unwrap();
+        page
+    }
+
+    fn write_page(path: &Path, id: u64, page: &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)).unwrap();
+        file.write_all(page).unwrap();
+    }
+
+    #[test]
+    fn plaintext_pages_should_be_rejected_after_encryption() {
+        const ENV: &str = "KV_TEST_BTREE_PLAINTEXT_KEY";
+        std::env::set_var(ENV, "33".repeat(32));
+        let config = config();
+        let encrypted = BTreeConfig {
+            encryption: Some(EncryptionConfig { key_file: None, key_env: Some(ENV.to_string()) }),
+            ..config.clone()
+        };
+
+        // 开启加密前的目录树根节点
+        let store = BTree::open(&config).unwrap();
+        store.set("a", "k1".to_string(), "plain".to_string()).unwrap();
+        let plain = read_page(&config.path, store.current.read().unwrap().meta.catalog);
+        drop(store);
+
+        let store = BTree::open(&encrypted).unwrap();
+        let meta = store.current.read().unwrap().meta;
+        assert!(meta.encrypted);
+        drop(store);
+
+        // 用明文页面替换加密的目录树根节点
+        write_page(&config.path, meta.catalog, &plain);
+        let err = BTree::open(&encrypted).err().unwrap();
+        let message = format!("page {} is not encrypted", meta.catalog);
+        assert!(matches!(storage_error("open", "")(err), KvError::IntegrityError(m) if m.contains(&message)));
+        assert!(BTree::open(&config).is_err());
+        cleanup(&config.path);
+    }
+
+    #[test]
+    fn version_3_meta_should_be_read() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(3u32.to_be_bytes());
+        buf.extend((PAGE_SIZE as u32).to_be_bytes());
+        for field in [7u64, 2, 3, 0] {
+            buf.extend(field.to_be_bytes());
+        }
+        buf.extend(crc32fast::hash(&buf).to_be_bytes());
+
+        let meta = Meta::decode(&buf).unwrap();
+        assert_eq!(Meta { txn: 7, catalog: 2, pages: 3, committed_at: 0, encrypted: false }, meta);
+        assert_eq!(Some(Meta { encrypted: true, ..meta }), Meta::decode(&Meta { encrypted: true, ..meta }.encode()));
+    }
+}
@@ -0,0 +1,307 @@
+//! 页面格式
+//!
+//! 叶子页：类型（1 字节）、条目数（2 字节）、每个条目的偏移（2 字节）、条目（key 和 value）。
+//! 分支页：类型、key 数、子节点页号（8 字节）、每个 key 的偏移、key。
+//! 条目中的数据为标记（1 字节）、长度（4 字节）和数据本身，超过 MAX_INLINE 时改为溢出页链表的第一页。
+//! 溢出页：类型、下一页（8 字节，0 表示结束）、本页数据长度（2 字节）和数据。
+//! 每页末尾保留 32 字节，加密时存放密钥 id、nonce 和认证标签，没有加密时全部为 0。
+
+use std::cmp::Ordering;
+use std::io;
+
+use crate::storage::{corrupted, crypto};
+
+pub const PAGE_SIZE: usize = 4096;
+/// 页面中可以存放数据的长度
+pub const PAGE_CAPACITY: usize = PAGE_SIZE - crypto::OVERHEAD;
+/// 超过该长度的 key 或 value 存放在溢出页中，保证每页至少能放下三个条目
+pub const MAX_INLINE: usize = 512;
+
+pub type PageId = u64;
+
+const LEAF: u8 = 1;
+const BRANCH: u8 = 2;
+const OVERFLOW: u8 = 3;
+
+const HEADER: usize = 3;
+const ITEM_HEADER: usize = 5;
+const OVERFLOW_HEADER: usize = 11;
+pub const OVERFLOW_CAPACITY: usize = PAGE_CAPACITY - OVERFLOW_HEADER;
+
+/// 写事务中修改过的数据
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    /// 提交时超过 MAX_INLINE 的数据写入新的溢出页
+    Inline(Vec<u8>),
+    /// 已经写入溢出页的数据
+    Overflow { page: PageId, len: u32 },
+}
+
+impl Item {
+    /// 在页面中占用的长度
+    pub fn size(&self) -> usize {
+        match self {
+            Item::Inline(bytes) if bytes.len() <= MAX_INLINE => ITEM_HEADER + bytes.len(),
+            _ => ITEM_HEADER + 8,
+        }
+    }
+}
+
+/// 直接引用映射内存中的数据
+#[derive(Debug, Clone, Copy)]
+pub enum ItemRef<'a> {
+    Inline(&'a [u8]),
+    Overflow { page: PageId, len: u32 },
+}
+
+impl ItemRef<'_> {
+    fn to_item(self) -> Item {
+        match self {
+            ItemRef::Inline(bytes) => Item::Inline(bytes.to_vec()),
+            ItemRef::Overflow { page, len } => Item::Overflow { page, len },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    /// 按 key 排序的条目
+    Leaf(Vec<(Item, Item)>),
+    /// keys[i] 是 children[i + 1] 中最小的 key
+    Branch { keys: Vec<Item>, children: Vec<PageId> },
+}
+
+impl Node {
+    /// 编码后的长度，超过 PAGE_CAPACITY 时需要分裂
+    pub fn size(&self) -> usize {
+        match self {
+            Node::Leaf(entries) => HEADER + entries.iter().map(|(k, v)| 2 + k.size() + v.size()).sum::<usize>(),
+            Node::Branch { keys, children } => {
+                HEADER + children.len() * 8 + keys.iter().map(|k| 2 + k.size()).sum::<usize>()
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Node::Leaf(entries) => entries.is_empty(),
+            Node::Branch { children, .. } => children.is_empty(),
+        }
+    }
+
+    /// 编码为 PAGE_CAPACITY 字节，需要写入溢出页的数据交给 spill，返回溢出页链表的第一页
+    pub fn encode(&self, spill: &mut impl FnMut(&[u8]) -> io::Result<PageId>) -> io::Result<Vec<u8>> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        let mut items = vec![];
+        let mut offsets = vec![];
+        match self {
+            Node::Leaf(entries) => {
+                page.push(LEAF);
+                page.extend((entries.len() as u16).to_be_bytes());
+                for (key, value) in entries {
+                    offsets.push(items.len());
+                    encode_item(&mut items, key, spill)?;
+                    encode_item(&mut items, value, spill)?;
+                }
+            }
+            Node::Branch { keys, children } => {
+                page.push(BRANCH);
+                page.extend((keys.len() as u16).to_be_bytes());
+                for child in children {
+                    page.extend(child.to_be_bytes());
+                }
+                for key in keys {
+                    offsets.push(items.len());
+                    encode_item(&mut items, key, spill)?;
+                }
+            }
+        }
+
+        let base = page.len() + offsets.len() * 2;
+        for offset in offsets {
+            page.extend(((base + offset) as u16).to_be_bytes());
+        }
+        page.extend(items);
+        debug_assert!(page.len() <= PAGE_CAPACITY);
+        page.resize(PAGE_CAPACITY, 0);
+        Ok(page)
+    }
+}
+
+fn encode_item(buf: &mut Vec<u8>, item: &Item, spill: &mut impl FnMut(&[u8]) -> io::Result<PageId>) -> io::Result<()> {
+    match item {
+        Item::Inline(bytes) if bytes.len() <= MAX_INLINE => {
+            buf.push(0);
+            buf.extend((bytes.len() as u32).to_be_bytes());
+            buf.extend(bytes);
+        }
+        Item::Inline(bytes) => {
+            let page = spill(bytes)?;
+            buf.push(1);
+            buf.extend((bytes.len() as u32).to_be_bytes());
+            buf.extend(page.to_be_bytes());
+        }
+        Item::Overflow { page, len } => {
+            buf.push(1);
+            buf.extend(len.to_be_bytes());
+            buf.extend(page.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// 溢出页，next 为 0 表示链表结束
+pub fn overflow_page(next: PageId, data: &[u8]) -> Vec<u8> {
+    let mut page = Vec::with_capacity(PAGE_SIZE);
+    page.push(OVERFLOW);
+    page.extend(next.to_be_bytes());
+    page.extend((data.len() as u16).to_be_bytes());
+    page.extend(data);
+    page.resize(PAGE_CAPACITY, 0);
+    page
+}
+
+/// 映射内存中的一页，读取时不复制数据；页面损坏时返回错误而不是 panic
+#[derive(Clone, Copy)]
+pub struct PageView<'a>(&'a [u8]);
+
+impl<'a> PageView<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self(buf)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.0[0] == LEAF
+    }
+
+    /// 叶子页的条目数或分支页的 key 数
+    pub fn count(&self) -> usize {
+        u16::from_be_bytes([self.0[1], self.0[2]]) as usize
+    }
+
+    fn bytes(&self, pos: usize, len: usize) -> io::Result<&'a [u8]> {
+        self.0.get(pos..pos + len).ok_or_else(|| corrupted(format!("page offset {pos} out of range")))
+    }
+
+    fn u16_at(&self, pos: usize) -> io::Result<usize> {
+        Ok(u16::from_be_bytes(self.bytes(pos, 2)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64_at(&self, pos: usize) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(pos, 8)?.try_into().unwrap()))
+    }
+
+    /// 返回数据和下一个数据的位置
+    fn item_at(&self, pos: usize) -> io::Result<(ItemRef<'a>, usize)> {
+        let tag = self.bytes(pos, 1)?[0];
+        let len = u32::from_be_bytes(self.bytes(pos + 1, 4)?.try_into().unwrap());
+        match tag {
+            0 => Ok((ItemRef::Inline(self.bytes(pos + ITEM_HEADER, len as usize)?), pos + ITEM_HEADER + len as usize)),
+            1 => Ok((ItemRef::Overflow { page: self.u64_at(pos + ITEM_HEADER)?, len }, pos + ITEM_HEADER + 8)),
+            _ => Err(corrupted(format!("invalid item tag {tag}"))),
+        }
+    }
+
+    fn check(&self, kind: u8) -> io::Result<()> {
+        if self.0[0] != kind {
+            return Err(corrupted(format!("expected page type {kind}, found {}", self.0[0])));
+        }
+        Ok(())
+    }
+
+    pub fn leaf_entry(&self, i: usize) -> io::Result<(ItemRef<'a>, ItemRef<'a>)> {
+        let (key, next) = self.item_at(self.u16_at(HEADER + 2 * i)?)?;
+        let (value, _) = self.item_at(next)?;
+        Ok((key, value))
+    }
+
+    pub fn branch_child(&self, i: usize) -> io::Result<PageId> {
+        self.u64_at(HEADER + 8 * i)
+    }
+
+    pub fn branch_key(&self, i: usize) -> io::Result<ItemRef<'a>> {
+        let offset = self.u16_at(HEADER + 8 * (self.count() + 1) + 2 * i)?;
+        Ok(self.item_at(offset)?.0)
+    }
+
+    pub fn decode(&self) -> io::Result<Node> {
+        let count = self.count();
+        if self.is_leaf() {
+            let entries = (0..count)
+                .map(|i| self.leaf_entry(i).map(|(k, v)| (k.to_item(), v.to_item())))
+                .collect::<io::Result<_>>()?;
+            return Ok(Node::Leaf(entries));
+        }
+        self.check(BRANCH)?;
+        let keys = (0..count).map(|i| self.branch_key(i).map(ItemRef::to_item)).collect::<io::Result<_>>()?;
+        let children = (0..=count).map(|i| self.branch_child(i)).collect::<io::Result<_>>()?;
+        Ok(Node::Branch { keys, children })
+    }
+
+    /// 溢出页的下一页和数据
+    pub fn overflow(&self) -> io::Result<(PageId, &'a [u8])> {
+        self.check(OVERFLOW)?;
+        let next = self.u64_at(1)?;
+        let len = self.u16_at(9)?;
+        Ok((next, self.bytes(OVERFLOW_HEADER, len)?))
+    }
+}
+
+/// 比较函数可能失败的二分查找，返回值同 slice::binary_search
+pub fn binary_search(len: usize, mut cmp: impl FnMut(usize) -> io::Result<Ordering>) -> io::Result<Result<usize, usize>> {
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match cmp(mid)? {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Greater => hi = mid,
+            Ordering::Equal => return Ok(Ok(mid)),
+        }
+    }
+    Ok(Err(lo))
+}
+
+/// 分支页中包含 key 的子节点：等于 keys[i] 时属于 children[i + 1]
+pub fn child_index(found: Result<usize, usize>) -> usize {
+    match found {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::btree::page::{overflow_page, Item, ItemRef, Node, PageView, MAX_INLINE, PAGE_CAPACITY};
+
+    #[test]
+    fn node_should_round_trip() {
+        let big = vec![b'x'; MAX_INLINE + 1];
+        let leaf = Node::Leaf(vec![
+            (Item::Inline(b"a".to_vec()), Item::Inline(b"1".to_vec())),
+            (Item::Inline(b"b".to_vec()), Item::Inline(big.clone())),
+        ]);
+        let mut spilled = vec![];
+        let page = leaf.encode(&mut |bytes| {
+            spilled.push(bytes.to_vec());
+            Ok(42)
+        }).unwrap();
+        assert_eq!(PAGE_CAPACITY, page.len());
+        assert_eq!(vec![big.clone()], spilled);
+
+        let view = PageView::new(&page);
+        assert!(view.is_leaf());
+        assert_eq!(2, view.count());
+        assert!(matches!(view.leaf_entry(1).unwrap(), (ItemRef::Inline(b"b"), ItemRef::Overflow { page: 42, len }) if len as usize == big.len()));
+
+        let branch = Node::Branch { keys: vec![Item::Inline(b"m".to_vec())], children: vec![3, 4] };
+        let page = branch.encode(&mut |_| unreachable!()).unwrap();
+        let Node::Branch { keys, children } = PageView::new(&page).decode().unwrap() else { panic!() };
+        assert_eq!(vec![Item::Inline(b"m".to_vec())], keys);
+        assert_eq!(vec![3, 4], children);
+
+        let page = overflow_page(7, b"data");
+        assert_eq!((7, &b"data"[..]), PageView::new(&page).overflow().unwrap());
+        // 类型不符时返回错误
+        assert!(PageView::new(&page).decode().is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+
+use crate::storage::btree::page::{binary_search, child_index, overflow_page, Item, Node, PageId, PageView, OVERFLOW_CAPACITY, PAGE_CAPACITY};
+use crate::storage::btree::Snapshot;
+
+/// 写事务
+///
+/// 修改已经提交的页面时先复制到新分配的页面（dirty），再逐层修改父节点直到根节点，
+/// 已经提交的页面不会被覆盖，读操作在事务提交前后都能看到完整的树。
+pub struct Txn<'a> {
+    snapshot: &'a Snapshot,
+    /// 可以重新使用的页面
+    free: &'a mut Vec<PageId>,
+    /// 文件中已经使用的页数
+    pages: u64,
+    dirty: HashMap<PageId, Node>,
+    /// 本次事务中不再使用的已提交页面，旧版本的读操作结束后才能重新使用
+    freed: Vec<PageId>,
+    /// 命名空间到 (根节点, key 数量) 的 B+ 树
+    catalog: PageId,
+}
+
+/// 分裂后的节点：最小的 key（第一个节点为 None）和页号
+type Part = (Option<Item>, PageId);
+
+/// 提交事务需要写入的内容
+pub struct Commit {
+    pub pages: Vec<(PageId, Vec<u8>)>,
+    pub freed: Vec<PageId>,
+    pub catalog: PageId,
+    pub page_count: u64,
+}
+
+impl<'a> Txn<'a> {
+    pub fn new(snapshot: &'a Snapshot, free: &'a mut Vec<PageId>) -> Self {
+        let meta = snapshot.meta;
+        Self { snapshot, free, pages: meta.pages, dirty: HashMap::new(), freed: vec![], catalog: meta.catalog }
+    }
+
+    fn alloc(&mut self) -> PageId {
+        self.free.pop().unwrap_or_else(|| {
+            self.pages += 1;
+            self.pages - 1
+        })
+    }
+
+    /// 修改前取出节点，已提交的节点复制到新的页面
+    fn take(&mut self, id: PageId) -> io::Result<(PageId, Node)> {
+        if let Some(node) = self.dirty.remove(&id) {
+            return Ok((id, node));
+        }
+        let node = PageView::new(&self.snapshot.page(id)?).decode()?;
+        self.freed.push(id);
+        Ok((self.alloc(), node))
+    }
+
+    /// take 返回的页面只在本次事务中使用过，不再需要时可以立即重新使用
+    fn discard(&mut self, id: PageId) {
+        self.free.push(id);
+    }
+
+    fn node(&self, id: PageId) -> io::Result<Cow<'_, Node>> {
+        match self.dirty.get(&id) {
+            Some(node) => Ok(Cow::Borrowed(node)),
+            None => Ok(Cow::Owned(PageView::new(&self.snapshot.page(id)?).decode()?)),
+        }
+    }
+
+    fn bytes<'b>(&self, item: &'b Item) -> io::Result<Cow<'b, [u8]>> {
+        match item {
+            Item::Inline(bytes) => Ok(Cow::Borrowed(bytes)),
+            Item::Overflow { page, len } => Ok(Cow::Owned(self.snapshot.overflow(*page, *len)?)),
+        }
+    }
+
+    fn search(&self, items: &[Item], key: &[u8]) -> io::Result<Result<usize, usize>> {
+        binary_search(items.len(), |i| Ok(self.bytes(&items[i])?.as_ref().cmp(key)))
+    }
+
+    fn search_leaf(&self, entries: &[(Item, Item)], key: &[u8]) -> io::Result<Result<usize, usize>> {
+        binary_search(entries.len(), |i| Ok(self.bytes(&entries[i].0)?.as_ref().cmp(key)))
+    }
+
+    /// 复制数据，分支页中的 key 不和叶子页共用溢出页
+    fn copy(&self, item: &Item) -> io::Result<Item> {
+        Ok(Item::Inline(self.bytes(item)?.into_owned()))
+    }
+
+    fn free_item(&mut self, item: Item) -> io::Result<()> {
+        if let Item::Overflow { mut page, .. } = item {
+            while page != 0 {
+                let next = self.snapshot.next_overflow(page)?;
+                self.freed.push(page);
+                page = next;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, root: PageId, key: &[u8]) -> io::Result<Option<Item>> {
+        let mut id = root;
+        while id != 0 {
+            let node = self.node(id)?;
+            match node.as_ref() {
+                Node::Leaf(entries) => {
+                    return Ok(self.search_leaf(entries, key)?.ok().map(|i| entries[i].1.clone()));
+                }
+                Node::Branch { keys, children } => id = children[child_index(self.search(keys, key)?)],
+            }
+        }
+        Ok(None)
+    }
+
+    /// 写入 key，返回新的根节点以及 key 是否已经存在
+    pub fn insert(&mut self, root: PageId, key: &[u8], value: Item) -> io::Result<(PageId, bool)> {
+        if root == 0 {
+            let id = self.alloc();
+            self.dirty.insert(id, Node::Leaf(vec![(Item::Inline(key.to_vec()), value)]));
+            return Ok((id, false));
+        }
+
+        let (mut parts, existed) = self.insert_into(root, key, value)?;
+        // 根节点分裂后增加一层
+        while parts.len() > 1 {
+            let children = parts.iter().map(|(_, id)| *id).collect();
+            let keys = parts.into_iter().skip(1).filter_map(|(key, _)| key).collect();
+            let id = self.alloc();
+            parts = self.split(id, Node::Branch { keys, children })?;
+        }
+        Ok((parts[0].1, existed))
+    }
+
+    fn insert_into(&mut self, id: PageId, key: &[u8], value: Item) -> io::Result<(Vec<Part>, bool)> {
+        let (id, mut node) = self.take(id)?;
+        let existed = match &mut node {
+            Node::Leaf(entries) => match self.search_leaf(entries, key)? {
+                Ok(i) => {
+                    let old = mem::replace(&mut entries[i].1, value);
+                    self.free_item(old)?;
+                    true
+                }
+                Err(i) => {
+                    entries.insert(i, (Item::Inline(key.to_vec()), value));
+                    false
+                }
+            },
+            Node::Branch { keys, children } => {
+                let i = child_index(self.search(keys, key)?);
+                let (parts, existed) = self.insert_into(children[i], key, value)?;
+                let mut parts = parts.into_iter();
+                children[i] = parts.next().unwrap().1;
+                for (j, (key, child)) in parts.enumerate() {
+                    keys.insert(i + j, key.unwrap());
+                    children.insert(i + j + 1, child);
+                }
+                existed
+            }
+        };
+        Ok((self.split(id, node)?, existed))
+    }
+
+    /// 超过一页时分裂，返回每个节点的最小 key（第一个节点除外）和页号
+    fn split(&mut self, id: PageId, node: Node) -> io::Result<Vec<Part>> {
+        if node.size() <= PAGE_CAPACITY {
+            self.dirty.insert(id, node);
+            return Ok(vec![(None, id)]);
+        }
+
+        let (left, separator, right) = match node {
+            Node::Leaf(mut entries) => {
+                let mid = split_point(entries.iter().map(|(key, value)| 2 + key.size() + value.size()));
+                let right = entries.split_off(mid);
+                let separator = self.copy(&right[0].0)?;
+                (Node::Leaf(entries), separator, Node::Leaf(right))
+            }
+            Node::Branch { mut keys, mut children } => {
+                let mid = split_point(keys.iter().map(|key| 2 + key.size()));
+                let right_keys = keys.split_off(mid + 1);
+                let separator = keys.pop().unwrap();
+                let right_children = children.split_off(mid + 1);
+                (Node::Branch { keys, children }, separator, Node::Branch { keys: right_keys, children: right_children })
+            }
+        };
+
+        let right_id = self.alloc();
+        let mut parts = self.split(id, left)?;
+        let mut right = self.split(right_id, right)?;
+        right[0].0 = Some(separator);
+        parts.extend(right);
+        Ok(parts)
+    }
+
+    /// 删除已经存在的 key，返回新的根节点，树为空时返回 0
+    pub fn remove(&mut self, root: PageId, key: &[u8]) -> io::Result<PageId> {
+        let mut root = self.remove_from(root, key)?.unwrap_or(0);
+        // 只有一个子节点的根节点去掉
+        while root != 0 {
+            let child = match self.node(root)?.as_ref() {
+                Node::Branch { children, .. } if children.len() == 1 => children[0],
+                _ => break,
+            };
+            self.dirty.remove(&root);
+            self.discard(root);
+            root = child;
+        }
+        Ok(root)
+    }
+
+    fn remove_from(&mut self, id: PageId, key: &[u8]) -> io::Result<Option<PageId>> {
+        let (id, mut node) = self.take(id)?;
+        match &mut node {
+            Node::Leaf(entries) => {
+                if let Ok(i) = self.search_leaf(entries, key)? {
+                    let (key, value) = entries.remove(i);
+                    self.free_item(key)?;
+                    self.free_item(value)?;
+                }
+            }
+            Node::Branch { keys, children } => {
+                let i = child_index(self.search(keys, key)?);
+                match self.remove_from(children[i], key)? {
+                    Some(child) => {
+                        children[i] = child;
+                        self.merge(keys, children, i)?;
+                    }
+                    None => {
+                        children.remove(i);
+                        if !keys.is_empty() {
+                            let key = keys.remove(i.saturating_sub(1));
+                            self.free_item(key)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if node.is_empty() {
+            self.discard(id);
+            return Ok(None);
+        }
+        self.dirty.insert(id, node);
+        Ok(Some(id))
+    }
+
+    /// children[i] 变小后尝试和相邻的节点合并
+    fn merge(&mut self, keys: &mut Vec<Item>, children: &mut Vec<PageId>, i: usize) -> io::Result<()> {
+        if children.len() < 2 || self.node(children[i])?.size() > PAGE_CAPACITY / 4 {
+            return Ok(());
+        }
+        let j = if i + 1 < children.len() { i } else { i - 1 };
+        let separator_size = 2 + keys[j].size();
+        if self.node(children[j])?.size() + self.node(children[j + 1])?.size() + separator_size > PAGE_CAPACITY {
+            return Ok(());
+        }
+
+        let (left_id, left) = self.take(children[j])?;
+        let (right_id, right) = self.take(children[j + 1])?;
+        let separator = keys.remove(j);
+        let merged = match (left, right) {
+            (Node::Leaf(mut left), Node::Leaf(right)) => {
+                self.free_item(separator)?;
+                left.extend(right);
+                Node::Leaf(left)
+            }
+            (Node::Branch { keys: mut left_keys, children: mut left_children }, Node::Branch { keys, children }) => {
+                left_keys.push(separator);
+                left_keys.extend(keys);
+                left_children.extend(children);
+                Node::Branch { keys: left_keys, children: left_children }
+            }
+            _ => return Err(crate::storage::corrupted("sibling pages have different types".to_string())),
+        };
+        self.discard(right_id);
+        self.dirty.insert(left_id, merged);
+        children[j] = left_id;
+        children.remove(j + 1);
+        Ok(())
+    }
+
+    /// 释放整棵树
+    pub fn free_tree(&mut self, id: PageId) -> io::Result<()> {
+        if id == 0 {
+            return Ok(());
+        }
+        let node = match self.dirty.remove(&id) {
+            Some(node) => {
+                self.discard(id);
+                node
+            }
+            None => {
+                self.freed.push(id);
+                PageView::new(&self.snapshot.page(id)?).decode()?
+            }
+        };
+        match node {
+            Node::Leaf(entries) => {
+                for (key, value) in entries {
+                    self.free_item(key)?;
+                    self.free_item(value)?;
+                }
+            }
+            Node::Branch { keys, children } => {
+                for key in keys {
+                    self.free_item(key)?;
+                }
+                for child in children {
+                    self.free_tree(child)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 用 key 为 current 的密钥重写目录树和所有命名空间的树中使用其他密钥的页面，
+    /// 最多复制 budget 个页面，返回是否有修改
+    pub fn rewrite_all(&mut self, current: u32, budget: &mut usize) -> io::Result<bool> {
+        // 先重写目录树，之后更新命名空间时修改的是已经复制的页面
+        let mut changed = false;
+        if let Some(catalog) = self.rewrite(self.catalog, current, budget)? {
+            self.catalog = catalog;
+            changed = true;
+        }
+        for (ns, root, count) in self.snapshot.namespaces()? {
+            if let Some(root) = self.rewrite(root, current, budget)? {
+                self.set_namespace(&ns, root, count)?;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// 复制需要重写的页面以及它们的所有祖先，子树没有需要重写的页面时返回 None
+    fn rewrite(&mut self, id: PageId, current: u32, budget: &mut usize) -> io::Result<Option<PageId>> {
+        if id == 0 || *budget == 0 {
+            return Ok(None);
+        }
+        let mut changed = self.snapshot.key_id(id)? != current;
+        let node = match PageView::new(&self.snapshot.page(id)?).decode()? {
+            Node::Leaf(mut entries) => {
+                for (key, value) in &mut entries {
+                    changed |= self.rewrite_item(key, current)?;
+                    changed |= self.rewrite_item(value, current)?;
+                }
+                Node::Leaf(entries)
+            }
+            Node::Branch { mut keys, mut children } => {
+                for key in &mut keys {
+                    changed |= self.rewrite_item(key, current)?;
+                }
+                for child in &mut children {
+                    if let Some(new) = self.rewrite(*child, current, budget)? {
+                        *child = new;
+                        changed = true;
+                    }
+                }
+                Node::Branch { keys, children }
+            }
+        };
+        if !changed {
+            return Ok(None);
+        }
+
+        *budget = budget.saturating_sub(1);
+        self.freed.push(id);
+        let new = self.alloc();
+        self.dirty.insert(new, node);
+        Ok(Some(new))
+    }
+
+    /// 溢出页链表中有需要重写的页面时读出数据，提交时写入新的溢出页
+    fn rewrite_item(&mut self, item: &mut Item, current: u32) -> io::Result<bool> {
+        let Item::Overflow { mut page, .. } = *item else {
+            return Ok(false);
+        };
+        let mut stale = false;
+        while page != 0 && !stale {
+            stale = self.snapshot.key_id(page)? != current;
+            page = self.snapshot.next_overflow(page)?;
+        }
+        if !stale {
+            return Ok(false);
+        }
+        let bytes = self.bytes(item)?.into_owned();
+        let old = mem::replace(item, Item::Inline(bytes));
+        self.free_item(old)?;
+        Ok(true)
+    }
+
+    /// 命名空间的根节点和 key 数量
+    pub fn namespace(&self, ns: &str) -> io::Result<Option<(PageId, u64)>> {
+        match self.get(self.catalog, ns.as_bytes())? {
+            Some(item) => decode_namespace(&self.bytes(&item)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// 更新命名空间，key 数量为 0 时删除
+    pub fn set_namespace(&mut self, ns: &str, root: PageId, count: u64) -> io::Result<()> {
+        if count == 0 {
+            debug_assert_eq!(0, root);
+            if self.get(self.catalog, ns.as_bytes())?.is_some() {
+                self.catalog = self.remove(self.catalog, ns.as_bytes())?;
+            }
+            return Ok(());
+        }
+        let value = [root.to_be_bytes(), count.to_be_bytes()].concat();
+        self.catalog = self.insert(self.catalog, ns.as_bytes(), Item::Inline(value))?.0;
+        Ok(())
+    }
+
+    /// 编码修改过的节点，超过 MAX_INLINE 的数据写入新分配的溢出页
+    pub fn finish(mut self) -> io::Result<Commit> {
+        let dirty = mem::take(&mut self.dirty);
+        let mut pages = Vec::with_capacity(dirty.len());
+        for (id, node) in &dirty {
+            let page = node.encode(&mut |bytes| Ok(self.spill(bytes, &mut pages)))?;
+            pages.push((*id, page));
+        }
+        pages.sort_by_key(|(id, _)| *id);
+        Ok(Commit { pages, freed: self.freed, catalog: self.catalog, page_count: self.pages })
+    }
+
+    fn spill(&mut self, bytes: &[u8], pages: &mut Vec<(PageId, Vec<u8>)>) -> PageId {
+        let chunks: Vec<_> = bytes.chunks(OVERFLOW_CAPACITY).collect();
+        let ids: Vec<_> = chunks.iter().map(|_| self.alloc()).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = ids.get(i + 1).copied().unwrap_or(0);
+            pages.push((ids[i], overflow_page(next, chunk)));
+        }
+        ids[0]
+    }
+}
+
+/// 命名空间的值为根节点和 key 数量
+pub fn decode_namespace(bytes: &[u8]) -> io::Result<(PageId, u64)> {
+    if bytes.len() != 16 {
+        return Err(crate::storage::corrupted(format!("invalid namespace entry of {} bytes", bytes.len())));
+    }
+    Ok((u64::from_be_bytes(bytes[..8].try_into().unwrap()), u64::from_be_bytes(bytes[8..].try_into().unwrap())))
+}
+
+/// 按编码后的大小找到分裂的位置，两边都不为空
+fn split_point(sizes: impl Iterator<Item = usize>) -> usize {
+    let sizes: Vec<_> = sizes.collect();
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut total = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        total += size;
+        if total >= half {
+            return (i + 1).clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() - 1
+}
@@ -0,0 +1,353 @@
+//! 存储引擎的一致性测试
+//!
+//! 自定义的 [Storage] 实现在自己的测试中调用 [run]，持久化的引擎调用 [run_persistent]，
+//! 与内置引擎使用完全相同的检查。只在测试或者开启 `testing` feature 时编译：
+//!
+//! ```ignore
+//! #[test]
+//! fn conformance() {
+//!     kv_server::storage::conformance::run(MyStorage::new);
+//! }
+//! ```
+//!
+//! 每项检查使用单独的命名空间，开始前先清空该命名空间，持久化引擎中已有的其他数据不影响结果。
+//! 检查失败时 panic。
+
+use std::collections::BTreeMap;
+use std::thread;
+
+use kv_core::domain::KV;
+
+use crate::storage::Storage;
+
+/// 并发检查的线程数
+const THREADS: usize = 8;
+
+/// 并发检查中每个线程的操作次数
+const OPS_PER_THREAD: usize = 500;
+
+/// 大值检查中 value 的大小
+const LARGE_VALUE_SIZE: usize = 4 * 1024 * 1024;
+
+/// 运行所有不需要重新打开存储的检查，每项检查调用一次 open 得到存储
+pub fn run<S: Storage + Sync>(mut open: impl FnMut() -> S) {
+    basic(&open());
+    overwrite(&open());
+    mget_order(&open());
+    delete_missing(&open());
+    namespaces(&open());
+    scan(&open());
+    snapshot(&open());
+    large_values(&open());
+    concurrency(&open());
+}
+
+/// 运行所有检查，open 每次打开同一份持久化的数据
+pub fn run_persistent<S: Storage + Sync>(mut open: impl FnMut() -> S) {
+    run(&mut open);
+    persistence(open);
+}
+
+/// get、mget、set、del 的基本语义
+pub fn basic(store: &impl Storage) {
+    let ns = prepare(store, "conformance.basic");
+
+    // 空的命名空间
+    assert_eq!(Ok(vec![]), store.get(ns, "k1"));
+    assert_eq!(Ok(vec![]), store.mget(ns, &keys(&["k1", "k2"])));
+
+    // 空的 mset 不产生命名空间
+    assert!(store.mset(ns, vec![]).is_ok());
+    assert_absent(store, ns);
+
+    // 插入单个值
+    assert!(store.set(ns, String::from("k1"), String::from("v1")).is_ok());
+    assert_eq!(Ok(strings(&["v1"])), store.get(ns, "k1"));
+    assert_eq!(Ok(strings(&["v1"])), store.mget(ns, &keys(&["k1", "k2"])));
+
+    // 插入多个值
+    assert!(store.mset(ns, vec![kv("k2", "v2"), kv("k3", "v3")]).is_ok());
+    assert_eq!(Ok(strings(&["v1", "v2", "v3"])), store.mget(ns, &keys(&["k1", "k2", "k3"])));
+    assert_count(store, ns, 3);
+
+    // 删除
+    assert!(store.del(ns, &keys(&["k1", "k2", "k3"])).is_ok());
+    assert_eq!(Ok(vec![]), store.get(ns, "k1"));
+    assert_eq!(Ok(vec![]), store.mget(ns, &keys(&["k1", "k2", "k3"])));
+    assert_deleted(store, ns);
+}
+
+/// 写入已经存在的 key 时覆盖旧值，不增加 key 的数量
+pub fn overwrite(store: &impl Storage) {
+    let ns = prepare(store, "conformance.overwrite");
+
+    store.set(ns, String::from("k1"), String::from("v1")).unwrap();
+    store.set(ns, String::from("k1"), String::from("v2")).unwrap();
+    assert_eq!(Ok(strings(&["v2"])), store.get(ns, "k1"));
+
+    // 同一次 mset 中重复的 key 以最后一个为准
+    store.mset(ns, vec![kv("k1", "v3"), kv("k2", "a"), kv("k2", "b")]).unwrap();
+    assert_eq!(Ok(strings(&["v3", "b"])), store.mget(ns, &keys(&["k1", "k2"])));
+
+    // 覆盖为空字符串不等于删除
+    store.set(ns, String::from("k1"), String::new()).unwrap();
+    assert_eq!(Ok(strings(&[""])), store.get(ns, "k1"));
+    assert_count(store, ns, 2);
+}
+
+/// mget 按请求中 key 的顺序返回，不存在的 key 不返回值，重复的 key 返回多次
+pub fn mget_order(store: &impl Storage) {
+    let ns = prepare(store, "conformance.mget_order");
+
+    store.mset(ns, vec![kv("a", "1"), kv("b", "2"), kv("c", "3")]).unwrap();
+    assert_eq!(Ok(strings(&["3", "1", "2"])), store.mget(ns, &keys(&["c", "a", "b"])));
+    assert_eq!(Ok(strings(&["3", "1"])), store.mget(ns, &keys(&["c", "missing", "a"])));
+    assert_eq!(Ok(strings(&["2", "2"])), store.mget(ns, &keys(&["b", "b"])));
+    assert_eq!(Ok(vec![]), store.mget(ns, &[]));
+}
+
+/// 删除不存在的 key 不报错，也不影响其他 key
+pub fn delete_missing(store: &impl Storage) {
+    let ns = prepare(store, "conformance.delete_missing");
+
+    assert!(store.del(ns, &keys(&["missing"])).is_ok());
+    assert!(store.del("conformance.missing_namespace", &keys(&["missing"])).is_ok());
+
+    store.set(ns, String::from("k1"), String::from("v1")).unwrap();
+    assert!(store.del(ns, &keys(&["missing", "k2"])).is_ok());
+    assert_eq!(Ok(strings(&["v1"])), store.get(ns, "k1"));
+
+    // 重复删除
+    assert!(store.del(ns, &keys(&["k1", "k1"])).is_ok());
+    assert!(store.del(ns, &keys(&["k1"])).is_ok());
+    assert_eq!(Ok(vec![]), store.get(ns, "k1"));
+    assert!(store.del(ns, &[]).is_ok());
+}
+
+/// 不同命名空间中的同名 key 互相独立，flush 只删除一个命名空间
+pub fn namespaces(store: &impl Storage) {
+    let a = prepare(store, "conformance.namespaces.a");
+    let b = prepare(store, "conformance.namespaces.b");
+
+    store.set(a, String::from("k1"), String::from("a1")).unwrap();
+    store.set(a, String::from("k2"), String::from("a2")).unwrap();
+    store.set(b, String::from("k1"), String::from("b1")).unwrap();
+
+    assert_eq!(Ok(strings(&["a1"])), store.get(a, "k1"));
+    assert_eq!(Ok(strings(&["b1"])), store.get(b, "k1"));
+    assert_eq!(Ok(vec![]), store.get("conformance.namespaces.c", "k1"));
+
+    assert_count(store, a, 2);
+    assert_count(store, b, 1);
+    let stats = store.stats();
+    assert_eq!(stats.keys, stats.namespaces.values().sum::<u64>());
+
+    store.flush(a).unwrap();
+    assert_eq!(Ok(vec![]), store.get(a, "k1"));
+    assert_eq!(Ok(strings(&["b1"])), store.get(b, "k1"));
+    assert_absent(store, a);
+
+    // 删除最后一个 key 后不再统计该命名空间，近似的统计要等到合并之后
+    store.del(b, &keys(&["k1"])).unwrap();
+    assert_deleted(store, b);
+}
+
+/// scan 按字典序分页返回 key
+pub fn scan(store: &impl Storage) {
+    let ns = prepare(store, "conformance.scan");
+
+    for key in ["k3", "k1", "k2", "k4"] {
+        store.set(ns, key.to_string(), String::from("v")).unwrap();
+    }
+    store.set("conformance.scan.other", String::from("k0"), String::from("v")).unwrap();
+
+    let page = |after: Option<&str>, count| store.scan(ns, after, count).unwrap();
+    assert_eq!(strings(&["k1", "k2"]), page(None, 2));
+    assert_eq!(strings(&["k3", "k4"]), page(Some("k2"), 10));
+    assert_eq!(strings(&["k2"]), page(Some("k11"), 1));
+    assert!(page(Some("k4"), 10).is_empty());
+    assert!(page(None, 0).is_empty());
+    assert!(store.scan("conformance.scan.missing", None, 10).unwrap().is_empty());
+
+    store.flush("conformance.scan.other").unwrap();
+}
+
+/// snapshot 按 (命名空间, key) 排序，可以分批读取，之后的修改对已经创建的游标不可见
+pub fn snapshot(store: &impl Storage) {
+    let a = prepare(store, "conformance.snapshot.a");
+    let b = prepare(store, "conformance.snapshot.b");
+
+    store.set(b, String::from("k1"), String::from("b1")).unwrap();
+    store.set(a, String::from("k2"), String::from("a2")).unwrap();
+    store.set(a, String::from("k1"), String::from("a1")).unwrap();
+
+    let snapshot = store.snapshot().unwrap().read_to_end().unwrap();
+    let entries: Vec<(&str, &str, &str)> = snapshot.iter()
+        .filter(|(ns, _)| ns == a || ns == b)
+        .map(|(ns, kv)| (ns.as_str(), kv.key.as_str(), kv.value.as_str()))
+        .collect();
+    assert_eq!(vec![(a, "k1", "a1"), (a, "k2", "a2"), (b, "k1", "b1")], entries);
+    assert!(snapshot.windows(2).all(|w| (&w[0].0, &w[0].1.key) < (&w[1].0, &w[1].1.key)));
+
+    // 每批一条读取的结果和一次读完相同，读取期间的修改不可见
+    let mut cursor = store.snapshot().unwrap();
+    let mut paged = cursor.next_batch(1).unwrap();
+    assert_eq!(1, paged.len());
+    store.flush(a).unwrap();
+    store.set(b, String::from("k0"), String::from("b0")).unwrap();
+    store.set(b, String::from("k1"), String::from("changed")).unwrap();
+    loop {
+        let batch = cursor.next_batch(1).unwrap();
+        if batch.is_empty() {
+            break;
+        }
+        assert_eq!(1, batch.len());
+        paged.extend(batch);
+    }
+    assert_eq!(snapshot, paged);
+    assert!(cursor.next_batch(1).unwrap().is_empty());
+
+    let snapshot = store.snapshot().unwrap().read_to_end().unwrap();
+    let entries: Vec<(&str, &str, &str)> = snapshot.iter()
+        .filter(|(ns, _)| ns == a || ns == b)
+        .map(|(ns, kv)| (ns.as_str(), kv.key.as_str(), kv.value.as_str()))
+        .collect();
+    assert_eq!(vec![(b, "k0", "b0"), (b, "k1", "changed")], entries);
+}
+
+/// 大的 value、较长的 key 以及非 ASCII 字符原样保存
+pub fn large_values(store: &impl Storage) {
+    let ns = prepare(store, "conformance.large_values");
+
+    let value: String = (0..LARGE_VALUE_SIZE).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    store.set(ns, String::from("large"), value.clone()).unwrap();
+    assert_eq!(Ok(vec![value.clone()]), store.get(ns, "large"));
+
+    let key = "k".repeat(64 * 1024);
+    store.set(ns, key.clone(), String::from("long key")).unwrap();
+    assert_eq!(Ok(strings(&["long key"])), store.get(ns, &key));
+
+    let unicode = "键\u{1F600}\n\t\"'\\\0";
+    store.set(ns, unicode.to_string(), unicode.to_string()).unwrap();
+    assert_eq!(Ok(strings(&[unicode])), store.get(ns, unicode));
+    assert_eq!(Ok(vec![value, String::from("long key")]), store.mget(ns, &[String::from("large"), key]));
+}
+
+/// 多个线程同时读写，每个线程写自己的 key，同时竞争写同一个 key
+pub fn concurrency<S: Storage + Sync>(store: &S) {
+    let ns = prepare(store, "conformance.concurrency");
+
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            scope.spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    let key = format!("t{t}.k{i}");
+                    store.set(ns, key.clone(), i.to_string()).unwrap();
+                    assert_eq!(Ok(vec![i.to_string()]), store.get(ns, &key));
+
+                    store.set(ns, String::from("hot"), format!("t{t}")).unwrap();
+                    // 其他线程的写入只会整体可见
+                    let hot = store.get(ns, "hot").unwrap();
+                    assert!(hot.len() == 1 && hot[0].starts_with('t'), "{hot:?}");
+
+                    // 删除一半的 key
+                    if i % 2 == 1 {
+                        store.del(ns, &[key]).unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    let expected = (THREADS * OPS_PER_THREAD / 2 + 1) as u64;
+    assert_count(store, ns, expected);
+    for t in 0..THREADS {
+        assert_eq!(Ok(strings(&["0"])), store.get(ns, &format!("t{t}.k0")));
+        assert_eq!(Ok(vec![]), store.get(ns, &format!("t{t}.k1")));
+    }
+}
+
+/// 关闭后重新打开，已经写入的数据仍然存在，删除的数据不会恢复
+pub fn persistence<S: Storage>(mut open: impl FnMut() -> S) {
+    let ns = "conformance.persistence";
+    let other = "conformance.persistence.flushed";
+
+    let mut expected = BTreeMap::new();
+    {
+        let store = open();
+        store.flush(ns).unwrap();
+        store.flush(other).unwrap();
+
+        store.mset(ns, (0..100).map(|i| kv(&format!("k{i}"), &format!("v{i}"))).collect()).unwrap();
+        store.set(ns, String::from("k0"), String::from("overwritten")).unwrap();
+        store.del(ns, &keys(&["k1"])).unwrap();
+        store.set(other, String::from("k1"), String::from("v1")).unwrap();
+        store.flush(other).unwrap();
+
+        for (_, kv) in store.snapshot().unwrap().read_to_end().unwrap().into_iter().filter(|(n, _)| n == ns) {
+            expected.insert(kv.key, kv.value);
+        }
+    }
+    assert_eq!(99, expected.len());
+
+    // 重新打开两次，检查恢复过程本身不会修改数据
+    for _ in 0..2 {
+        let store = open();
+        assert_eq!(Ok(strings(&["overwritten"])), store.get(ns, "k0"));
+        assert_eq!(Ok(vec![]), store.get(ns, "k1"));
+        assert_eq!(Ok(vec![]), store.get(other, "k1"));
+        assert_count(&store, ns, 99);
+
+        let actual: BTreeMap<String, String> = store.snapshot().unwrap().read_to_end().unwrap().into_iter()
+            .filter(|(n, _)| n == ns)
+            .map(|(_, kv)| (kv.key, kv.value))
+            .collect();
+        assert_eq!(expected, actual);
+    }
+
+    open().flush(ns).unwrap();
+}
+
+/// 检查命名空间的 key 数量，近似的统计只要求不小于实际的数量
+fn assert_count(store: &impl Storage, ns: &str, expected: u64) {
+    let stats = store.stats();
+    let actual = stats.namespaces.get(ns).copied();
+    if stats.approximate {
+        assert!(actual.is_some_and(|actual| actual >= expected), "{ns}: {actual:?} < {expected}");
+    } else {
+        assert_eq!(Some(expected), actual, "{ns}");
+    }
+}
+
+/// 检查命名空间没有被创建或者已经被 flush，所有存储引擎都不再统计该命名空间
+fn assert_absent(store: &impl Storage, ns: &str) {
+    assert_eq!(Ok(vec![]), store.scan(ns, None, 1));
+    assert_eq!(None, store.stats().namespaces.get(ns), "{ns}");
+}
+
+/// 检查命名空间的 key 已经全部删除，近似的统计在合并前可能仍然保留删除前的数量
+fn assert_deleted(store: &impl Storage, ns: &str) {
+    assert_eq!(Ok(vec![]), store.scan(ns, None, 1));
+    let stats = store.stats();
+    if !stats.approximate {
+        assert_eq!(None, stats.namespaces.get(ns), "{ns}");
+    }
+}
+
+/// 清空检查使用的命名空间
+fn prepare<'a>(store: &impl Storage, ns: &'a str) -> &'a str {
+    store.flush(ns).unwrap();
+    ns
+}
+
+fn kv(key: &str, value: &str) -> KV {
+    KV { key: key.to_string(), value: value.to_string() }
+}
+
+fn keys(keys: &[&str]) -> Vec<String> {
+    strings(keys)
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|s| s.to_string()).collect()
+}
@@ -0,0 +1,160 @@
+//! 静态数据加密
+//!
+//! 使用 AES-256-GCM，每次加密生成随机的 96 位 nonce。加密后的数据为与明文等长的密文加上 32 字节的尾部：
+//! 密钥 id（4 字节）、nonce（12 字节）和认证标签（16 字节）。密钥 id 为密钥 SHA-1 的前 4 字节，
+//! 0 保留给没有加密的数据。调用方把数据所在的位置作为附加数据，数据被移动到其他位置后同样无法通过认证。
+
+use std::collections::HashMap;
+use std::io;
+
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
+use sha1::{Digest, Sha1};
+
+use crate::config::EncryptionConfig;
+use crate::storage::integrity_error;
+
+/// 加密后增加的字节数
+pub const OVERHEAD: usize = 32;
+
+const KEY_SIZE: usize = 32;
+
+/// 当前密钥和轮换前的旧密钥
+pub struct Cipher {
+    current: u32,
+    keys: HashMap<u32, Aes256Gcm>,
+}
+
+impl Cipher {
+    /// 第一个密钥用于加密，所有密钥都可以用于解密
+    pub fn new(keys: &[[u8; KEY_SIZE]]) -> io::Result<Self> {
+        let current = keys.first().map(|key| fingerprint(key)).ok_or_else(|| invalid(String::from("no encryption key")))?;
+        let keys = keys.iter().map(|key| (fingerprint(key), Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))).collect();
+        Ok(Self { current, keys })
+    }
+
+    /// 从 key_file 或 key_env 加载密钥，内容为十六进制编码的密钥，多个密钥用换行或逗号分隔
+    pub fn load(config: &EncryptionConfig) -> io::Result<Self> {
+        let content = match (&config.key_file, &config.key_env) {
+            (Some(path), None) => std::fs::read_to_string(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("read encryption key from {}: {e}", path.display())))?,
+            (None, Some(name)) => std::env::var(name).map_err(|e| invalid(format!("read encryption key from env {name}: {e}")))?,
+            _ => return Err(invalid(String::from("exactly one of key_file and key_env should be set"))),
+        };
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> io::Result<Self> {
+        let keys = content
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let mut key = [0; KEY_SIZE];
+                hex::decode_to_slice(s, &mut key).map_err(|e| invalid(format!("invalid encryption key: {e}")))?;
+                Ok(key)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Self::new(&keys)
+    }
+
+    /// 用于加密的密钥 id
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// 使用当前密钥加密，aad 为数据所在的位置
+    pub fn encrypt(&self, mut data: Vec<u8>, aad: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let tag = self.keys[&self.current]
+            .encrypt_in_place_detached(&nonce, aad, &mut data)
+            .expect("data too large to encrypt");
+        data.extend(self.current.to_be_bytes());
+        data.extend(nonce);
+        data.extend(tag);
+        data
+    }
+
+    /// 解密 encrypt 的结果，数据被篡改、位置不符或者密钥没有配置时返回 IntegrityError
+    pub fn decrypt(&self, mut data: Vec<u8>, aad: &[u8]) -> io::Result<Vec<u8>> {
+        let id = key_id(&data).ok_or_else(|| integrity_error(String::from("encrypted data is truncated")))?;
+        let cipher = self.keys.get(&id).ok_or_else(|| integrity_error(format!("unknown encryption key {id:08x}")))?;
+        let trailer = data.split_off(data.len() - OVERHEAD);
+        cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&trailer[4..16]), aad, &mut data, Tag::from_slice(&trailer[16..]))
+            .map_err(|_| integrity_error(String::from("authentication failed")))?;
+        Ok(data)
+    }
+}
+
+/// 加密数据的尾部记录的密钥 id，数据不完整时返回 None
+pub fn key_id(data: &[u8]) -> Option<u32> {
+    let trailer = data.len().checked_sub(OVERHEAD).map(|start| &data[start..])?;
+    Some(u32::from_be_bytes(trailer[..4].try_into().unwrap()))
+}
+
+fn fingerprint(key: &[u8]) -> u32 {
+    let digest = Sha1::digest(key);
+    u32::from_be_bytes(digest[..4].try_into().unwrap()).max(1)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::EncryptionConfig;
+    use crate::storage::crypto::{key_id, Cipher, OVERHEAD};
+
+    const OLD: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const NEW: &str = "f0e0d0c0b0a090807060504030201000f0e0d0c0b0a090807060504030201000";
+
+    #[test]
+    fn data_should_round_trip_and_detect_tampering() {
+        let cipher = Cipher::parse(OLD).unwrap();
+        let sealed = cipher.encrypt(b"secret".to_vec(), b"page 7");
+        assert_eq!(6 + OVERHEAD, sealed.len());
+        assert_eq!(Some(cipher.current()), key_id(&sealed));
+        assert_ne!(&b"secret"[..], &sealed[..6]);
+        assert_eq!(b"secret".to_vec(), cipher.decrypt(sealed.clone(), b"page 7").unwrap());
+
+        // 修改任何一个字节、换一个位置或者截断都无法通过认证
+        for i in [0, 5, 6 + 8, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(cipher.decrypt(tampered, b"page 7").is_err());
+        }
+        assert!(cipher.decrypt(sealed.clone(), b"page 8").is_err());
+        assert!(cipher.decrypt(sealed[..OVERHEAD - 1].to_vec(), b"page 7").is_err());
+    }
+
+    #[test]
+    fn old_keys_should_only_decrypt() {
+        let old = Cipher::parse(OLD).unwrap();
+        let sealed = old.encrypt(b"secret".to_vec(), b"");
+
+        // 轮换后新密钥在前，旧密钥仍然可以解密
+        let rotated = Cipher::parse(&format!("{NEW}\n{OLD}\n")).unwrap();
+        assert_ne!(old.current(), rotated.current());
+        assert_eq!(b"secret".to_vec(), rotated.decrypt(sealed.clone(), b"").unwrap());
+        assert_eq!(Some(rotated.current()), key_id(&rotated.encrypt(vec![], b"")));
+
+        // 只配置新密钥时无法解密旧数据
+        let err = Cipher::parse(NEW).unwrap().decrypt(sealed, b"").unwrap_err();
+        assert!(err.to_string().contains("unknown encryption key"));
+
+        assert!(Cipher::parse("").is_err());
+        assert!(Cipher::parse("abcd").is_err());
+    }
+
+    #[test]
+    fn keys_should_be_loaded_from_env() {
+        std::env::set_var("KV_TEST_ENCRYPTION_KEY", format!("{NEW},{OLD}"));
+        let config = EncryptionConfig { key_file: None, key_env: Some(String::from("KV_TEST_ENCRYPTION_KEY")) };
+        assert_eq!(2, Cipher::load(&config).unwrap().keys.len());
+
+        assert!(Cipher::load(&EncryptionConfig::default()).is_err());
+        let missing = EncryptionConfig { key_file: Some("/nonexistent/kv.key".into()), key_env: None };
+        assert!(Cipher::load(&missing).is_err());
+    }
+}
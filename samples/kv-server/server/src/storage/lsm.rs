@@ -0,0 +1,1342 @@
+//! LSM 存储引擎，适合写多、数据量超过内存的场景
+//!
+//! 写操作先追加到 WAL，再写入内存中有序的 memtable。memtable 超过 memtable_bytes 后切换到新的 memtable
+//! 和新的 WAL，旧的 memtable 在不持有锁的情况下写成第 0 层的 SSTable，期间读写照常进行。第 0 层的 SSTable 之间 key 可能重叠，数量达到 l0_tables 后全部与第 1 层合并；
+//! 第 1 层及以上每层内的 key 互不重叠，大小超过上限后轮流选一个 SSTable 与下一层合并。合并在后台线程中进行。
+//!
+//! 删除写入删除标记，合并到没有更旧数据的层时才真正丢弃。FlushDb 不逐个删除 key，而是把命名空间的代数加一，
+//! 旧代的数据不再可见，在合并时丢弃。
+//!
+//! 数据目录中的文件：
+//! - `MANIFEST`：SSTable 列表以及其他已经持久化的状态
+//! - `<编号>.wal`：WAL，编号不小于 MANIFEST 中记录的 WAL 的都需要回放
+//! - `<编号>.sst`：SSTable
+//!
+//! 配置了 compress_threshold 时，SSTable 中较大的值使用 lz4 压缩，WAL 和 memtable 中的值不压缩。
+//!
+//! 配置了 encryption 时，WAL 的每条记录和 SSTable 的每个块使用当前密钥加密，MANIFEST 不加密。
+//! 轮换密钥后，旧密钥加密或者没有加密的 SSTable 在合并时用当前密钥重写；没有其他合并时逐个重写，
+//! 全部重写后旧密钥可以从配置中去掉。所有 SSTable 都加密后在 MANIFEST 中记录，之后打开时
+//! 没有加密的 SSTable 和 WAL 记录都视为被篡改，不能用明文文件替换加密的数据。
+//!
+//! 每个命名空间的 key 数量是近似值：写操作只查找 memtable，不查找 SSTable，覆盖或者删除 SSTable 中已有的 key
+//! 时数量偏大，但不会偏小。合并时把同一个 key 的多个版本合成一个，同时修正数量。
+//!
+//! 打开时回放 WAL 并写成 SSTable，然后删除 MANIFEST 没有引用的文件。
+//! 写入出错后拒绝之后的写操作，重新打开后从 WAL 恢复。
+
+mod bloom;
+pub(crate) mod fs;
+mod manifest;
+mod sstable;
+mod wal;
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use kv_core::compress::Counter;
+use kv_core::domain::KV;
+use kv_core::error::KvError;
+use tracing::{debug, error, info, warn};
+
+use crate::config::LsmConfig;
+use crate::storage::crypto::Cipher;
+use crate::storage::lsm::fs::{Fs, StdFs};
+use crate::storage::lsm::manifest::Manifest;
+use crate::storage::lsm::sstable::{Table, TableWriter};
+use crate::storage::lsm::wal::{Record, Wal};
+use crate::storage::{corrupted, integrity_error, storage_error, Cursor, Storage, StorageStats};
+
+/// 层数
+const LEVELS: usize = 7;
+
+/// 第 2 层及以上每层的大小上限是上一层的倍数
+const LEVEL_MULTIPLIER: u64 = 10;
+
+/// 第 0 层的 SSTable 数量达到 l0_tables 的这个倍数后，写操作等待合并完成
+const L0_STOP_MULTIPLIER: usize = 3;
+
+/// memtable 中每个条目除 key 和 value 以外的估算开销
+const ENTRY_OVERHEAD: usize = 64;
+
+/// memtable 和 SSTable 中的 key，按 (命名空间, 代数, key) 排序
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Key {
+    pub ns: String,
+    pub gen: u64,
+    pub key: String,
+}
+
+/// None 为删除标记
+pub type Value = Option<String>;
+
+type Source<'a> = Box<dyn Iterator<Item = io::Result<(Key, Value)>> + 'a>;
+
+/// LSM 存储引擎
+pub struct Lsm {
+    inner: Arc<Inner>,
+    // 正在运行的合并线程
+    compactor: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct Inner {
+    fs: Arc<dyn Fs>,
+    config: LsmConfig,
+    state: RwLock<State>,
+    compaction: Mutex<Compaction>,
+    // 写入 SSTable 的值压缩前后的字节数，包括合并时重写的值
+    compression: Arc<Counter>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+#[derive(Default)]
+struct Compaction {
+    running: bool,
+    // 合并过程中又有新的请求，结束后再检查一遍
+    requested: bool,
+}
+
+struct State {
+    memtable: BTreeMap<Key, Value>,
+    // memtable 的估算大小
+    memtable_bytes: usize,
+    // 正在写成 SSTable 的 memtable，同一时间最多一个
+    immutable: Option<Immutable>,
+    wal: Wal,
+    // 每层的 SSTable，第 0 层按写入顺序排列，其他层按 key 排列
+    levels: Vec<Vec<Arc<Table>>>,
+    // 包括 WAL 中的修改在内的最新状态，持久化的部分在 manifest 中
+    generations: BTreeMap<String, u64>,
+    // 近似的 key 数量，等于所有 memtable 和 SSTable 中各个值的权重之和，见 [weight]
+    counts: BTreeMap<String, u64>,
+    manifest: Manifest,
+    // 每层上一次合并的最大 key，下一次从它之后开始
+    cursors: Vec<Option<Key>>,
+    // 写入出错的原因
+    failed: Option<String>,
+}
+
+/// 切换出来等待写成 SSTable 的 memtable
+struct Immutable {
+    memtable: Arc<BTreeMap<Key, Value>>,
+    bytes: usize,
+    // 切换后写入的 WAL，写成 SSTable 后记录到 MANIFEST 中
+    wal: u64,
+    // 切换时的代数和 key 数量，与 SSTable 一起保存
+    generations: BTreeMap<String, u64>,
+    counts: BTreeMap<String, u64>,
+}
+
+/// 合并对 key 数量的修正：(命名空间, 代数) -> 多算的数量
+type Corrections = BTreeMap<(String, u64), u64>;
+
+/// 一次合并：level 层的 inputs 与 target 层的 overlaps 合并后写入 target 层
+struct Task {
+    level: usize,
+    // 通常是下一层，用当前密钥重写时与 level 相同
+    target: usize,
+    // 按新旧排列，较新的在前
+    inputs: Vec<Arc<Table>>,
+    // 按 key 排列
+    overlaps: Vec<Arc<Table>>,
+    largest: Key,
+    // 更低的层中没有重叠的数据，可以丢弃删除标记
+    bottom: bool,
+    // 已经持久化的代数，更旧的数据可以丢弃
+    generations: BTreeMap<String, u64>,
+}
+
+impl Lsm {
+    /// 打开数据目录，不存在时创建
+    pub fn open(config: &LsmConfig) -> io::Result<Self> {
+        Self::open_with(Arc::new(StdFs), config)
+    }
+
+    pub(crate) fn open_with(fs: Arc<dyn Fs>, config: &LsmConfig) -> io::Result<Self> {
+        let dir = &config.path;
+        fs.create_dir_all(dir)?;
+        let cipher = config.encryption.as_ref().map(Cipher::load).transpose()?.map(Arc::new);
+
+        let mut manifest = Manifest::load(&*fs, dir)?.unwrap_or_else(|| Manifest { next_file: 1, ..Default::default() });
+        if manifest.levels.len() > LEVELS {
+            return Err(corrupted(format!("too many levels: {}", manifest.levels.len())));
+        }
+        manifest.levels.resize(LEVELS, vec![]);
+        if manifest.encrypted && cipher.is_none() {
+            return Err(corrupted(String::from("storage is encrypted, but no encryption key is configured")));
+        }
+
+        let mut levels = vec![];
+        for ids in &manifest.levels {
+            let tables = ids.iter()
+                .map(|&id| {
+                    let table = Table::open(id, fs.open(&table_path(dir, id))?, cipher.clone())?;
+                    if manifest.encrypted && table.key.is_none() {
+                        return Err(integrity_error(format!("table {id} is not encrypted")));
+                    }
+                    Ok(Arc::new(table))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            levels.push(tables);
+        }
+
+        // 上次写成 SSTable 之后可能又切换过 WAL，按编号顺序全部回放
+        let mut wals: Vec<u64> = fs.list(dir)?.iter()
+            .filter_map(|name| name.strip_suffix(".wal")?.parse().ok())
+            .filter(|&id| id >= manifest.wal)
+            .collect();
+        wals.sort_unstable();
+        let mut records = vec![];
+        for &id in &wals {
+            records.extend(wal::replay(&*fs, &wal_path(dir, id), id, cipher.as_deref(), manifest.encrypted)?);
+        }
+        // 切换 WAL 时不保存 MANIFEST，其中的下一个文件编号可能已经被使用
+        manifest.next_file = manifest.next_file.max(wals.last().map_or(0, |id| id + 1));
+
+        // 回放期间不写 WAL，回放的数据写成 SSTable 后切换到这个 WAL
+        let wal_id = manifest.allocate_file();
+        let wal = Wal::create(&*fs, &wal_path(dir, wal_id), wal_id, config.sync, cipher.clone())?;
+
+        let mut state = State {
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            immutable: None,
+            wal,
+            levels,
+            generations: manifest.generations.clone(),
+            counts: manifest.counts.clone(),
+            manifest,
+            cursors: vec![None; LEVELS],
+            failed: None,
+        };
+
+        let replayed = records.len();
+        for record in records {
+            state.apply(record);
+        }
+        state.freeze();
+
+        // 回放的数据写成 SSTable 并切换到新的 WAL，旧 WAL 末尾不完整的记录随之丢弃
+        let inner = Inner {
+            fs,
+            config: config.clone(),
+            state: RwLock::new(state),
+            compaction: Mutex::default(),
+            compression: Arc::default(),
+            cipher,
+        };
+        inner.flush_immutable()?;
+        inner.remove_obsolete(&inner.state.read().unwrap().manifest);
+        info!("Opened lsm storage at {}, replayed {replayed} records.", dir.display());
+
+        let lsm = Self { inner: Arc::new(inner), compactor: Mutex::new(None) };
+        lsm.schedule_compaction();
+        Ok(lsm)
+    }
+
+    /// 等待正在进行的合并结束
+    pub fn wait_for_compaction(&self) {
+        if let Some(handle) = self.compactor.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+
+    fn write(&self, record: Record) -> io::Result<()> {
+        let flushed = {
+            let mut state = self.inner.state.write().unwrap();
+            if let Some(reason) = &state.failed {
+                return Err(io::Error::other(format!("storage is read-only after an earlier error: {reason}")));
+            }
+            self.inner.write_locked(&mut state, record).inspect_err(|e| self.inner.fail(&mut state, e))?
+        };
+        // 切换出来的 memtable 由触发切换的写操作写成 SSTable，此时不持有锁。
+        // 记录已经写入 WAL，重新打开时会回放，写 SSTable 失败时这次写操作仍然成功，只拒绝之后的写操作
+        if flushed {
+            if let Err(e) = self.inner.flush_immutable() {
+                self.inner.fail(&mut self.inner.state.write().unwrap(), &e);
+                return Ok(());
+            }
+        }
+
+        let l0 = self.inner.state.read().unwrap().levels[0].len();
+        if l0 >= self.inner.config.l0_tables * L0_STOP_MULTIPLIER {
+            // 合并跟不上写入时等待，避免第 0 层无限增长
+            self.wait_for_compaction();
+        }
+        // 写入 SSTable 后各层的大小和已经持久化的代数都可能变化
+        if flushed {
+            self.schedule_compaction();
+        }
+        Ok(())
+    }
+
+    /// 没有正在运行的合并时启动一个，否则让正在运行的合并结束后再检查一遍
+    fn schedule_compaction(&self) {
+        let mut compactor = self.compactor.lock().unwrap();
+        {
+            let mut compaction = self.inner.compaction.lock().unwrap();
+            if compaction.running {
+                compaction.requested = true;
+                return;
+            }
+            compaction.running = true;
+        }
+
+        if let Some(handle) = compactor.take() {
+            let _ = handle.join();
+        }
+        let inner = self.inner.clone();
+        *compactor = Some(thread::spawn(move || loop {
+            if let Err(e) = inner.compact() {
+                error!("Compaction failed: {e}");
+            }
+
+            let mut compaction = inner.compaction.lock().unwrap();
+            if !mem::take(&mut compaction.requested) {
+                compaction.running = false;
+                break;
+            }
+        }));
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        self.wait_for_compaction();
+    }
+}
+
+impl Inner {
+    /// 返回是否切换了 memtable，切换出来的 memtable 需要写成 SSTable
+    ///
+    /// 只有写入 WAL 失败时返回错误；之后切换 WAL 失败时记录已经生效，存储进入只读状态。
+    fn write_locked(&self, state: &mut State, record: Record) -> io::Result<bool> {
+        // 不修改数据的操作不写入 WAL
+        if !state.changes(&record) {
+            return Ok(false);
+        }
+
+        state.wal.append(&record)?;
+        state.apply(record);
+
+        // 上一个 memtable 还没有写完时继续写入当前的 memtable
+        if state.memtable_bytes < self.config.memtable_bytes || state.immutable.is_some() {
+            return Ok(false);
+        }
+        if let Err(e) = self.switch_memtable(state) {
+            self.fail(state, &e);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn fail(&self, state: &mut State, e: &io::Error) {
+        error!("Write failed, storage is read-only until restart: {e}");
+        state.failed = Some(e.to_string());
+    }
+
+    fn table_writer(&self, id: u64) -> io::Result<TableWriter> {
+        let mut writer = TableWriter::new(self.fs.create(&table_path(&self.config.path, id))?, self.config.block_bytes);
+        if let Some(threshold) = self.config.compress_threshold {
+            writer = writer.with_compression(threshold, self.compression.clone());
+        }
+        if let Some(cipher) = &self.cipher {
+            writer = writer.with_encryption(id, cipher.clone());
+        }
+        Ok(writer)
+    }
+
+    fn open_table(&self, id: u64) -> io::Result<Arc<Table>> {
+        Ok(Arc::new(Table::open(id, self.fs.open(&table_path(&self.config.path, id))?, self.cipher.clone())?))
+    }
+
+    /// 配置了加密，但 SSTable 没有加密或者使用的不是当前密钥
+    fn needs_rewrite(&self, table: &Table) -> bool {
+        self.cipher.as_ref().is_some_and(|cipher| table.key != Some(cipher.current()))
+    }
+
+    /// 配置了加密并且所有 SSTable 都已经加密，当前 WAL 总是用 cipher 创建
+    fn fully_encrypted(&self, levels: &[Vec<Arc<Table>>]) -> bool {
+        self.cipher.is_some() && levels.iter().flatten().all(|table| table.key.is_some())
+    }
+
+    /// 切换到新的 WAL 和空的 memtable，不保存 MANIFEST，打开时回放所有较新的 WAL
+    fn switch_memtable(&self, state: &mut State) -> io::Result<()> {
+        let wal_id = state.manifest.allocate_file();
+        state.wal = Wal::create(&*self.fs, &wal_path(&self.config.path, wal_id), wal_id, self.config.sync, self.cipher.clone())?;
+        state.freeze();
+        Ok(())
+    }
+
+    /// 把切换出来的 memtable 写成第 0 层的 SSTable，写入和 fsync 期间不持有锁
+    fn flush_immutable(&self) -> io::Result<()> {
+        let dir = &self.config.path;
+        let (memtable, id) = {
+            let mut state = self.state.write().unwrap();
+            let Some(immutable) = &state.immutable else {
+                return Ok(());
+            };
+            let memtable = immutable.memtable.clone();
+            let id = (!memtable.is_empty()).then(|| state.manifest.allocate_file());
+            (memtable, id)
+        };
+
+        let table = match id {
+            Some(id) => {
+                let mut writer = self.table_writer(id)?;
+                for (key, value) in memtable.iter() {
+                    writer.add(key, value)?;
+                }
+                writer.finish()?;
+                Some(self.open_table(id)?)
+            }
+            None => None,
+        };
+
+        let mut state = self.state.write().unwrap();
+        let immutable = state.immutable.take().expect("immutable memtable");
+        let mut manifest = state.manifest.clone();
+        if let Some(table) = &table {
+            manifest.levels[0].push(table.id);
+        }
+        let old = mem::replace(&mut manifest.wal, immutable.wal);
+        manifest.generations = immutable.generations.clone();
+        manifest.counts = immutable.counts.clone();
+        manifest.flushed_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default());
+        // 新的 SSTable 已经用当前密钥加密
+        manifest.encrypted |= self.fully_encrypted(&state.levels);
+        if let Err(e) = manifest.save(&*self.fs, dir) {
+            state.immutable = Some(immutable);
+            return Err(e);
+        }
+
+        if let Some(table) = table {
+            debug!("Flushed memtable to table {}, {} bytes.", table.id, table.size);
+            state.levels[0].push(table);
+        }
+        state.manifest = manifest;
+
+        self.remove(&wal_path(dir, old));
+        Ok(())
+    }
+
+    /// 合并直到每层都不超过上限
+    fn compact(&self) -> io::Result<()> {
+        self.drop_stale_tables()?;
+        while let Some(task) = self.pick() {
+            self.run(task)?;
+        }
+        Ok(())
+    }
+
+    fn pick(&self) -> Option<Task> {
+        let state = self.state.read().unwrap();
+
+        // 第 0 层的 SSTable 按写入顺序排列，不能单独重写，需要重写时全部合并到第 1 层
+        let (level, target, inputs) = if state.levels[0].len() >= self.config.l0_tables
+            || state.levels[0].iter().any(|table| self.needs_rewrite(table))
+        {
+            (0, 1, state.levels[0].iter().rev().cloned().collect::<Vec<_>>())
+        } else if let Some(level) = state.oversized_level(&self.config) {
+            let tables = &state.levels[level];
+            // 从上一次合并结束的位置继续，轮流合并每个 SSTable
+            let table = state.cursors[level].as_ref()
+                .and_then(|cursor| tables.iter().find(|table| table.smallest > *cursor))
+                .unwrap_or(&tables[0]);
+            (level, level + 1, vec![table.clone()])
+        } else {
+            // 没有其他合并时逐个重写需要换密钥的 SSTable，结果留在原来的层
+            let (level, table) = (1..LEVELS)
+                .flat_map(|level| state.levels[level].iter().map(move |table| (level, table)))
+                .find(|(_, table)| self.needs_rewrite(table))?;
+            info!("Re-encrypting table {} at level {level} with the current key.", table.id);
+            (level, level, vec![table.clone()])
+        };
+
+        let mut smallest = inputs.iter().map(|table| &table.smallest).min()?.clone();
+        let mut largest = inputs.iter().map(|table| &table.largest).max()?.clone();
+        // 原地重写时输入本身不算重叠
+        let overlaps: Vec<Arc<Table>> = state.levels[target].iter()
+            .filter(|table| table.overlaps(&smallest, &largest) && inputs.iter().all(|input| input.id != table.id))
+            .cloned()
+            .collect();
+        if let (Some(first), Some(last)) = (overlaps.first(), overlaps.last()) {
+            smallest = smallest.min(first.smallest.clone());
+            largest = largest.max(last.largest.clone());
+        }
+
+        let bottom = state.levels[target + 1..].iter().flatten().all(|table| !table.overlaps(&smallest, &largest));
+        Some(Task { level, target, inputs, overlaps, largest, bottom, generations: state.manifest.generations.clone() })
+    }
+
+    fn run(&self, task: Task) -> io::Result<()> {
+        let mut sources: Vec<Source> = task.inputs.iter().map(|table| Box::new(table.iter(None)) as Source).collect();
+        sources.push(Box::new(task.overlaps.iter().flat_map(|table| table.iter(None))));
+
+        let mut outputs = vec![];
+        let mut corrections = Corrections::new();
+        let mut writer: Option<(u64, TableWriter)> = None;
+        let mut merge = Merge::new(sources);
+        while let Some(entry) = merge.next() {
+            let (key, value) = entry?;
+            if key.gen < task.generations.get(&key.ns).copied().unwrap_or_default() {
+                continue;
+            }
+
+            // 同一个 key 的多个版本只保留一个，多算的数量在合并完成后减去
+            let kept = value.is_some() || !task.bottom;
+            let counted: u64 = merge.shadowed.iter().chain([&value]).map(weight).sum();
+            let excess = counted - if kept { weight(&value) } else { 0 };
+            if excess > 0 {
+                *corrections.entry((key.ns.clone(), key.gen)).or_default() += excess;
+            }
+            if !kept {
+                continue;
+            }
+
+            let (_, table) = match &mut writer {
+                Some(writer) => writer,
+                None => {
+                    let id = self.state.write().unwrap().manifest.allocate_file();
+                    writer.insert((id, self.table_writer(id)?))
+                }
+            };
+            table.add(&key, &value)?;
+
+            if table.size() >= self.config.table_bytes {
+                outputs.push(self.finish_table(writer.take())?);
+            }
+        }
+        if writer.is_some() {
+            outputs.push(self.finish_table(writer)?);
+        }
+
+        drop(merge);
+        self.install(task, outputs, corrections)
+    }
+
+    fn finish_table(&self, writer: Option<(u64, TableWriter)>) -> io::Result<Arc<Table>> {
+        let (id, writer) = writer.expect("table writer");
+        writer.finish()?;
+        self.open_table(id)
+    }
+
+    /// 用合并的结果替换输入的 SSTable，MANIFEST 保存成功后删除输入文件
+    fn install(&self, task: Task, outputs: Vec<Arc<Table>>, corrections: Corrections) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        let removed: HashSet<u64> = task.inputs.iter().chain(&task.overlaps).map(|table| table.id).collect();
+        let (level, next) = (task.level, task.target);
+        info!("Compacting {} tables from level {level} into {} tables at level {next}.", removed.len(), outputs.len());
+
+        let mut levels = state.levels.clone();
+        levels[level].retain(|table| !removed.contains(&table.id));
+        levels[next].retain(|table| !removed.contains(&table.id));
+        levels[next].extend(outputs);
+        levels[next].sort_by(|a, b| a.smallest.cmp(&b.smallest));
+
+        self.replace_levels(&mut state, levels, removed, &corrections)?;
+        state.cursors[level] = Some(task.largest);
+        Ok(())
+    }
+
+    /// 删除只包含旧代数据的 SSTable，不需要读取和合并
+    ///
+    /// FlushDb 后其他数据的写入不一定会与旧数据重叠，只靠合并时无法及时回收空间。
+    fn drop_stale_tables(&self) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        let generations = &state.manifest.generations;
+        let removed: HashSet<u64> = state.levels.iter().flatten()
+            .filter(|table| {
+                let gen = generations.get(&table.largest.ns).copied().unwrap_or_default();
+                table.smallest.ns == table.largest.ns && table.largest.gen < gen
+            })
+            .map(|table| table.id)
+            .collect();
+        if removed.is_empty() {
+            return Ok(());
+        }
+        info!("Dropping {} tables of flushed namespaces.", removed.len());
+
+        let mut levels = state.levels.clone();
+        for tables in &mut levels {
+            tables.retain(|table| !removed.contains(&table.id));
+        }
+        self.replace_levels(&mut state, levels, removed, &Corrections::new())
+    }
+
+    /// 保存新的 SSTable 列表和修正后的 key 数量，成功后删除不再引用的文件
+    fn replace_levels(
+        &self,
+        state: &mut State,
+        levels: Vec<Vec<Arc<Table>>>,
+        removed: HashSet<u64>,
+        corrections: &Corrections,
+    ) -> io::Result<()> {
+        let mut manifest = state.manifest.clone();
+        manifest.levels = levels.iter().map(|tables| tables.iter().map(|table| table.id).collect()).collect();
+        manifest.encrypted |= self.fully_encrypted(&levels);
+        correct(&mut manifest.counts, &manifest.generations, corrections);
+        manifest.save(&*self.fs, &self.config.path)?;
+
+        // 合并的输入都早于当前和正在写入的 memtable，它们的数量同样需要修正
+        correct(&mut state.counts, &state.generations, corrections);
+        if let Some(immutable) = &mut state.immutable {
+            correct(&mut immutable.counts, &immutable.generations, corrections);
+        }
+        state.levels = levels;
+        state.manifest = manifest;
+        for id in removed {
+            self.remove(&table_path(&self.config.path, id));
+        }
+        Ok(())
+    }
+
+    /// 删除 MANIFEST 没有引用的文件，它们是崩溃前没有完成的写入或合并留下的
+    fn remove_obsolete(&self, manifest: &Manifest) {
+        let dir = &self.config.path;
+        match self.fs.list(dir) {
+            Ok(names) => {
+                for name in names.iter().filter(|name| manifest.is_obsolete(name)) {
+                    self.remove(&dir.join(name));
+                }
+            }
+            Err(e) => warn!("List {} failed: {e}", dir.display()),
+        }
+    }
+
+    fn remove(&self, path: &Path) {
+        if let Err(e) = self.fs.remove(path) {
+            warn!("Remove {} failed: {e}", path.display());
+        }
+    }
+}
+
+impl State {
+    fn generation(&self, ns: &str) -> u64 {
+        self.generations.get(ns).copied().unwrap_or_default()
+    }
+
+    fn key(&self, ns: &str, key: String) -> Key {
+        Key { ns: ns.to_string(), gen: self.generation(ns), key }
+    }
+
+    /// 依次查找 memtable、第 0 层以及之后的每一层，返回第一个找到的值
+    fn lookup(&self, key: &Key) -> io::Result<Value> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.immutable.as_ref().and_then(|immutable| immutable.memtable.get(key)) {
+            return Ok(value.clone());
+        }
+        for table in self.levels[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        for tables in &self.levels[1..] {
+            let i = tables.partition_point(|table| table.largest < *key);
+            if let Some(table) = tables.get(i) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// 从 start 开始按顺序遍历所有数据，同一个 key 只返回最新的值，包括删除标记
+    fn iter<'a>(&'a self, start: &'a Key) -> Merge<'a> {
+        let immutable = self.immutable.as_ref().map(|immutable| &*immutable.memtable);
+        merge([&self.memtable].into_iter().chain(immutable), &self.levels, start)
+    }
+
+    /// 操作是否可能修改数据，不查找 SSTable
+    fn changes(&self, record: &Record) -> bool {
+        match record {
+            Record::Set { kvs, .. } => !kvs.is_empty(),
+            // 数量是上限，没有统计的命名空间中没有数据
+            Record::Del { ns, keys } => !keys.is_empty() && self.counts.contains_key(ns),
+            Record::Flush { ns } => self.counts.contains_key(ns),
+        }
+    }
+
+    /// 把一条 WAL 记录应用到 memtable，同时更新近似的 key 数量
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Set { ns, kvs } => {
+                for KV { key, value } in kvs {
+                    let key = self.key(&ns, key);
+                    self.insert(key, Some(value));
+                }
+            }
+            Record::Del { ns, keys } => {
+                for key in keys {
+                    let key = self.key(&ns, key);
+                    self.insert(key, None);
+                }
+            }
+            Record::Flush { ns } => {
+                self.counts.remove(&ns);
+                let gen = self.generations.entry(ns.clone()).or_default();
+                *gen += 1;
+
+                // memtable 中旧代的数据可以直接删除，SSTable 中的在合并时删除
+                let start = Key { ns: ns.clone(), gen: 0, key: String::new() };
+                let end = Key { ns, gen: *gen, key: String::new() };
+                let stale: Vec<Key> = self.memtable.range(start..end).map(|(key, _)| key.clone()).collect();
+                for key in stale {
+                    if let Some(value) = self.memtable.remove(&key) {
+                        self.memtable_bytes = self.memtable_bytes.saturating_sub(entry_size(&key, &value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 写入 memtable，key 数量按新旧值的权重之差调整
+    fn insert(&mut self, key: Key, value: Value) {
+        self.memtable_bytes += entry_size(&key, &value);
+        let key_size = entry_size(&key, &None);
+        let added = weight(&value);
+        let ns = key.ns.clone();
+        let removed = match self.memtable.insert(key, value) {
+            Some(old) => {
+                self.memtable_bytes -= key_size + old.as_ref().map_or(0, String::len);
+                weight(&old)
+            }
+            None => 0,
+        };
+
+        let count = self.counts.entry(ns.clone()).or_default();
+        *count = *count + added - removed;
+        if *count == 0 {
+            self.counts.remove(&ns);
+        }
+    }
+
+    /// 把 memtable 切换为正在写成 SSTable 的 memtable，之后的写操作写入当前 WAL 和新的 memtable
+    fn freeze(&mut self) {
+        self.immutable = Some(Immutable {
+            memtable: Arc::new(mem::take(&mut self.memtable)),
+            bytes: mem::take(&mut self.memtable_bytes),
+            wal: self.wal.id,
+            generations: self.generations.clone(),
+            counts: self.counts.clone(),
+        });
+    }
+
+    /// 第一个超过大小上限的层，最后一层没有上限
+    fn oversized_level(&self, config: &LsmConfig) -> Option<usize> {
+        (1..LEVELS - 1).find(|&level| {
+            let size: u64 = self.levels[level].iter().map(|table| table.size).sum();
+            size > config.level_bytes * LEVEL_MULTIPLIER.pow(level as u32 - 1)
+        })
+    }
+}
+
+/// 从 start 开始按顺序遍历 memtables（按新旧排列）和各层的 SSTable
+fn merge<'a>(memtables: impl IntoIterator<Item = &'a BTreeMap<Key, Value>>, levels: &'a [Vec<Arc<Table>>], start: &'a Key) -> Merge<'a> {
+    let mut sources: Vec<Source> = memtables.into_iter()
+        .map(|memtable| Box::new(memtable.range(start..).map(|(k, v)| Ok((k.clone(), v.clone())))) as Source)
+        .collect();
+    for table in levels[0].iter().rev() {
+        sources.push(Box::new(table.iter(Some(start))));
+    }
+    for tables in &levels[1..] {
+        let first = tables.partition_point(|table| table.largest < *start);
+        sources.push(Box::new(tables[first..].iter().flat_map(move |table| table.iter(Some(start)))));
+    }
+    Merge::new(sources)
+}
+
+/// 游标固定的版本：memtable 的副本、正在写入的 memtable 和当时的 SSTable
+///
+/// 合并删除的 SSTable 文件在游标结束前仍然可以通过已经打开的文件读取。
+struct LsmCursor {
+    memtables: Vec<Arc<BTreeMap<Key, Value>>>,
+    levels: Vec<Vec<Arc<Table>>>,
+    generations: BTreeMap<String, u64>,
+    // 下一批从这个 key 开始，None 表示已经读完
+    start: Option<Key>,
+}
+
+impl Cursor for LsmCursor {
+    fn next_batch(&mut self, count: usize) -> Result<Vec<(String, KV)>, KvError> {
+        let Some(start) = self.start.take() else {
+            return Ok(vec![]);
+        };
+
+        let mut entries = vec![];
+        let memtables = self.memtables.iter().map(|memtable| &**memtable);
+        for entry in merge(memtables, &self.levels, &start) {
+            let (key, value) = entry.map_err(storage_error("dump", ""))?;
+            if entries.len() >= count {
+                self.start = Some(key);
+                break;
+            }
+            let gen = self.generations.get(&key.ns).copied().unwrap_or_default();
+            if let Some(value) = value.filter(|_| key.gen == gen) {
+                entries.push((key.ns, KV { key: key.key, value }));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// 多路归并，sources 按新旧排列，同一个 key 只返回最新的来源中的值
+struct Merge<'a> {
+    heads: Vec<Head<'a>>,
+    // 上一次返回的 key 在较旧的来源中被跳过的值
+    shadowed: Vec<Value>,
+}
+
+struct Head<'a> {
+    entry: Option<io::Result<(Key, Value)>>,
+    source: Source<'a>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Self {
+        let heads = sources.into_iter()
+            .map(|mut source| Head { entry: source.next(), source })
+            .collect();
+        Self { heads, shadowed: vec![] }
+    }
+}
+
+impl Head<'_> {
+    fn advance(&mut self) -> Option<io::Result<(Key, Value)>> {
+        mem::replace(&mut self.entry, self.source.next())
+    }
+
+    fn is(&self, key: &Key) -> bool {
+        matches!(&self.entry, Some(Ok((k, _))) if k == key)
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            match &head.entry {
+                None => {}
+                // 出错时直接返回
+                Some(Err(_)) => {
+                    min = Some(i);
+                    break;
+                }
+                Some(Ok((key, _))) => {
+                    let smaller = min.is_none_or(|m| matches!(&self.heads[m].entry, Some(Ok((k, _))) if key < k));
+                    if smaller {
+                        min = Some(i);
+                    }
+                }
+            }
+        }
+
+        self.shadowed.clear();
+        let entry = self.heads[min?].advance()?;
+        if let Ok((key, _)) = &entry {
+            // 跳过较旧的来源中相同的 key
+            for head in &mut self.heads {
+                while head.is(key) {
+                    if let Some(Ok((_, value))) = head.advance() {
+                        self.shadowed.push(value);
+                    }
+                }
+            }
+        }
+        Some(entry)
+    }
+}
+
+impl Storage for Lsm {
+    fn name(&self) -> &'static str {
+        "lsm"
+    }
+
+    fn get(&self, ns: &str, key: &str) -> Result<Vec<String>, KvError> {
+        let state = self.inner.state.read().unwrap();
+        let value = state.lookup(&state.key(ns, key.to_string())).map_err(storage_error("get", key))?;
+        Ok(value.into_iter().collect())
+    }
+
+    fn mget(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let state = self.inner.state.read().unwrap();
+        let mut res = vec![];
+        for key in keys {
+            res.extend(state.lookup(&state.key(ns, key.clone())).map_err(storage_error("mget", key))?);
+        }
+        Ok(res)
+    }
+
+    fn set(&self, ns: &str, key: String, value: String) -> Result<Vec<String>, KvError> {
+        let error = storage_error("set", &key);
+        self.write(Record::Set { ns: ns.to_string(), kvs: vec![KV { key, value }] }).map_err(error)?;
+        Ok(vec![])
+    }
+
+    fn mset(&self, ns: &str, kvs: Vec<KV>) -> Result<Vec<String>, KvError> {
+        let error = storage_error("mset", kvs.first().map_or("", |kv| kv.key.as_str()));
+        self.write(Record::Set { ns: ns.to_string(), kvs }).map_err(error)?;
+        Ok(vec![])
+    }
+
+    fn del(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let error = storage_error("del", keys.first().map_or("", String::as_str));
+        self.write(Record::Del { ns: ns.to_string(), keys: keys.to_vec() }).map_err(error)?;
+        Ok(vec![])
+    }
+
+    fn flush(&self, ns: &str) -> Result<Vec<String>, KvError> {
+        self.write(Record::Flush { ns: ns.to_string() }).map_err(storage_error("flushdb", ""))?;
+        Ok(vec![])
+    }
+
+    fn scan(&self, ns: &str, after: Option<&str>, count: usize) -> Result<Vec<String>, KvError> {
+        let state = self.inner.state.read().unwrap();
+        let start = state.key(ns, after.unwrap_or_default().to_string());
+
+        let mut keys = vec![];
+        for entry in state.iter(&start) {
+            if keys.len() >= count {
+                break;
+            }
+            let (key, value) = entry.map_err(storage_error("scan", after.unwrap_or_default()))?;
+            if key.ns != ns || key.gen != start.gen {
+                break;
+            }
+            if value.is_some() && Some(key.key.as_str()) != after {
+                keys.push(key.key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cursor>, KvError> {
+        // 只复制当前的 memtable，大小不超过 memtable_bytes，其余部分引用已有的数据
+        let state = self.inner.state.read().unwrap();
+        let mut memtables = vec![Arc::new(state.memtable.clone())];
+        memtables.extend(state.immutable.as_ref().map(|immutable| immutable.memtable.clone()));
+
+        Ok(Box::new(LsmCursor {
+            memtables,
+            levels: state.levels.clone(),
+            generations: state.generations.clone(),
+            start: Some(Key { ns: String::new(), gen: 0, key: String::new() }),
+        }))
+    }
+
+    fn stats(&self) -> StorageStats {
+        let state = self.inner.state.read().unwrap();
+        let tables: usize = state.levels.iter().flatten().map(|table| table.memory_bytes()).sum();
+
+        StorageStats {
+            keys: state.counts.values().sum(),
+            namespaces: state.counts.clone(),
+            approximate: true,
+            memory_bytes: (state.memtable_bytes + state.immutable.as_ref().map_or(0, |immutable| immutable.bytes) + tables) as u64,
+            last_snapshot: state.manifest.flushed_at,
+            wal_bytes: Some(state.wal.size()),
+            cache: None,
+            compression: self.inner.config.compress_threshold.map(|_| self.inner.compression.info()),
+        }
+    }
+}
+
+/// 值在 key 数量中的权重：值为 1，删除标记为 0
+///
+/// 同一个 key 在 memtable 和各个 SSTable 中可能有多个版本，数量按所有版本的权重之和计算，
+/// 因此不会小于实际的数量，合并只保留一个版本时减去多算的部分。
+fn weight(value: &Value) -> u64 {
+    value.is_some() as u64
+}
+
+/// 从代数相同的命名空间的数量中减去合并时发现的多算的部分
+fn correct(counts: &mut BTreeMap<String, u64>, generations: &BTreeMap<String, u64>, corrections: &Corrections) {
+    for ((ns, gen), excess) in corrections {
+        if generations.get(ns).copied().unwrap_or_default() != *gen {
+            continue;
+        }
+        if let Some(count) = counts.get_mut(ns) {
+            *count = count.saturating_sub(*excess);
+            if *count == 0 {
+                counts.remove(ns);
+            }
+        }
+    }
+}
+
+fn entry_size(key: &Key, value: &Value) -> usize {
+    key.ns.len() + key.key.len() + value.as_ref().map_or(0, String::len) + ENTRY_OVERHEAD
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:06}.sst"))
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:06}.wal"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use kv_core::domain::KV;
+
+    use kv_core::error::KvError;
+
+    use crate::config::{EncryptionConfig, LsmConfig};
+    use crate::storage::lsm::fs::{FaultyFs, Fs};
+    use crate::storage::lsm::wal::{Record, Wal};
+    use crate::storage::lsm::{table_path, wal_path, Lsm};
+    use crate::storage::{storage_error, Storage};
+
+    /// 很小的 memtable 和层大小，少量写入就会触发写入 SSTable 和多层合并
+    fn config() -> LsmConfig {
+        LsmConfig {
+            path: PathBuf::from("/lsm"),
+            memtable_bytes: 2048,
+            sync: true,
+            block_bytes: 256,
+            table_bytes: 2048,
+            l0_tables: 2,
+            level_bytes: 2048,
+            compress_threshold: None,
+            encryption: None,
+        }
+    }
+
+    fn kv(key: usize, value: usize) -> KV {
+        KV { key: format!("k{key:03}"), value: format!("v{value}") }
+    }
+
+    /// 确定的写入序列，包括覆盖写、删除和清空命名空间
+    fn records() -> Vec<Record> {
+        (0..400)
+            .map(|i| {
+                let ns = if i % 3 == 0 { "b" } else { "a" }.to_string();
+                match i % 10 {
+                    0..=4 => Record::Set { ns, kvs: vec![kv(i * 7 % 80, i)] },
+                    5 | 6 => Record::Set { ns, kvs: (0..3).map(|j| kv((i + j * 13) % 80, i)).collect() },
+                    7 | 8 => Record::Del { ns, keys: vec![format!("k{:03}", i * 11 % 80)] },
+                    _ if i % 70 == 9 => Record::Flush { ns },
+                    _ => Record::Set { ns, kvs: vec![kv(i % 80, i)] },
+                }
+            })
+            .collect()
+    }
+
+    /// 参考模型：(命名空间, key) -> value
+    fn apply(model: &mut BTreeMap<(String, String), String>, record: &Record) {
+        match record {
+            Record::Set { ns, kvs } => {
+                for kv in kvs {
+                    model.insert((ns.clone(), kv.key.clone()), kv.value.clone());
+                }
+            }
+            Record::Del { ns, keys } => {
+                for key in keys {
+                    model.remove(&(ns.clone(), key.clone()));
+                }
+            }
+            Record::Flush { ns } => model.retain(|(n, _), _| n != ns),
+        }
+    }
+
+    fn contents(store: &Lsm) -> BTreeMap<(String, String), String> {
+        store.snapshot().unwrap().read_to_end().unwrap().into_iter().map(|(ns, kv)| ((ns, kv.key), kv.value)).collect()
+    }
+
+    fn counts(model: &BTreeMap<(String, String), String>) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for (ns, _) in model.keys() {
+            *counts.entry(ns.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// 近似的 key 数量不小于实际的数量
+    fn assert_counts_cover(model: &BTreeMap<(String, String), String>, store: &Lsm) {
+        let actual = store.stats().namespaces;
+        for (ns, count) in counts(model) {
+            assert!(actual.get(&ns).is_some_and(|c| *c >= count), "{ns}: {actual:?} < {count}");
+        }
+    }
+
+    /// 把 memtable 写成 SSTable 并等待合并完成
+    fn settle(store: &Lsm) {
+        store.inner.switch_memtable(&mut store.inner.state.write().unwrap()).unwrap();
+        store.inner.flush_immutable().unwrap();
+        store.schedule_compaction();
+        store.wait_for_compaction();
+    }
+
+    #[test]
+    fn data_should_survive_flushes_and_compactions() {
+        let fs = Arc::new(FaultyFs::new(u64::MAX));
+        let mut model = BTreeMap::new();
+        {
+            let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+            for record in records() {
+                apply(&mut model, &record);
+                store.write(record).unwrap();
+            }
+            store.wait_for_compaction();
+
+            let state = store.inner.state.read().unwrap();
+            assert!(state.levels[0].len() < config().l0_tables);
+            assert!(state.levels[2..].iter().any(|tables| !tables.is_empty()), "data should reach level 2");
+            // 每层（第 0 层除外）内的 SSTable 互不重叠
+            for tables in &state.levels[1..] {
+                assert!(tables.windows(2).all(|w| w[0].largest < w[1].smallest));
+            }
+        }
+
+        for _ in 0..2 {
+            let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+            assert_eq!(model, contents(&store));
+            assert_counts_cover(&model, &store);
+        }
+    }
+
+    #[test]
+    fn compaction_should_drop_deleted_and_flushed_data() {
+        // 各层都不会超过大小上限，旧数据只能通过第 0 层的合并和删除整个 SSTable 回收；
+        // 第 0 层的每个 SSTable 都合并到第 1 层，合并完成后 key 数量是准确的
+        let config = LsmConfig { level_bytes: 1 << 30, l0_tables: 1, ..config() };
+        let store = Lsm::open_with(Arc::new(FaultyFs::new(u64::MAX)), &config).unwrap();
+
+        for i in 0..200 {
+            store.set("a", format!("k{i:03}"), "v".repeat(32)).unwrap();
+            store.set("b", format!("k{i:03}"), "v".repeat(32)).unwrap();
+        }
+        store.flush("a").unwrap();
+        store.del("b", &(0..200).map(|i| format!("k{i:03}")).collect::<Vec<_>>()).unwrap();
+
+        // 继续写入其他命名空间，把旧数据推到更低的层
+        for i in 0..400 {
+            store.set("c", format!("k{:03}", i % 100), i.to_string()).unwrap();
+        }
+        settle(&store);
+
+        let state = store.inner.state.read().unwrap();
+        let entries: Vec<_> = state.levels[1..].iter().flatten()
+            .flat_map(|table| table.iter(None))
+            .map(|entry| entry.unwrap())
+            .collect();
+        assert!(!entries.is_empty());
+        assert!(entries.iter().all(|(key, _)| key.ns == "c"), "stale entries left after compaction");
+        drop(state);
+
+        assert_eq!(Some(&100), store.stats().namespaces.get("c"));
+        assert!(store.scan("a", None, 10).unwrap().is_empty());
+        assert!(store.scan("b", None, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn crash_should_keep_acknowledged_writes() {
+        // 先完整执行一遍，得到写入的总字节数
+        let total = {
+            let fs = FaultyFs::new(u64::MAX);
+            let store = Lsm::open_with(Arc::new(fs.clone()), &config()).unwrap();
+            for record in records() {
+                store.write(record).unwrap();
+            }
+            drop(store);
+            fs.written()
+        };
+
+        let mut logged_before_failure = 0;
+        for budget in (0..total).step_by((total / 150) as usize + 1) {
+            let fs = FaultyFs::new(budget);
+            let mut model = BTreeMap::new();
+            // 返回错误的写操作，崩溃后可能生效也可能不生效
+            let mut pending = None;
+
+            if let Ok(store) = Lsm::open_with(Arc::new(fs.clone()), &config()) {
+                for record in records() {
+                    if store.write(record.clone()).is_err() {
+                        pending = Some(record);
+                        break;
+                    }
+                    apply(&mut model, &record);
+                    // 已经写入 WAL 之后的失败不让这次写操作返回错误
+                    if store.inner.state.read().unwrap().failed.is_some() {
+                        logged_before_failure += 1;
+                    }
+                }
+            }
+
+            let store = Lsm::open_with(Arc::new(fs.crash(budget)), &config())
+                .unwrap_or_else(|e| panic!("reopen failed after writing {budget} bytes: {e}"));
+            let actual = contents(&store);
+
+            let mut applied = model.clone();
+            if let Some(record) = &pending {
+                apply(&mut applied, record);
+            }
+            assert!(actual == model || actual == applied, "lost or corrupted data after writing {budget} bytes");
+            assert_counts_cover(&actual, &store);
+
+            // 恢复后可以继续写入
+            store.set("a", String::from("after"), String::from("crash")).unwrap();
+            assert_eq!(Ok(vec![String::from("crash")]), store.get("a", "after"));
+        }
+        assert!(logged_before_failure > 0, "no write failed after reaching the WAL");
+    }
+
+    #[test]
+    fn keys_should_be_rotated_by_compaction() {
+        const ENV: &str = "KV_TEST_LSM_ENCRYPTION_KEY";
+        let (old, new) = ("11".repeat(32), "22".repeat(32));
+        let encrypted = LsmConfig { encryption: Some(EncryptionConfig { key_file: None, key_env: Some(ENV.to_string()) }), ..config() };
+        let fs = Arc::new(FaultyFs::new(u64::MAX));
+        let mut model = BTreeMap::new();
+        {
+            let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+            for record in records() {
+                apply(&mut model, &record);
+                store.write(record).unwrap();
+            }
+        }
+
+        // 开启加密后没有加密的数据仍然可以读取，合并后全部用当前密钥重写；轮换密钥后同样如此
+        for keys in [old.clone(), format!("{new},{old}")] {
+            std::env::set_var(ENV, &keys);
+            let store = Lsm::open_with(fs.clone(), &encrypted).unwrap();
+            store.wait_for_compaction();
+            let current = store.inner.cipher.as_ref().unwrap().current();
+            let state = store.inner.state.read().unwrap();
+            assert!(state.levels.iter().flatten().all(|table| table.key == Some(current)), "tables left with an old key");
+            drop(state);
+            assert_eq!(model, contents(&store));
+        }
+
+        // 旧密钥已经不再需要；没有密钥或者只有旧密钥时无法打开
+        std::env::set_var(ENV, &new);
+        assert_eq!(model, contents(&Lsm::open_with(fs.clone(), &encrypted).unwrap()));
+        assert!(Lsm::open_with(fs.clone(), &config()).is_err());
+        std::env::set_var(ENV, &old);
+        let err = Lsm::open_with(fs.clone(), &encrypted).err().unwrap();
+        assert!(matches!(storage_error("open", "")(err), KvError::IntegrityError(message) if message.contains("unknown encryption key")));
+    }
+
+    #[test]
+    fn plaintext_should_be_rejected_after_encryption() {
+        const ENV: &str = "KV_TEST_LSM_PLAINTEXT_KEY";
+        std::env::set_var(ENV, "33".repeat(32));
+        let encrypted = LsmConfig { encryption: Some(EncryptionConfig { key_file: None, key_env: Some(ENV.to_string()) }), ..config() };
+        let fs = Arc::new(FaultyFs::new(u64::MAX));
+        let dir = config().path;
+
+        // 开启加密前写入的 SSTable
+        let plain = {
+            let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+            for record in records() {
+                store.write(record).unwrap();
+            }
+            let state = store.inner.state.read().unwrap();
+            let table = state.levels.iter().flatten().next().unwrap();
+            fs.read(&table_path(&dir, table.id)).unwrap()
+        };
+
+        // 合并重写所有 SSTable 后记录为已加密
+        let (table, wal) = {
+            let store = Lsm::open_with(fs.clone(), &encrypted).unwrap();
+            store.wait_for_compaction();
+            let state = store.inner.state.read().unwrap();
+            assert!(state.manifest.encrypted);
+            (state.levels.iter().flatten().next().unwrap().id, state.manifest.wal)
+        };
+
+        let rejected = |message: &str| {
+            let err = Lsm::open_with(fs.clone(), &encrypted).err().unwrap();
+            assert!(matches!(storage_error("open", "")(err), KvError::IntegrityError(m) if m.contains(message)));
+        };
+
+        // 用明文的 SSTable 替换加密的 SSTable
+        let original = fs.read(&table_path(&dir, table)).unwrap();
+        fs.create(&table_path(&dir, table)).unwrap().append(&plain).unwrap();
+        rejected(&format!("table {table} is not encrypted"));
+        fs.create(&table_path(&dir, table)).unwrap().append(&original).unwrap();
+
+        // 在 WAL 中写入明文记录
+        let mut plain_wal = Wal::create(&*fs, &wal_path(&dir, wal), wal, true, None).unwrap();
+        plain_wal.append(&Record::Flush { ns: String::from("a") }).unwrap();
+        rejected("is not encrypted");
+
+        // 去掉密钥后无法打开
+        assert!(Lsm::open_with(fs.clone(), &config()).is_err());
+    }
+
+    #[test]
+    fn counts_should_be_reconciled_by_compaction() {
+        let config = LsmConfig { level_bytes: 1 << 30, l0_tables: 1, ..config() };
+        let fs = Arc::new(FaultyFs::new(u64::MAX));
+        let mut model = BTreeMap::new();
+        {
+            let store = Lsm::open_with(fs.clone(), &config).unwrap();
+            // 覆盖和删除分布在不同的 SSTable 中，写入时只能按上限计数
+            for record in records() {
+                apply(&mut model, &record);
+                store.write(record).unwrap();
+            }
+            assert_counts_cover(&model, &store);
+
+            settle(&store);
+            assert_eq!(counts(&model), store.stats().namespaces);
+        }
+
+        // 修正后的数量与 SSTable 一起保存
+        let store = Lsm::open_with(fs.clone(), &config).unwrap();
+        assert_eq!(counts(&model), store.stats().namespaces);
+    }
+
+    #[test]
+    fn memtable_should_be_flushed_without_blocking_writes() {
+        let fs = Arc::new(FaultyFs::new(u64::MAX));
+        let dir = config().path;
+        let wals = |fs: &FaultyFs| fs.list(&dir).unwrap().into_iter().filter(|name| name.ends_with(".wal")).count();
+        {
+            let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+            store.set("a", String::from("k1"), String::from("v1")).unwrap();
+
+            // 切换后旧的 memtable 还没有写成 SSTable，读写照常进行
+            store.inner.switch_memtable(&mut store.inner.state.write().unwrap()).unwrap();
+            store.set("a", String::from("k2"), String::from("v2")).unwrap();
+            store.del("a", &[String::from("k1")]).unwrap();
+            assert_eq!(Ok(vec![]), store.get("a", "k1"));
+            assert_eq!(Ok(vec![String::from("v2")]), store.get("a", "k2"));
+            assert_eq!(Ok(vec![String::from("k2")]), store.scan("a", None, 10));
+            assert_eq!(2, wals(&fs));
+        }
+
+        // 写成 SSTable 之前关闭，两个 WAL 都会回放
+        let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+        let expected = BTreeMap::from([((String::from("a"), String::from("k2")), String::from("v2"))]);
+        assert_eq!(expected, contents(&store));
+        assert_eq!(1, wals(&fs));
+
+        store.inner.switch_memtable(&mut store.inner.state.write().unwrap()).unwrap();
+        store.set("a", String::from("k3"), String::from("v3")).unwrap();
+        store.inner.flush_immutable().unwrap();
+        assert!(store.inner.state.read().unwrap().immutable.is_none());
+        assert_eq!(1, wals(&fs));
+        drop(store);
+
+        let store = Lsm::open_with(fs.clone(), &config()).unwrap();
+        assert_eq!(Ok(vec![String::from("v3")]), store.get("a", "k3"));
+        assert_eq!(Ok(vec![String::from("v2")]), store.get("a", "k2"));
+    }
+}
@@ -0,0 +1,77 @@
+/// 布隆过滤器，用于跳过一定不包含某个 key 的 SSTable
+#[derive(Debug)]
+pub struct Bloom {
+    bits: Vec<u8>,
+    // 每个 key 设置的位数
+    hashes: u32,
+}
+
+impl Bloom {
+    /// 根据所有 key 的哈希值构造，误判率约为 0.6185 ^ bits_per_key
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> Self {
+        let bits = (hashes.len() * bits_per_key).max(64);
+        // k = ln2 * m / n 时误判率最低
+        let k = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+
+        let mut bloom = Self { bits: vec![0; bits.div_ceil(8)], hashes: k };
+        for &hash in hashes {
+            for bit in bloom.positions(hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.positions(hash).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// 位数组之后跟 1 个字节的哈希次数
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bits.clone();
+        buf.push(self.hashes as u8);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let (&hashes, bits) = buf.split_last()?;
+        if bits.is_empty() || hashes == 0 {
+            return None;
+        }
+        Some(Self { bits: bits.to_vec(), hashes: hashes as u32 })
+    }
+
+    pub fn size(&self) -> usize {
+        self.bits.len()
+    }
+
+    // 双重哈希：第 i 个位置为 h1 + i * h2
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let bits = self.bits.len() as u64 * 8;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = hash >> 32;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
+
+/// FNV-1a，结果写入文件，不能使用每次运行结果不同的哈希函数
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::lsm::bloom::{hash, Bloom};
+
+    #[test]
+    fn bloom_should_not_have_false_negatives() {
+        let keys: Vec<u64> = (0..1000).map(|i| hash(format!("k{i}").as_bytes())).collect();
+        let bloom = Bloom::decode(&Bloom::build(&keys, 10).encode()).unwrap();
+
+        assert!(keys.iter().all(|&key| bloom.may_contain(key)));
+
+        // 10 位每 key 时误判率约为 1%
+        let false_positives = (1000..11000).filter(|i| bloom.may_contain(hash(format!("k{i}").as_bytes()))).count();
+        assert!(false_positives < 300, "{false_positives}");
+    }
+}
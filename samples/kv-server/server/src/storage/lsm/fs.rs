@@ -0,0 +1,295 @@
+//! LSM 引擎使用的文件系统接口，测试中替换为可以注入故障的内存实现
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// 只追加写入、按位置读取的文件
+pub trait File: Send + Sync {
+    /// 在文件末尾追加数据
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// 把已经写入的数据刷到磁盘
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// 从 offset 开始读满 buf
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn size(&self) -> io::Result<u64>;
+}
+
+pub trait Fs: Send + Sync {
+    /// 创建文件，已经存在时清空
+    fn create(&self, path: &Path) -> io::Result<Box<dyn File>>;
+
+    /// 打开已有的文件
+    fn open(&self, path: &Path) -> io::Result<Box<dyn File>>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// 目录中的文件名
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// 把目录项的修改（创建、重命名、删除）刷到磁盘
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// 读取整个文件
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path)?;
+        let mut buf = vec![0; file.size()? as usize];
+        file.read_at(&mut buf, 0)?;
+        Ok(buf)
+    }
+}
+
+/// 操作系统的文件系统
+pub struct StdFs;
+
+struct StdFile {
+    // 读取时需要 seek，多个线程共享同一个文件
+    file: Mutex<std::fs::File>,
+}
+
+impl File for StdFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.get_mut().unwrap().write_all(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.get_mut().unwrap().sync_data()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.lock().unwrap().metadata()?.len())
+    }
+}
+
+impl Fs for StdFs {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn File>> {
+        std::fs::File::create(path)?;
+        self.open(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn File>> {
+        // append 模式下写入总是在文件末尾，不受读取时 seek 的影响
+        let file = std::fs::OpenOptions::new().read(true).append(true).open(path)?;
+        Ok(Box::new(StdFile { file: Mutex::new(file) }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        std::fs::File::open(dir)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub use faulty::FaultyFs;
+
+#[cfg(test)]
+mod faulty {
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use super::{File, Fs};
+
+    /// 内存中的文件系统，模拟进程在写入过程中被杀死以及之后的断电重启
+    ///
+    /// 累计写入的字节数达到 budget 后，当前的写入只写入一部分并返回错误，之后的所有操作都失败。
+    /// 目录项的修改立即生效，文件内容只有 sync 之后才保证保留。
+    #[derive(Clone)]
+    pub struct FaultyFs {
+        inner: Arc<Mutex<Inner>>,
+    }
+
+    struct Inner {
+        files: HashMap<PathBuf, Arc<Mutex<Data>>>,
+        budget: u64,
+        written: u64,
+        killed: bool,
+    }
+
+    #[derive(Default)]
+    struct Data {
+        bytes: Vec<u8>,
+        // sync 过的长度
+        synced: usize,
+    }
+
+    struct FaultyFile {
+        fs: Arc<Mutex<Inner>>,
+        data: Arc<Mutex<Data>>,
+    }
+
+    impl FaultyFs {
+        /// 写入 budget 个字节后模拟进程被杀死
+        pub fn new(budget: u64) -> Self {
+            let inner = Inner { files: HashMap::new(), budget, written: 0, killed: false };
+            Self { inner: Arc::new(Mutex::new(inner)) }
+        }
+
+        /// 累计写入的字节数
+        pub fn written(&self) -> u64 {
+            self.inner.lock().unwrap().written
+        }
+
+        /// 模拟断电后重启：每个文件保留 sync 过的数据，以及之后写入的数据中随机长度的前缀
+        pub fn crash(&self, seed: u64) -> FaultyFs {
+            let mut rng = seed.max(1);
+            let inner = self.inner.lock().unwrap();
+            let files = inner.files.iter()
+                .map(|(path, data)| {
+                    let data = data.lock().unwrap();
+                    let unsynced = (data.bytes.len() - data.synced) as u64;
+                    let kept = data.synced + (xorshift(&mut rng) % (unsynced + 1)) as usize;
+                    let bytes = data.bytes[..kept].to_vec();
+                    (path.clone(), Arc::new(Mutex::new(Data { bytes, synced: kept })))
+                })
+                .collect();
+
+            let inner = Inner { files, budget: u64::MAX, written: 0, killed: false };
+            FaultyFs { inner: Arc::new(Mutex::new(inner)) }
+        }
+
+        fn check(&self) -> io::Result<std::sync::MutexGuard<'_, Inner>> {
+            let inner = self.inner.lock().unwrap();
+            if inner.killed {
+                return Err(killed());
+            }
+            Ok(inner)
+        }
+    }
+
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn killed() -> io::Error {
+        io::Error::other("process killed")
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, path.display().to_string())
+    }
+
+    impl File for FaultyFile {
+        fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+            let mut fs = self.fs.lock().unwrap();
+            if fs.killed {
+                return Err(killed());
+            }
+
+            let written = buf.len().min(fs.budget as usize);
+            fs.budget -= written as u64;
+            fs.written += written as u64;
+            self.data.lock().unwrap().bytes.extend_from_slice(&buf[..written]);
+
+            if written < buf.len() {
+                fs.killed = true;
+                return Err(killed());
+            }
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            if self.fs.lock().unwrap().killed {
+                return Err(killed());
+            }
+            let mut data = self.data.lock().unwrap();
+            data.synced = data.bytes.len();
+            Ok(())
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let data = self.data.lock().unwrap();
+            let start = offset as usize;
+            let Some(bytes) = data.bytes.get(start..start + buf.len()) else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past end of file"));
+            };
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.data.lock().unwrap().bytes.len() as u64)
+        }
+    }
+
+    impl Fs for FaultyFs {
+        fn create(&self, path: &Path) -> io::Result<Box<dyn File>> {
+            let data = Arc::new(Mutex::new(Data::default()));
+            self.check()?.files.insert(path.to_path_buf(), data.clone());
+            Ok(Box::new(FaultyFile { fs: self.inner.clone(), data }))
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn File>> {
+            let data = self.check()?.files.get(path).cloned().ok_or_else(|| not_found(path))?;
+            Ok(Box::new(FaultyFile { fs: self.inner.clone(), data }))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            let mut inner = self.check()?;
+            let data = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+            inner.files.insert(to.to_path_buf(), data);
+            Ok(())
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.check()?.files.remove(path).map(|_| ()).ok_or_else(|| not_found(path))
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+            let names = self.check()?.files.keys()
+                .filter(|path| path.parent() == Some(dir))
+                .filter_map(|path| path.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect();
+            Ok(names)
+        }
+
+        fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+            self.check().map(|_| ())
+        }
+
+        fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+            self.check().map(|_| ())
+        }
+    }
+}
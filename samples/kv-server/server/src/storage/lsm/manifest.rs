@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::corrupted;
+use crate::storage::lsm::fs::Fs;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+/// 已经持久化的引擎状态，不包括 WAL 中的修改
+///
+/// 每次修改都写入完整的文件，先写临时文件再重命名，重命名之后才算生效。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// 下一个可用的文件编号，SSTable 和 WAL 共用
+    pub next_file: u64,
+    /// 最早的需要回放的 WAL 的文件编号，编号更大的 WAL 也需要回放
+    pub wal: u64,
+    /// 每层 SSTable 的文件编号。第 0 层按写入顺序排列，其他层按 key 排列且互不重叠
+    pub levels: Vec<Vec<u64>>,
+    /// 命名空间的代数，FlushDb 后加一，旧代的数据不再可见，在合并时删除
+    pub generations: BTreeMap<String, u64>,
+    /// SSTable 中各命名空间近似的 key 数量，不会小于实际的数量
+    pub counts: BTreeMap<String, u64>,
+    /// 最近一次把 memtable 写入 SSTable 的时间，unix 时间戳（秒）
+    pub flushed_at: Option<u64>,
+    /// 所有 SSTable 和当前 WAL 都已经加密，之后打开时拒绝没有加密的数据
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl Manifest {
+    /// 文件不存在时返回 None
+    pub fn load(fs: &dyn Fs, dir: &Path) -> io::Result<Option<Self>> {
+        match fs.read(&dir.join(MANIFEST)) {
+            Ok(buf) => serde_json::from_slice(&buf).map(Some).map_err(|e| corrupted(format!("invalid manifest: {e}"))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, fs: &dyn Fs, dir: &Path) -> io::Result<()> {
+        let tmp = dir.join(MANIFEST_TMP);
+        let mut file = fs.create(&tmp)?;
+        file.append(&serde_json::to_vec(self)?)?;
+        file.sync()?;
+        fs.rename(&tmp, &dir.join(MANIFEST))?;
+        fs.sync_dir(dir)
+    }
+
+    /// 分配一个新的文件编号
+    pub fn allocate_file(&mut self) -> u64 {
+        self.next_file += 1;
+        self.next_file - 1
+    }
+
+    /// 数据目录中除当前引用的文件以外的文件都可以删除
+    pub fn is_obsolete(&self, name: &str) -> bool {
+        if name == MANIFEST {
+            return false;
+        }
+        if name == MANIFEST_TMP {
+            return true;
+        }
+        match name.split_once('.') {
+            Some((id, "sst")) => !self.levels.iter().flatten().any(|table| id.parse() == Ok(*table)),
+            Some((id, "wal")) => id.parse().is_ok_and(|id: u64| id < self.wal),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::vec;
+
+use kv_core::compress::{self, Counter};
+
+use crate::storage::crypto::{self, Cipher};
+use crate::storage::{corrupted, integrity_error};
+use crate::storage::lsm::bloom::{self, Bloom};
+use crate::storage::lsm::fs::File;
+use crate::storage::lsm::{Key, Value};
+
+/// 文件尾的魔数，"kvlsmsst"
+const MAGIC: u64 = 0x6b76_6c73_6d73_7374;
+/// 加密的 SSTable 文件尾的魔数，"kvlsmenc"
+const ENCRYPTED_MAGIC: u64 = 0x6b76_6c73_6d65_6e63;
+
+const FOOTER_SIZE: u64 = 40;
+
+/// 布隆过滤器中每个 key 占用的位数，误判率约为 1%
+const BITS_PER_KEY: usize = 10;
+
+/// 数据块在文件中的位置，len 包括块末尾的校验和
+#[derive(Debug)]
+struct BlockHandle {
+    // 块中的第一个 key
+    first: Key,
+    offset: u64,
+    len: u32,
+}
+
+/// 按 key 的顺序写入一个 SSTable 文件
+///
+/// 文件格式：
+///
+/// ```text
+/// 数据块 | 数据块 | ... | 索引块 | 布隆过滤器 | 文件尾
+/// ```
+///
+/// 数据块中按顺序存放条目，写满 block_bytes 后开始下一个块，开启压缩时较大的值单独压缩；索引块记录每个数据块的第一个 key 和位置，
+/// 以及整个文件的最后一个 key。数据块、索引块和布隆过滤器末尾都有 4 字节的 CRC32。
+/// 文件尾依次为索引块的位置和长度、布隆过滤器的位置和长度、条目数以及魔数。
+/// 开启加密时数据块、索引块和布隆过滤器加密后再计算校验和，文件尾不加密，使用另一个魔数。
+pub struct TableWriter {
+    file: Box<dyn File>,
+    block_bytes: usize,
+    block: Vec<u8>,
+    // 当前块的第一个 key
+    first: Option<Key>,
+    last: Option<Key>,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    offset: u64,
+    // 压缩阈值和压缩前后的字节数
+    compression: Option<(usize, Arc<Counter>)>,
+    // SSTable 的编号，作为加密的附加数据
+    cipher: Option<(u64, Arc<Cipher>)>,
+}
+
+impl TableWriter {
+    pub fn new(file: Box<dyn File>, block_bytes: usize) -> Self {
+        Self {
+            file,
+            block_bytes,
+            block: vec![],
+            first: None,
+            last: None,
+            index: vec![],
+            hashes: vec![],
+            offset: 0,
+            compression: None,
+            cipher: None,
+        }
+    }
+
+    /// 不小于 threshold 的值压缩后写入，压缩前后的字节数记录在 counter 中
+    pub fn with_compression(mut self, threshold: usize, counter: Arc<Counter>) -> Self {
+        self.compression = Some((threshold, counter));
+        self
+    }
+
+    /// 使用 cipher 的当前密钥加密编号为 id 的 SSTable
+    pub fn with_encryption(mut self, id: u64, cipher: Arc<Cipher>) -> Self {
+        self.cipher = Some((id, cipher));
+        self
+    }
+
+    /// key 必须大于之前写入的所有 key
+    pub fn add(&mut self, key: &Key, value: &Value) -> io::Result<()> {
+        debug_assert!(self.last.as_ref().is_none_or(|last| last < key));
+
+        if self.first.is_none() {
+            self.first = Some(key.clone());
+        }
+        encode_key(&mut self.block, key);
+        match (value, &self.compression) {
+            (None, _) => self.block.push(0),
+            (Some(value), None) => {
+                self.block.push(1);
+                put_str(&mut self.block, value);
+            }
+            (Some(value), Some((threshold, counter))) => match compress::compress(value.as_bytes(), *threshold) {
+                Some(compressed) => {
+                    counter.record(value.len(), compressed.len());
+                    self.block.push(2);
+                    put_bytes(&mut self.block, &compressed);
+                }
+                None => {
+                    counter.record(value.len(), value.len());
+                    self.block.push(1);
+                    put_str(&mut self.block, value);
+                }
+            },
+        }
+        self.hashes.push(key_hash(key));
+        self.last = Some(key.clone());
+
+        if self.block.len() >= self.block_bytes {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// 已经写入的大小
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// 写入索引、布隆过滤器和文件尾，sync 后返回文件大小
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.flush_block()?;
+        let Some(last) = self.last.take() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty table"));
+        };
+
+        let mut index = vec![];
+        put_u32(&mut index, self.index.len() as u32);
+        for handle in &self.index {
+            encode_key(&mut index, &handle.first);
+            index.extend_from_slice(&handle.offset.to_be_bytes());
+            put_u32(&mut index, handle.len);
+        }
+        encode_key(&mut index, &last);
+        let (index_offset, index_len) = self.write_checked(index)?;
+
+        let bloom = Bloom::build(&self.hashes, BITS_PER_KEY).encode();
+        let (bloom_offset, bloom_len) = self.write_checked(bloom)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE as usize);
+        footer.extend_from_slice(&index_offset.to_be_bytes());
+        put_u32(&mut footer, index_len);
+        footer.extend_from_slice(&bloom_offset.to_be_bytes());
+        put_u32(&mut footer, bloom_len);
+        footer.extend_from_slice(&(self.hashes.len() as u64).to_be_bytes());
+        let magic = if self.cipher.is_some() { ENCRYPTED_MAGIC } else { MAGIC };
+        footer.extend_from_slice(&magic.to_be_bytes());
+        self.file.append(&footer)?;
+        self.file.sync()?;
+
+        Ok(self.offset + FOOTER_SIZE)
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        let Some(first) = self.first.take() else {
+            return Ok(());
+        };
+        let block = mem::take(&mut self.block);
+        let (offset, len) = self.write_checked(block)?;
+        self.index.push(BlockHandle { first, offset, len });
+        Ok(())
+    }
+
+    // 加密并追加校验和后写入，返回位置和长度
+    fn write_checked(&mut self, mut buf: Vec<u8>) -> io::Result<(u64, u32)> {
+        if let Some((id, cipher)) = &self.cipher {
+            buf = cipher.encrypt(buf, &aad(*id, self.offset));
+        }
+        let crc = crc32fast::hash(&buf);
+        put_u32(&mut buf, crc);
+        self.file.append(&buf)?;
+
+        let offset = self.offset;
+        self.offset += buf.len() as u64;
+        Ok((offset, buf.len() as u32))
+    }
+}
+
+/// 打开的 SSTable，索引和布隆过滤器常驻内存，数据块在读取时加载
+pub struct Table {
+    pub id: u64,
+    pub smallest: Key,
+    pub largest: Key,
+    /// 文件大小
+    pub size: u64,
+    /// 加密使用的密钥 id，没有加密时为 None
+    pub key: Option<u32>,
+    file: Box<dyn File>,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Table {
+    /// 没有加密的 SSTable 不需要 cipher，加密的 SSTable 没有 cipher 时返回错误
+    pub fn open(id: u64, file: Box<dyn File>, cipher: Option<Arc<Cipher>>) -> io::Result<Self> {
+        let size = file.size()?;
+        if size < FOOTER_SIZE {
+            return Err(corrupted(format!("table {id} is truncated")));
+        }
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_at(&mut footer, size - FOOTER_SIZE)?;
+        let mut reader = Reader(&footer);
+        let (index_offset, index_len) = (reader.u64()?, reader.u32()?);
+        let (bloom_offset, bloom_len) = (reader.u64()?, reader.u32()?);
+        let _entries = reader.u64()?;
+        let cipher = match (reader.u64()?, cipher) {
+            (MAGIC, _) => None,
+            (ENCRYPTED_MAGIC, Some(cipher)) => Some(cipher),
+            (ENCRYPTED_MAGIC, None) => return Err(corrupted(format!("table {id} is encrypted, but no encryption key is configured"))),
+            _ => return Err(corrupted(format!("table {id} has invalid magic"))),
+        };
+
+        let buf = read_checked(&*file, index_offset, index_len)?;
+        let key = cipher.as_ref().and_then(|_| crypto::key_id(&buf));
+        let buf = decrypt(cipher.as_deref(), id, index_offset, buf)?;
+        let mut reader = Reader(&buf);
+        let count = reader.u32()?;
+        let index = (0..count)
+            .map(|_| Ok(BlockHandle { first: reader.key()?, offset: reader.u64()?, len: reader.u32()? }))
+            .collect::<io::Result<Vec<_>>>()?;
+        let largest = reader.key()?;
+
+        let bloom = decrypt(cipher.as_deref(), id, bloom_offset, read_checked(&*file, bloom_offset, bloom_len)?)?;
+        let bloom = Bloom::decode(&bloom)
+            .ok_or_else(|| corrupted(format!("table {id} has invalid bloom filter")))?;
+        let smallest = index.first()
+            .map(|handle| handle.first.clone())
+            .ok_or_else(|| corrupted(format!("table {id} is empty")))?;
+
+        Ok(Self { id, smallest, largest, size, key, file, index, bloom, cipher })
+    }
+
+    /// 查找 key，Some(None) 表示 key 已经被删除
+    pub fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+        if *key < self.smallest || *key > self.largest || !self.bloom.may_contain(key_hash(key)) {
+            return Ok(None);
+        }
+
+        // 第一个 key 不大于目标 key 的最后一个块
+        let block = self.index.partition_point(|handle| handle.first <= *key) - 1;
+        let entry = self.read_block(block)?.into_iter().find(|(k, _)| k == key);
+        Ok(entry.map(|(_, value)| value))
+    }
+
+    /// 从第一个不小于 start 的 key 开始按顺序遍历
+    pub fn iter(&self, start: Option<&Key>) -> TableIter<'_> {
+        let block = start.map_or(0, |key| self.index.partition_point(|handle| handle.first <= *key).saturating_sub(1));
+        TableIter {
+            table: self,
+            block,
+            entries: vec![].into_iter(),
+            start: start.cloned(),
+            failed: false,
+        }
+    }
+
+    /// key 的范围是否与 [smallest, largest] 有重叠
+    pub fn overlaps(&self, smallest: &Key, largest: &Key) -> bool {
+        self.smallest <= *largest && self.largest >= *smallest
+    }
+
+    /// 常驻内存的索引和布隆过滤器的大小
+    pub fn memory_bytes(&self) -> usize {
+        let index: usize = self.index.iter().map(|handle| handle.first.ns.len() + handle.first.key.len()).sum();
+        index + self.index.len() * mem::size_of::<BlockHandle>() + self.bloom.size()
+    }
+
+    fn read_block(&self, block: usize) -> io::Result<Vec<(Key, Value)>> {
+        let handle = &self.index[block];
+        let buf = read_checked(&*self.file, handle.offset, handle.len)?;
+        let buf = decrypt(self.cipher.as_deref(), self.id, handle.offset, buf)?;
+
+        let mut reader = Reader(&buf);
+        let mut entries = vec![];
+        while !reader.0.is_empty() {
+            let key = reader.key()?;
+            let value = match reader.u8()? {
+                0 => None,
+                1 => Some(reader.string()?),
+                2 => Some(decompress(reader.bytes_with_len()?)?),
+                tag => return Err(corrupted(format!("invalid value tag {tag}"))),
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+}
+
+/// 按顺序遍历 SSTable，读取出错后停止
+pub struct TableIter<'a> {
+    table: &'a Table,
+    // 下一个要读取的块
+    block: usize,
+    entries: vec::IntoIter<(Key, Value)>,
+    // 跳过小于 start 的 key
+    start: Option<Key>,
+    failed: bool,
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = io::Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.entries.next() {
+                if self.start.as_ref().is_some_and(|start| key < *start) {
+                    continue;
+                }
+                self.start = None;
+                return Some(Ok((key, value)));
+            }
+
+            if self.failed || self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    self.block += 1;
+                    self.entries = entries.into_iter();
+                }
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// 布隆过滤器使用的哈希值
+fn key_hash(key: &Key) -> u64 {
+    let mut buf = vec![];
+    encode_key(&mut buf, key);
+    bloom::hash(&buf)
+}
+
+fn encode_key(buf: &mut Vec<u8>, key: &Key) {
+    put_str(buf, &key.ns);
+    buf.extend_from_slice(&key.gen.to_be_bytes());
+    put_str(buf, &key.key);
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+fn decompress(bytes: &[u8]) -> io::Result<String> {
+    let value = compress::decompress(bytes, u32::MAX as usize).ok_or_else(|| corrupted(String::from("invalid compressed value")))?;
+    String::from_utf8(value).map_err(|e| corrupted(e.to_string()))
+}
+
+// 读取 len 字节并检查末尾的校验和，返回去掉校验和的数据
+fn read_checked(file: &dyn File, offset: u64, len: u32) -> io::Result<Vec<u8>> {
+    if len < 4 {
+        return Err(corrupted(format!("invalid block length {len}")));
+    }
+
+    let mut buf = vec![0; len as usize];
+    file.read_at(&mut buf, offset)?;
+    let crc = buf.split_off(len as usize - 4);
+    if crc32fast::hash(&buf).to_be_bytes() != crc[..] {
+        return Err(corrupted(format!("checksum mismatch at offset {offset}")));
+    }
+    Ok(buf)
+}
+
+/// 加密的附加数据：SSTable 编号和块的位置
+fn aad(id: u64, offset: u64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(&id.to_be_bytes());
+    aad[8..].copy_from_slice(&offset.to_be_bytes());
+    aad
+}
+
+// 没有加密时原样返回
+fn decrypt(cipher: Option<&Cipher>, id: u64, offset: u64, buf: Vec<u8>) -> io::Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher
+            .decrypt(buf, &aad(id, offset))
+            .map_err(|e| integrity_error(format!("table {id} block at offset {offset}: {e}"))),
+        None => Ok(buf),
+    }
+}
+
+/// 按顺序解码大端整数和带长度的字符串
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(corrupted(String::from("unexpected end of block")));
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn bytes_with_len(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes_with_len()?.to_vec()).map_err(|e| corrupted(e.to_string()))
+    }
+
+    fn key(&mut self) -> io::Result<Key> {
+        Ok(Key { ns: self.string()?, gen: self.u64()?, key: self.string()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use kv_core::compress::Counter;
+    use kv_core::error::KvError;
+
+    use crate::storage::crypto::Cipher;
+    use crate::storage::lsm::fs::{FaultyFs, Fs};
+    use crate::storage::lsm::sstable::{Table, TableWriter};
+    use crate::storage::lsm::Key;
+    use crate::storage::storage_error;
+
+    fn key(i: usize) -> Key {
+        Key { ns: String::from("default"), gen: 0, key: format!("k{i:04}") }
+    }
+
+    fn table(fs: &FaultyFs, entries: usize) -> Table {
+        let path = Path::new("/lsm/000001.sst");
+        let mut writer = TableWriter::new(fs.create(path).unwrap(), 256);
+        for i in 0..entries {
+            // 奇数 key 为删除标记
+            let value = (i % 2 == 0).then(|| format!("v{i}"));
+            writer.add(&key(i), &value).unwrap();
+        }
+        writer.finish().unwrap();
+        Table::open(1, fs.open(path).unwrap(), None).unwrap()
+    }
+
+    #[test]
+    fn table_should_be_read_by_key_and_range() {
+        let fs = FaultyFs::new(u64::MAX);
+        let table = table(&fs, 1000);
+        assert_eq!(key(0), table.smallest);
+        assert_eq!(key(999), table.largest);
+
+        assert_eq!(Some(Some(String::from("v500"))), table.get(&key(500)).unwrap());
+        assert_eq!(Some(None), table.get(&key(501)).unwrap());
+        assert_eq!(None, table.get(&key(1000)).unwrap());
+
+        let from: Vec<Key> = table.iter(Some(&key(990))).map(|entry| entry.unwrap().0).collect();
+        assert_eq!((990..1000).map(key).collect::<Vec<_>>(), from);
+        assert_eq!(1000, table.iter(None).count());
+    }
+
+    #[test]
+    fn large_values_should_be_compressed() {
+        let fs = FaultyFs::new(u64::MAX);
+        let path = Path::new("/lsm/000001.sst");
+        let counter = Arc::new(Counter::default());
+        let mut writer = TableWriter::new(fs.create(path).unwrap(), 256).with_compression(64, counter.clone());
+        let big = "json".repeat(100);
+        writer.add(&key(0), &Some(big.clone())).unwrap();
+        writer.add(&key(1), &Some(String::from("small"))).unwrap();
+        writer.add(&key(2), &None).unwrap();
+        let size = writer.finish().unwrap();
+        assert!(size < big.len() as u64);
+
+        let info = counter.info();
+        assert_eq!(big.len() as u64 + 5, info.raw_bytes);
+        assert!(info.ratio() > 2.0);
+
+        let table = Table::open(1, fs.open(path).unwrap(), None).unwrap();
+        assert_eq!(Some(Some(big)), table.get(&key(0)).unwrap());
+        assert_eq!(Some(Some(String::from("small"))), table.get(&key(1)).unwrap());
+        assert_eq!(Some(None), table.get(&key(2)).unwrap());
+    }
+
+    #[test]
+    fn corrupted_block_should_be_detected() {
+        let fs = FaultyFs::new(u64::MAX);
+        drop(table(&fs, 10));
+
+        // 修改第一个数据块中的一个字节
+        let path = Path::new("/lsm/000001.sst");
+        let mut bytes = fs.read(path).unwrap();
+        bytes[20] ^= 0xff;
+        fs.create(path).unwrap().append(&bytes).unwrap();
+
+        let table = Table::open(1, fs.open(path).unwrap(), None).unwrap();
+        assert!(table.get(&key(0)).is_err());
+        assert!(table.iter(None).any(|entry| entry.is_err()));
+    }
+
+    #[test]
+    fn encrypted_table_should_be_authenticated() {
+        let fs = FaultyFs::new(u64::MAX);
+        let path = Path::new("/lsm/000001.sst");
+        let cipher = Arc::new(Cipher::new(&[[7; 32]]).unwrap());
+        let mut writer = TableWriter::new(fs.create(path).unwrap(), 4096).with_encryption(1, cipher.clone());
+        for i in 0..10 {
+            writer.add(&key(i), &Some(format!("secret-{i}"))).unwrap();
+        }
+        writer.finish().unwrap();
+        let bytes = fs.read(path).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("secret"));
+
+        let table = Table::open(1, fs.open(path).unwrap(), Some(cipher.clone())).unwrap();
+        assert_eq!(Some(cipher.current()), table.key);
+        assert_eq!(Some(Some(String::from("secret-3"))), table.get(&key(3)).unwrap());
+        assert_eq!(10, table.iter(None).count());
+        // 没有密钥或者编号不符时无法打开
+        assert!(Table::open(1, fs.open(path).unwrap(), None).is_err());
+        assert!(Table::open(2, fs.open(path).unwrap(), Some(cipher.clone())).is_err());
+
+        // 修改数据块并更新校验和，解密失败转换为 IntegrityError
+        let index_offset = u64::from_be_bytes(bytes[bytes.len() - 40..bytes.len() - 32].try_into().unwrap()) as usize;
+        let mut tampered = bytes.clone();
+        tampered[0] ^= 1;
+        let crc = crc32fast::hash(&tampered[..index_offset - 4]);
+        tampered[index_offset - 4..index_offset].copy_from_slice(&crc.to_be_bytes());
+        fs.create(path).unwrap().append(&tampered).unwrap();
+
+        let table = Table::open(1, fs.open(path).unwrap(), Some(cipher)).unwrap();
+        let err = storage_error("get", "k0003")(table.get(&key(3)).unwrap_err());
+        assert!(matches!(err, KvError::IntegrityError(message) if message.contains("table 1 block at offset 0")));
+    }
+}
@@ -0,0 +1,182 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use kv_core::domain::KV;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::crypto::Cipher;
+use crate::storage::lsm::fs::{File, Fs};
+use crate::storage::{corrupted, integrity_error};
+
+/// 长度的最高位表示记录是加密的
+const ENCRYPTED: u32 = 1 << 31;
+
+/// WAL 中的一条记录，对应一次写操作，恢复时整条记录要么全部生效，要么全部不生效
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Record {
+    Set { ns: String, kvs: Vec<KV> },
+    Del { ns: String, keys: Vec<String> },
+    Flush { ns: String },
+}
+
+/// 预写日志，每条记录为 4 字节长度、4 字节 CRC32 和 JSON 格式的记录
+///
+/// 配置了加密时记录加密后写入，CRC32 为密文的校验和。
+pub struct Wal {
+    pub id: u64,
+    file: Box<dyn File>,
+    size: u64,
+    // 每次写入后 sync
+    sync: bool,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Wal {
+    pub fn create(fs: &dyn Fs, path: &Path, id: u64, sync: bool, cipher: Option<Arc<Cipher>>) -> io::Result<Self> {
+        let mut file = fs.create(path)?;
+        file.sync()?;
+        Ok(Self { id, file, size: 0, sync, cipher })
+    }
+
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut payload = serde_json::to_vec(record)?;
+        let mut len = payload.len() as u32;
+        if let Some(cipher) = &self.cipher {
+            payload = cipher.encrypt(payload, &aad(self.id, self.size));
+            len = payload.len() as u32 | ENCRYPTED;
+        }
+
+        let mut buf = Vec::with_capacity(payload.len() + 8);
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        buf.extend_from_slice(&payload);
+
+        self.file.append(&buf)?;
+        if self.sync {
+            self.file.sync()?;
+        }
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// 读取 WAL 中的所有记录
+///
+/// 写入过程中崩溃时最后一条记录可能不完整，遇到长度不足或校验和不一致的记录时停止，
+/// 之后的数据都被丢弃。文件不存在时返回空。校验和正确但无法解密的记录不是写入不完整造成的，返回错误。
+/// encrypted 为 true 时没有加密的记录同样返回错误。
+pub fn replay(fs: &dyn Fs, path: &Path, id: u64, cipher: Option<&Cipher>, encrypted: bool) -> io::Result<Vec<Record>> {
+    let buf = match fs.read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = vec![];
+    let mut rest = &buf[..];
+    while rest.len() >= 8 {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap());
+        let crc = u32::from_be_bytes(rest[4..8].try_into().unwrap());
+        let (is_encrypted, len) = (len & ENCRYPTED != 0, (len & !ENCRYPTED) as usize);
+        let Some(payload) = rest.get(8..8 + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        let offset = (buf.len() - rest.len()) as u64;
+        let payload = match (is_encrypted, cipher) {
+            (false, _) if encrypted => return Err(integrity_error(format!("wal {id} record at offset {offset} is not encrypted"))),
+            (false, _) => payload.to_vec(),
+            (true, Some(cipher)) => cipher.decrypt(payload.to_vec(), &aad(id, offset))
+                .map_err(|e| integrity_error(format!("wal {id} record at offset {offset}: {e}")))?,
+            (true, None) => return Err(corrupted(format!("wal {id} is encrypted, but no encryption key is configured"))),
+        };
+        let Ok(record) = serde_json::from_slice(&payload) else {
+            break;
+        };
+
+        records.push(record);
+        rest = &rest[8 + len..];
+    }
+    Ok(records)
+}
+
+/// 加密的附加数据：WAL 编号和记录的位置
+fn aad(id: u64, offset: u64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(&id.to_be_bytes());
+    aad[8..].copy_from_slice(&offset.to_be_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::sync::Arc;
+
+    use crate::storage::crypto::Cipher;
+    use crate::storage::lsm::fs::{FaultyFs, Fs};
+    use crate::storage::lsm::wal::{replay, Record, Wal};
+
+    fn flush(ns: &str) -> Record {
+        Record::Flush { ns: ns.to_string() }
+    }
+
+    #[test]
+    fn torn_tail_should_be_ignored() {
+        let path = Path::new("/lsm/000001.wal");
+        let fs = FaultyFs::new(u64::MAX);
+        let mut wal = Wal::create(&fs, path, 1, true, None).unwrap();
+        wal.append(&flush("a")).unwrap();
+        wal.append(&flush("b")).unwrap();
+        assert_eq!(vec![flush("a"), flush("b")], replay(&fs, path, 1, None, false).unwrap());
+
+        // 去掉最后一个字节
+        let bytes = fs.read(path).unwrap();
+        fs.create(path).unwrap().append(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(vec![flush("a")], replay(&fs, path, 1, None, false).unwrap());
+
+        assert!(replay(&fs, Path::new("/lsm/missing.wal"), 1, None, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn encrypted_records_should_be_authenticated() {
+        let path = Path::new("/lsm/000001.wal");
+        let fs = FaultyFs::new(u64::MAX);
+        let cipher = Arc::new(Cipher::new(&[[7; 32]]).unwrap());
+        let mut wal = Wal::create(&fs, path, 1, true, Some(cipher.clone())).unwrap();
+        wal.append(&flush("secret-namespace")).unwrap();
+        wal.append(&flush("b")).unwrap();
+        let bytes = fs.read(path).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("secret-namespace"));
+        assert_eq!(vec![flush("secret-namespace"), flush("b")], replay(&fs, path, 1, Some(&cipher), false).unwrap());
+
+        // 没有密钥、换了编号或者密文被修改（校验和同时更新）时返回错误，而不是当作不完整的记录丢弃
+        assert!(replay(&fs, path, 1, None, false).is_err());
+        assert!(replay(&fs, path, 2, Some(&cipher), false).is_err());
+        let mut tampered = bytes.clone();
+        tampered[10] ^= 1;
+        let len = u32::from_be_bytes(tampered[..4].try_into().unwrap()) as usize & !(1 << 31);
+        let crc = crc32fast::hash(&tampered[8..8 + len]);
+        tampered[4..8].copy_from_slice(&crc.to_be_bytes());
+        fs.create(path).unwrap().append(&tampered).unwrap();
+        assert!(replay(&fs, path, 1, Some(&cipher), false).unwrap_err().to_string().contains("authentication failed"));
+
+        // 末尾不完整的加密记录仍然忽略
+        fs.create(path).unwrap().append(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(vec![flush("secret-namespace")], replay(&fs, path, 1, Some(&cipher), false).unwrap());
+
+        // 完成加密后，没有加密的记录不能替换加密的记录
+        let mut plain = Wal::create(&fs, path, 1, true, None).unwrap();
+        plain.append(&flush("b")).unwrap();
+        assert!(replay(&fs, path, 1, Some(&cipher), true).unwrap_err().to_string().contains("not encrypted"));
+        assert_eq!(vec![flush("b")], replay(&fs, path, 1, Some(&cipher), false).unwrap());
+    }
+}
@@ -1,14 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Bound;
 use std::sync::RwLock;
+
+use im::OrdMap;
 use kv_core::domain::KV;
 use kv_core::error::KvError;
 
-use crate::storage::Storage;
+use crate::storage::{Cursor, Storage, StorageStats};
+
+/// 每个条目的固定开销
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<(String, String)>();
 
+/// 内存存储引擎，进程退出后数据丢失
+///
+/// 每个命名空间是一棵持久化的有序树，游标复制时共享所有节点；之后的写操作只复制从根到被修改的 key 的路径上的节点。
 #[derive(Debug, Default)]
-pub(crate) struct Memory {
+pub struct Memory {
     // todo: use better cache lib in future
-    map: RwLock<HashMap<String, String>>,
+    // 命名空间 -> key -> value，key 有序，便于按范围遍历
+    map: RwLock<HashMap<String, Namespace>>,
+}
+
+/// 一个命名空间中的数据，以及写操作时更新的估算内存占用，统计时不需要遍历所有 key
+#[derive(Debug, Default)]
+struct Namespace {
+    map: OrdMap<String, String>,
+    bytes: usize,
+}
+
+/// 游标固定的版本：按名称排序的命名空间，以及当前命名空间中上一次读到的 key
+struct MemoryCursor {
+    namespaces: VecDeque<(String, OrdMap<String, String>)>,
+    after: Option<String>,
 }
 
 impl Memory {
@@ -17,49 +40,159 @@ impl Memory {
     }
 }
 
+impl Namespace {
+    fn insert(&mut self, key: String, value: String) {
+        let added = entry_bytes(&key, &value);
+        let key_len = key.len();
+        if let Some(old) = self.map.insert(key, value) {
+            self.bytes -= key_len + old.len() + ENTRY_OVERHEAD;
+        }
+        self.bytes += added;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.map.remove(key) {
+            self.bytes -= entry_bytes(key, &old);
+        }
+    }
+}
+
+/// 估算值：key 和 value 占用的堆内存加上每个条目的固定开销
+fn entry_bytes(key: &str, value: &str) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
 impl Storage for Memory {
-    fn get(&self, key: &str) -> Result<Vec<String>, KvError> {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get(&self, ns: &str, key: &str) -> Result<Vec<String>, KvError> {
         let mut res = vec![];
 
         let guard = self.map.read().unwrap();
-        if let Some(v) = guard.get(key) {
+        if let Some(v) = guard.get(ns).and_then(|namespace| namespace.map.get(key)) {
             res.push(String::from(v));
         }
 
         Ok(res)
     }
 
-    fn mget(&self, keys: &[String]) -> Result<Vec<String>, KvError> {
+    fn mget(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
         let guard = self.map.read().unwrap();
+        let Some(namespace) = guard.get(ns) else {
+            return Ok(vec![]);
+        };
 
         let res = keys.iter()
-            .filter_map(|key| guard.get(key))
-            .map(|s| String::from(s))
+            .filter_map(|key| namespace.map.get(key))
+            .map(String::from)
             .collect();
 
         Ok(res)
     }
 
-    fn set(&self, key: String, value: String) -> Result<Vec<String>, KvError> {
-        self.map.write().unwrap().insert(key, value);
+    fn set(&self, ns: &str, key: String, value: String) -> Result<Vec<String>, KvError> {
+        self.map.write().unwrap().entry(ns.to_string()).or_default().insert(key, value);
         Ok(vec![])
     }
 
-    fn mset(&self, kvs: Vec<KV>) -> Result<Vec<String>, KvError> {
+    fn mset(&self, ns: &str, kvs: Vec<KV>) -> Result<Vec<String>, KvError> {
+        // 不创建空的命名空间
+        if kvs.is_empty() {
+            return Ok(vec![]);
+        }
+
         let mut guard = self.map.write().unwrap();
+        let namespace = guard.entry(ns.to_string()).or_default();
 
         for KV { key, value } in kvs {
-            guard.insert(key, value);
+            namespace.insert(key, value);
         }
 
         Ok(vec![])
     }
 
-    fn del(&self, keys: &[String]) -> Result<Vec<String>, KvError> {
+    fn del(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
         let mut guard = self.map.write().unwrap();
-        for key in keys {
-            guard.remove(key);
+        if let Some(namespace) = guard.get_mut(ns) {
+            for key in keys {
+                namespace.remove(key);
+            }
+            // 空的命名空间不再保留
+            if namespace.map.is_empty() {
+                guard.remove(ns);
+            }
         }
         Ok(vec![])
     }
+
+    fn flush(&self, ns: &str) -> Result<Vec<String>, KvError> {
+        self.map.write().unwrap().remove(ns);
+        Ok(vec![])
+    }
+
+    fn scan(&self, ns: &str, after: Option<&str>, count: usize) -> Result<Vec<String>, KvError> {
+        let guard = self.map.read().unwrap();
+        let Some(namespace) = guard.get(ns) else {
+            return Ok(vec![]);
+        };
+
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let keys = namespace.map.range::<_, str>((start, Bound::Unbounded))
+            .take(count)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        Ok(keys)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cursor>, KvError> {
+        let mut namespaces: Vec<_> = self.map.read().unwrap().iter().map(|(ns, namespace)| (ns.clone(), namespace.map.clone())).collect();
+        namespaces.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(Box::new(MemoryCursor { namespaces: namespaces.into(), after: None }))
+    }
+
+    fn stats(&self) -> StorageStats {
+        let guard = self.map.read().unwrap();
+
+        let memory_bytes = guard.values().map(|namespace| namespace.bytes).sum::<usize>();
+        let namespaces: BTreeMap<String, u64> = guard.iter()
+            .map(|(ns, namespace)| (ns.clone(), namespace.map.len() as u64))
+            .collect();
+
+        StorageStats {
+            keys: namespaces.values().sum(),
+            memory_bytes: memory_bytes as u64,
+            namespaces,
+            ..Default::default()
+        }
+    }
+}
+
+impl Cursor for MemoryCursor {
+    fn next_batch(&mut self, count: usize) -> Result<Vec<(String, KV)>, KvError> {
+        let mut entries = vec![];
+        while entries.len() < count {
+            let Some((ns, map)) = self.namespaces.front() else {
+                break;
+            };
+            let start = self.after.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+            let limit = count - entries.len();
+            let batch: Vec<_> = map.range::<_, str>((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(key, value)| (ns.clone(), KV { key: key.clone(), value: value.clone() }))
+                .collect();
+
+            // 不足 limit 条时这个命名空间已经读完
+            if batch.len() < limit {
+                self.namespaces.pop_front();
+                self.after = None;
+            } else {
+                self.after = batch.last().map(|(_, kv)| kv.key.clone());
+            }
+            entries.extend(batch);
+        }
+        Ok(entries)
+    }
 }
@@ -0,0 +1,333 @@
+//! 基于模型的随机测试：同一组随机请求分别作用于 BTreeMap 模型和存储引擎，结果必须完全一致，近似的 key 数量除外。
+//! 并发部分记录多线程读写的历史，检查 Memory 和 Tiered 的读写是否可线性化。
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use kv_core::domain::{Request, KV};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use uuid::Uuid;
+
+use crate::config::{BTreeConfig, LsmConfig};
+use crate::request_handler::{self, MAX_SCAN_COUNT};
+use crate::storage::btree::BTree;
+use crate::storage::lsm::fs::FaultyFs;
+use crate::storage::lsm::Lsm;
+use crate::storage::memory::Memory;
+use crate::storage::tiered::Tiered;
+use crate::storage::Storage;
+
+const NAMESPACES: [&str; 2] = ["a", "b"];
+
+/// key 的范围较小，保证读写经常命中同一个 key
+fn key() -> impl Strategy<Value = String> {
+    (0..8u8).prop_map(|i| format!("k{i}"))
+}
+
+fn value() -> impl Strategy<Value = String> {
+    "[a-z]{0,4}"
+}
+
+fn kv() -> impl Strategy<Value = KV> {
+    (key(), value()).prop_map(|(key, value)| KV { key, value })
+}
+
+/// 数据读写相关的请求
+fn request() -> impl Strategy<Value = Request> {
+    prop_oneof![
+        3 => key().prop_map(|key| Request::Get { key }),
+        2 => vec(key(), 0..5).prop_map(|keys| Request::MGet { keys }),
+        3 => kv().prop_map(|kv| Request::Set { kv }),
+        2 => vec(kv(), 0..5).prop_map(|kvs| Request::MSet { kvs }),
+        2 => vec(key(), 0..4).prop_map(|keys| Request::Del { keys }),
+        2 => (proptest::option::of(key()), 0..5usize).prop_map(|(after, count)| Request::Scan { after, count }),
+        1 => Just(Request::FlushDb),
+    ]
+}
+
+/// (命名空间, 请求) 的序列
+fn requests() -> impl Strategy<Value = Vec<(&'static str, Request)>> {
+    vec((prop::sample::select(&NAMESPACES[..]), request()), 0..64)
+}
+
+/// 参考模型：命名空间 -> key -> value
+#[derive(Default)]
+struct Model {
+    data: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Model {
+    fn apply(&mut self, ns: &str, request: Request) -> Vec<String> {
+        let map = self.data.entry(ns.to_string()).or_default();
+
+        let values = match request {
+            Request::Get { key } => map.get(&key).cloned().into_iter().collect(),
+            Request::MGet { keys } => keys.iter().filter_map(|key| map.get(key).cloned()).collect(),
+            Request::Set { kv } => {
+                map.insert(kv.key, kv.value);
+                vec![]
+            }
+            Request::MSet { kvs } => {
+                for kv in kvs {
+                    map.insert(kv.key, kv.value);
+                }
+                vec![]
+            }
+            Request::Del { keys } => {
+                for key in keys {
+                    map.remove(&key);
+                }
+                vec![]
+            }
+            Request::Scan { after, count } => map.keys()
+                .filter(|key| after.as_ref().is_none_or(|after| *key > after))
+                .take(count.min(MAX_SCAN_COUNT))
+                .cloned()
+                .collect(),
+            Request::FlushDb => {
+                map.clear();
+                vec![]
+            }
+            request => unreachable!("unexpected request {request:?}"),
+        };
+
+        self.data.retain(|_, map| !map.is_empty());
+        values
+    }
+
+    fn namespaces(&self) -> BTreeMap<String, u64> {
+        self.data.iter().map(|(ns, map)| (ns.clone(), map.len() as u64)).collect()
+    }
+}
+
+/// 依次执行请求，每个请求的结果、最终的统计信息和快照都要与模型一致
+fn check_against_model(store: &impl Storage, requests: Vec<(&str, Request)>) -> Result<(), TestCaseError> {
+    let mut model = Model::default();
+
+    for (i, (ns, request)) in requests.into_iter().enumerate() {
+        let expected = model.apply(ns, request.clone());
+        let actual = request_handler::handle(request.clone(), ns, store).map(|res| res.values);
+        prop_assert_eq!(Ok(expected), actual, "request #{} {:?} in {}", i, request, ns);
+    }
+
+    let stats = store.stats();
+    let keys = model.data.values().map(|map| map.len() as u64).sum::<u64>();
+    if stats.approximate {
+        // 近似的统计不小于实际的数量
+        for (ns, count) in model.namespaces() {
+            prop_assert!(stats.namespaces.get(&ns).is_some_and(|c| *c >= count), "{}: {:?} < {}", ns, stats.namespaces, count);
+        }
+        prop_assert!(stats.keys >= keys);
+    } else {
+        prop_assert_eq!(model.namespaces(), stats.namespaces);
+        prop_assert_eq!(keys, stats.keys);
+    }
+
+    let snapshot: Vec<(String, String, String)> = store.snapshot().unwrap().read_to_end().unwrap().into_iter()
+        .map(|(ns, kv)| (ns, kv.key, kv.value))
+        .collect();
+    let expected: Vec<(String, String, String)> = model.data.iter()
+        .flat_map(|(ns, map)| map.iter().map(move |(k, v)| (ns.clone(), k.clone(), v.clone())))
+        .collect();
+    prop_assert_eq!(expected, snapshot);
+    Ok(())
+}
+
+/// 单个 key 上的操作
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Get,
+    Set(String),
+    Del,
+}
+
+/// 一次操作的历史记录，call、ret 为全局递增的逻辑时间
+#[derive(Debug, Clone)]
+struct Event {
+    op: Op,
+    // Get 读到的值
+    observed: Option<String>,
+    call: u64,
+    ret: u64,
+}
+
+/// 多个线程并发执行 ops，返回每个 key 上的历史
+fn run_concurrently(store: &(impl Storage + Sync), ops: &[Vec<(String, Op)>]) -> BTreeMap<String, Vec<Event>> {
+    let clock = AtomicU64::new(0);
+
+    let histories: Vec<Vec<(String, Event)>> = thread::scope(|scope| {
+        let handles: Vec<_> = ops.iter()
+            .map(|ops| {
+                let clock = &clock;
+                scope.spawn(move || {
+                    ops.iter()
+                        .map(|(key, op)| {
+                            let call = clock.fetch_add(1, Ordering::SeqCst);
+                            let observed = match op {
+                                Op::Get => store.get("default", key).unwrap().into_iter().next(),
+                                Op::Set(value) => {
+                                    store.set("default", key.clone(), value.clone()).unwrap();
+                                    None
+                                }
+                                Op::Del => {
+                                    store.del("default", std::slice::from_ref(key)).unwrap();
+                                    None
+                                }
+                            };
+                            let ret = clock.fetch_add(1, Ordering::SeqCst);
+                            (key.clone(), Event { op: op.clone(), observed, call, ret })
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut by_key: BTreeMap<String, Vec<Event>> = BTreeMap::new();
+    for (key, event) in histories.into_iter().flatten() {
+        by_key.entry(key).or_default().push(event);
+    }
+    by_key
+}
+
+/// 检查单个 key 的历史是否可线性化（Wing & Gong 算法，记忆化搜索）
+///
+/// 不同 key 的操作互不影响，可线性化可以按 key 分别检查。初始值为不存在。
+fn linearizable(history: &[Event]) -> bool {
+    assert!(history.len() <= 128, "history too long");
+
+    fn search(history: &[Event], done: u128, value: Option<&str>, visited: &mut HashSet<(u128, Option<String>)>) -> bool {
+        if done.count_ones() as usize == history.len() {
+            return true;
+        }
+        if !visited.insert((done, value.map(String::from))) {
+            return false;
+        }
+
+        let pending = || history.iter().enumerate().filter(|(i, _)| done & (1 << i) == 0);
+        // 可以排在最前面的操作：在所有未完成的操作返回之前开始
+        let min_ret = pending().map(|(_, e)| e.ret).min().unwrap_or(u64::MAX);
+
+        for (i, event) in pending().filter(|(_, e)| e.call < min_ret) {
+            let next = done | (1 << i);
+            let found = match &event.op {
+                Op::Get => event.observed.as_deref() == value && search(history, next, value, visited),
+                Op::Set(v) => search(history, next, Some(v), visited),
+                Op::Del => search(history, next, None, visited),
+            };
+            if found {
+                return true;
+            }
+        }
+        false
+    }
+
+    search(history, 0, None, &mut HashSet::new())
+}
+
+fn thread_ops() -> impl Strategy<Value = Vec<(String, Op)>> {
+    let op = prop_oneof![
+        2 => Just(Op::Get),
+        // value 在所有线程中唯一，读到的值可以对应到具体的写入
+        2 => Just(Op::Set(String::new())),
+        1 => Just(Op::Del),
+    ];
+    vec(((0..3u8).prop_map(|i| format!("k{i}")), op), 1..24)
+}
+
+proptest! {
+    #[test]
+    fn memory_should_match_model(requests in requests()) {
+        check_against_model(&Memory::new(), requests)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn lsm_should_match_model(requests in requests()) {
+        // 很小的 memtable，随机请求会经过 SSTable 和合并
+        let config = LsmConfig { memtable_bytes: 256, block_bytes: 64, table_bytes: 256, l0_tables: 2, level_bytes: 512, ..LsmConfig::default() };
+        let store = Lsm::open_with(Arc::new(FaultyFs::new(u64::MAX)), &config).unwrap();
+        check_against_model(&store, requests)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn btree_should_match_model(requests in requests()) {
+        let dir = std::env::temp_dir().join(format!("kv-btree-{}", Uuid::new_v4()));
+        let store = BTree::open(&BTreeConfig { path: dir.join("data.btree"), sync: false, compress_threshold: None, encryption: None }).unwrap();
+        let result = check_against_model(&store, requests);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result?;
+    }
+
+    #[test]
+    fn tiered_should_match_model(requests in requests()) {
+        let store = Tiered::new(Memory::new(), 256);
+        check_against_model(&store, requests)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn memory_should_be_linearizable(ops in vec(thread_ops(), 2..5)) {
+        let store = Memory::new();
+        for (key, history) in run_concurrently(&store, &unique_values(ops)) {
+            prop_assert!(linearizable(&history), "history of {} is not linearizable: {:?}", key, history);
+        }
+    }
+
+    #[test]
+    fn tiered_should_be_linearizable(ops in vec(thread_ops(), 2..5)) {
+        // 缓存只能放下两个 key，读操作填充缓存与写操作交错进行
+        let store = Tiered::new(Memory::new(), 160);
+        for (key, history) in run_concurrently(&store, &unique_values(ops)) {
+            prop_assert!(linearizable(&history), "history of {} is not linearizable: {:?}", key, history);
+        }
+    }
+}
+
+/// 把写入的值替换为线程和序号，每个值只写入一次
+fn unique_values(ops: Vec<Vec<(String, Op)>>) -> Vec<Vec<(String, Op)>> {
+    ops.into_iter()
+        .enumerate()
+        .map(|(t, ops)| ops.into_iter()
+            .enumerate()
+            .map(|(i, (key, op))| match op {
+                Op::Set(_) => (key, Op::Set(format!("t{t}.{i}"))),
+                op => (key, op),
+            })
+            .collect())
+        .collect()
+}
+
+#[test]
+fn stale_read_should_not_be_linearizable() {
+    let event = |op, observed: Option<&str>, call, ret| Event { op, observed: observed.map(String::from), call, ret };
+
+    // 写入完成之后才开始的读取读到了旧值
+    let history = vec![
+        event(Op::Set(String::from("v1")), None, 0, 1),
+        event(Op::Get, None, 2, 3),
+    ];
+    assert!(!linearizable(&history));
+
+    // 读取与写入重叠时，读到新值或旧值都可以
+    let history = vec![
+        event(Op::Set(String::from("v1")), None, 0, 3),
+        event(Op::Get, None, 1, 2),
+        event(Op::Get, Some("v1"), 1, 4),
+    ];
+    assert!(linearizable(&history));
+}
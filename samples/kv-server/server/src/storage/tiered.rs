@@ -0,0 +1,327 @@
+//! 分层存储：热点 key 在内存中，冷 key 只在磁盘上
+//!
+//! Memory 作为持久化引擎的缓存。写操作先写入持久化引擎，再更新已经在缓存中的 key；
+//! 读操作先查缓存，未命中时从持久化引擎读取并放入缓存。缓存超过 cache_bytes 后按 LRU 淘汰最久没有访问的 key，
+//! 被淘汰的 key 仍然保存在持久化引擎中，内存大小与数据量无关。scan、快照和 key 数量直接使用持久化引擎。
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use kv_core::domain::{CacheInfo, KV};
+use kv_core::error::KvError;
+
+use crate::storage::memory::Memory;
+use crate::storage::{Cursor, Storage, StorageStats};
+
+/// 缓存中每个条目除 key 和 value 以外的估算开销
+const ENTRY_OVERHEAD: u64 = 64;
+
+pub struct Tiered<S> {
+    disk: S,
+    cache: Memory,
+    capacity: u64,
+    /// 缓存中的内容只在持有该锁时修改，与 lru 中的条目保持一致
+    lru: Mutex<Lru>,
+    // 写操作依次执行，保证缓存中的值与持久化引擎中最后写入的值一致
+    writes: Mutex<()>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    tick: u64,
+    /// 命名空间 -> key -> (最近访问的序号, 占用的字节数)
+    entries: HashMap<String, HashMap<String, (u64, u64)>>,
+    /// 最近访问的序号 -> (命名空间, key)，第一个是最久没有访问的
+    order: BTreeMap<u64, (String, String)>,
+    bytes: u64,
+    /// 每次写操作加一。读取持久化引擎期间发生了写操作时，读到的值可能已经过期，不放入缓存
+    epoch: u64,
+}
+
+impl Lru {
+    fn contains(&self, ns: &str, key: &str) -> bool {
+        self.entries.get(ns).is_some_and(|keys| keys.contains_key(key))
+    }
+
+    /// 更新最近访问的时间
+    fn touch(&mut self, ns: &str, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((last, _)) = self.entries.get_mut(ns).and_then(|keys| keys.get_mut(key)) {
+            let entry = self.order.remove(last).unwrap();
+            self.order.insert(tick, entry);
+            *last = tick;
+        }
+    }
+
+    fn insert(&mut self, ns: &str, key: String, size: u64) {
+        self.remove(ns, &key);
+        self.tick += 1;
+        self.order.insert(self.tick, (ns.to_string(), key.clone()));
+        self.entries.entry(ns.to_string()).or_default().insert(key, (self.tick, size));
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, ns: &str, key: &str) -> bool {
+        let Some(keys) = self.entries.get_mut(ns) else {
+            return false;
+        };
+        let Some((tick, size)) = keys.remove(key) else {
+            return false;
+        };
+        if keys.is_empty() {
+            self.entries.remove(ns);
+        }
+        self.order.remove(&tick);
+        self.bytes -= size;
+        true
+    }
+
+    fn remove_namespace(&mut self, ns: &str) {
+        for (_, (tick, size)) in self.entries.remove(ns).unwrap_or_default() {
+            self.order.remove(&tick);
+            self.bytes -= size;
+        }
+    }
+
+    /// 超过 capacity 时淘汰最久没有访问的 key
+    fn evict(&mut self, capacity: u64) -> Vec<(String, String)> {
+        let mut evicted = vec![];
+        while self.bytes > capacity {
+            let Some((_, (ns, key))) = self.order.pop_first() else {
+                break;
+            };
+            let (_, size) = self.entries.get_mut(&ns).and_then(|keys| keys.remove(&key)).unwrap();
+            if self.entries[&ns].is_empty() {
+                self.entries.remove(&ns);
+            }
+            self.bytes -= size;
+            evicted.push((ns, key));
+        }
+        evicted
+    }
+
+    fn keys(&self) -> u64 {
+        self.entries.values().map(|keys| keys.len() as u64).sum()
+    }
+}
+
+impl<S: Storage> Tiered<S> {
+    /// 缓存最多占用 cache_bytes 字节，为 0 时不缓存
+    pub fn new(disk: S, cache_bytes: u64) -> Self {
+        Self {
+            disk,
+            cache: Memory::new(),
+            capacity: cache_bytes,
+            lru: Mutex::new(Lru::default()),
+            writes: Mutex::new(()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn load(&self, ns: &str, key: &str) -> Result<Option<String>, KvError> {
+        if let Some(value) = self.cache.get(ns, key)?.pop() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.lru.lock().unwrap().touch(ns, key);
+            return Ok(Some(value));
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let epoch = self.lru.lock().unwrap().epoch;
+        let value = self.disk.get(ns, key)?.pop();
+        if let Some(value) = &value {
+            let mut lru = self.lru.lock().unwrap();
+            if lru.epoch == epoch {
+                self.admit(&mut lru, ns, key.to_string(), value.clone())?;
+            }
+        }
+        Ok(value)
+    }
+
+    /// 放入缓存，超过容量时淘汰其他 key；单个条目超过容量时不缓存
+    fn admit(&self, lru: &mut Lru, ns: &str, key: String, value: String) -> Result<(), KvError> {
+        let size = (ns.len() + key.len() + value.len()) as u64 + ENTRY_OVERHEAD;
+        if size > self.capacity {
+            if lru.remove(ns, &key) {
+                self.cache.del(ns, &[key])?;
+            }
+            return Ok(());
+        }
+
+        lru.insert(ns, key.clone(), size);
+        self.cache.set(ns, key, value)?;
+        for (ns, key) in lru.evict(self.capacity) {
+            self.cache.del(&ns, &[key])?;
+        }
+        Ok(())
+    }
+
+    pub fn cache_info(&self) -> CacheInfo {
+        let lru = self.lru.lock().unwrap();
+        CacheInfo {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            keys: lru.keys(),
+            bytes: lru.bytes,
+            capacity_bytes: self.capacity,
+        }
+    }
+}
+
+impl<S: Storage> Storage for Tiered<S> {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+
+    fn get(&self, ns: &str, key: &str) -> Result<Vec<String>, KvError> {
+        Ok(self.load(ns, key)?.into_iter().collect())
+    }
+
+    fn mget(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let mut res = vec![];
+        for key in keys {
+            res.extend(self.load(ns, key)?);
+        }
+        Ok(res)
+    }
+
+    fn set(&self, ns: &str, key: String, value: String) -> Result<Vec<String>, KvError> {
+        self.mset(ns, vec![KV { key, value }])
+    }
+
+    fn mset(&self, ns: &str, kvs: Vec<KV>) -> Result<Vec<String>, KvError> {
+        let _writes = self.writes.lock().unwrap();
+        self.disk.mset(ns, kvs.clone())?;
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.epoch += 1;
+        for kv in kvs {
+            // 写操作不把新的 key 放入缓存，避免批量写入把热点 key 淘汰
+            if lru.contains(ns, &kv.key) {
+                self.admit(&mut lru, ns, kv.key, kv.value)?;
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn del(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let _writes = self.writes.lock().unwrap();
+        self.disk.del(ns, keys)?;
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.epoch += 1;
+        for key in keys {
+            if lru.remove(ns, key) {
+                self.cache.del(ns, std::slice::from_ref(key))?;
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn flush(&self, ns: &str) -> Result<Vec<String>, KvError> {
+        let _writes = self.writes.lock().unwrap();
+        self.disk.flush(ns)?;
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.epoch += 1;
+        lru.remove_namespace(ns);
+        self.cache.flush(ns)
+    }
+
+    fn scan(&self, ns: &str, after: Option<&str>, count: usize) -> Result<Vec<String>, KvError> {
+        self.disk.scan(ns, after, count)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cursor>, KvError> {
+        self.disk.snapshot()
+    }
+
+    fn stats(&self) -> StorageStats {
+        let mut stats = self.disk.stats();
+        let cache = self.cache_info();
+        stats.memory_bytes += cache.bytes;
+        stats.cache = Some(cache);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::memory::Memory;
+    use crate::storage::tiered::{Tiered, ENTRY_OVERHEAD};
+    use crate::storage::Storage;
+
+    fn get(store: &impl Storage, key: &str) -> Option<String> {
+        store.get("default", key).unwrap().pop()
+    }
+
+    fn set(store: &impl Storage, key: &str, value: &str) {
+        store.set("default", key.to_string(), value.to_string()).unwrap();
+    }
+
+    /// 每个条目为 "default" + 两个字节的 key + 两个字节的 value
+    const ENTRY: u64 = 11 + ENTRY_OVERHEAD;
+
+    #[test]
+    fn reads_should_populate_cache() {
+        let store = Tiered::new(Memory::new(), 2 * ENTRY);
+        set(&store, "k1", "v1");
+        // 写操作不放入缓存
+        assert_eq!(0, store.cache_info().keys);
+
+        assert_eq!(Some("v1".to_string()), get(&store, "k1"));
+        assert_eq!(Some("v1".to_string()), get(&store, "k1"));
+        assert_eq!(None, get(&store, "k2"));
+        let info = store.cache_info();
+        assert_eq!((1, 2, 1, ENTRY), (info.hits, info.misses, info.keys, info.bytes));
+        assert!((info.hit_ratio() - 1.0 / 3.0).abs() < 1e-9);
+
+        // 缓存中的 key 被覆盖和删除
+        set(&store, "k1", "v2");
+        assert_eq!(Some("v2".to_string()), get(&store, "k1"));
+        store.del("default", &["k1".to_string()]).unwrap();
+        assert_eq!(None, get(&store, "k1"));
+        assert_eq!(0, store.cache_info().keys);
+
+        let stats = store.stats();
+        assert_eq!(2, stats.cache.unwrap().hits);
+        assert_eq!(0, stats.keys);
+    }
+
+    #[test]
+    fn least_recently_used_key_should_be_evicted() {
+        let store = Tiered::new(Memory::new(), 2 * ENTRY);
+        for key in ["k1", "k2", "k3"] {
+            set(&store, key, "vv");
+        }
+
+        get(&store, "k1");
+        get(&store, "k2");
+        // k1 比 k2 更近访问过，放入 k3 时淘汰 k2
+        get(&store, "k1");
+        get(&store, "k3");
+        assert_eq!(2, store.cache_info().keys);
+
+        let hits = store.cache_info().hits;
+        get(&store, "k1");
+        get(&store, "k3");
+        assert_eq!(hits + 2, store.cache_info().hits);
+        // 被淘汰的 key 仍然可以从磁盘读到
+        let misses = store.cache_info().misses;
+        assert_eq!(Some("vv".to_string()), get(&store, "k2"));
+        assert_eq!(misses + 1, store.cache_info().misses);
+
+        // 超过容量的条目不缓存
+        set(&store, "big", &"x".repeat(1000));
+        assert_eq!(Some("x".repeat(1000)), get(&store, "big"));
+        assert!(store.cache_info().bytes <= 2 * ENTRY);
+
+        store.flush("default").unwrap();
+        assert_eq!((0, 0), (store.cache_info().keys, store.cache_info().bytes));
+        assert_eq!(None, get(&store, "k1"));
+    }
+}
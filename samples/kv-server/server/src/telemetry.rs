@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use kv_core::domain::TraceContext;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::Context;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::TracingConfig;
+
+/// 初始化日志，配置了 tracing 时同时通过 OTLP 导出 span
+///
+/// 返回的 provider 需要在退出前调用 shutdown，把缓存的 span 发送出去。
+pub fn init(config: Option<&TracingConfig>) -> Result<Option<SdkTracerProvider>> {
+    let provider = match config {
+        Some(config) => {
+            let exporter = SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(&config.otlp_endpoint)
+                .build()?;
+            let resource = Resource::builder().with_service_name(config.service_name.clone()).build();
+            Some(SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource).build())
+        }
+        None => None,
+    };
+
+    let otel = provider.as_ref().map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("kv-server")));
+    tracing_subscriber::registry()
+        .with(LevelFilter::TRACE)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+
+    Ok(provider)
+}
+
+/// 从客户端传入的 W3C Trace Context 中解析出父 span
+pub fn extract(trace: &TraceContext) -> Context {
+    let mut carrier = HashMap::new();
+    carrier.insert(String::from("traceparent"), trace.traceparent.clone());
+    if let Some(state) = &trace.tracestate {
+        carrier.insert(String::from("tracestate"), state.clone());
+    }
+    TraceContextPropagator::new().extract(&carrier)
+}
@@ -0,0 +1,242 @@
+//! key 的变更订阅，gRPC 的 Watch 使用
+//!
+//! 写命令和脚本通过 [Observed] 写入存储，写入成功后通知同一命名空间中订阅了这些 key 或前缀的订阅者。
+//! 集群模式下每个节点应用已提交的日志时通知本节点的订阅者。
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use kv_core::domain::KV;
+use kv_core::error::KvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+use crate::config::PubSubConfig;
+use crate::storage::{Cursor, Storage, StorageStats};
+
+/// key 的一次变更
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyEvent {
+    Set { key: String, value: String },
+    Del { key: String },
+    /// 命名空间被清空
+    Flush,
+}
+
+impl KeyEvent {
+    fn key(&self) -> Option<&str> {
+        match self {
+            KeyEvent::Set { key, .. } | KeyEvent::Del { key } => Some(key),
+            KeyEvent::Flush => None,
+        }
+    }
+}
+
+struct Watcher {
+    ns: String,
+    keys: HashSet<String>,
+    prefixes: Vec<String>,
+    tx: mpsc::Sender<KeyEvent>,
+    // 队列满时通知订阅结束
+    lagged: Arc<Notify>,
+}
+
+impl Watcher {
+    fn matches(&self, ns: &str, event: &KeyEvent) -> bool {
+        self.ns == ns
+            && match event.key() {
+                Some(key) => self.keys.contains(key) || self.prefixes.iter().any(|p| key.starts_with(p.as_str())),
+                None => true,
+            }
+    }
+
+    fn send(&self, event: KeyEvent) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(event) {
+            self.lagged.notify_one();
+        }
+    }
+}
+
+/// 当前节点上所有的 key 订阅
+pub struct Watches {
+    queue_size: usize,
+    next_id: AtomicU64,
+    // 订阅 id -> 订阅者
+    watchers: RwLock<HashMap<u64, Watcher>>,
+}
+
+impl Watches {
+    /// 队列大小与发布订阅相同
+    pub fn new(config: &PubSubConfig) -> Self {
+        Self { queue_size: config.queue_size.max(1), next_id: AtomicU64::new(0), watchers: RwLock::new(HashMap::new()) }
+    }
+
+    /// 订阅命名空间 ns 中的 keys 以及以 prefixes 开头的 key，drop 返回值即可取消
+    pub fn watch(self: &Arc<Self>, ns: &str, keys: Vec<String>, prefixes: Vec<String>) -> KeyWatch {
+        let (tx, rx) = mpsc::channel(self.queue_size);
+        let lagged = Arc::new(Notify::new());
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let watcher = Watcher { ns: ns.to_string(), keys: keys.into_iter().collect(), prefixes, tx, lagged: lagged.clone() };
+        self.watchers.write().unwrap().insert(id, watcher);
+
+        KeyWatch { id, watches: self.clone(), rx, lagged }
+    }
+
+    fn notify(&self, ns: &str, events: impl IntoIterator<Item = KeyEvent>) {
+        let watchers = self.watchers.read().unwrap();
+        if watchers.is_empty() {
+            return;
+        }
+        for event in events {
+            for watcher in watchers.values().filter(|w| w.matches(ns, &event)) {
+                watcher.send(event.clone());
+            }
+        }
+    }
+}
+
+/// 一个 Watch 调用的订阅
+pub struct KeyWatch {
+    id: u64,
+    watches: Arc<Watches>,
+    rx: mpsc::Receiver<KeyEvent>,
+    lagged: Arc<Notify>,
+}
+
+impl KeyWatch {
+    /// 等待下一个变更，积压的变更超过队列大小时返回 SlowSubscriber
+    pub async fn recv(&mut self) -> Result<KeyEvent, KvError> {
+        tokio::select! {
+            biased;
+            _ = self.lagged.notified() => Err(KvError::SlowSubscriber(self.watches.queue_size)),
+            // 发送端在 Watches 中，drop 之前不会被关闭
+            event = self.rx.recv() => event.ok_or_else(|| KvError::Internal(String::from("Watch closed."))),
+        }
+    }
+}
+
+impl Drop for KeyWatch {
+    fn drop(&mut self) {
+        self.watches.watchers.write().unwrap().remove(&self.id);
+    }
+}
+
+/// 写入成功后通知订阅者的存储
+pub struct Observed<'a, S> {
+    storage: &'a S,
+    watches: &'a Watches,
+}
+
+impl<'a, S: Storage> Observed<'a, S> {
+    pub fn new(storage: &'a S, watches: &'a Watches) -> Self {
+        Self { storage, watches }
+    }
+}
+
+impl<S: Storage> Storage for Observed<'_, S> {
+    fn name(&self) -> &'static str {
+        self.storage.name()
+    }
+
+    fn get(&self, ns: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.storage.get(ns, key)
+    }
+
+    fn mget(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        self.storage.mget(ns, keys)
+    }
+
+    fn set(&self, ns: &str, key: String, value: String) -> Result<Vec<String>, KvError> {
+        let event = KeyEvent::Set { key: key.clone(), value: value.clone() };
+        let result = self.storage.set(ns, key, value)?;
+        self.watches.notify(ns, [event]);
+        Ok(result)
+    }
+
+    fn mset(&self, ns: &str, kvs: Vec<KV>) -> Result<Vec<String>, KvError> {
+        let events: Vec<_> = kvs.iter().map(|kv| KeyEvent::Set { key: kv.key.clone(), value: kv.value.clone() }).collect();
+        let result = self.storage.mset(ns, kvs)?;
+        self.watches.notify(ns, events);
+        Ok(result)
+    }
+
+    fn del(&self, ns: &str, keys: &[String]) -> Result<Vec<String>, KvError> {
+        let result = self.storage.del(ns, keys)?;
+        self.watches.notify(ns, keys.iter().map(|key| KeyEvent::Del { key: key.clone() }));
+        Ok(result)
+    }
+
+    fn flush(&self, ns: &str) -> Result<Vec<String>, KvError> {
+        let result = self.storage.flush(ns)?;
+        self.watches.notify(ns, [KeyEvent::Flush]);
+        Ok(result)
+    }
+
+    fn scan(&self, ns: &str, after: Option<&str>, count: usize) -> Result<Vec<String>, KvError> {
+        self.storage.scan(ns, after, count)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cursor>, KvError> {
+        self.storage.snapshot()
+    }
+
+    fn stats(&self) -> StorageStats {
+        self.storage.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use kv_core::domain::KV;
+    use kv_core::error::KvError;
+
+    use crate::config::PubSubConfig;
+    use crate::storage::memory::Memory;
+    use crate::storage::Storage;
+    use crate::watch::{KeyEvent, Observed, Watches};
+
+    fn set(key: &str, value: &str) -> KeyEvent {
+        KeyEvent::Set { key: key.to_string(), value: value.to_string() }
+    }
+
+    #[tokio::test]
+    async fn writes_should_notify_matching_watchers() {
+        let watches = Arc::new(Watches::new(&PubSubConfig::default()));
+        let storage = Memory::new();
+        let observed = Observed::new(&storage, &watches);
+        let mut watch = watches.watch("team-a", vec![String::from("k1")], vec![String::from("user:")]);
+
+        observed.set("team-a", String::from("k1"), String::from("v1")).unwrap();
+        // 其他命名空间和不匹配的 key 不通知
+        observed.set("team-b", String::from("k1"), String::from("v1")).unwrap();
+        observed.set("team-a", String::from("k2"), String::from("v2")).unwrap();
+        let kvs = vec![KV { key: String::from("user:1"), value: String::from("a") }, KV { key: String::from("k3"), value: String::from("b") }];
+        observed.mset("team-a", kvs).unwrap();
+        observed.del("team-a", &[String::from("k1"), String::from("k2")]).unwrap();
+        observed.flush("team-a").unwrap();
+
+        assert_eq!(set("k1", "v1"), watch.recv().await.unwrap());
+        assert_eq!(set("user:1", "a"), watch.recv().await.unwrap());
+        assert_eq!(KeyEvent::Del { key: String::from("k1") }, watch.recv().await.unwrap());
+        assert_eq!(KeyEvent::Flush, watch.recv().await.unwrap());
+
+        drop(watch);
+        assert!(watches.watchers.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_watcher_should_be_dropped() {
+        let watches = Arc::new(Watches::new(&PubSubConfig { queue_size: 2 }));
+        let storage = Memory::new();
+        let observed = Observed::new(&storage, &watches);
+        let mut watch = watches.watch("default", vec![], vec![String::new()]);
+
+        for i in 0..3 {
+            observed.set("default", format!("k{i}"), String::from("v")).unwrap();
+        }
+        assert!(matches!(watch.recv().await, Err(KvError::SlowSubscriber(2))));
+    }
+}